@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Write};
+use std::io;
+use std::path::Path;
+
+use crate::data_files::*;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BookImage {
+    pub src: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BookNode {
+    Text(String),
+    Break,
+    Paragraph,
+    Image(BookImage),
+    Switch { tag: String, attributes: Vec<(String, String)> },
+    Element { tag: String, attributes: Vec<(String, String)>, children: Vec<BookNode> },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BookIssue {
+    MalformedTag { line: usize },
+    UnclosedTag { line: usize, tag: String },
+    UnexpectedCloseTag { line: usize, tag: String },
+    MissingImageSource { line: usize },
+    MissingTexture { line: usize, src: String },
+}
+
+impl Display for BookIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BookIssue::MalformedTag { line } => write!(f, "line {}: unterminated tag", line),
+            BookIssue::UnclosedTag { line, tag } => write!(f, "line {}: {} tag is never closed", line, tag),
+            BookIssue::UnexpectedCloseTag { line, tag } => write!(f, "line {}: unexpected /{} tag", line, tag),
+            BookIssue::MissingImageSource { line } => write!(f, "line {}: IMG tag without SRC", line),
+            BookIssue::MissingTexture { line, src } => write!(f, "line {}: texture '{}' not found", line, src),
+        }
+    }
+}
+
+const VOID_TAGS: &[&str] = &["BR", "P", "IMG"];
+
+const SWITCH_TAGS: &[&str] = &["DIV", "FONT"];
+
+struct OpenElement {
+    tag: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<BookNode>,
+    line: usize,
+}
+
+fn parse_attributes(mut s: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    loop {
+        s = s.trim_start();
+        if s.is_empty() { break; }
+        let name_end = s.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(s.len());
+        let name = s[.. name_end].to_uppercase();
+        s = s[name_end ..].trim_start();
+        let value = if let Some(rest) = s.strip_prefix('=') {
+            let rest = rest.trim_start();
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                s = quoted.get(end + 1 ..).unwrap_or("");
+                quoted[.. end].to_string()
+            } else {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                s = &rest[end ..];
+                rest[.. end].to_string()
+            }
+        } else {
+            String::new()
+        };
+        attributes.push((name, value));
+    }
+    attributes
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct BookDocument {
+    pub nodes: Vec<BookNode>,
+    pub images: Vec<(usize, BookImage)>,
+}
+
+impl BookDocument {
+    pub fn parse(text: &str) -> (BookDocument, Vec<BookIssue>) {
+        let mut issues = Vec::new();
+        let mut images = Vec::new();
+        let mut stack = vec![OpenElement { tag: String::new(), attributes: Vec::new(), children: Vec::new(), line: 1 }];
+        let mut line = 1;
+        let mut rest = text;
+        while !rest.is_empty() {
+            let text_end = rest.find('<').unwrap_or(rest.len());
+            if text_end != 0 {
+                let text = &rest[.. text_end];
+                line += text.matches('\n').count();
+                let text = text.replace(&['\r', '\n'][..], "");
+                if !text.is_empty() {
+                    let children = &mut stack.last_mut().unwrap().children;
+                    if let Some(BookNode::Text(last)) = children.last_mut() {
+                        last.push_str(&text);
+                    } else {
+                        children.push(BookNode::Text(text));
+                    }
+                }
+                rest = &rest[text_end ..];
+                continue;
+            }
+            let tag_end = if let Some(tag_end) = rest.find('>') { tag_end } else {
+                issues.push(BookIssue::MalformedTag { line });
+                stack.last_mut().unwrap().children.push(BookNode::Text(rest.replace(&['\r', '\n'][..], "")));
+                break;
+            };
+            let tag_line = line;
+            let content = &rest[1 .. tag_end];
+            line += content.matches('\n').count();
+            rest = &rest[tag_end + 1 ..];
+            let (closing, content) = match content.trim_start().strip_prefix('/') {
+                Some(content) => (true, content),
+                None => (false, content.trim_start())
+            };
+            let name_end = content.find(char::is_whitespace).unwrap_or(content.len());
+            let tag = content[.. name_end].trim_end_matches('/').to_uppercase();
+            if tag.is_empty() {
+                issues.push(BookIssue::MalformedTag { line: tag_line });
+                continue;
+            }
+            if closing {
+                if VOID_TAGS.contains(&tag.as_str()) || SWITCH_TAGS.contains(&tag.as_str()) { continue; }
+                let position = stack.iter().skip(1).rposition(|x| x.tag == tag).map(|x| x + 1);
+                if let Some(position) = position {
+                    while stack.len() > position {
+                        let element = stack.pop().unwrap();
+                        if stack.len() != position {
+                            issues.push(BookIssue::UnclosedTag { line: element.line, tag: element.tag.clone() });
+                        }
+                        stack.last_mut().unwrap().children.push(BookNode::Element {
+                            tag: element.tag, attributes: element.attributes, children: element.children
+                        });
+                    }
+                } else {
+                    issues.push(BookIssue::UnexpectedCloseTag { line: tag_line, tag });
+                }
+                continue;
+            }
+            let attributes = parse_attributes(content[name_end ..].trim_end_matches('/'));
+            let node = match tag.as_str() {
+                "BR" => BookNode::Break,
+                "P" => BookNode::Paragraph,
+                "IMG" => {
+                    let image = BookImage {
+                        src: attribute(&attributes, "SRC").filter(|x| !x.is_empty()).map(String::from),
+                        width: attribute(&attributes, "WIDTH").and_then(|x| x.parse().ok()),
+                        height: attribute(&attributes, "HEIGHT").and_then(|x| x.parse().ok()),
+                    };
+                    if image.src.is_none() {
+                        issues.push(BookIssue::MissingImageSource { line: tag_line });
+                    }
+                    images.push((tag_line, image.clone()));
+                    BookNode::Image(image)
+                },
+                "DIV" | "FONT" => BookNode::Switch { tag, attributes },
+                _ => {
+                    stack.push(OpenElement { tag, attributes, children: Vec::new(), line: tag_line });
+                    continue;
+                }
+            };
+            stack.last_mut().unwrap().children.push(node);
+        }
+        while stack.len() > 1 {
+            let element = stack.pop().unwrap();
+            issues.push(BookIssue::UnclosedTag { line: element.line, tag: element.tag.clone() });
+            stack.last_mut().unwrap().children.push(BookNode::Element {
+                tag: element.tag, attributes: element.attributes, children: element.children
+            });
+        }
+        issues.sort_by_key(|x| match x {
+            BookIssue::MalformedTag { line } | BookIssue::UnclosedTag { line, .. } |
+            BookIssue::UnexpectedCloseTag { line, .. } | BookIssue::MissingImageSource { line } |
+            BookIssue::MissingTexture { line, .. } => *line
+        });
+        (BookDocument { nodes: stack.pop().unwrap().children, images }, issues)
+    }
+
+    pub fn from_record(record: &Record) -> Option<(BookDocument, Vec<BookIssue>)> {
+        record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+            (TEXT, Field::StringList(lines)) => Some(BookDocument::parse(&lines.join("\n"))),
+            _ => None
+        })
+    }
+
+    pub fn missing_textures(&self, textures: &HashSet<String>) -> Vec<BookIssue> {
+        self.images.iter().filter_map(|(line, image)| {
+            let src = image.src.as_ref()?;
+            let path = normalize_path(src);
+            let dds = match path.rfind('.') {
+                Some(dot) if !path[dot ..].contains('/') => format!("{}.dds", &path[.. dot]),
+                _ => format!("{}.dds", path)
+            };
+            if textures.contains(&path) || textures.contains(&dds) {
+                None
+            } else {
+                Some(BookIssue::MissingTexture { line: *line, src: src.clone() })
+            }
+        }).collect()
+    }
+
+    pub fn to_plain_text(&self) -> String {
+        let mut text = String::new();
+        plain_text(&self.nodes, &mut text);
+        text
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut text = String::new();
+        markdown(&self.nodes, &mut text);
+        text
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut text = String::new();
+        let mut state = HtmlState::default();
+        html(&self.nodes, &mut text, &mut state);
+        state.close(&mut text);
+        text
+    }
+}
+
+fn ensure_newline(text: &mut String) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+fn plain_text(nodes: &[BookNode], text: &mut String) {
+    for node in nodes {
+        match node {
+            BookNode::Text(s) => text.push_str(s),
+            BookNode::Break => text.push('\n'),
+            BookNode::Paragraph => {
+                ensure_newline(text);
+                text.push('\n');
+            },
+            BookNode::Image(_) => { },
+            BookNode::Switch { tag, .. } => if tag == "DIV" {
+                ensure_newline(text);
+            },
+            BookNode::Element { children, .. } => plain_text(children, text),
+        }
+    }
+}
+
+fn markdown(nodes: &[BookNode], text: &mut String) {
+    for node in nodes {
+        match node {
+            BookNode::Text(s) => for c in s.chars() {
+                if "\\*_`[]#".contains(c) {
+                    text.push('\\');
+                }
+                text.push(c);
+            },
+            BookNode::Break => text.push_str("  \n"),
+            BookNode::Paragraph => {
+                ensure_newline(text);
+                text.push('\n');
+            },
+            BookNode::Image(image) => if let Some(src) = &image.src {
+                write!(text, "![]({})", src.replace('\\', "/")).unwrap();
+            },
+            BookNode::Switch { tag, .. } => if tag == "DIV" {
+                ensure_newline(text);
+            },
+            BookNode::Element { tag, children, .. } => {
+                let emphasis = match tag.as_str() {
+                    "B" => "**",
+                    "I" => "*",
+                    _ => ""
+                };
+                text.push_str(emphasis);
+                markdown(children, text);
+                text.push_str(emphasis);
+            },
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[derive(Default)]
+struct HtmlState {
+    div: bool,
+    span: Option<String>,
+}
+
+impl HtmlState {
+    fn close(&mut self, text: &mut String) {
+        if self.span.is_some() {
+            text.push_str("</span>");
+        }
+        if self.div {
+            text.push_str("</div>\n");
+        }
+    }
+}
+
+fn html(nodes: &[BookNode], text: &mut String, state: &mut HtmlState) {
+    for node in nodes {
+        match node {
+            BookNode::Text(s) => text.push_str(&escape_html(s)),
+            BookNode::Break => text.push_str("<br>\n"),
+            BookNode::Paragraph => text.push_str("<p>"),
+            BookNode::Image(image) => {
+                text.push_str("<img");
+                if let Some(src) = &image.src {
+                    write!(text, " src=\"{}\"", escape_html(&src.replace('\\', "/"))).unwrap();
+                }
+                if let Some(width) = image.width {
+                    write!(text, " width=\"{}\"", width).unwrap();
+                }
+                if let Some(height) = image.height {
+                    write!(text, " height=\"{}\"", height).unwrap();
+                }
+                text.push('>');
+            },
+            BookNode::Switch { tag, attributes } if tag == "DIV" => {
+                state.close(text);
+                match attribute(attributes, "ALIGN") {
+                    Some(align) => write!(text, "<div style=\"text-align: {}\">", escape_html(&align.to_lowercase())).unwrap(),
+                    None => text.push_str("<div>")
+                }
+                state.div = true;
+                if let Some(span) = &state.span {
+                    text.push_str(span);
+                }
+            },
+            BookNode::Switch { attributes, .. } => {
+                if state.span.is_some() {
+                    text.push_str("</span>");
+                }
+                let mut style = Vec::new();
+                if let Some(color) = attribute(attributes, "COLOR") {
+                    style.push(format!("color: #{}", color.trim_start_matches('#')));
+                }
+                if let Some(face) = attribute(attributes, "FACE") {
+                    style.push(format!("font-family: '{}'", face));
+                }
+                let span = format!("<span style=\"{}\">", escape_html(&style.join("; ")));
+                text.push_str(&span);
+                state.span = Some(span);
+            },
+            BookNode::Element { tag, children, .. } => {
+                let (open, close) = match tag.as_str() {
+                    "B" => ("<b>", "</b>"),
+                    "I" => ("<i>", "</i>"),
+                    _ => ("", "")
+                };
+                text.push_str(open);
+                html(children, text, state);
+                text.push_str(close);
+            },
+        }
+    }
+}
+
+pub fn texture_index(data_files: &Path) -> io::Result<HashSet<String>> {
+    let mut files = HashMap::new();
+    if let Some(textures) = find_dir(data_files, "Textures") {
+        list_files(&textures, &textures, &mut files)?;
+    }
+    Ok(files.into_keys().collect())
+}
+
+pub fn validate_books<'a>(records: impl IntoIterator<Item=&'a Record>, textures: Option<&HashSet<String>>)
+    -> Vec<(usize, BookIssue)> {
+
+    let mut issues = Vec::new();
+    for (index, record) in records.into_iter().enumerate() {
+        if record.tag != BOOK || record.flags.contains(RecordFlags::DELETED) { continue; }
+        if let Some((document, mut book_issues)) = BookDocument::from_record(record) {
+            if let Some(textures) = textures {
+                book_issues.extend(document.missing_textures(textures));
+            }
+            issues.extend(book_issues.into_iter().map(|x| (index, x)));
+        }
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &[&str] = &[
+        "<DIV ALIGN=\"CENTER\"><FONT COLOR=\"000000\" SIZE=\"3\" FACE=\"Magic Cards\"><BR>",
+        "The Lusty Argonian <B>Maid</B><BR>",
+        "<IMG SRC=\"bookart\\letter_a.tga\" WIDTH=\"29\" HEIGHT=\"60\">ct One<P>",
+        "<DIV ALIGN=\"LEFT\">Scene_1</FONT>",
+    ];
+
+    #[test]
+    fn parse_and_render_book() {
+        let (document, issues) = BookDocument::parse(&SOURCE.join("\n"));
+        assert!(issues.is_empty());
+        assert_eq!(document.images, vec![(3, BookImage {
+            src: Some("bookart\\letter_a.tga".into()), width: Some(29), height: Some(60)
+        })]);
+        assert_eq!(document.to_plain_text(), "\nThe Lusty Argonian Maid\nct One\n\nScene_1");
+        assert_eq!(document.to_markdown(), "  \nThe Lusty Argonian **Maid**  \n![](bookart/letter_a.tga)ct One\n\nScene\\_1");
+        assert_eq!(document.to_html(), "\
+<div style=\"text-align: center\"><span style=\"color: #000000; font-family: 'Magic Cards'\"><br>
+The Lusty Argonian <b>Maid</b><br>
+<img src=\"bookart/letter_a.tga\" width=\"29\" height=\"60\">ct One<p></span></div>
+<div style=\"text-align: left\"><span style=\"color: #000000; font-family: 'Magic Cards'\">Scene_1</span></div>
+");
+        let textures = vec!["bookart/letter_a.dds".to_string()].into_iter().collect();
+        assert!(document.missing_textures(&textures).is_empty());
+        assert_eq!(document.missing_textures(&HashSet::new()), vec![
+            BookIssue::MissingTexture { line: 3, src: "bookart\\letter_a.tga".into() }
+        ]);
+    }
+
+    #[test]
+    fn validate_book_records() {
+        let records = vec![Record {
+            tag: BOOK,
+            flags: RecordFlags::empty(),
+            fields: vec![(TEXT, Field::StringList(vec!["<IMG WIDTH=\"1\"></FONT></B><I><BR".into()]))]
+        }];
+        assert_eq!(validate_books(&records, None).into_iter().map(|x| x.1.to_string()).collect::<Vec<_>>(), vec![
+            "line 1: IMG tag without SRC",
+            "line 1: unexpected /B tag",
+            "line 1: unterminated tag",
+            "line 1: I tag is never closed",
+        ]);
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashSet;
+
+use crate::field::*;
+use crate::record::*;
+use crate::strings::*;
+
+macro_rules! cell_members {
+    ($name:ident $(($flag:ident))? { $($tag:ident $member:ident: $ty:ty = $variant:ident),* $(,)? }) => {
+        #[derive(Debug, Clone, Default)]
+        pub struct $name {
+            $(pub $member: Option<$ty>,)*
+            $(pub $flag: bool,)?
+            pub extra: Vec<(Tag, Field)>,
+            layout: Vec<Tag>,
+        }
+
+        impl $name {
+            const TAGS: &'static [Tag] = &[$($tag),*];
+
+            fn take(&mut self, tag: Tag, field: Field) -> Option<Field> {
+                match (tag, field) {
+                    $(
+                    ($tag, Field::$variant(v)) if self.$member.is_none() => {
+                        self.$member = Some(v);
+                        None
+                    },
+                    )*
+                    (_, field) => Some(field)
+                }
+            }
+
+            fn get(&self, tag: Tag) -> Option<Field> {
+                match tag {
+                    $($tag => self.$member.clone().map(Field::$variant),)*
+                    _ => None
+                }
+            }
+
+            fn push(&mut self, tag: Tag, field: Field) {
+                if let Some(field) = self.take(tag, field) {
+                    self.extra.push((tag, field));
+                }
+                self.layout.push(tag);
+            }
+
+            fn write(&self, excluded: &[Tag], fields: &mut Vec<(Tag, Field)>) {
+                write_members(&self.layout, Self::TAGS, excluded, |tag| self.get(tag), &self.extra, fields);
+            }
+
+            pub fn to_fields(&self) -> Vec<(Tag, Field)> {
+                let mut fields = Vec::new();
+                self.write(&[], &mut fields);
+                fields
+            }
+        }
+    }
+}
+
+fn write_members(
+    layout: &[Tag], tags: &[Tag], excluded: &[Tag],
+    get: impl Fn(Tag) -> Option<Field>, extra: &[(Tag, Field)],
+    fields: &mut Vec<(Tag, Field)>
+) {
+    let rank = |tag: Tag| tags.iter().position(|&x| x == tag);
+    let mut members = Vec::new();
+    let mut written = HashSet::new();
+    let mut extra = extra.iter();
+    let mut last_rank = 0;
+    for &tag in layout {
+        match rank(tag) {
+            Some(tag_rank) if written.insert(tag) => {
+                last_rank = tag_rank;
+                if excluded.contains(&tag) { continue; }
+                if let Some(field) = get(tag) {
+                    members.push((tag_rank, tag, field));
+                }
+            },
+            _ => if let Some((tag, field)) = extra.next() {
+                members.push((last_rank, *tag, field.clone()));
+            }
+        }
+    }
+    for (tag_rank, &tag) in tags.iter().enumerate() {
+        if written.contains(&tag) || excluded.contains(&tag) { continue; }
+        if let Some(field) = get(tag) {
+            let index = members.iter().position(|&(r, _, _)| r > tag_rank).unwrap_or(members.len());
+            members.insert(index, (tag_rank, tag, field));
+        }
+    }
+    fields.extend(members.into_iter().map(|(_, tag, field)| (tag, field)));
+    fields.extend(extra.cloned());
+}
+
+cell_members!(CellHeader {
+    NAME name: StringZ = StringZ,
+    DATA cell: Cell = Cell,
+    RGNN region: StringZ = StringZ,
+    NAM5 map_color: i32 = I32,
+    INTV water_level: f32 = F32,
+    WHGT water_height: f32 = F32,
+    AMBI interior: Interior = Interior,
+    NAM0 temporary_count: i32 = I32,
+});
+
+cell_members!(Reference (temporary) {
+    MVRF moved: i32 = I32,
+    CNDT moved_to: Grid = Grid,
+    FRMR ref_num: i32 = I32,
+    NAME object: StringZ = StringZ,
+    XSCL scale: f32 = F32,
+    ANAM owner: StringZ = StringZ,
+    BNAM global: StringZ = StringZ,
+    CNAM faction: StringZ = StringZ,
+    INDX faction_rank: i32 = I32,
+    XSOL soul: StringZ = StringZ,
+    XCHG charge: f32 = F32,
+    INTV uses: f32 = F32,
+    NAM9 count: i32 = I32,
+    XHLT health: i32 = I32,
+    DODT door_destination: Position = Position,
+    DNAM door_destination_cell: StringZ = StringZ,
+    FLTV lock_level: f32 = F32,
+    KNAM key: StringZ = StringZ,
+    TNAM trap: StringZ = StringZ,
+    ZNAM blocked: u8 = U8,
+    DELE deleted: i32 = I32,
+    DATA position: Position = Position,
+});
+
+impl Reference {
+    pub fn is_moved(&self) -> bool { self.moved.is_some() }
+
+    pub fn is_deleted(&self) -> bool { self.deleted.is_some() }
+
+    pub fn object_index(&self) -> Option<u32> {
+        self.ref_num.or(self.moved).map(|x| (x as u32) & 0x00FF_FFFF)
+    }
+
+    pub fn master_index(&self) -> Option<u8> {
+        self.ref_num.or(self.moved).map(|x| ((x as u32) >> 24) as u8)
+    }
+
+    fn starts_with(tag: Tag, current: Option<&Reference>) -> bool {
+        match tag {
+            MVRF => true,
+            FRMR => !matches!(current, Some(x) if x.layout.iter().all(|&t| t == MVRF || t == CNDT)),
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FileContext<'a> {
+    pub name: Option<&'a str>,
+    pub masters: &'a [String],
+}
+
+pub(crate) fn file_masters(records: &[Record]) -> Vec<String> {
+    records.iter().filter(|x| x.tag == TES3).flat_map(|x| x.fields.iter()).filter_map(|(tag, field)| match (*tag, field) {
+        (MAST, Field::StringZ(s)) => Some(s.string.clone()),
+        _ => None
+    }).collect()
+}
+
+pub(crate) fn reference_master(reference: &Reference, file: FileContext) -> (Option<String>, u8) {
+    let master_index = reference.master_index().unwrap_or(0);
+    let master = match master_index {
+        0 => file.name,
+        index => file.masters.get(index as usize - 1).map(|x| x.as_str())
+    };
+    match master {
+        Some(master) => (Some(master.to_lowercase()), 0),
+        None => (None, master_index)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CellData {
+    pub header: CellHeader,
+    pub references: Vec<Reference>,
+}
+
+impl CellData {
+    pub fn from_fields(fields: &[(Tag, Field)]) -> CellData {
+        let mut cell = CellData::default();
+        let mut temporary = false;
+        for (tag, field) in fields {
+            let (tag, field) = (*tag, field.clone());
+            if tag == NAM0 && !temporary {
+                if let Field::I32(count) = field {
+                    temporary = true;
+                    if !cell.references.is_empty() {
+                        cell.header.temporary_count = Some(count);
+                        continue;
+                    }
+                }
+            }
+            if Reference::starts_with(tag, cell.references.last()) {
+                cell.references.push(Reference { temporary, ..Reference::default() });
+            }
+            if let Some(reference) = cell.references.last_mut() {
+                reference.push(tag, field);
+            } else {
+                cell.header.push(tag, field);
+            }
+        }
+        cell
+    }
+
+    pub fn to_fields(&self) -> Vec<(Tag, Field)> {
+        let mut fields = Vec::new();
+        let separate_count = !self.header.layout.contains(&NAM0);
+        self.header.write(if separate_count { &[NAM0] } else { &[] }, &mut fields);
+        let mut count_written = !separate_count;
+        for reference in &self.references {
+            if reference.temporary && !count_written {
+                self.write_temporary_count(&mut fields);
+                count_written = true;
+            }
+            reference.write(&[], &mut fields);
+        }
+        if !count_written {
+            self.write_temporary_count(&mut fields);
+        }
+        fields
+    }
+
+    fn write_temporary_count(&self, fields: &mut Vec<(Tag, Field)>) {
+        if let Some(count) = self.header.temporary_count {
+            fields.push((NAM0, Field::I32(count)));
+        }
+    }
+
+    pub fn persistent_references(&self) -> impl Iterator<Item=&Reference> {
+        self.references.iter().filter(|x| !x.temporary)
+    }
+
+    pub fn temporary_references(&self) -> impl Iterator<Item=&Reference> {
+        self.references.iter().filter(|x| x.temporary)
+    }
+
+    pub fn is_interior(&self) -> bool {
+        matches!(&self.header.cell, Some(x) if x.flags.contains(CellFlags::INTERIOR))
+    }
+
+    pub fn grid(&self) -> Option<Grid> {
+        self.header.cell.as_ref().filter(|x| !x.flags.contains(CellFlags::INTERIOR)).map(|x| x.grid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(x: f32) -> Field {
+        Field::Position(Position { x, y: 0.0, z: 0.0, x_rot: 0.0, y_rot: 0.0, z_rot: 0.0 })
+    }
+
+    fn cell_fields() -> Vec<(Tag, Field)> {
+        vec![
+            (NAME, Field::StringZ("".into())),
+            (DATA, Field::Cell(Cell { flags: CellFlags::HAS_WATER, grid: Grid { x: 2, y: -3 } })),
+            (RGNN, Field::StringZ("Bitter Coast Region".into())),
+            (FRMR, Field::I32(1)),
+            (NAME, Field::StringZ("door".into())),
+            (DODT, position(1.0)),
+            (DNAM, Field::StringZ("Seyda Neen".into())),
+            (DATA, position(2.0)),
+            (MVRF, Field::I32(0x0100_0005)),
+            (CNDT, Field::Grid(Grid { x: 3, y: -3 })),
+            (FRMR, Field::I32(0x0100_0005)),
+            (NAME, Field::StringZ("barrel".into())),
+            (DATA, position(3.0)),
+            (NAM0, Field::I32(1)),
+            (FRMR, Field::I32(2)),
+            (NAME, Field::StringZ("rock".into())),
+            (XSCL, Field::F32(1.5)),
+            (DATA, position(4.0)),
+            (NAM9, Field::U8List(vec![1, 2])),
+        ]
+    }
+
+    #[test]
+    fn cell_data_round_trip() {
+        let fields = cell_fields();
+        let cell = CellData::from_fields(&fields);
+        assert_eq!(cell.grid(), Some(Grid { x: 2, y: -3 }));
+        assert_eq!(cell.header.region.as_ref().map(|x| x.string.as_str()), Some("Bitter Coast Region"));
+        assert_eq!(cell.references.len(), 3);
+        assert_eq!(cell.references[0].door_destination_cell.as_ref().map(|x| x.string.as_str()), Some("Seyda Neen"));
+        assert!(cell.references[1].is_moved());
+        assert_eq!(cell.references[1].moved_to, Some(Grid { x: 3, y: -3 }));
+        assert_eq!(cell.references[1].master_index(), Some(1));
+        assert_eq!(cell.references[1].object_index(), Some(5));
+        assert_eq!(cell.temporary_references().count(), 1);
+        assert_eq!(cell.persistent_references().count(), 2);
+        assert_eq!(cell.references[2].scale, Some(1.5));
+        assert_eq!(cell.references[2].extra.len(), 1);
+        assert_eq!(cell.to_fields(), fields);
+    }
+
+    #[test]
+    fn cell_data_edit() {
+        let mut cell = CellData::from_fields(&cell_fields());
+        cell.references[0].scale = Some(2.0);
+        cell.references[2].scale = None;
+        cell.references.remove(1);
+        let fields = cell.to_fields();
+        assert_eq!(&fields[3 .. 9], &[
+            (FRMR, Field::I32(1)),
+            (NAME, Field::StringZ("door".into())),
+            (XSCL, Field::F32(2.0)),
+            (DODT, position(1.0)),
+            (DNAM, Field::StringZ("Seyda Neen".into())),
+            (DATA, position(2.0)),
+        ][..]);
+        assert_eq!(fields[9], (NAM0, Field::I32(1)));
+        assert_eq!(fields.len(), 14);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use crate::cell::*;
+use crate::diff::*;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Copy, Clone)]
+enum GmstValue {
+    Float(f32),
+    Int(i32),
+    String(&'static str),
+}
+
+const EVIL_GMSTS: &[(&str, GmstValue)] = &[
+    ("fCombatDistanceWerewolfMod", GmstValue::Float(0.3)),
+    ("fFleeDistance", GmstValue::Float(3000.0)),
+    ("fNPCHealthBarFade", GmstValue::Float(0.5)),
+    ("fNPCHealthBarTime", GmstValue::Float(5.0)),
+    ("fWereWolfAcrobatics", GmstValue::Float(1.5)),
+    ("fWereWolfAgility", GmstValue::Float(150.0)),
+    ("fWereWolfAlchemy", GmstValue::Float(100.0)),
+    ("fWereWolfAlteration", GmstValue::Float(100.0)),
+    ("fWereWolfArmorer", GmstValue::Float(100.0)),
+    ("fWereWolfAthletics", GmstValue::Float(150.0)),
+    ("fWereWolfAxe", GmstValue::Float(100.0)),
+    ("fWereWolfBlock", GmstValue::Float(100.0)),
+    ("fWereWolfBluntWeapon", GmstValue::Float(100.0)),
+    ("fWereWolfConjuration", GmstValue::Float(100.0)),
+    ("fWereWolfDestruction", GmstValue::Float(100.0)),
+    ("fWereWolfEnchant", GmstValue::Float(100.0)),
+    ("fWereWolfEndurance", GmstValue::Float(150.0)),
+    ("fWereWolfFatigue", GmstValue::Float(400.0)),
+    ("fWereWolfHandtoHand", GmstValue::Float(100.0)),
+    ("fWereWolfHealth", GmstValue::Float(2.0)),
+    ("fWereWolfHeavyArmor", GmstValue::Float(100.0)),
+    ("fWereWolfIllusion", GmstValue::Float(100.0)),
+    ("fWereWolfIntellegence", GmstValue::Float(0.1)),
+    ("fWereWolfLightArmor", GmstValue::Float(100.0)),
+    ("fWereWolfLongBlade", GmstValue::Float(100.0)),
+    ("fWereWolfLuck", GmstValue::Float(1.0)),
+    ("fWereWolfMagicka", GmstValue::Float(100.0)),
+    ("fWereWolfMarksman", GmstValue::Float(100.0)),
+    ("fWereWolfMediumArmor", GmstValue::Float(100.0)),
+    ("fWereWolfMerchantile", GmstValue::Float(1.0)),
+    ("fWereWolfMysticism", GmstValue::Float(100.0)),
+    ("fWereWolfPersonality", GmstValue::Float(1.0)),
+    ("fWereWolfRestoration", GmstValue::Float(100.0)),
+    ("fWereWolfRunMult", GmstValue::Float(1.3)),
+    ("fWereWolfSecurity", GmstValue::Float(100.0)),
+    ("fWereWolfShortBlade", GmstValue::Float(100.0)),
+    ("fWereWolfSilverWeaponDamageMult", GmstValue::Float(1.5)),
+    ("fWereWolfSneak", GmstValue::Float(1.0)),
+    ("fWereWolfSpear", GmstValue::Float(100.0)),
+    ("fWereWolfSpeechcraft", GmstValue::Float(1.0)),
+    ("fWereWolfSpeed", GmstValue::Float(150.0)),
+    ("fWereWolfStrength", GmstValue::Float(150.0)),
+    ("fWereWolfUnarmored", GmstValue::Float(100.0)),
+    ("fWereWolfWillPower", GmstValue::Float(1.0)),
+    ("iWereWolfBounty", GmstValue::Int(10000)),
+    ("iWereWolfFightMod", GmstValue::Int(100)),
+    ("iWereWolfFleeMod", GmstValue::Int(100)),
+    ("iWereWolfLevelToAttack", GmstValue::Int(20)),
+    ("sCompanionShare", GmstValue::String("Companion Share")),
+    ("sCompanionWarningButtonOne", GmstValue::String("Let the mercenary quit.")),
+    ("sCompanionWarningButtonTwo", GmstValue::String("Return to Companion Share display.")),
+    ("sCompanionWarningMessage", GmstValue::String(
+        "Your mercenary is poorer now than when he contracted with you.  Your mercenary will quit if you do not give him gold or goods to bring his Profit Value to a positive value."
+    )),
+    ("sDeleteNote", GmstValue::String("Delete Note?")),
+    ("sEditNote", GmstValue::String("Edit Note")),
+    ("sEffectSummonCreature01", GmstValue::String("sEffectSummonCreature01")),
+    ("sEffectSummonCreature02", GmstValue::String("sEffectSummonCreature02")),
+    ("sEffectSummonCreature03", GmstValue::String("sEffectSummonCreature03")),
+    ("sEffectSummonCreature04", GmstValue::String("sEffectSummonCreature04")),
+    ("sEffectSummonCreature05", GmstValue::String("sEffectSummonCreature05")),
+    ("sEffectSummonFabricant", GmstValue::String("sEffectSummonFabricant")),
+    ("sLevitateDisabled", GmstValue::String("Levitation magic does not work here.")),
+    ("sMagicCreature01ID", GmstValue::String("sMagicCreature01ID")),
+    ("sMagicCreature02ID", GmstValue::String("sMagicCreature02ID")),
+    ("sMagicCreature03ID", GmstValue::String("sMagicCreature03ID")),
+    ("sMagicCreature04ID", GmstValue::String("sMagicCreature04ID")),
+    ("sMagicCreature05ID", GmstValue::String("sMagicCreature05ID")),
+    ("sMagicFabricantID", GmstValue::String("Fabricant")),
+    ("sMaxSale", GmstValue::String("Max Sale")),
+    ("sProfitValue", GmstValue::String("Profit Value")),
+    ("sTeleportDisabled", GmstValue::String("Teleportation magic does not work here.")),
+    ("sWerewolfAlarmMessage", GmstValue::String("You have been detected changing from a werewolf state.")),
+    ("sWerewolfPopup", GmstValue::String("Werewolf")),
+    ("sWerewolfRefusal", GmstValue::String("You cannot do this as a werewolf.")),
+    ("sWerewolfRestMessage", GmstValue::String("You cannot rest in werewolf form.")),
+];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Removal {
+    Identical(RecordKey),
+    EvilGmst(RecordKey),
+    Reference(RecordKey, i32),
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CleanReport {
+    pub removed: Vec<Removal>,
+}
+
+impl Display for CleanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for removal in &self.removed {
+            match removal {
+                Removal::Identical(key) => writeln!(f, "- {} (identical to master)", key)?,
+                Removal::EvilGmst(key) => writeln!(f, "- {} (evil GMST)", key)?,
+                Removal::Reference(key, ref_num) => writeln!(f, "- {}: FRMR {} (unchanged reference)", key, ref_num)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_evil_gmst(key: &RecordKey, record: &Record) -> bool {
+    if key.tag != GMST { return false; }
+    let default = if let Some((_, default)) = EVIL_GMSTS.iter().find(|(name, _)| name.eq_ignore_ascii_case(&key.id)) {
+        default
+    } else {
+        return false;
+    };
+    record.fields.iter().any(|(tag, field)| match (*tag, field, default) {
+        (FLTV, Field::F32(value), GmstValue::Float(default)) => value.to_bits() == default.to_bits(),
+        (INTV, Field::I32(value), GmstValue::Int(default)) => value == default,
+        (STRV, Field::String(value), GmstValue::String(default)) => value == default,
+        _ => false
+    })
+}
+
+fn without_temporary_count(header: &CellHeader) -> Vec<(Tag, Field)> {
+    header.to_fields().into_iter().filter(|(tag, _)| *tag != NAM0).collect()
+}
+
+fn master_reference<'a>(masters: &'a [CellData], reference: &Reference) -> Option<&'a Reference> {
+    if reference.is_moved() { return None; }
+    let master = masters.get((reference.master_index()? as usize).checked_sub(1)?)?;
+    let object_index = reference.object_index()? as i32;
+    master.references.iter().find(|x| !x.is_moved() && x.ref_num == Some(object_index))
+}
+
+fn clean_cell(record: &mut Record, masters: &[CellData], key: &RecordKey, report: &mut CleanReport) -> bool {
+    let mut cell = CellData::from_fields(&record.fields);
+    let before = cell.references.len();
+    cell.references.retain(|reference| {
+        let redundant = match master_reference(masters, reference) {
+            Some(master) => {
+                let mut master = master.clone();
+                master.ref_num = reference.ref_num;
+                master.temporary = reference.temporary;
+                master.to_fields() == reference.to_fields()
+            },
+            None => false
+        };
+        if redundant {
+            report.removed.push(Removal::Reference(key.clone(), reference.ref_num.unwrap()));
+        }
+        !redundant
+    });
+    if cell.references.len() != before {
+        record.fields = cell.to_fields();
+    }
+    let header = without_temporary_count(&cell.header);
+    cell.references.is_empty() && masters.iter().any(|x| without_temporary_count(&x.header) == header)
+}
+
+pub fn clean_plugin(records: &mut Vec<Record>, masters: &[&[Record]]) -> CleanReport {
+    let mut report = CleanReport::default();
+    let mut master_records = HashMap::new();
+    let mut master_cells: HashMap<RecordLookup, Vec<CellData>> = HashMap::new();
+    for (index, master) in masters.iter().enumerate() {
+        for ((lookup, _), record) in record_keys(master).into_iter().zip(master.iter()) {
+            if record.tag == CELL {
+                let cells = master_cells.entry(lookup.clone()).or_insert_with(|| vec![CellData::default(); masters.len()]);
+                cells[index] = CellData::from_fields(&record.fields);
+            }
+            master_records.insert(lookup, record);
+        }
+    }
+    let keys = record_keys(records);
+    let mut removals = Vec::with_capacity(records.len());
+    for ((lookup, key), record) in keys.into_iter().zip(records.iter_mut()) {
+        let removal = if record.tag == TES3 {
+            None
+        } else if master_records.get(&lookup) == Some(&&*record) {
+            Some(Removal::Identical(key))
+        } else if is_evil_gmst(&key, record) && !master_records.contains_key(&lookup) {
+            Some(Removal::EvilGmst(key))
+        } else if let Some(cells) = master_cells.get(&lookup) {
+            if clean_cell(record, cells, &key, &mut report) { Some(Removal::Identical(key)) } else { None }
+        } else {
+            None
+        };
+        removals.push(removal);
+    }
+    for index in 0 .. records.len() {
+        if records[index].tag != DIAL || removals[index].is_none() { continue; }
+        let infos = records[index + 1 ..].iter().take_while(|x| x.tag == INFO).count();
+        if removals[index + 1 ..= index + infos].iter().any(|x| x.is_none()) {
+            removals[index] = None;
+        }
+    }
+    let mut kept = Vec::with_capacity(records.len());
+    for (record, removal) in records.drain(..).zip(removals) {
+        match removal {
+            Some(removal) => report.removed.push(removal),
+            None => kept.push(record),
+        }
+    }
+    *records = kept;
+    let count = records.iter().filter(|x| x.tag != TES3).count() as u32;
+    for record in records.iter_mut().filter(|x| x.tag == TES3) {
+        for (_, field) in &mut record.fields {
+            if let Field::FileMetadata(metadata) = field {
+                metadata.records = count;
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tag: Tag, fields: Vec<(Tag, Field)>) -> Record {
+        Record { tag, flags: RecordFlags::empty(), fields }
+    }
+
+    fn header(records: u32) -> Record {
+        record(TES3, vec![(HEDR, Field::FileMetadata(FileMetadata {
+            version: 0x3FA66666, file_type: FileType::ESP, author: String::new(), description: Vec::new(), records
+        }))])
+    }
+
+    fn gmst(name: &str, value: f32) -> Record {
+        record(GMST, vec![(NAME, Field::String(name.into())), (FLTV, Field::F32(value))])
+    }
+
+    fn cell(refs: &[(i32, &str)]) -> Record {
+        let mut fields = vec![
+            (NAME, Field::StringZ("Seyda Neen, Arrille's Tradehouse".into())),
+            (DATA, Field::Cell(Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } })),
+        ];
+        for &(ref_num, object) in refs {
+            fields.push((FRMR, Field::I32(ref_num)));
+            fields.push((NAME, Field::StringZ(object.into())));
+        }
+        record(CELL, fields)
+    }
+
+    #[test]
+    fn clean_against_master() {
+        let master = vec![
+            header(4),
+            gmst("fJumpAcrobaticsBase", 128.0),
+            cell(&[(1, "chest"), (2, "barrel")]),
+            record(DIAL, vec![(NAME, Field::StringZ("latest rumors".into()))]),
+            record(INFO, vec![(INAM, Field::StringZ("1".into())), (NAME, Field::String("Nothing new.".into()))]),
+        ];
+        let mut plugin = vec![
+            header(7),
+            gmst("fJumpAcrobaticsBase", 128.0),
+            gmst("fWereWolfRunMult", 1.3),
+            gmst("fWereWolfSpeed", 200.0),
+            gmst("fFallDamageDistanceMin", 500.0),
+            cell(&[(0x0100_0001, "chest"), (0x0100_0002, "barrel_02"), (3, "crate")]),
+            master[3].clone(),
+            record(INFO, vec![(INAM, Field::StringZ("1".into())), (NAME, Field::String("A ship came.".into()))]),
+        ];
+        let info = plugin[7].clone();
+        let report = clean_plugin(&mut plugin, &[&master]);
+        assert_eq!(plugin, vec![
+            header(5),
+            gmst("fWereWolfSpeed", 200.0),
+            gmst("fFallDamageDistanceMin", 500.0),
+            cell(&[(0x0100_0002, "barrel_02"), (3, "crate")]),
+            master[3].clone(),
+            info,
+        ]);
+        assert_eq!(report.to_string(), "\
+- CELL Seyda Neen, Arrille's Tradehouse: FRMR 16777217 (unchanged reference)
+- GMST fJumpAcrobaticsBase (identical to master)
+- GMST fWereWolfRunMult (evil GMST)
+");
+    }
+
+    #[test]
+    fn clean_redundant_cell() {
+        let master = vec![cell(&[(1, "chest")]), record(DIAL, vec![(NAME, Field::StringZ("rumors".into()))])];
+        let mut plugin = vec![cell(&[(0x0100_0001, "chest")]), master[1].clone()];
+        let report = clean_plugin(&mut plugin, &[&master]);
+        assert!(plugin.is_empty());
+        assert_eq!(report.removed, vec![
+            Removal::Reference(RecordKey { tag: CELL, id: "Seyda Neen, Arrille's Tradehouse".into() }, 0x0100_0001),
+            Removal::Identical(RecordKey { tag: CELL, id: "Seyda Neen, Arrille's Tradehouse".into() }),
+            Removal::Identical(RecordKey { tag: DIAL, id: "rumors".into() }),
+        ]);
+    }
+}
@@ -1,34 +1,235 @@
-use encoding::{Encoding};
-use encoding::all::{WINDOWS_1251, WINDOWS_1252};
-use macro_attr_2018::macro_attr;
-use enum_derive_2018::IterVariants;
+use encoding::{ByteWriter, CodecError, DecoderTrap, Encoding, RawDecoder, RawEncoder, StringWriter};
+use encoding::all::{WINDOWS_1250, WINDOWS_1251, WINDOWS_1252, WINDOWS_1253, WINDOWS_1254, WINDOWS_1257};
+use once_cell::sync::{self};
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::slice;
+use std::sync::{Arc, Mutex};
 
-macro_attr! {
-    #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
-    #[derive(IterVariants!(CodePageVariants))]
-    pub enum CodePage {
-        English,
-        Russian,
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum CodePage {
+    English,
+    Russian,
+    CentralEuropean,
+    Greek,
+    Turkish,
+    Baltic,
+    Custom(&'static CodeTable),
+}
+
+static BUILT_IN_CODE_PAGES: [CodePage; 6] = [
+    CodePage::English, CodePage::Russian, CodePage::CentralEuropean, CodePage::Greek, CodePage::Turkish, CodePage::Baltic
+];
+
+const MAX_CUSTOM_CODE_TABLES: usize = 64;
+
+static GREEK: sync::Lazy<CodeTable> = sync::Lazy::new(|| single_byte_table("windows-1253", WINDOWS_1253));
+
+static BALTIC: sync::Lazy<CodeTable> = sync::Lazy::new(|| single_byte_table("windows-1257", WINDOWS_1257));
+
+fn single_byte_table(name: &str, encoding: &dyn Encoding) -> CodeTable {
+    let mut chars = [0 as char; 256];
+    for (byte, c) in chars.iter_mut().enumerate() {
+        let decoded = encoding.decode(&[byte as u8], DecoderTrap::Strict).ok().and_then(|x| x.chars().next());
+        *c = decoded.unwrap_or_else(|| char::from(byte as u8));
     }
+    CodeTable::new(name, chars).unwrap()
 }
 
+static CUSTOM_CODE_TABLES: sync::Lazy<Mutex<Vec<&'static CodeTable>>> = sync::Lazy::new(|| Mutex::new(Vec::new()));
+
+#[derive(Debug, Clone)]
+pub struct CodePageVariants(slice::Iter<'static, CodePage>);
+
+impl Iterator for CodePageVariants {
+    type Item = CodePage;
+
+    fn next(&mut self) -> Option<CodePage> { self.0.next().copied() }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.0.size_hint() }
+}
+
+impl ExactSizeIterator for CodePageVariants { }
+
 impl CodePage {
+    pub fn iter_variants() -> CodePageVariants {
+        CodePageVariants(BUILT_IN_CODE_PAGES.iter())
+    }
+
+    pub fn custom(table: CodeTable) -> Result<CodePage, CodeTableError> {
+        let mut tables = CUSTOM_CODE_TABLES.lock().unwrap();
+        if let Some(&interned) = tables.iter().find(|&&x| *x == table) {
+            return Ok(CodePage::Custom(interned));
+        }
+        if tables.len() >= MAX_CUSTOM_CODE_TABLES {
+            return Err(CodeTableError::TooManyTables { limit: MAX_CUSTOM_CODE_TABLES });
+        }
+        let interned = Box::leak(Box::new(table));
+        tables.push(interned);
+        Ok(CodePage::Custom(interned))
+    }
+
     pub fn encoding(self) -> &'static dyn Encoding {
         match self {
             CodePage::English => WINDOWS_1252,
             CodePage::Russian => WINDOWS_1251,
+            CodePage::CentralEuropean => WINDOWS_1250,
+            CodePage::Greek => &*GREEK,
+            CodePage::Turkish => WINDOWS_1254,
+            CodePage::Baltic => &*BALTIC,
+            CodePage::Custom(table) => table,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CodeTableError {
+    NotAsciiCompatible { byte: u8 },
+    DuplicateChar { c: char, first: u8, second: u8 },
+    InvalidLine { line: usize },
+    TooManyTables { limit: usize },
+}
+
+impl Display for CodeTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodeTableError::NotAsciiCompatible { byte } =>
+                write!(f, "byte 0x{:02X} should map to the same ASCII char", byte),
+            CodeTableError::DuplicateChar { c, first, second } =>
+                write!(f, "the '{}' char is mapped by both 0x{:02X} and 0x{:02X} bytes", c, first, second),
+            CodeTableError::InvalidLine { line } => write!(f, "line {} is not a valid mapping", line),
+            CodeTableError::TooManyTables { limit } => write!(f, "no more than {} custom code tables can be used", limit),
+        }
+    }
+}
+
+impl Error for CodeTableError { }
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CodeTable {
+    name: String,
+    chars: Arc<[char; 256]>,
+}
+
+impl Debug for CodeTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CodeTable").field(&self.name).finish()
+    }
+}
+
+impl CodeTable {
+    pub fn new(name: impl Into<String>, chars: [char; 256]) -> Result<CodeTable, CodeTableError> {
+        for byte in 0u8 .. 128 {
+            if chars[byte as usize] != byte as char {
+                return Err(CodeTableError::NotAsciiCompatible { byte });
+            }
         }
+        for second in 128u8 ..= 255 {
+            let c = chars[second as usize];
+            if let Some(first) = (0u8 .. second).find(|&first| chars[first as usize] == c) {
+                return Err(CodeTableError::DuplicateChar { c, first, second });
+            }
+        }
+        Ok(CodeTable { name: name.into(), chars: Arc::new(chars) })
+    }
+
+    pub fn parse(name: impl Into<String>, text: &str) -> Result<CodeTable, CodeTableError> {
+        let mut chars = [0 as char; 256];
+        for (byte, c) in chars.iter_mut().enumerate() {
+            *c = char::from(byte as u8);
+        }
+        for (index, line) in text.lines().enumerate() {
+            let invalid = || CodeTableError::InvalidLine { line: index + 1 };
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() { continue; }
+            let mut columns = line.split_whitespace();
+            let byte = parse_hex(columns.next().unwrap()).filter(|&b| b <= 0xFF).ok_or_else(invalid)?;
+            let c = match columns.next() {
+                None => continue,
+                Some(c) => parse_hex(c).and_then(std::char::from_u32).ok_or_else(invalid)?
+            };
+            if columns.next().is_some() { return Err(invalid()); }
+            chars[byte as usize] = c;
+        }
+        CodeTable::new(name, chars)
     }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn chars(&self) -> &[char; 256] { &self.chars }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+impl Encoding for CodeTable {
+    fn name(&self) -> &'static str { "custom" }
+
+    fn raw_encoder(&self) -> Box<dyn RawEncoder> { Box::new(CodeTableCodec(self.chars.clone())) }
+
+    fn raw_decoder(&self) -> Box<dyn RawDecoder> { Box::new(CodeTableCodec(self.chars.clone())) }
+}
+
+struct CodeTableCodec(Arc<[char; 256]>);
+
+impl RawEncoder for CodeTableCodec {
+    fn from_self(&self) -> Box<dyn RawEncoder> { Box::new(CodeTableCodec(self.0.clone())) }
+
+    fn is_ascii_compatible(&self) -> bool { true }
+
+    fn raw_feed(&mut self, input: &str, output: &mut dyn ByteWriter) -> (usize, Option<CodecError>) {
+        output.writer_hint(input.len());
+        for (i, c) in input.char_indices() {
+            if c.is_ascii() {
+                output.write_byte(c as u8);
+            } else if let Some(byte) = self.0[128 ..].iter().position(|&x| x == c) {
+                output.write_byte(128 + byte as u8);
+            } else {
+                return (i, Some(CodecError {
+                    upto: (i + c.len_utf8()) as isize, cause: "unrepresentable character".into()
+                }));
+            }
+        }
+        (input.len(), None)
+    }
+
+    fn raw_finish(&mut self, _output: &mut dyn ByteWriter) -> Option<CodecError> { None }
+}
+
+impl RawDecoder for CodeTableCodec {
+    fn from_self(&self) -> Box<dyn RawDecoder> { Box::new(CodeTableCodec(self.0.clone())) }
+
+    fn is_ascii_compatible(&self) -> bool { true }
+
+    fn raw_feed(&mut self, input: &[u8], output: &mut dyn StringWriter) -> (usize, Option<CodecError>) {
+        output.writer_hint(input.len());
+        for &byte in input {
+            output.write_char(self.0[byte as usize]);
+        }
+        (input.len(), None)
+    }
+
+    fn raw_finish(&mut self, _output: &mut dyn StringWriter) -> Option<CodecError> { None }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::code::*;
-    use encoding::{DecoderTrap, EncoderTrap};
+    use encoding::{DecoderTrap, EncoderTrap, Encoding};
+
+    fn koi8_r() -> CodePage {
+        let mut chars = [0 as char; 256];
+        for (byte, c) in chars.iter_mut().enumerate() {
+            *c = encoding::all::KOI8_R.decode(&[byte as u8], DecoderTrap::Strict).unwrap().chars().next().unwrap();
+        }
+        CodePage::custom(CodeTable::new("KOI8-R", chars).unwrap()).unwrap()
+    }
 
     #[test]
     fn all_code_pages_are_single_byte_encodings() {
-        for code_page in CodePage::iter_variants() {
+        for code_page in CodePage::iter_variants().chain(Some(koi8_r())) {
             let encoding = code_page.encoding();
             for byte in 0u8 ..= 255 {
                 let c = encoding.decode(&[byte], DecoderTrap::Strict).unwrap();
@@ -43,4 +244,31 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn custom_code_table() {
+        let code_page = koi8_r();
+        assert_eq!(code_page.encoding().decode(b"\xf0\xd2\xc9", DecoderTrap::Strict).unwrap(), "При");
+        assert_eq!(code_page.encoding().encode("Мир!", EncoderTrap::Strict).unwrap(), b"\xed\xc9\xd2!");
+        assert!(code_page.encoding().encode("Zürich", EncoderTrap::Strict).is_err());
+        assert_eq!(code_page.encoding().encode("Zürich", EncoderTrap::Replace).unwrap(), b"Z?rich");
+        let text = "# test table\n0x41\t0x0042\t#B\n";
+        assert_eq!(CodeTable::parse("test", text), Err(CodeTableError::NotAsciiCompatible { byte: 0x41 }));
+        let text = "0xC0\t0x0410\t#CYRILLIC CAPITAL LETTER A\n0xC1\t0x0410\n";
+        assert_eq!(CodeTable::parse("test", text), Err(CodeTableError::DuplicateChar { c: 'А', first: 0xC0, second: 0xC1 }));
+        assert_eq!(CodeTable::parse("test", "0xC0 A"), Err(CodeTableError::InvalidLine { line: 1 }));
+        let table = CodeTable::parse("test", "0x80\t0x20AC\t#EURO SIGN\n0x81\t\t#UNDEFINED\n").unwrap();
+        assert_eq!(table.chars()[0x80], '€');
+        assert_eq!(table.chars()[0x81], '\u{81}');
+        let code_page = CodePage::custom(table.clone()).unwrap();
+        assert_eq!(format!("{:?}", code_page), "Custom(CodeTable(\"test\"))");
+        match (code_page, CodePage::custom(table).unwrap()) {
+            (CodePage::Custom(a), CodePage::Custom(b)) => assert!(std::ptr::eq(a, b)),
+            _ => unreachable!()
+        }
+        assert_eq!(CodePage::iter_variants().len(), 6);
+        assert_eq!(CodePage::Greek.encoding().encode("Ωμέγα", EncoderTrap::Strict).unwrap(), b"\xd9\xec\xdd\xe3\xe1");
+        assert_eq!(CodePage::Baltic.encoding().encode("Šalūnas", EncoderTrap::Strict).unwrap(), b"\xd0al\xfbnas");
+        assert_eq!(code_page.encoding().encode("€", EncoderTrap::Strict).unwrap(), b"\x80");
+    }
 }
@@ -0,0 +1,178 @@
+use byteorder::{ByteOrder, LittleEndian};
+use encoding::DecoderTrap;
+
+use crate::code::code_page::*;
+use crate::field::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CodePageGuess {
+    pub code_page: CodePage,
+    pub confidence: f32,
+}
+
+const COMMON_LATIN_LETTERS: &str = "àâäçéèêëîïôöùûüÿœæßáíóúñìòąćęłńśźżčďěňřšťůýžőű";
+
+const SINGLE_LETTER_WORDS: &str = "àèéó";
+
+const NEUTRAL_CHARS: &str = "‘’‚“”„–—…«»•№©®™°·§\u{A0}";
+
+fn is_text_field(record_tag: Tag, field_tag: Tag) -> bool {
+    if ![NAME, FNAM, TEXT].contains(&field_tag) { return false; }
+    matches!(
+        FieldType::from_tags(record_tag, field_tag),
+        FieldType::String(_) | FieldType::StringZ | FieldType::StringZList | FieldType::Multiline(_)
+    )
+}
+
+fn text_fields(mut bytes: &[u8]) -> Vec<&[u8]> {
+    let mut strings = Vec::new();
+    while bytes.len() >= 16 {
+        let record_tag = Tag::from(LittleEndian::read_u32(&bytes[0 .. 4]));
+        let record_size = LittleEndian::read_u32(&bytes[4 .. 8]) as usize;
+        let mut body = &bytes[16 .. (16 + record_size).min(bytes.len())];
+        bytes = &bytes[16 + body.len() ..];
+        while body.len() >= 8 {
+            let field_tag = Tag::from(LittleEndian::read_u32(&body[0 .. 4]));
+            let field_size = LittleEndian::read_u32(&body[4 .. 8]) as usize;
+            let field = &body[8 .. (8 + field_size).min(body.len())];
+            body = &body[8 + field.len() ..];
+            if is_text_field(record_tag, field_tag) && field.iter().any(|&b| b >= 0x80) {
+                strings.push(field);
+            }
+        }
+    }
+    strings
+}
+
+fn decode_table(code_page: CodePage) -> Vec<char> {
+    (0u8 ..= 255).map(|byte| {
+        let c = code_page.encoding().decode(&[byte], DecoderTrap::Replace).unwrap();
+        c.chars().next().and_then(|c| c.to_lowercase().next()).unwrap_or('\u{FFFD}')
+    }).collect()
+}
+
+fn is_plausible(chars: &[char], i: usize) -> bool {
+    let c = chars[i];
+    if NEUTRAL_CHARS.contains(c) { return true; }
+    let word_start = chars[.. i].iter().rposition(|c| !c.is_alphabetic()).map_or(0, |n| n + 1);
+    let word_end = chars[i ..].iter().position(|c| !c.is_alphabetic()).map_or(chars.len(), |n| i + n);
+    let word = &chars[word_start .. word_end];
+    let has_ascii = word.iter().any(|c| c.is_ascii());
+    if matches!(c, 'а' ..= 'я' | 'ё') {
+        !has_ascii
+    } else if word.len() == 1 {
+        SINGLE_LETTER_WORDS.contains(c)
+    } else {
+        COMMON_LATIN_LETTERS.contains(c) && has_ascii
+    }
+}
+
+fn plausibility(table: &[char], strings: &[&[u8]]) -> Vec<bool> {
+    let mut result = Vec::new();
+    for s in strings {
+        let chars = s.iter().map(|&b| table[b as usize]).collect::<Vec<_>>();
+        for (i, &b) in s.iter().enumerate() {
+            if b >= 0x80 {
+                result.push(is_plausible(&chars, i));
+            }
+        }
+    }
+    result
+}
+
+fn ratio(flags: impl Iterator<Item=bool>) -> (f32, usize) {
+    let (good, total) = flags.fold((0, 0), |(good, total), x| (good + x as usize, total + 1));
+    if total == 0 { (1.0, 0) } else { (good as f32 / total as f32, total) }
+}
+
+pub fn detect_code_page_among(bytes: &[u8], candidates: &[CodePage]) -> Option<CodePageGuess> {
+    if candidates.is_empty() { return None; }
+    let strings = text_fields(bytes);
+    let high_bytes = strings.iter().flat_map(|s| s.iter().copied()).filter(|&b| b >= 0x80).collect::<Vec<_>>();
+    let tables = candidates.iter().map(|&code_page| decode_table(code_page)).collect::<Vec<_>>();
+    let scores = tables.iter().map(|table| plausibility(table, &strings)).collect::<Vec<_>>();
+    let mut best = 0;
+    let mut best_score = -1.0;
+    for (index, flags) in scores.iter().enumerate() {
+        let score = ratio(flags.iter().copied()).0;
+        if score > best_score {
+            best = index;
+            best_score = score;
+        }
+    }
+    let mut confidence = best_score;
+    for other in (0 .. candidates.len()).filter(|&other| other != best) {
+        let differs = high_bytes.iter().map(|&b| tables[best][b as usize] != tables[other][b as usize]).collect::<Vec<_>>();
+        let (best_ratio, count) = ratio(scores[best].iter().zip(&differs).filter(|x| *x.1).map(|x| *x.0));
+        if count == 0 { continue; }
+        let other_ratio = ratio(scores[other].iter().zip(&differs).filter(|x| *x.1).map(|x| *x.0)).0;
+        let margin = (best_ratio - other_ratio) * count as f32 / (count as f32 + 2.0);
+        confidence = confidence.min(margin);
+    }
+    Some(CodePageGuess { code_page: candidates[best], confidence: confidence.max(0.0) })
+}
+
+pub fn detect_code_page(bytes: &[u8]) -> CodePageGuess {
+    let candidates = CodePage::iter_variants().collect::<Vec<_>>();
+    detect_code_page_among(bytes, &candidates).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::serialize;
+    use crate::record::*;
+
+    fn plugin(code_page: CodePage, names: &[&str], text: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (i, &name) in names.iter().enumerate() {
+            let record = Record {
+                tag: MISC, flags: RecordFlags::empty(),
+                fields: vec![
+                    (NAME, Field::StringZ(format!("misc_{}", i).into())),
+                    (FNAM, Field::StringZ(name.into())),
+                ]
+            };
+            bytes.extend(serialize(&record, code_page, true).unwrap());
+        }
+        let record = Record {
+            tag: INFO, flags: RecordFlags::empty(),
+            fields: vec![(NAME, Field::String(text.into()))]
+        };
+        bytes.extend(serialize(&record, code_page, true).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn detect_russian() {
+        let bytes = plugin(CodePage::Russian, &["Серебряный меч", "Зелье лечения"], "Я слышал, что в Балморе неспокойно.");
+        let guess = detect_code_page(&bytes);
+        assert_eq!(guess.code_page, CodePage::Russian);
+        assert!(guess.confidence > 0.8);
+    }
+
+    #[test]
+    fn detect_western() {
+        let bytes = plugin(CodePage::English, &["Épée d'argent", "Potion de soins"], "J'ai entendu dire qu'à Balmora, ça ne va pas très bien.");
+        let guess = detect_code_page(&bytes);
+        assert_eq!(guess.code_page, CodePage::English);
+        assert!(guess.confidence > 0.2);
+        let bytes = plugin(CodePage::English, &["Silberschwert"], "Ich habe gehört, dass es in Balmora unruhig ist. Grüße!");
+        assert_eq!(detect_code_page(&bytes).code_page, CodePage::English);
+    }
+
+    #[test]
+    fn detect_central_european() {
+        let bytes = plugin(CodePage::CentralEuropean, &["Srebrny miecz", "Mikstura leczenia"], "Słyszałem, że w Balmorze dzieje się coś złego.");
+        let guess = detect_code_page(&bytes);
+        assert_eq!(guess.code_page, CodePage::CentralEuropean);
+        assert!(guess.confidence > 0.4);
+    }
+
+    #[test]
+    fn detect_ascii() {
+        let bytes = plugin(CodePage::English, &["Silver Sword"], "I've heard that Balmora is restless.");
+        assert_eq!(detect_code_page(&bytes), CodePageGuess { code_page: CodePage::English, confidence: 1.0 });
+        assert_eq!(detect_code_page_among(&bytes, &[]), None);
+    }
+}
@@ -4,9 +4,16 @@ pub mod de;
 mod code_page;
 pub use code_page::*;
 
+mod detect;
+pub use detect::*;
+
+mod policy;
+pub use policy::*;
+
 use serde::de::DeserializeSeed;
 use crate::code::de::*;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::io::{Read, Write};
 use crate::code::ser::*;
 
@@ -70,25 +77,47 @@ fn bytes_deserializer<'a, 'de>(bytes: &'a mut &'de [u8], code_page: CodePage, is
 }
 
 pub fn serialized_size<T: Serialize + ?Sized>(v: &T, code_page: CodePage, isolated: bool) -> Result<usize, ser::IoError> {
+    serialized_size_with_policy(v, code_page, EncoderPolicy::Strict, isolated).map(|(size, _)| size)
+}
+
+pub fn serialized_size_with_policy<T: Serialize + ?Sized>(v: &T, code_page: CodePage, policy: EncoderPolicy, isolated: bool)
+    -> Result<(usize, SubstitutionReport), ser::IoError> {
+
+    let report = RefCell::new(SubstitutionReport::default());
     let mut writer = Size(0);
-    let serializer = EslSerializer::new(isolated, code_page, &mut writer);
+    let serializer = EslSerializer::with_encoder(isolated, Encoder { code_page, policy, report: Some(&report) }, &mut writer);
     v.serialize(serializer)?;
-    Ok(writer.0)
+    Ok((writer.0, report.into_inner()))
 }
 
 pub fn serialize_into<T: Serialize + ?Sized>(v: &T, writer: &mut (impl Write + ?Sized), code_page: CodePage, isolated: bool) -> Result<(), ser::IoError> {
+    serialize_into_with_policy(v, writer, code_page, EncoderPolicy::Strict, isolated).map(|_| ())
+}
+
+pub fn serialize_into_with_policy<T: Serialize + ?Sized>(v: &T, writer: &mut (impl Write + ?Sized), code_page: CodePage, policy: EncoderPolicy, isolated: bool)
+    -> Result<SubstitutionReport, ser::IoError> {
+
+    let report = RefCell::new(SubstitutionReport::default());
     let mut writer = GenericWriter::new(writer);
-    let serializer = EslSerializer::new(isolated, code_page, &mut writer);
-    v.serialize(serializer)
+    let serializer = EslSerializer::with_encoder(isolated, Encoder { code_page, policy, report: Some(&report) }, &mut writer);
+    v.serialize(serializer)?;
+    Ok(report.into_inner())
 }
 
 pub fn serialize_into_slice<'a, 'b: 'a, T: Serialize + ?Sized>(v: &T, bytes: &'b mut &'a mut [u8], code_page: CodePage, isolated: bool) -> Result<(), ser::IoError> {
+    serialize_into_slice_with_policy(v, bytes, code_page, EncoderPolicy::Strict, isolated).map(|_| ())
+}
+
+pub fn serialize_into_slice_with_policy<'a, 'b: 'a, T: Serialize + ?Sized>(v: &T, bytes: &'b mut &'a mut [u8], code_page: CodePage, policy: EncoderPolicy, isolated: bool)
+    -> Result<SubstitutionReport, ser::IoError> {
+
+    let report = RefCell::new(SubstitutionReport::default());
     let mut writer = SliceWriter::new(*bytes);
-    let serializer = EslSerializer::new(isolated, code_page, &mut writer);
+    let serializer = EslSerializer::with_encoder(isolated, Encoder { code_page, policy, report: Some(&report) }, &mut writer);
     v.serialize(serializer)?;
     let written = writer.written();
     *bytes = &mut (*bytes)[written ..];
-    Ok(())
+    Ok(report.into_inner())
 }
 
 fn no_io_error(e: ser::IoError) -> ser::Error {
@@ -99,13 +128,26 @@ fn no_io_error(e: ser::IoError) -> ser::Error {
 }
 
 pub fn serialize_into_vec<T: Serialize + ?Sized>(v: &T, bytes: &mut Vec<u8>, code_page: CodePage, isolated: bool) -> Result<(), ser::Error> {
-    let serializer = EslSerializer::new(isolated, code_page, bytes);
-    v.serialize(serializer).map_err(no_io_error)
+    serialize_into_vec_with_policy(v, bytes, code_page, EncoderPolicy::Strict, isolated).map(|_| ())
+}
+
+pub fn serialize_into_vec_with_policy<T: Serialize + ?Sized>(v: &T, bytes: &mut Vec<u8>, code_page: CodePage, policy: EncoderPolicy, isolated: bool)
+    -> Result<SubstitutionReport, ser::Error> {
+
+    let report = RefCell::new(SubstitutionReport::default());
+    let serializer = EslSerializer::with_encoder(isolated, Encoder { code_page, policy, report: Some(&report) }, bytes);
+    v.serialize(serializer).map_err(no_io_error)?;
+    Ok(report.into_inner())
 }
 
 pub fn serialize<T: Serialize + ?Sized>(v: &T, code_page: CodePage, isolated: bool) -> Result<Vec<u8>, ser::Error> {
+    serialize_with_policy(v, code_page, EncoderPolicy::Strict, isolated).map(|(bytes, _)| bytes)
+}
+
+pub fn serialize_with_policy<T: Serialize + ?Sized>(v: &T, code_page: CodePage, policy: EncoderPolicy, isolated: bool)
+    -> Result<(Vec<u8>, SubstitutionReport), ser::Error> {
+
     let mut bytes = Vec::new();
-    let serializer = EslSerializer::new(isolated, code_page, &mut bytes);
-    v.serialize(serializer).map_err(no_io_error)?;
-    Ok(bytes)
+    let report = serialize_into_vec_with_policy(v, &mut bytes, code_page, policy, isolated)?;
+    Ok((bytes, report))
 }
@@ -0,0 +1,182 @@
+use encoding::EncoderTrap;
+
+use crate::code::code_page::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum EncoderPolicy {
+    Strict,
+    Replace,
+    Transliterate,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Substitution {
+    pub original: char,
+    pub replacement: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SubstitutionReport {
+    pub substitutions: Vec<Substitution>,
+}
+
+impl SubstitutionReport {
+    pub fn is_empty(&self) -> bool { self.substitutions.is_empty() }
+
+    pub fn total(&self) -> usize { self.substitutions.iter().map(|x| x.count).sum() }
+
+    pub(crate) fn add(&mut self, original: char, replacement: &str) {
+        if let Some(substitution) = self.substitutions.iter_mut().find(|x| x.original == original && x.replacement == replacement) {
+            substitution.count += 1;
+        } else {
+            self.substitutions.push(Substitution { original, replacement: replacement.into(), count: 1 });
+        }
+    }
+}
+
+const PUNCTUATION: &[(&str, &str)] = &[
+    ("‘’‚‛′`", "'"),
+    ("“”„‟″«»", "\""),
+    ("‐‑‒–—―−", "-"),
+    ("…", "..."),
+    ("•·", "*"),
+    ("‹", "<"),
+    ("›", ">"),
+    ("×", "x"),
+    ("№", "No."),
+    ("€", "EUR"),
+    ("©", "(C)"),
+    ("®", "(R)"),
+    ("™", "(TM)"),
+    ("\u{A0}\u{2002}\u{2003}\u{2009}", " "),
+];
+
+const LATIN: &[(&str, &str)] = &[
+    ("ÀÁÂÃÄÅĀĂĄ", "A"), ("àáâãäåāăą", "a"),
+    ("ÇĆĈĊČ", "C"), ("çćĉċč", "c"),
+    ("ĎĐ", "D"), ("ďđ", "d"),
+    ("ÈÉÊËĒĔĖĘĚ", "E"), ("èéêëēĕėęě", "e"),
+    ("ĜĞĠĢ", "G"), ("ĝğġģ", "g"),
+    ("ĤĦ", "H"), ("ĥħ", "h"),
+    ("ÌÍÎÏĨĪĬĮİ", "I"), ("ìíîïĩīĭįı", "i"),
+    ("Ĵ", "J"), ("ĵ", "j"),
+    ("Ķ", "K"), ("ķ", "k"),
+    ("ĹĻĽĿŁ", "L"), ("ĺļľŀł", "l"),
+    ("ÑŃŅŇ", "N"), ("ñńņň", "n"),
+    ("ÒÓÔÕÖØŌŎŐ", "O"), ("òóôõöøōŏő", "o"),
+    ("ŔŖŘ", "R"), ("ŕŗř", "r"),
+    ("ŚŜŞŠ", "S"), ("śŝşš", "s"),
+    ("ŢŤŦ", "T"), ("ţťŧ", "t"),
+    ("ÙÚÛÜŨŪŬŮŰŲ", "U"), ("ùúûüũūŭůűų", "u"),
+    ("Ŵ", "W"), ("ŵ", "w"),
+    ("ÝŸŶ", "Y"), ("ýÿŷ", "y"),
+    ("ŹŻŽ", "Z"), ("źżž", "z"),
+    ("Æ", "AE"), ("æ", "ae"),
+    ("Œ", "OE"), ("œ", "oe"),
+    ("Þ", "Th"), ("þ", "th"),
+    ("ß", "ss"),
+];
+
+const CYRILLIC: &[&str] = &[
+    "a", "b", "v", "g", "d", "e", "zh", "z", "i", "y", "k", "l", "m", "n", "o", "p",
+    "r", "s", "t", "u", "f", "kh", "ts", "ch", "sh", "shch", "", "y", "", "e", "yu", "ya",
+];
+
+fn cyrillic(c: char) -> Option<String> {
+    let (lower, upper) = match c {
+        'а' ..= 'я' => (c, false),
+        'А' ..= 'Я' => (char::from_u32(c as u32 + 0x20).unwrap(), true),
+        'ё' => ('е', false),
+        'Ё' => ('е', true),
+        _ => return None
+    };
+    let latin = CYRILLIC[(lower as u32 - 'а' as u32) as usize];
+    if !upper { return Some(latin.into()); }
+    let mut chars = latin.chars();
+    Some(chars.next().map_or_else(String::new, |first| first.to_ascii_uppercase().to_string() + chars.as_str()))
+}
+
+pub fn transliterate(c: char) -> Option<String> {
+    PUNCTUATION.iter().chain(LATIN)
+        .find(|(from, _)| from.contains(c))
+        .map(|(_, to)| (*to).to_string())
+        .or_else(|| cyrillic(c))
+}
+
+pub(crate) fn is_representable(code_page: CodePage, s: &str) -> bool {
+    code_page.encoding().encode(s, EncoderTrap::Strict).is_ok()
+}
+
+pub(crate) fn substitute(code_page: CodePage, policy: EncoderPolicy, c: char) -> Option<String> {
+    match policy {
+        EncoderPolicy::Strict => None,
+        EncoderPolicy::Replace => Some("?".into()),
+        EncoderPolicy::Transliterate => Some(
+            transliterate(c).filter(|s| is_representable(code_page, s)).unwrap_or_else(|| "?".into())
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::*;
+    use crate::field::*;
+    use crate::record::*;
+
+    #[test]
+    fn transliterate_chars() {
+        assert_eq!(transliterate('—').as_deref(), Some("-"));
+        assert_eq!(transliterate('“').as_deref(), Some("\""));
+        assert_eq!(transliterate('…').as_deref(), Some("..."));
+        assert_eq!(transliterate('ł').as_deref(), Some("l"));
+        assert_eq!(transliterate('Ж').as_deref(), Some("Zh"));
+        assert_eq!(transliterate('щ').as_deref(), Some("shch"));
+        assert_eq!(transliterate('Ъ').as_deref(), Some(""));
+        assert_eq!(transliterate('Ё').as_deref(), Some("E"));
+        assert_eq!(transliterate('漢'), None);
+        assert_eq!(substitute(CodePage::Russian, EncoderPolicy::Transliterate, '漢').as_deref(), Some("?"));
+        assert_eq!(substitute(CodePage::Russian, EncoderPolicy::Strict, 'é'), None);
+        assert_eq!(substitute(CodePage::Russian, EncoderPolicy::Replace, 'é').as_deref(), Some("?"));
+    }
+
+    #[test]
+    fn serialize_with_policies() {
+        let text = "Café — “Ёлка” and café";
+        let error = serialize(text, CodePage::English, true).err().unwrap();
+        assert_eq!(error.to_string(), "the 'Ё' char is not representable in English code page");
+        let (bytes, report) = serialize_with_policy(text, CodePage::English, EncoderPolicy::Replace, true).unwrap();
+        assert_eq!(bytes, serialize("Café — “????” and café", CodePage::English, true).unwrap());
+        assert_eq!(report.total(), 4);
+        let (bytes, report) = serialize_with_policy(text, CodePage::Russian, EncoderPolicy::Transliterate, true).unwrap();
+        assert_eq!(bytes, serialize("Cafe — “Ёлка” and cafe", CodePage::Russian, true).unwrap());
+        assert_eq!(report, SubstitutionReport { substitutions: vec![
+            Substitution { original: 'é', replacement: "e".into(), count: 2 }
+        ] });
+        let (_, report) = serialize_with_policy(&'Щ', CodePage::English, EncoderPolicy::Transliterate, true).unwrap();
+        assert_eq!(report.substitutions[0].replacement, "?");
+        let (_, report) = serialize_with_policy(&('Щ', "Щ"), CodePage::English, EncoderPolicy::Transliterate, true).unwrap();
+        assert_eq!(report, SubstitutionReport { substitutions: vec![
+            Substitution { original: 'Щ', replacement: "?".into(), count: 1 },
+            Substitution { original: 'Щ', replacement: "Shch".into(), count: 1 },
+        ] });
+        let book = |text: &str, script: &str, item_id: &str| Record {
+            tag: BOOK, flags: RecordFlags::empty(),
+            fields: vec![
+                (TEXT, Field::StringList(vec![text.into(), "--".into()])),
+                (SCTX, Field::StringList(vec![script.into()])),
+                (NPCO, Field::Item(Item { count: 1, item_id: item_id.into() })),
+            ]
+        };
+        let (bytes, report) = serialize_with_policy(
+            &book("Жук и борщ", "; Щит", "Жук"), CodePage::English, EncoderPolicy::Transliterate, true
+        ).unwrap();
+        assert_eq!(bytes, serialize(&book("Zhuk i borshch", "; Shchit", "Zhuk"), CodePage::English, true).unwrap());
+        assert!(report.substitutions.iter().all(|x| x.replacement != "?"));
+        let error = serialize_with_policy(
+            &book("", "", &"Щ".repeat(10)), CodePage::English, EncoderPolicy::Transliterate, true
+        ).err().unwrap();
+        assert_eq!(error.to_string(), "encoded string length is above 32 bytes");
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Serializer, Serialize};
+use std::cell::RefCell;
 use std::mem::{replace};
 use std::fmt::{self, Display, Debug};
 use encoding::{EncoderTrap};
@@ -9,12 +10,15 @@ use std::io::{self, Write};
 use byteorder::{WriteBytesExt, LittleEndian};
 
 use crate::code::code_page::*;
+use crate::code::policy::*;
+use crate::serde_helpers::FIXED_STRING;
 
 #[derive(Debug)]
 pub enum Error {
     Custom(String),
     LargeObject(usize),
     UnrepresentableChar(char, CodePage),
+    LongString(usize),
     ZeroSizedLastSequenceElement,
     VariantIndexMismatch { variant_index: u32, variant_size: u32 },
     ZeroSizedOptional,
@@ -26,6 +30,7 @@ impl Display for Error {
             Error::Custom(s) => Display::fmt(s, f),
             Error::LargeObject(size) => write!(f, "object has too large size ({} B)", size),
             Error::UnrepresentableChar(c, p) => write!(f, "the '{}' char is not representable in {:?} code page", c, p),
+            Error::LongString(len) => write!(f, "encoded string length is above {} bytes", len),
             Error::ZeroSizedLastSequenceElement => write!(f, "last element in sequence or map cannot have zero size"),
             Error::VariantIndexMismatch { variant_index, variant_size } =>
                 write!(f, "variant index ({}) should be equal to variant size ({})", variant_index, variant_size),
@@ -257,23 +262,81 @@ impl Writer for Size {
     }
 }
 
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Encoder<'a> {
+    pub code_page: CodePage,
+    pub policy: EncoderPolicy,
+    pub report: Option<&'a RefCell<SubstitutionReport>>,
+}
+
+impl<'a> Encoder<'a> {
+    fn encode(self, v: &str) -> Result<Vec<u8>, Error> {
+        let encoding = self.code_page.encoding();
+        if let Ok(bytes) = encoding.encode(v, EncoderTrap::Strict) {
+            return Ok(bytes);
+        }
+        let mut bytes = Vec::with_capacity(v.len());
+        let mut buf = [0; 4];
+        for c in v.chars() {
+            if let Ok(c_bytes) = encoding.encode(c.encode_utf8(&mut buf), EncoderTrap::Strict) {
+                bytes.extend_from_slice(&c_bytes);
+                continue;
+            }
+            let replacement = substitute(self.code_page, self.policy, c)
+                .ok_or(Error::UnrepresentableChar(c, self.code_page))?;
+            bytes.extend_from_slice(&encoding.encode(&replacement, EncoderTrap::Strict).unwrap());
+            self.report(c, &replacement);
+        }
+        Ok(bytes)
+    }
+
+    fn encode_char(self, v: char) -> Result<u8, Error> {
+        let encoding = self.code_page.encoding();
+        let mut buf = [0; 4];
+        if let Ok(bytes) = encoding.encode(v.encode_utf8(&mut buf), EncoderTrap::Strict) {
+            debug_assert_eq!(bytes.len(), 1);
+            return Ok(bytes[0]);
+        }
+        let mut replacement = substitute(self.code_page, self.policy, v)
+            .ok_or(Error::UnrepresentableChar(v, self.code_page))?;
+        if replacement.chars().count() != 1 {
+            replacement = "?".into();
+        }
+        let bytes = encoding.encode(&replacement, EncoderTrap::Strict).unwrap();
+        self.report(v, &replacement);
+        Ok(bytes[0])
+    }
+
+    fn report(self, original: char, replacement: &str) {
+        if let Some(report) = self.report {
+            report.borrow_mut().add(original, replacement);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct EslSerializer<'r, 'a, W: Writer> {
     isolated: bool,
-    code_page: CodePage,
+    encoder: Encoder<'a>,
     writer: &'a mut W,
     map_entry_value_buf: Option<&'r mut Option<W::Buf>>
 }
 
 impl<'r, 'a, W: Writer> EslSerializer<'r, 'a, W> {
+    #[cfg(test)]
     pub fn new(isolated: bool, code_page: CodePage, writer: &'a mut W) -> Self {
-        EslSerializer { isolated, code_page, writer, map_entry_value_buf: None }
+        let encoder = Encoder { code_page, policy: EncoderPolicy::Strict, report: None };
+        EslSerializer::with_encoder(isolated, encoder, writer)
+    }
+
+    pub fn with_encoder(isolated: bool, encoder: Encoder<'a>, writer: &'a mut W) -> Self {
+        EslSerializer { isolated, encoder, writer, map_entry_value_buf: None }
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct SeqSerializer<'a, W: Writer> {
-    code_page: CodePage,
+    encoder: Encoder<'a>,
     writer: &'a mut W,
     last_element_has_zero_size: bool,
     buf_and_start_pos: Option<(W::Buf, usize)>,
@@ -281,17 +344,18 @@ pub(crate) struct SeqSerializer<'a, W: Writer> {
 
 #[derive(Debug)]
 pub(crate) struct MapSerializer<'a, W: Writer> {
-    code_page: CodePage,
+    encoder: Encoder<'a>,
     writer: &'a mut W,
     value_buf_and_pos: Option<(W::Buf, usize)>,
 }
 
 #[derive(Debug)]
 pub(crate) struct StructSerializer<'r, 'a, W: Writer> {
-    code_page: CodePage,
+    encoder: Encoder<'a>,
     writer: &'a mut W,
     len: Option<usize>,
     start_pos_and_variant_index: Option<(usize, u32)>,
+    start_pos_and_string_len: Option<(usize, usize)>,
     value_buf: Option<&'r mut Option<W::Buf>>,
 }
 
@@ -303,7 +367,7 @@ impl<'a, W: Writer> SerializeSeq for SeqSerializer<'a, W> {
         let element_pos = self.writer.pos();
         v.serialize(EslSerializer {
             isolated: false,
-            writer: self.writer, code_page: self.code_page,
+            writer: self.writer, encoder: self.encoder,
             map_entry_value_buf: None
         })?;
         self.last_element_has_zero_size = self.writer.pos() == element_pos;
@@ -329,7 +393,7 @@ impl<'a, W: Writer> SerializeMap for MapSerializer<'a, W> {
         let mut value_buf = None;
         key.serialize(EslSerializer {
             writer: self.writer,
-            code_page: self.code_page,
+            encoder: self.encoder,
             map_entry_value_buf: Some(&mut value_buf),
             isolated: false
         })?;
@@ -346,7 +410,7 @@ impl<'a, W: Writer> SerializeMap for MapSerializer<'a, W> {
         v.serialize(EslSerializer {
             isolated: true,
             writer: self.writer,
-            code_page: self.code_page,
+            encoder: self.encoder,
             map_entry_value_buf: None
         })?;
         let (value_buf, value_pos) = self.value_buf_and_pos.take().unwrap();
@@ -367,9 +431,16 @@ impl<'r, 'a, W: Writer> StructSerializer<'r, 'a, W> {
         }
         v.serialize(EslSerializer {
             isolated: self.len.map_or(false, |len| len == 0),
-            writer: self.writer, code_page: self.code_page,
+            writer: self.writer, encoder: self.encoder,
             map_entry_value_buf: None
         })?;
+        if let Some((start_pos, string_len)) = self.start_pos_and_string_len {
+            let encoded_len = self.writer.pos() - start_pos;
+            if encoded_len > string_len {
+                return Err(Error::LongString(string_len).into());
+            }
+            self.writer.write_all(&vec![0; string_len - encoded_len])?;
+        }
         if let &mut Some(ref mut value_buf) = &mut self.value_buf {
             if value_buf.is_none() {
                 **value_buf = Some(self.writer.begin_isolate()?);
@@ -542,17 +613,12 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
     }
 
     fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
-        let v = self.code_page.encoding()
-            .encode(&v.to_string(), EncoderTrap::Strict)
-            .map_err(|_| Error::UnrepresentableChar(v, self.code_page))?;
-        debug_assert_eq!(v.len(), 1);
-        self.serialize_u8(v[0])
+        let v = self.encoder.encode_char(v)?;
+        self.serialize_u8(v)
     }
 
     fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
-        let bytes = self.code_page.encoding()
-            .encode(v, EncoderTrap::Strict)
-            .map_err(|s| Error::UnrepresentableChar(s.chars().next().unwrap(), self.code_page))?;
+        let bytes = self.encoder.encode(v)?;
         self.serialize_bytes(&bytes)
     }
 
@@ -600,7 +666,7 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
         value.serialize(EslSerializer {
             isolated: true,
             writer: self.writer,
-            code_page: self.code_page,
+            encoder: self.encoder,
             map_entry_value_buf: None
         })?;
         if self.writer.pos() == value_pos {
@@ -622,7 +688,7 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
         v.serialize(EslSerializer {
             isolated: true,
             writer: self.writer,
-            code_page: self.code_page,
+            encoder: self.encoder,
             map_entry_value_buf: None
         })?;
         let variant_size = size(self.writer.pos() - value_pos)?;
@@ -641,7 +707,8 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
         Ok(StructSerializer {
             len: Some(len),
             start_pos_and_variant_index: Some((self.writer.pos(), variant_index)),
-            writer: self.writer, code_page: self.code_page,
+            start_pos_and_string_len: None,
+            writer: self.writer, encoder: self.encoder,
             value_buf: None
         })
     }
@@ -655,7 +722,8 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
         Ok(StructSerializer {
             len: Some(len),
             start_pos_and_variant_index: Some((self.writer.pos(), variant_index)),
-            writer: self.writer, code_page: self.code_page,
+            start_pos_and_string_len: None,
+            writer: self.writer, encoder: self.encoder,
             value_buf: None
         })
     }
@@ -663,17 +731,28 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
         Ok(StructSerializer {
             len: if self.isolated { Some(len) } else { None },
-            writer: self.writer, code_page: self.code_page,
+            writer: self.writer, encoder: self.encoder,
             start_pos_and_variant_index: None,
+            start_pos_and_string_len: None,
             value_buf: self.map_entry_value_buf
         })
     }
 
-    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, Self::Error> {
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        if name == FIXED_STRING {
+            return Ok(StructSerializer {
+                len: Some(1),
+                start_pos_and_string_len: Some((self.writer.pos(), len)),
+                writer: self.writer, encoder: self.encoder,
+                start_pos_and_variant_index: None,
+                value_buf: None
+            });
+        }
         Ok(StructSerializer {
             len: if self.isolated { Some(len) } else { None },
-            writer: self.writer, code_page: self.code_page,
+            writer: self.writer, encoder: self.encoder,
             start_pos_and_variant_index: None,
+            start_pos_and_string_len: None,
             value_buf: None
         })
     }
@@ -681,8 +760,9 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
     fn serialize_struct(self, _: &'static str, len: usize) -> Result<Self::SerializeStruct, Self::Error> {
         Ok(StructSerializer {
             len: if self.isolated { Some(len) } else { None },
-            writer: self.writer, code_page: self.code_page,
+            writer: self.writer, encoder: self.encoder,
             start_pos_and_variant_index: None,
+            start_pos_and_string_len: None,
             value_buf: None
         })
     }
@@ -691,7 +771,7 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
         let buf = if !self.isolated { Some((self.writer.begin_isolate()?, self.writer.pos())) } else { None };
         Ok(SeqSerializer {
             writer: self.writer,
-            code_page: self.code_page,
+            encoder: self.encoder,
             last_element_has_zero_size: false,
             buf_and_start_pos: buf
         })
@@ -701,7 +781,7 @@ impl<'r, 'a, W: Writer> Serializer for EslSerializer<'r, 'a, W> {
         assert_eq!(len, Some(1), "Maps with length different from 1 not supported.");
         Ok(MapSerializer {
             value_buf_and_pos: None,
-            writer: self.writer, code_page: self.code_page,
+            writer: self.writer, encoder: self.encoder,
         })
     }
 }
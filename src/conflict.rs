@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use crate::cell::*;
+use crate::diff::*;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Definition {
+    pub plugin: usize,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefinitionDifference {
+    pub older: usize,
+    pub newer: usize,
+    pub flags: Option<(RecordFlags, RecordFlags)>,
+    pub fields: Vec<FieldChange>,
+    pub references: Vec<ReferenceChange>,
+}
+
+impl DefinitionDifference {
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_none() && self.fields.is_empty() && self.references.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub key: RecordKey,
+    pub definitions: Vec<Definition>,
+    pub differences: Vec<DefinitionDifference>,
+}
+
+impl Conflict {
+    pub fn winner(&self) -> Definition { *self.definitions.last().unwrap() }
+
+    pub fn is_identical(&self) -> bool { self.differences.iter().all(|x| x.is_empty()) }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConflictReport {
+    pub plugins: Vec<String>,
+    pub conflicts: Vec<Conflict>,
+}
+
+pub(crate) fn record_definitions(plugins: &[(&str, &[Record])]) -> Vec<(RecordKey, Vec<Definition>)> {
+    let mut lookup: HashMap<RecordLookup, usize> = HashMap::new();
+    let mut definitions: Vec<(RecordKey, Vec<Definition>)> = Vec::new();
+    for (plugin, (_, records)) in plugins.iter().enumerate() {
+        for (index, (record_lookup, key)) in record_keys(records).into_iter().enumerate() {
+            if key.tag == TES3 { continue; }
+            let record = *lookup.entry(record_lookup).or_insert_with(|| {
+                definitions.push((key, Vec::new()));
+                definitions.len() - 1
+            });
+            definitions[record].1.push(Definition { plugin, index });
+        }
+    }
+    definitions
+}
+
+pub fn find_conflicts(plugins: &[(&str, &[Record])]) -> ConflictReport {
+    let definitions = record_definitions(plugins);
+    let masters = plugins.iter().map(|(_, records)| file_masters(records)).collect::<Vec<_>>();
+    let file = |plugin: usize| FileContext { name: Some(plugins[plugin].0), masters: &masters[plugin] };
+    let conflicts = definitions.into_iter().filter(|(_, definitions)| definitions.len() > 1).map(|(key, definitions)| {
+        let mut differences = Vec::new();
+        for (older, older_definition) in definitions.iter().enumerate() {
+            for (newer, newer_definition) in definitions.iter().enumerate().skip(older + 1) {
+                let older_record = &plugins[older_definition.plugin].1[older_definition.index];
+                let newer_record = &plugins[newer_definition.plugin].1[newer_definition.index];
+                let (flags, fields, references) = if older_record == newer_record {
+                    (None, Vec::new(), Vec::new())
+                } else {
+                    diff_record(older_record, file(older_definition.plugin), newer_record, file(newer_definition.plugin))
+                };
+                differences.push(DefinitionDifference { older, newer, flags, fields, references });
+            }
+        }
+        Conflict { key, definitions, differences }
+    }).collect();
+    ConflictReport { plugins: plugins.iter().map(|(name, _)| name.to_string()).collect(), conflicts }
+}
+
+impl ConflictReport {
+    pub fn differing(&self) -> impl Iterator<Item=&Conflict> {
+        self.conflicts.iter().filter(|x| !x.is_identical())
+    }
+}
+
+impl Display for ConflictReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for conflict in &self.conflicts {
+            let plugin = |definition: usize| &self.plugins[conflict.definitions[definition].plugin];
+            writeln!(f, "{}", conflict.key)?;
+            for definition in 0 .. conflict.definitions.len() - 1 {
+                writeln!(f, "    {}", plugin(definition))?;
+            }
+            writeln!(f, "    {} (wins)", plugin(conflict.definitions.len() - 1))?;
+            if conflict.is_identical() {
+                writeln!(f, "    identical")?;
+                continue;
+            }
+            for difference in conflict.differences.iter().filter(|x| !x.is_empty()) {
+                writeln!(f, "    {} -> {}", plugin(difference.older), plugin(difference.newer))?;
+                if let Some((old, new)) = difference.flags {
+                    writeln!(f, "        flags: {} -> {}", old, new)?;
+                }
+                write_field_changes(f, "        ", &difference.fields)?;
+                write_reference_changes(f, "        ", &difference.references)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tag: Tag, fields: Vec<(Tag, Field)>) -> Record {
+        Record { tag, flags: RecordFlags::empty(), fields }
+    }
+
+    fn misc(id: &str, value: u32) -> Record {
+        record(MISC, vec![
+            (NAME, Field::StringZ(id.into())),
+            (MCDT, Field::MiscItem(MiscItem { weight: 1.0, value, is_key: false })),
+        ])
+    }
+
+    fn dialogue(text: &str) -> Vec<Record> {
+        vec![
+            record(DIAL, vec![(NAME, Field::StringZ("Balmora".into()))]),
+            record(INFO, vec![(INAM, Field::StringZ("42".into())), (NAME, Field::String(text.into()))]),
+        ]
+    }
+
+    fn cell(refs: &[(i32, &str)]) -> Record {
+        let mut fields = vec![
+            (NAME, Field::StringZ("Balmora, Guild of Mages".into())),
+            (DATA, Field::Cell(Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } })),
+        ];
+        for &(ref_num, object) in refs {
+            fields.push((FRMR, Field::I32(ref_num)));
+            fields.push((NAME, Field::StringZ(object.into())));
+        }
+        record(CELL, fields)
+    }
+
+    #[test]
+    fn load_order_conflicts() {
+        let mut master = vec![misc("misc_quill", 1), misc("misc_ink", 2), cell(&[(1, "chest")])];
+        master.extend(dialogue("A fine city."));
+        let header = |masters: &[&str]| record(TES3, masters.iter().flat_map(|&x| vec![
+            (MAST, Field::StringZ(x.into())), (DATA, Field::I64(x.len() as i64))
+        ]).collect());
+        master.insert(0, header(&[]));
+        let mut first = vec![header(&["Morrowind.esm"]), misc("Misc_Quill", 5), misc("misc_ink", 2)];
+        first.extend(dialogue("A terrible city."));
+        let second = vec![
+            header(&["Morrowind.esm", "First.esp"]),
+            misc("misc_quill", 7),
+            cell(&[(0x0100_0001, "chest"), (2, "barrel")]),
+        ];
+        let report = find_conflicts(&[("Morrowind.esm", &master), ("First.esp", &first), ("Second.esp", &second)]);
+        assert_eq!(report.conflicts.len(), 5);
+        let quill = &report.conflicts[0];
+        assert_eq!(quill.key, RecordKey { tag: MISC, id: "misc_quill".into() });
+        assert_eq!(quill.definitions.iter().map(|x| x.plugin).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(quill.definitions[0], Definition { plugin: 0, index: 1 });
+        assert_eq!(quill.winner(), Definition { plugin: 2, index: 1 });
+        assert_eq!(quill.differences.len(), 3);
+        assert!(report.conflicts[1].is_identical());
+        assert_eq!(report.differing().count(), 3);
+        assert_eq!(report.conflicts[4].key, RecordKey { tag: INFO, id: "Balmora:42".into() });
+        assert_eq!(report.to_string(), "\
+MISC misc_quill
+    Morrowind.esm
+    First.esp
+    Second.esp (wins)
+    Morrowind.esm -> First.esp
+        ~ NAME: \"misc_quill\" -> \"Misc_Quill\"
+        ~ MCDT MiscItem.value: 1 -> 5
+    Morrowind.esm -> Second.esp
+        ~ MCDT MiscItem.value: 1 -> 7
+    First.esp -> Second.esp
+        ~ NAME: \"Misc_Quill\" -> \"misc_quill\"
+        ~ MCDT MiscItem.value: 5 -> 7
+MISC misc_ink
+    Morrowind.esm
+    First.esp (wins)
+    identical
+CELL Balmora, Guild of Mages
+    Morrowind.esm
+    Second.esp (wins)
+    Morrowind.esm -> Second.esp
+        + FRMR 2
+DIAL Balmora
+    Morrowind.esm
+    First.esp (wins)
+    identical
+INFO Balmora:42
+    Morrowind.esm
+    First.esp (wins)
+    Morrowind.esm -> First.esp
+        ~ NAME: \"A fine city.\" -> \"A terrible city.\"
+");
+    }
+}
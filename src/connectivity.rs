@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Write};
+
+use crate::cell::*;
+use crate::field::*;
+use crate::placement::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum CellId {
+    Interior(String),
+    Exterior(Grid),
+}
+
+impl Display for CellId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CellId::Interior(name) => write!(f, "{}", name),
+            CellId::Exterior(grid) => write!(f, "({}, {})", grid.x, grid.y),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum LinkKind {
+    Door,
+    Travel,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Link {
+    pub from: CellId,
+    pub to: CellId,
+    pub kind: LinkKind,
+    pub source: String,
+    pub record_index: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BadDestination {
+    MissingInterior { link: Link },
+    EmptyExterior { link: Link },
+}
+
+impl Display for BadDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BadDestination::MissingInterior { link } =>
+                write!(f, "{} in {} leads to non-existent interior cell '{}'", link.source, link.from, link.to),
+            BadDestination::EmptyExterior { link } =>
+                write!(f, "{} in {} leads to empty exterior location {}", link.source, link.from, link.to),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WorldGraph {
+    pub cells: Vec<CellId>,
+    pub cell_names: HashMap<CellId, String>,
+    pub links: Vec<Link>,
+    pub bad_destinations: Vec<BadDestination>,
+}
+
+struct Destination {
+    position: Position,
+    cell: Option<String>,
+}
+
+fn destinations(fields: &[(Tag, Field)]) -> Vec<Destination> {
+    let mut destinations: Vec<Destination> = Vec::new();
+    for (tag, field) in fields {
+        match (*tag, field) {
+            (DODT, Field::Position(position)) => destinations.push(Destination { position: position.clone(), cell: None }),
+            (DNAM, Field::StringZ(cell)) => if let Some(destination) = destinations.last_mut() {
+                destination.cell = Some(cell.string.clone());
+            },
+            _ => { }
+        }
+    }
+    destinations
+}
+
+fn record_id(record: &Record) -> Option<&str> {
+    record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (NAME, Field::StringZ(name)) => Some(name.string.as_str()),
+        _ => None
+    })
+}
+
+impl WorldGraph {
+    pub fn new<'a>(records: impl IntoIterator<Item=&'a Record>) -> WorldGraph {
+        let records = records.into_iter().collect::<Vec<_>>();
+        let mut interiors = HashMap::new();
+        let mut exteriors = HashSet::new();
+        let mut cells: Vec<(usize, CellId, String, CellData)> = Vec::new();
+        let mut travel = HashMap::new();
+        for (record_index, &record) in records.iter().enumerate() {
+            let deleted = record.flags.contains(RecordFlags::DELETED);
+            match record.tag {
+                CELL => {
+                    let cell = CellData::from_fields(&record.fields);
+                    let name = cell.header.name.as_ref().map_or_else(String::new, |x| x.string.clone());
+                    let id = if let Some(grid) = cell.grid() {
+                        CellId::Exterior(grid)
+                    } else if cell.is_interior() {
+                        CellId::Interior(interiors.get(&name.to_lowercase()).cloned().unwrap_or_else(|| name.clone()))
+                    } else {
+                        continue;
+                    };
+                    if deleted {
+                        cells.retain(|x| x.1 != id);
+                        match &id {
+                            CellId::Exterior(grid) => { exteriors.remove(grid); },
+                            CellId::Interior(_) => { interiors.remove(&name.to_lowercase()); },
+                        }
+                    } else {
+                        match &id {
+                            CellId::Exterior(grid) => { exteriors.insert(*grid); },
+                            CellId::Interior(interior) => { interiors.insert(name.to_lowercase(), interior.clone()); },
+                        }
+                        cells.push((record_index, id, name, cell));
+                    }
+                },
+                NPC_ | CREA => if let Some(id) = record_id(record) {
+                    let destinations = destinations(&record.fields);
+                    if deleted || destinations.is_empty() {
+                        travel.remove(&id.to_lowercase());
+                    } else {
+                        travel.insert(id.to_lowercase(), (id.to_string(), destinations));
+                    }
+                },
+                _ => { }
+            }
+        }
+        let mut graph = WorldGraph::default();
+        let mut known_links = HashSet::new();
+        for (record_index, id, name, cell) in cells {
+            if !graph.cell_names.contains_key(&id) {
+                graph.cells.push(id.clone());
+            }
+            if !name.is_empty() || !graph.cell_names.contains_key(&id) {
+                graph.cell_names.insert(id.clone(), name);
+            }
+            for reference in &cell.references {
+                if reference.is_deleted() { continue; }
+                let from = match (&id, reference.moved_to) {
+                    (CellId::Exterior(_), Some(grid)) => CellId::Exterior(grid),
+                    _ => id.clone()
+                };
+                let object = reference.object.as_ref().map_or("", |x| x.string.as_str());
+                let (kind, destinations) = if let Some(position) = &reference.door_destination {
+                    let cell = reference.door_destination_cell.as_ref().map(|x| x.string.clone());
+                    (LinkKind::Door, vec![Destination { position: position.clone(), cell }])
+                } else if let Some((_, destinations)) = travel.get(&object.to_lowercase()) {
+                    let destinations = destinations.iter()
+                        .map(|x| Destination { position: x.position.clone(), cell: x.cell.clone() })
+                        .collect();
+                    (LinkKind::Travel, destinations)
+                } else {
+                    continue;
+                };
+                for destination in destinations {
+                    let (to, exists) = if let Some(cell) = destination.cell {
+                        match interiors.get(&cell.to_lowercase()) {
+                            Some(name) => (CellId::Interior(name.clone()), true),
+                            None => (CellId::Interior(cell), false)
+                        }
+                    } else {
+                        let grid = position_grid(&destination.position);
+                        (CellId::Exterior(grid), exteriors.contains(&grid))
+                    };
+                    let link = Link { from: from.clone(), to, kind, source: object.to_string(), record_index };
+                    if !known_links.insert((link.from.clone(), link.to.clone(), link.kind, link.source.to_lowercase())) {
+                        continue;
+                    }
+                    if !exists {
+                        graph.bad_destinations.push(match &link.to {
+                            CellId::Interior(_) => BadDestination::MissingInterior { link: link.clone() },
+                            CellId::Exterior(_) => BadDestination::EmptyExterior { link: link.clone() },
+                        });
+                    }
+                    graph.links.push(link);
+                }
+            }
+        }
+        graph
+    }
+
+    pub fn neighbors<'a>(&'a self, cell: &'a CellId) -> impl Iterator<Item=&'a Link> + 'a {
+        self.links.iter().filter(move |x| &x.from == cell)
+    }
+
+    fn label(&self, cell: &CellId) -> String {
+        match (cell, self.cell_names.get(cell)) {
+            (CellId::Exterior(_), Some(name)) if !name.is_empty() => format!("{} {}", name, cell),
+            _ => cell.to_string()
+        }
+    }
+
+    fn node_id(cell: &CellId) -> String {
+        match cell {
+            CellId::Interior(name) => format!("int:{}", name),
+            CellId::Exterior(grid) => format!("ext:{},{}", grid.x, grid.y),
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut dot = String::new();
+        writeln!(dot, "digraph world {{").unwrap();
+        let mut nodes = self.cells.iter().collect::<HashSet<_>>();
+        for link in &self.links {
+            nodes.insert(&link.from);
+            nodes.insert(&link.to);
+        }
+        let mut nodes = nodes.into_iter().collect::<Vec<_>>();
+        nodes.sort();
+        for node in nodes {
+            let missing = !self.cell_names.contains_key(node);
+            let shape = match node {
+                CellId::Interior(_) => "box",
+                CellId::Exterior(_) => "ellipse",
+            };
+            write!(dot, "    {} [label={}, shape={}", quote(&WorldGraph::node_id(node)), quote(&self.label(node)), shape).unwrap();
+            if missing {
+                write!(dot, ", color=red").unwrap();
+            }
+            writeln!(dot, "];").unwrap();
+        }
+        let mut edges = HashSet::new();
+        for link in &self.links {
+            let (from, to) = (quote(&WorldGraph::node_id(&link.from)), quote(&WorldGraph::node_id(&link.to)));
+            let edge = match link.kind {
+                LinkKind::Door => format!("    {} -> {};", from, to),
+                LinkKind::Travel => format!("    {} -> {} [label={}, style=dashed];", from, to, quote(&link.source)),
+            };
+            if edges.insert(edge.clone()) {
+                writeln!(dot, "{}", edge).unwrap();
+            }
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(x: f32, y: f32) -> Position {
+        Position { x, y, z: 0.0, x_rot: 0.0, y_rot: 0.0, z_rot: 0.0 }
+    }
+
+    fn cell(name: &str, cell: Cell, references: Vec<(Tag, Field)>) -> Record {
+        let mut fields = vec![(NAME, Field::StringZ(name.into())), (DATA, Field::Cell(cell))];
+        fields.extend(references);
+        Record { tag: CELL, flags: RecordFlags::empty(), fields }
+    }
+
+    fn interior(name: &str, references: Vec<(Tag, Field)>) -> Record {
+        cell(name, Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } }, references)
+    }
+
+    fn door(index: i32, destination: Option<&str>, x: f32) -> Vec<(Tag, Field)> {
+        let mut fields = vec![
+            (FRMR, Field::I32(index)),
+            (NAME, Field::StringZ("door".into())),
+            (DODT, Field::Position(position(x, 0.0))),
+        ];
+        if let Some(destination) = destination {
+            fields.push((DNAM, Field::StringZ(destination.into())));
+        }
+        fields.push((DATA, Field::Position(position(0.0, 0.0))));
+        fields
+    }
+
+    #[test]
+    fn doors_and_travel() {
+        let mut balmora_refs = door(1, Some("balmora, guild"), 0.0);
+        balmora_refs.extend(vec![
+            (FRMR, Field::I32(2)),
+            (NAME, Field::StringZ("caravaner".into())),
+            (DATA, Field::Position(position(10.0, 10.0))),
+        ]);
+        let records = vec![
+            cell("Balmora", Cell { flags: CellFlags::empty(), grid: Grid { x: 0, y: 0 } }, balmora_refs),
+            interior("Balmora, Guild", [door(3, None, 100.0), door(4, Some("Nowhere"), 0.0)].concat()),
+            Record {
+                tag: NPC_,
+                flags: RecordFlags::empty(),
+                fields: vec![
+                    (NAME, Field::StringZ("Caravaner".into())),
+                    (DODT, Field::Position(position(9000.0, 0.0))),
+                ]
+            },
+        ];
+        let graph = WorldGraph::new(&records);
+        let balmora = CellId::Exterior(Grid { x: 0, y: 0 });
+        let guild = CellId::Interior("Balmora, Guild".into());
+        assert_eq!(graph.links.len(), 4);
+        assert_eq!(graph.neighbors(&balmora).map(|x| (x.to.clone(), x.kind)).collect::<Vec<_>>(), vec![
+            (guild.clone(), LinkKind::Door),
+            (CellId::Exterior(Grid { x: 1, y: 0 }), LinkKind::Travel),
+        ]);
+        assert_eq!(graph.neighbors(&guild).next().unwrap().to, balmora);
+        assert_eq!(graph.bad_destinations.len(), 2);
+        assert!(matches!(&graph.bad_destinations[0], BadDestination::EmptyExterior { link } if link.source == "caravaner"));
+        assert!(matches!(&graph.bad_destinations[1], BadDestination::MissingInterior { link } if link.to == CellId::Interior("Nowhere".into())));
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph world {\n"));
+        assert!(dot.contains("    \"ext:0,0\" [label=\"Balmora (0, 0)\", shape=ellipse];\n"));
+        assert!(dot.contains("    \"int:Nowhere\" [label=\"Nowhere\", shape=box, color=red];\n"));
+        assert!(dot.contains("    \"ext:0,0\" -> \"ext:1,0\" [label=\"caravaner\", style=dashed];\n"));
+        assert!(dot.contains("    \"int:Balmora, Guild\" -> \"ext:0,0\";\n"));
+        let mut deleted = records[2].clone();
+        deleted.flags = RecordFlags::DELETED;
+        let graph = WorldGraph::new(records.iter().chain(Some(&deleted)));
+        assert!(graph.links.iter().all(|x| x.kind == LinkKind::Door));
+    }
+
+    #[test]
+    fn deleted_cells_and_creature_travel() {
+        let records = vec![
+            cell("", Cell { flags: CellFlags::empty(), grid: Grid { x: 0, y: 0 } }, [
+                door(1, Some("Balmora, Guild"), 0.0),
+                vec![
+                    (FRMR, Field::I32(2)),
+                    (NAME, Field::StringZ("strider".into())),
+                    (DATA, Field::Position(position(10.0, 10.0))),
+                ],
+            ].concat()),
+            cell("", Cell { flags: CellFlags::empty(), grid: Grid { x: -1, y: 0 } }, Vec::new()),
+            interior("Balmora, Guild", door(3, None, 0.0)),
+            Record {
+                tag: CREA,
+                flags: RecordFlags::empty(),
+                fields: vec![
+                    (NAME, Field::StringZ("strider".into())),
+                    (DODT, Field::Position(position(-100.0, 0.0))),
+                ]
+            },
+        ];
+        let graph = WorldGraph::new(&records);
+        assert!(graph.bad_destinations.is_empty());
+        assert_eq!(graph.links.iter().map(|x| (x.source.as_str(), x.kind)).collect::<Vec<_>>(), vec![
+            ("door", LinkKind::Door),
+            ("strider", LinkKind::Travel),
+            ("door", LinkKind::Door),
+        ]);
+        let mut deleted_interior = interior("balmora, guild", Vec::new());
+        deleted_interior.flags = RecordFlags::DELETED;
+        let mut deleted_exterior = records[1].clone();
+        deleted_exterior.flags = RecordFlags::DELETED;
+        let graph = WorldGraph::new(records.iter().chain([&deleted_interior, &deleted_exterior]));
+        assert_eq!(graph.cells, vec![CellId::Exterior(Grid { x: 0, y: 0 })]);
+        assert_eq!(graph.links.len(), 2);
+        assert!(matches!(&graph.bad_destinations[0], BadDestination::MissingInterior { link } if link.to == CellId::Interior("Balmora, Guild".into())));
+        assert!(matches!(&graph.bad_destinations[1], BadDestination::EmptyExterior { link } if link.source == "strider"));
+    }
+}
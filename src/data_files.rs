@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub(crate) fn normalize_path(path: &str) -> String {
+    path.replace('\\', "/").to_lowercase()
+}
+
+pub(crate) fn list_files(root: &Path, dir: &Path, files: &mut HashMap<String, PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(root, &path, files)?;
+        } else {
+            let relative = path.strip_prefix(root).unwrap().to_string_lossy().to_string();
+            files.insert(normalize_path(&relative), path);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn find_dir(parent: &Path, name: &str) -> Option<PathBuf> {
+    fs::read_dir(parent).ok()?.filter_map(|x| x.ok()).map(|x| x.path())
+        .find(|x| x.is_dir() && matches!(x.file_name(), Some(x) if x.to_string_lossy().eq_ignore_ascii_case(name)))
+}
@@ -0,0 +1,598 @@
+use either::{Left, Right};
+use std::collections::HashMap;
+
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DialogueConditionValue {
+    Integer(i32),
+    Float(f32),
+}
+
+impl DialogueConditionValue {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            DialogueConditionValue::Integer(v) => v as f64,
+            DialogueConditionValue::Float(v) => v as f64,
+        }
+    }
+
+    fn to_field(self) -> (Tag, Field) {
+        match self {
+            DialogueConditionValue::Integer(v) => (INTV, Field::I32(v)),
+            DialogueConditionValue::Float(v) => (FLTV, Field::F32(v)),
+        }
+    }
+}
+
+fn condition_value(field: Option<&(Tag, Field)>) -> Option<DialogueConditionValue> {
+    match field {
+        Some((INTV, Field::I32(v))) => Some(DialogueConditionValue::Integer(*v)),
+        Some((FLTV, Field::F32(v))) => Some(DialogueConditionValue::Float(*v)),
+        _ => None
+    }
+}
+
+pub fn dialogue_conditions(record: &Record) -> Vec<(&DialogueCondition, Option<DialogueConditionValue>)> {
+    record.fields.iter().enumerate().filter_map(|(index, (tag, field))| match (*tag, field) {
+        (SCVR, Field::DialogueCondition(condition)) => Some((condition, condition_value(record.fields.get(index + 1)))),
+        _ => None
+    }).collect()
+}
+
+pub fn set_dialogue_conditions(record: &mut Record, conditions: &[(DialogueCondition, DialogueConditionValue)]) {
+    let mut position = None;
+    let mut index = 0;
+    while index < record.fields.len() {
+        if record.fields[index].0 != SCVR {
+            index += 1;
+            continue;
+        }
+        position.get_or_insert(index);
+        record.fields.remove(index);
+        if condition_value(record.fields.get(index)).is_some() {
+            record.fields.remove(index);
+        }
+    }
+    let position = position.unwrap_or_else(|| record.fields.iter()
+        .position(|(tag, _)| [BNAM, QSTN, QSTF, QSTR].contains(tag))
+        .unwrap_or(record.fields.len())
+    );
+    let fields = conditions.iter().flat_map(|(condition, value)| vec![
+        (SCVR, Field::DialogueCondition(condition.clone())),
+        value.to_field()
+    ]);
+    record.fields.splice(position .. position, fields);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DialogueSpeaker {
+    pub id: String,
+    pub race: String,
+    pub class: String,
+    pub faction: Option<String>,
+    pub rank: i32,
+    pub sex: Option<Sex>,
+    pub cell: String,
+    pub disposition: i32,
+    pub locals: Option<HashMap<String, f64>>,
+}
+
+impl DialogueSpeaker {
+    fn is_in_faction(&self, faction: &str) -> bool {
+        match &self.faction {
+            Some(x) => x.eq_ignore_ascii_case(faction),
+            None => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DialogueState {
+    pub speaker: DialogueSpeaker,
+    pub journal: HashMap<String, i32>,
+    pub globals: HashMap<String, f64>,
+    pub items: HashMap<String, i32>,
+    pub dead: HashMap<String, i32>,
+    pub pc_factions: HashMap<String, i32>,
+    pub functions: HashMap<DialogueFunction, f64>,
+}
+
+fn lookup<V: Copy>(map: &HashMap<String, V>, key: &str) -> Option<V> {
+    map.get(key).copied().or_else(|| map.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, &v)| v))
+}
+
+fn bool_value(b: bool) -> f64 { if b { 1.0 } else { 0.0 } }
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.len() >= prefix.len() && s.is_char_boundary(prefix.len()) && s[.. prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+impl DialogueState {
+    fn condition_operand(&self, condition: &DialogueCondition) -> Option<f64> {
+        let speaker = &self.speaker;
+        let name = condition.name.as_str();
+        Some(match condition.kind {
+            DialogueConditionKind::Function =>
+                self.functions.get(&condition.function.unwrap()).copied().unwrap_or(0.0),
+            DialogueConditionKind::Global => lookup(&self.globals, name).unwrap_or(0.0),
+            DialogueConditionKind::Local => lookup(speaker.locals.as_ref()?, name)?,
+            DialogueConditionKind::Journal => lookup(&self.journal, name).unwrap_or(0) as f64,
+            DialogueConditionKind::Item => lookup(&self.items, name).unwrap_or(0) as f64,
+            DialogueConditionKind::Dead => lookup(&self.dead, name).unwrap_or(0) as f64,
+            DialogueConditionKind::NotId => bool_value(!speaker.id.eq_ignore_ascii_case(name)),
+            DialogueConditionKind::NotFaction =>
+                bool_value(!speaker.is_in_faction(name)),
+            DialogueConditionKind::NotClass => bool_value(!speaker.class.eq_ignore_ascii_case(name)),
+            DialogueConditionKind::NotRace => bool_value(!speaker.race.eq_ignore_ascii_case(name)),
+            DialogueConditionKind::NotCell => bool_value(!starts_with_ignore_case(&speaker.cell, name)),
+            DialogueConditionKind::NotLocal =>
+                bool_value(speaker.locals.as_ref().and_then(|x| lookup(x, name)).is_none()),
+        })
+    }
+
+    pub fn test_condition(&self, condition: &DialogueCondition, value: Option<DialogueConditionValue>) -> bool {
+        let operand = if let Some(operand) = self.condition_operand(condition) { operand } else { return false; };
+        let value = value.map_or(0.0, |x| x.as_f64());
+        match condition.operator {
+            DialogueConditionOperator::Equal => operand == value,
+            DialogueConditionOperator::NotEqual => operand != value,
+            DialogueConditionOperator::Greater => operand > value,
+            DialogueConditionOperator::GreaterOrEqual => operand >= value,
+            DialogueConditionOperator::Less => operand < value,
+            DialogueConditionOperator::LessOrEqual => operand <= value,
+        }
+    }
+
+    pub fn test_info(&self, record: &Record) -> bool {
+        let speaker = &self.speaker;
+        let mut speaker_faction = None;
+        let mut pc_faction = None;
+        for (tag, field) in &record.fields {
+            let passed = match (*tag, field) {
+                (DATA, Field::Info(info)) => {
+                    let sex = match info.sex {
+                        Right(sex) => speaker.sex == Some(sex),
+                        Left(_) => true
+                    };
+                    sex && (info.dialog_type == DialogType::Journal || speaker.disposition >= info.disp_index as i32)
+                },
+                (ONAM, Field::StringZ(id)) => speaker.id.eq_ignore_ascii_case(&id.string),
+                (RNAM, Field::StringZ(race)) => speaker.race.eq_ignore_ascii_case(&race.string),
+                (CNAM, Field::StringZ(class)) => speaker.class.eq_ignore_ascii_case(&class.string),
+                (FNAM, Field::StringZ(faction)) => if faction.string == "FFFF" {
+                    speaker.faction.is_none()
+                } else {
+                    speaker_faction = Some(faction.string.as_str());
+                    speaker.is_in_faction(&faction.string)
+                },
+                (ANAM, Field::StringZ(cell)) => starts_with_ignore_case(&speaker.cell, &cell.string),
+                (DNAM, Field::StringZ(faction)) => {
+                    pc_faction = Some(faction.string.as_str());
+                    lookup(&self.pc_factions, &faction.string).is_some()
+                },
+                _ => true
+            };
+            if !passed { return false; }
+        }
+        if let Some(Field::Info(info)) = record.fields.iter().find(|(tag, _)| *tag == DATA).map(|(_, field)| field) {
+            if let Some(rank) = info.rank {
+                if speaker.faction.is_none() || speaker.rank < rank as i32 { return false; }
+            }
+            if let Some(pc_rank) = info.pc_rank {
+                let faction = pc_faction.or(speaker_faction).or(speaker.faction.as_deref());
+                let pc_rank_in_faction = faction.and_then(|x| lookup(&self.pc_factions, x)).unwrap_or(-1);
+                if pc_rank_in_faction < pc_rank as i32 { return false; }
+            }
+        }
+        dialogue_conditions(record).into_iter().all(|(condition, value)| self.test_condition(condition, value))
+    }
+}
+
+pub(crate) fn record_string(record: &Record, field_tag: Tag) -> Option<&str> {
+    record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (t, Field::StringZ(s)) if t == field_tag => Some(s.string.as_str()),
+        (t, Field::String(s)) if t == field_tag => Some(s.as_str()),
+        _ => None
+    })
+}
+
+pub(crate) fn dialog_type(record: &Record) -> Option<DialogType> {
+    record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (DATA, Field::DialogType(t)) => Some(*t),
+        _ => None
+    })
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SelectedInfo {
+    pub topic: String,
+    pub dialog_type: DialogType,
+    pub record_index: Option<usize>,
+    pub info_id: Option<String>,
+}
+
+pub fn select_infos<'a>(records: impl IntoIterator<Item=&'a Record>, state: &DialogueState) -> Vec<SelectedInfo> {
+    let mut blocks: Vec<(&Record, Vec<(usize, &Record)>)> = Vec::new();
+    let mut in_topic = false;
+    for (index, record) in records.into_iter().enumerate() {
+        match record.tag {
+            DIAL => {
+                in_topic = record_string(record, NAME).is_some();
+                if in_topic { blocks.push((record, Vec::new())); }
+            },
+            INFO => if in_topic {
+                blocks.last_mut().unwrap().1.push((index, record));
+            },
+            _ => in_topic = false
+        }
+    }
+    let mut topics: Vec<(Topic, HashMap<String, usize>)> = Vec::new();
+    let mut by_name = HashMap::new();
+    for (dial, infos) in blocks {
+        let indices = infos.iter().map(|(index, info)| (info_link(info, INAM).to_string(), *index));
+        let infos = infos.iter().map(|(_, info)| (*info).clone());
+        let name = record_string(dial, NAME).unwrap().to_lowercase();
+        if let Some(&topic) = by_name.get(&name) {
+            let (topic, topic_indices): &mut (Topic, HashMap<String, usize>) = &mut topics[topic];
+            topic.dial = dial.clone();
+            topic.merge(infos);
+            topic_indices.extend(indices);
+        } else {
+            by_name.insert(name, topics.len());
+            let infos = infos.filter(|x| !x.flags.contains(RecordFlags::DELETED)).collect();
+            topics.push((Topic { dial: dial.clone(), infos }, indices.collect()));
+        }
+    }
+    topics.into_iter().filter_map(|(topic, indices)| {
+        let dialog_type = dialog_type(&topic.dial)?;
+        if dialog_type == DialogType::Journal { return None; }
+        let info = topic.infos.iter().find(|x| state.test_info(x));
+        let info_id = info.map(|x| info_link(x, INAM).to_string());
+        Some(SelectedInfo {
+            topic: topic.name().to_string(),
+            dialog_type,
+            record_index: info_id.as_ref().and_then(|x| indices.get(x).copied()),
+            info_id
+        })
+    }).collect()
+}
+
+fn info_link(record: &Record, tag: Tag) -> &str {
+    record_string(record, tag).unwrap_or("")
+}
+
+fn set_info_link(record: &mut Record, tag: Tag, id: &str) {
+    if let Some((_, field)) = record.fields.iter_mut().find(|(t, _)| *t == tag) {
+        if let Field::StringZ(s) = field {
+            if s.string == id { return; }
+        }
+        *field = Field::StringZ(id.into());
+    } else {
+        let position = record.fields.iter().rposition(|(t, _)| *t == INAM || *t == PNAM).map_or(0, |x| x + 1);
+        record.fields.insert(position, (tag, Field::StringZ(id.into())));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Topic {
+    pub dial: Record,
+    pub infos: Vec<Record>,
+}
+
+impl Topic {
+    pub fn new(dial: Record, infos: Vec<Record>) -> Topic {
+        let mut topic = Topic { dial, infos: Vec::new() };
+        topic.merge(infos);
+        topic
+    }
+
+    pub fn name(&self) -> &str { record_string(&self.dial, NAME).unwrap_or("") }
+
+    pub fn position(&self, id: &str) -> Option<usize> {
+        self.infos.iter().position(|x| info_link(x, INAM) == id)
+    }
+
+    pub fn insert(&mut self, index: usize, info: Record) {
+        self.infos.insert(index, info);
+        self.relink();
+    }
+
+    pub fn move_info(&mut self, from: usize, to: usize) {
+        let info = self.infos.remove(from);
+        self.infos.insert(to, info);
+        self.relink();
+    }
+
+    pub fn remove(&mut self, index: usize) -> Record {
+        let info = self.infos.remove(index);
+        self.relink();
+        info
+    }
+
+    pub fn relink(&mut self) {
+        let ids = self.infos.iter().map(|x| info_link(x, INAM).to_string()).collect::<Vec<_>>();
+        for (index, info) in self.infos.iter_mut().enumerate() {
+            let previous = if index == 0 { "" } else { &ids[index - 1] };
+            let next = ids.get(index + 1).map_or("", |x| x.as_str());
+            set_info_link(info, PNAM, previous);
+            set_info_link(info, NNAM, next);
+        }
+    }
+
+    pub fn merge(&mut self, infos: impl IntoIterator<Item=Record>) {
+        let mut pending = Vec::new();
+        for info in infos {
+            let id = info_link(&info, INAM).to_string();
+            let deleted = info.flags.contains(RecordFlags::DELETED);
+            if let Some(index) = self.position(&id) {
+                let previous = if index == 0 { "" } else { info_link(&self.infos[index - 1], INAM) };
+                if !deleted && record_string(&info, PNAM).filter(|&x| x != previous).is_none() {
+                    self.infos[index] = info;
+                    continue;
+                }
+                self.infos.remove(index);
+            }
+            if deleted { continue; }
+            pending.push(info);
+        }
+        while !pending.is_empty() {
+            let placed = pending.iter().position(|info| {
+                let previous = info_link(info, PNAM);
+                previous.is_empty() || self.position(previous).is_some()
+            });
+            let info = pending.remove(placed.unwrap_or(0));
+            let previous = info_link(&info, PNAM);
+            let next = info_link(&info, NNAM);
+            let index = if placed.is_none() {
+                self.position(next).unwrap_or(self.infos.len())
+            } else if previous.is_empty() {
+                0
+            } else {
+                self.position(previous).unwrap() + 1
+            };
+            self.infos.insert(index, info);
+        }
+        self.relink();
+    }
+
+    pub fn into_records(self) -> Vec<Record> {
+        let mut records = vec![self.dial];
+        records.extend(self.infos);
+        records
+    }
+}
+
+fn group_topics(records: impl IntoIterator<Item=Record>) -> Vec<(Record, Vec<Record>)> {
+    let mut topics: Vec<(Record, Vec<Record>)> = Vec::new();
+    for record in records {
+        match record.tag {
+            DIAL => topics.push((record, Vec::new())),
+            INFO => if let Some((_, infos)) = topics.last_mut() {
+                infos.push(record);
+            },
+            _ => { }
+        }
+    }
+    topics
+}
+
+pub fn topics(records: impl IntoIterator<Item=Record>) -> Vec<Topic> {
+    group_topics(records).into_iter().map(|(dial, infos)| Topic::new(dial, infos)).collect()
+}
+
+pub fn merge_topics(master: Vec<Topic>, plugins: impl IntoIterator<Item=Vec<Record>>) -> Vec<Topic> {
+    let mut merged = master;
+    for plugin in plugins {
+        for (dial, infos) in group_topics(plugin) {
+            let name = record_string(&dial, NAME).unwrap_or("").to_lowercase();
+            if let Some(existing) = merged.iter_mut().find(|x| x.name().to_lowercase() == name) {
+                existing.dial = dial;
+                existing.merge(infos);
+            } else {
+                merged.push(Topic::new(dial, infos));
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_dialogue_conditions() {
+        let condition = DialogueCondition::from_str("03sX0NoLore").unwrap();
+        assert_eq!(condition.index, 0);
+        assert_eq!(condition.kind, DialogueConditionKind::Local);
+        assert_eq!(condition.var_type, Some(DialogueVarType::Short));
+        assert_eq!(condition.operator, DialogueConditionOperator::Equal);
+        assert_eq!(condition.name, "NoLore");
+        assert_eq!(condition.to_string(), "03sX0NoLore");
+        let condition = DialogueCondition::from_str("51463").unwrap();
+        assert_eq!(condition.index, 5);
+        assert_eq!(condition.function, Some(DialogueFunction::SameFaction));
+        assert_eq!(condition.operator, DialogueConditionOperator::GreaterOrEqual);
+        assert_eq!(condition.to_string(), "51463");
+        let condition = DialogueCondition::from_str("1CfX5Var").unwrap();
+        assert_eq!(condition.kind, DialogueConditionKind::NotLocal);
+        assert_eq!(condition.to_string(), "1CfX5Var");
+        assert_eq!(DialogueCondition::from_str("2BLX1Balmora").unwrap().kind, DialogueConditionKind::NotCell);
+        assert!(DialogueCondition::from_str("01sX0fargoth").is_err());
+        assert!(DialogueCondition::from_str("61000").is_err());
+        assert!(DialogueCondition::from_str("01740").is_err());
+        assert!(DialogueCondition::from_str("04IX0").is_err());
+        assert!(DialogueCondition::from_str("01éé0x").is_err());
+        assert!(DialogueCondition::from_str("2bLX1Balmora").is_err());
+        assert_eq!(DialogueCondition::from_str("2BLX1Балмора").unwrap().name, "Балмора");
+    }
+
+    fn info(id: &str, fields: Vec<(Tag, Field)>) -> Record {
+        let mut record = Record {
+            tag: INFO,
+            flags: RecordFlags::empty(),
+            fields: vec![
+                (INAM, Field::StringZ(id.into())),
+                (DATA, Field::Info(Info {
+                    dialog_type: DialogType::Topic, disp_index: 0, rank: None,
+                    sex: Left(None), pc_rank: None, padding: 0
+                })),
+            ]
+        };
+        record.fields.extend(fields);
+        record
+    }
+
+    fn condition(s: &str, value: i32) -> Vec<(Tag, Field)> {
+        vec![(SCVR, Field::DialogueCondition(DialogueCondition::from_str(s).unwrap())), (INTV, Field::I32(value))]
+    }
+
+    #[test]
+    fn select_topic_infos() {
+        let records = vec![
+            Record {
+                tag: DIAL,
+                flags: RecordFlags::empty(),
+                fields: vec![(NAME, Field::StringZ("latest rumors".into())), (DATA, Field::DialogType(DialogType::Topic))]
+            },
+            info("1", [vec![(ONAM, Field::StringZ("fargoth".into()))], condition("04JX3A1_1_FindSpymaster", 10)].concat()),
+            info("2", [condition("01063", 5), condition("12sX0GameHour", 0)].concat()),
+            info("3", [vec![(RNAM, Field::StringZ("Dark Elf".into()))], condition("13sX0NoLore", 0)].concat()),
+            info("4", [vec![(FNAM, Field::StringZ("FFFF".into()))], condition("17XX0Fargoth", 0)].concat()),
+            info("5", vec![]),
+        ];
+        let mut state = DialogueState::default();
+        state.speaker.id = "Fargoth".into();
+        state.speaker.race = "Wood Elf".into();
+        state.speaker.locals = Some(HashMap::new());
+        state.journal.insert("a1_1_findspymaster".into(), 10);
+        let selected = |state: &DialogueState| select_infos(&records, state)[0].info_id.clone();
+        assert_eq!(selected(&state), Some("1".into()));
+        state.journal.clear();
+        state.functions.insert(DialogueFunction::PcLevel, 5.0);
+        assert_eq!(selected(&state), Some("2".into()));
+        state.globals.insert("GameHour".into(), 12.0);
+        assert_eq!(selected(&state), Some("4".into()));
+        state.speaker.faction = Some("Hlaalu".into());
+        assert_eq!(selected(&state), Some("5".into()));
+        state.speaker.race = "Dark Elf".into();
+        assert_eq!(selected(&state), Some("5".into()));
+        state.speaker.locals.as_mut().unwrap().insert("NoLore".into(), 0.0);
+        assert_eq!(selected(&state), Some("3".into()));
+        let mut ranked = info("6", vec![]);
+        if let Field::Info(info) = &mut ranked.fields[1].1 {
+            info.rank = Some(2);
+        }
+        state.speaker.rank = 3;
+        assert!(state.test_info(&ranked));
+        state.speaker.faction = None;
+        assert!(!state.test_info(&ranked));
+    }
+
+    #[test]
+    fn select_infos_across_plugins() {
+        let mut records = vec![
+            dial("Latest Rumors"),
+            linked_info("1", "", "2"),
+            linked_info("2", "1", ""),
+            dial("latest rumors"),
+            linked_info("3", "", "1"),
+        ];
+        records[4].fields.push((ONAM, Field::StringZ("fargoth".into())));
+        let mut state = DialogueState::default();
+        state.speaker.id = "fargoth".into();
+        let selected = select_infos(&records, &state);
+        assert_eq!(selected, vec![SelectedInfo {
+            topic: "latest rumors".into(), dialog_type: DialogType::Topic, record_index: Some(4), info_id: Some("3".into())
+        }]);
+        state.speaker.id = "hrisskar".into();
+        assert_eq!(select_infos(&records, &state)[0].record_index, Some(1));
+        records[4].flags = RecordFlags::DELETED;
+        records[4].fields[0].1 = Field::StringZ("1".into());
+        assert_eq!(select_infos(&records, &state)[0].info_id, Some("2".into()));
+    }
+
+    fn linked_info(id: &str, previous: &str, next: &str) -> Record {
+        info(id, vec![(PNAM, Field::StringZ(previous.into())), (NNAM, Field::StringZ(next.into()))])
+    }
+
+    fn dial(name: &str) -> Record {
+        Record {
+            tag: DIAL,
+            flags: RecordFlags::empty(),
+            fields: vec![(NAME, Field::StringZ(name.into())), (DATA, Field::DialogType(DialogType::Topic))]
+        }
+    }
+
+    fn ids(topic: &Topic) -> Vec<(&str, &str, &str)> {
+        topic.infos.iter().map(|x| (info_link(x, INAM), info_link(x, PNAM), info_link(x, NNAM))).collect()
+    }
+
+    #[test]
+    fn edit_info_chain() {
+        let mut topic = topics(vec![
+            dial("Background"),
+            linked_info("b", "a", "c"),
+            linked_info("a", "", "b"),
+            linked_info("c", "b", ""),
+        ]).remove(0);
+        assert_eq!(ids(&topic), vec![("a", "", "b"), ("b", "a", "c"), ("c", "b", "")]);
+        topic.insert(1, info("x", vec![]));
+        assert_eq!(ids(&topic), vec![("a", "", "x"), ("x", "a", "b"), ("b", "x", "c"), ("c", "b", "")]);
+        assert_eq!(topic.infos[1].fields[1].0, PNAM);
+        assert_eq!(topic.infos[1].fields[2].0, NNAM);
+        topic.move_info(3, 0);
+        assert_eq!(ids(&topic), vec![("c", "", "a"), ("a", "c", "x"), ("x", "a", "b"), ("b", "x", "")]);
+        topic.remove(2);
+        assert_eq!(ids(&topic), vec![("c", "", "a"), ("a", "c", "b"), ("b", "a", "")]);
+    }
+
+    #[test]
+    fn merge_plugin_infos() {
+        let master = topics(vec![
+            dial("Background"),
+            linked_info("a", "", "b"),
+            linked_info("b", "a", "c"),
+            linked_info("c", "b", ""),
+        ]);
+        let mut deleted = linked_info("c", "b", "");
+        deleted.flags |= RecordFlags::DELETED;
+        let plugin1 = vec![dial("background"), linked_info("p1", "a", "b"), deleted];
+        let plugin2 = vec![dial("Background"), linked_info("p2", "p1", "b"), linked_info("p0", "", "a")];
+        let merged = merge_topics(master, vec![plugin1, plugin2]);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(ids(&merged[0]).into_iter().map(|x| x.0).collect::<Vec<_>>(), vec!["p0", "a", "p1", "p2", "b"]);
+        assert_eq!(ids(&merged[0])[4], ("b", "p2", ""));
+        let plugin3 = vec![dial("Background"), linked_info("b", "p0", "a"), linked_info("a", "b", "p1")];
+        let merged = merge_topics(merged, vec![plugin3]);
+        assert_eq!(ids(&merged[0]).into_iter().map(|x| x.0).collect::<Vec<_>>(), vec!["p0", "b", "a", "p1", "p2"]);
+    }
+
+    #[test]
+    fn set_conditions() {
+        let mut record = Record {
+            tag: INFO,
+            flags: RecordFlags::empty(),
+            fields: vec![
+                (INAM, Field::String("1".into())),
+                (SCVR, Field::DialogueCondition(DialogueCondition::from_str("03sX0NoLore").unwrap())),
+                (INTV, Field::I32(0)),
+                (BNAM, Field::StringList(vec!["Journal A 10".into()])),
+            ]
+        };
+        assert_eq!(dialogue_conditions(&record)[0].1, Some(DialogueConditionValue::Integer(0)));
+        set_dialogue_conditions(&mut record, &[
+            (DialogueCondition::from_str("01060").unwrap(), DialogueConditionValue::Integer(1)),
+            (DialogueCondition::from_str("12fX2Timescale").unwrap(), DialogueConditionValue::Float(10.5)),
+        ]);
+        assert_eq!(record.fields.len(), 6);
+        assert_eq!(record.fields[5].0, BNAM);
+        let conditions = dialogue_conditions(&record);
+        assert_eq!(conditions.len(), 2);
+        assert_eq!(conditions[0].0.function, Some(DialogueFunction::PcLevel));
+        assert_eq!(conditions[1].1, Some(DialogueConditionValue::Float(10.5)));
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::dialogue::record_string;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DialogueNode {
+    Topic { name: String, dialog_type: DialogType },
+    Info { topic: String, id: String, text: String, index: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DialogueEdgeKind {
+    Response,
+    Journal(i32),
+    Choice { text: String, number: i32 },
+    AddTopic,
+    Mention,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DialogueEdge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: DialogueEdgeKind,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DialogueGraph {
+    pub nodes: Vec<DialogueNode>,
+    pub edges: Vec<DialogueEdge>,
+}
+
+fn tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                if quoted {
+                    tokens.push(token.clone());
+                    token.clear();
+                }
+                quoted = !quoted;
+            },
+            ';' if !quoted => break,
+            c if !quoted && (c.is_whitespace() || c == ',') => if !token.is_empty() {
+                tokens.push(token.clone());
+                token.clear();
+            },
+            c => token.push(c)
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum ResultCommand {
+    Journal(String, i32),
+    AddTopic(String),
+    Choice(Vec<(String, i32)>),
+}
+
+fn result_commands(lines: &[String]) -> Vec<ResultCommand> {
+    lines.iter().filter_map(|line| {
+        let tokens = tokens(line);
+        let command = tokens.first()?.to_lowercase();
+        match command.as_str() {
+            "journal" | "setjournalindex" =>
+                Some(ResultCommand::Journal(tokens.get(1)?.clone(), tokens.get(2)?.parse().ok()?)),
+            "addtopic" => Some(ResultCommand::AddTopic(tokens.get(1)?.clone())),
+            "choice" => Some(ResultCommand::Choice(
+                tokens[1 ..].chunks(2).filter_map(|x| Some((x[0].clone(), x.get(1)?.parse().ok()?))).collect()
+            )),
+            _ => None
+        }
+    }).collect()
+}
+
+fn choice_number(record: &Record) -> Option<i32> {
+    record.fields.windows(2).find_map(|x| match (&x[0], &x[1]) {
+        ((SCVR, Field::DialogueCondition(c)), (INTV, Field::I32(n)))
+            if c.function == Some(DialogueFunction::Choice) && c.operator == DialogueConditionOperator::Equal => Some(*n),
+        _ => None
+    })
+}
+
+impl DialogueGraph {
+    pub fn new<'a>(records: impl IntoIterator<Item=&'a Record>) -> DialogueGraph {
+        let mut graph = DialogueGraph::default();
+        let mut topics = HashMap::new();
+        let mut overrides: Vec<(usize, Option<&Record>)> = Vec::new();
+        let mut info_ids = HashMap::new();
+        let mut current = None;
+        for record in records {
+            let deleted = record.flags.contains(RecordFlags::DELETED);
+            match record.tag {
+                DIAL => {
+                    current = None;
+                    if deleted { continue; }
+                    let name = if let Some(name) = record_string(record, NAME) { name } else { continue; };
+                    let dialog_type = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+                        (DATA, Field::DialogType(t)) => Some(*t),
+                        _ => None
+                    });
+                    let dialog_type = if let Some(t) = dialog_type { t } else { continue; };
+                    let node = *topics.entry(name.to_lowercase()).or_insert_with(|| {
+                        graph.nodes.push(DialogueNode::Topic { name: name.to_string(), dialog_type });
+                        graph.nodes.len() - 1
+                    });
+                    current = Some(node);
+                },
+                INFO => if let Some(topic_node) = current {
+                    let id = record_string(record, INAM).unwrap_or("").to_string();
+                    let record = if deleted { None } else { Some(record) };
+                    match info_ids.get(&(topic_node, id.clone())) {
+                        Some(&index) => overrides[index] = (topic_node, record),
+                        None => {
+                            info_ids.insert((topic_node, id), overrides.len());
+                            overrides.push((topic_node, record));
+                        }
+                    }
+                },
+                _ => current = None
+            }
+        }
+        let mut infos: Vec<(usize, usize, &Record)> = Vec::new();
+        for (topic_node, record) in overrides {
+            let record = if let Some(record) = record { record } else { continue; };
+            let topic = match &graph.nodes[topic_node] {
+                DialogueNode::Topic { name, .. } => name.clone(),
+                DialogueNode::Info { .. } => unreachable!()
+            };
+            let id = record_string(record, INAM).unwrap_or("").to_string();
+            let text = record_string(record, NAME).unwrap_or("").to_string();
+            let index = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+                (DATA, Field::Info(info)) => Some(info.disp_index),
+                _ => None
+            }).unwrap_or(0);
+            graph.nodes.push(DialogueNode::Info { topic, id, text, index });
+            let node = graph.nodes.len() - 1;
+            graph.edges.push(DialogueEdge { from: topic_node, to: node, kind: DialogueEdgeKind::Response });
+            infos.push((topic_node, node, record));
+        }
+        let journal_entry = |graph: &DialogueGraph, topic_node: usize, journal_index: i32| {
+            infos.iter().find(|&&(t, n, _)| t == topic_node && matches!(
+                &graph.nodes[n], DialogueNode::Info { index, .. } if *index as i32 == journal_index
+            )).map_or(topic_node, |&(_, n, _)| n)
+        };
+        let mentionable = graph.nodes.iter().enumerate().filter_map(|(node, x)| match x {
+            DialogueNode::Topic { name, dialog_type: DialogType::Topic } => Some((node, name.to_lowercase())),
+            _ => None
+        }).collect::<Vec<_>>();
+        for &(topic_node, node, record) in &infos {
+            let result = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+                (BNAM, Field::StringList(lines)) => Some(lines.as_slice()),
+                _ => None
+            }).unwrap_or(&[]);
+            for command in result_commands(result) {
+                match command {
+                    ResultCommand::Journal(journal, journal_index) => if let Some(&journal_node) = topics.get(&journal.to_lowercase()) {
+                        let to = journal_entry(&graph, journal_node, journal_index);
+                        graph.edges.push(DialogueEdge { from: node, to, kind: DialogueEdgeKind::Journal(journal_index) });
+                    },
+                    ResultCommand::AddTopic(topic) => if let Some(&to) = topics.get(&topic.to_lowercase()) {
+                        graph.edges.push(DialogueEdge { from: node, to, kind: DialogueEdgeKind::AddTopic });
+                    },
+                    ResultCommand::Choice(choices) => for (text, number) in choices {
+                        for &(t, choice_node, choice_record) in &infos {
+                            if t == topic_node && choice_number(choice_record) == Some(number) {
+                                let kind = DialogueEdgeKind::Choice { text: text.clone(), number };
+                                graph.edges.push(DialogueEdge { from: node, to: choice_node, kind });
+                            }
+                        }
+                    },
+                }
+            }
+            if let DialogueNode::Info { text, .. } = &graph.nodes[node] {
+                let text = text.to_lowercase();
+                let mentions = mentionable.iter()
+                    .filter(|(to, name)| *to != topic_node && !name.is_empty() && text.contains(name.as_str()))
+                    .map(|&(to, _)| DialogueEdge { from: node, to, kind: DialogueEdgeKind::Mention })
+                    .collect::<Vec<_>>();
+                graph.edges.extend(mentions);
+            }
+        }
+        graph
+    }
+
+    fn node_label(&self, node: usize) -> String {
+        match &self.nodes[node] {
+            DialogueNode::Topic { name, .. } => name.clone(),
+            DialogueNode::Info { text, .. } => {
+                let mut label = text.chars().take(40).collect::<String>();
+                if label.len() < text.len() {
+                    label.push_str("...");
+                }
+                label
+            }
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"));
+        let mut dot = String::new();
+        writeln!(dot, "digraph dialogue {{").unwrap();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let shape = match node {
+                DialogueNode::Topic { dialog_type: DialogType::Journal, .. } => "folder",
+                DialogueNode::Topic { .. } => "box",
+                DialogueNode::Info { .. } => "note",
+            };
+            writeln!(dot, "    n{} [label={}, shape={}];", index, quote(&self.node_label(index)), shape).unwrap();
+        }
+        for edge in &self.edges {
+            let attributes = match &edge.kind {
+                DialogueEdgeKind::Response => String::new(),
+                DialogueEdgeKind::Journal(index) => format!(" [label={}, color=blue]", quote(&index.to_string())),
+                DialogueEdgeKind::Choice { text, number } =>
+                    format!(" [label={}, color=darkgreen]", quote(&format!("{}: {}", number, text))),
+                DialogueEdgeKind::AddTopic => " [label=\"AddTopic\", style=dashed]".into(),
+                DialogueEdgeKind::Mention => " [style=dotted]".into(),
+            };
+            writeln!(dot, "    n{} -> n{}{};", edge.from, edge.to, attributes).unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let quote = |s: &str| {
+            let mut q = String::from("\"");
+            for c in s.chars() {
+                match c {
+                    '"' => q.push_str("\\\""),
+                    '\\' => q.push_str("\\\\"),
+                    '\n' => q.push_str("\\n"),
+                    '\r' => q.push_str("\\r"),
+                    '\t' => q.push_str("\\t"),
+                    c if (c as u32) < 0x20 => write!(q, "\\u{:04x}", c as u32).unwrap(),
+                    c => q.push(c)
+                }
+            }
+            q.push('"');
+            q
+        };
+        let nodes = self.nodes.iter().enumerate().map(|(index, node)| match node {
+            DialogueNode::Topic { name, dialog_type } => format!(
+                "{{\"id\":{},\"kind\":\"topic\",\"name\":{},\"dialog_type\":{}}}",
+                index, quote(name), quote(&dialog_type.to_string())
+            ),
+            DialogueNode::Info { topic, id, text, index: disp_index } => format!(
+                "{{\"id\":{},\"kind\":\"info\",\"topic\":{},\"info_id\":{},\"text\":{},\"index\":{}}}",
+                index, quote(topic), quote(id), quote(text), disp_index
+            ),
+        }).collect::<Vec<_>>();
+        let edges = self.edges.iter().map(|edge| {
+            let kind = match &edge.kind {
+                DialogueEdgeKind::Response => "\"kind\":\"response\"".to_string(),
+                DialogueEdgeKind::Journal(index) => format!("\"kind\":\"journal\",\"index\":{}", index),
+                DialogueEdgeKind::Choice { text, number } =>
+                    format!("\"kind\":\"choice\",\"text\":{},\"number\":{}", quote(text), number),
+                DialogueEdgeKind::AddTopic => "\"kind\":\"add_topic\"".to_string(),
+                DialogueEdgeKind::Mention => "\"kind\":\"mention\"".to_string(),
+            };
+            format!("{{\"from\":{},\"to\":{},{}}}", edge.from, edge.to, kind)
+        }).collect::<Vec<_>>();
+        format!("{{\"nodes\":[{}],\"edges\":[{}]}}", nodes.join(","), edges.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use either::Left;
+    use std::str::FromStr;
+
+    fn dial(name: &str, dialog_type: DialogType) -> Record {
+        Record {
+            tag: DIAL,
+            flags: RecordFlags::empty(),
+            fields: vec![(NAME, Field::StringZ(name.into())), (DATA, Field::DialogType(dialog_type))]
+        }
+    }
+
+    fn info(id: &str, disp_index: u32, text: &str, fields: Vec<(Tag, Field)>) -> Record {
+        let mut record = Record {
+            tag: INFO,
+            flags: RecordFlags::empty(),
+            fields: vec![
+                (INAM, Field::StringZ(id.into())),
+                (DATA, Field::Info(Info {
+                    dialog_type: DialogType::Topic, disp_index, rank: None,
+                    sex: Left(None), pc_rank: None, padding: 0
+                })),
+                (NAME, Field::String(text.into())),
+            ]
+        };
+        record.fields.extend(fields);
+        record
+    }
+
+    #[test]
+    fn result_script_commands() {
+        assert_eq!(result_commands(&[
+            "Journal \"A1_1_FindSpymaster\", 10 ; comment".into(),
+            "choice \"Yes, sure\" 1 \"No\" 2".into(),
+            "AddTopic \"Latest Rumors\"".into(),
+            "ModDisposition 10".into(),
+        ]), vec![
+            ResultCommand::Journal("A1_1_FindSpymaster".into(), 10),
+            ResultCommand::Choice(vec![("Yes, sure".into(), 1), ("No".into(), 2)]),
+            ResultCommand::AddTopic("Latest Rumors".into()),
+        ]);
+    }
+
+    #[test]
+    fn dialogue_graph() {
+        let records = vec![
+            dial("A1_1_FindSpymaster", DialogType::Journal),
+            info("j1", 1, "I must find Caius.", vec![]),
+            info("j10", 10, "I found Caius.", vec![]),
+            dial("Caius", DialogType::Topic),
+            info("c1", 0, "Ask about Latest Rumors.", vec![
+                (BNAM, Field::StringList(vec!["Journal A1_1_FindSpymaster 10".into(), "Choice \"Yes\" 1 \"No\" 2".into()])),
+            ]),
+            info("c2", 0, "Good.", vec![
+                (SCVR, Field::DialogueCondition(DialogueCondition::from_str("01500").unwrap())),
+                (INTV, Field::I32(1)),
+            ]),
+            dial("latest rumors", DialogType::Topic),
+            dial("Caius", DialogType::Topic),
+            info("c2", 0, "Good, good.", vec![
+                (SCVR, Field::DialogueCondition(DialogueCondition::from_str("01500").unwrap())),
+                (INTV, Field::I32(1)),
+            ]),
+            Record { flags: RecordFlags::DELETED, ..dial("Gossip", DialogType::Topic) },
+            info("g1", 0, "Nothing new.", vec![]),
+        ];
+        let graph = DialogueGraph::new(&records);
+        assert_eq!(graph.nodes.len(), 7);
+        assert!(matches!(&graph.nodes[6], DialogueNode::Info { topic, text, .. } if topic == "Caius" && text == "Good, good."));
+        assert_eq!(graph.edges, vec![
+            DialogueEdge { from: 0, to: 3, kind: DialogueEdgeKind::Response },
+            DialogueEdge { from: 0, to: 4, kind: DialogueEdgeKind::Response },
+            DialogueEdge { from: 1, to: 5, kind: DialogueEdgeKind::Response },
+            DialogueEdge { from: 1, to: 6, kind: DialogueEdgeKind::Response },
+            DialogueEdge { from: 3, to: 1, kind: DialogueEdgeKind::Mention },
+            DialogueEdge { from: 4, to: 1, kind: DialogueEdgeKind::Mention },
+            DialogueEdge { from: 5, to: 4, kind: DialogueEdgeKind::Journal(10) },
+            DialogueEdge { from: 5, to: 6, kind: DialogueEdgeKind::Choice { text: "Yes".into(), number: 1 } },
+            DialogueEdge { from: 5, to: 2, kind: DialogueEdgeKind::Mention },
+        ]);
+        let dot = graph.to_dot();
+        assert!(dot.contains("    n4 [label=\"I found Caius.\", shape=note];\n"));
+        assert!(dot.contains("    n5 -> n6 [label=\"1: Yes\", color=darkgreen];\n"));
+        let json = graph.to_json();
+        assert!(json.starts_with("{\"nodes\":[{\"id\":0,\"kind\":\"topic\",\"name\":\"A1_1_FindSpymaster\",\"dialog_type\":\"Journal\"}"));
+        assert!(json.ends_with("{\"from\":5,\"to\":2,\"kind\":\"mention\"}]}"));
+    }
+}
@@ -0,0 +1,868 @@
+use educe::Educe;
+use either::Right;
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeSeed, EnumAccess, IntoDeserializer, VariantAccess, Visitor};
+use serde::ser::{self, Serialize, Serializer};
+use serde::forward_to_deserialize_any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use crate::cell::*;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, Educe)]
+#[educe(PartialEq)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(#[educe(PartialEq(method="eq_f32"))] f32),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::None => write!(f, "none"),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::UInt(v) => write!(f, "{}", v),
+            Value::Float(v) if v.is_nan() => write!(f, "nan{:08X}", v.to_bits()),
+            Value::Float(v) => write!(f, "{}", v),
+            Value::Str(v) => write!(f, "{:?}", v),
+            Value::Bytes(v) => {
+                write!(f, "[")?;
+                for (i, b) in v.iter().enumerate() {
+                    if i != 0 { write!(f, " ")?; }
+                    write!(f, "{:02X}", b)?;
+                }
+                write!(f, "]")
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MemberError(String);
+
+impl Display for MemberError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.0) }
+}
+
+impl Error for MemberError { }
+
+impl ser::Error for MemberError {
+    fn custom<T: Display>(msg: T) -> Self { MemberError(msg.to_string()) }
+}
+
+impl de::Error for MemberError {
+    fn custom<T: Display>(msg: T) -> Self { MemberError(msg.to_string()) }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Member {
+    Value(Value),
+    Seq(Vec<Member>),
+    TupleStruct(&'static str, Vec<Member>),
+    Struct(&'static str, Vec<(&'static str, Member)>),
+    Map(Vec<(Member, Member)>),
+    Variant(&'static str, Box<Member>),
+    TupleVariant(&'static str, Vec<Member>),
+    StructVariant(&'static str, Vec<(&'static str, Member)>),
+}
+
+fn member_path(path: &str, name: &str) -> String {
+    if path.is_empty() { name.to_string() } else { format!("{}.{}", path, name) }
+}
+
+impl Member {
+    fn collect<'a>(&'a self, path: &str, out: &mut Vec<(String, &'a Value)>) {
+        let elements = |items: &'a [Member], path: &str, out: &mut Vec<(String, &'a Value)>| {
+            for (index, item) in items.iter().enumerate() {
+                item.collect(&format!("{}[{}]", path, index), out);
+            }
+        };
+        let fields = |fields: &'a [(&'static str, Member)], path: &str, out: &mut Vec<(String, &'a Value)>| {
+            for (name, member) in fields {
+                member.collect(&member_path(path, name), out);
+            }
+        };
+        match self {
+            Member::Value(value) => out.push((path.to_string(), value)),
+            Member::Seq(items) => elements(items, path, out),
+            Member::TupleStruct(name, items) => elements(items, if path.is_empty() { name } else { path }, out),
+            Member::Struct(name, members) => fields(members, if path.is_empty() { name } else { path }, out),
+            Member::Map(entries) => for (key, value) in entries {
+                let key = key.leaves().into_iter().map(|(_, v)| match v {
+                    Value::Str(s) => s,
+                    v => v.to_string()
+                }).collect::<Vec<_>>().join(",");
+                value.collect(&member_path(path, &key), out);
+            },
+            Member::Variant(name, member) => member.collect(&member_path(path, name), out),
+            Member::TupleVariant(name, items) => elements(items, &member_path(path, name), out),
+            Member::StructVariant(name, members) => fields(members, &member_path(path, name), out),
+        }
+    }
+
+    pub(crate) fn leaves(&self) -> Vec<(String, Value)> {
+        let mut out = Vec::new();
+        self.collect("", &mut out);
+        out.into_iter().map(|(path, value)| (path, value.clone())).collect()
+    }
+
+    pub(crate) fn values_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Member::Value(value) => vec![value],
+            Member::Seq(items) | Member::TupleStruct(_, items) | Member::TupleVariant(_, items) =>
+                items.iter_mut().flat_map(|x| x.values_mut()).collect(),
+            Member::Struct(_, members) | Member::StructVariant(_, members) =>
+                members.iter_mut().flat_map(|(_, x)| x.values_mut()).collect(),
+            Member::Map(entries) => entries.iter_mut().flat_map(|(_, x)| x.values_mut()).collect(),
+            Member::Variant(_, member) => member.values_mut(),
+        }
+    }
+}
+
+enum CompoundKind {
+    Seq,
+    TupleStruct(&'static str),
+    Struct(&'static str),
+    Map,
+    TupleVariant(&'static str),
+    StructVariant(&'static str),
+}
+
+struct Compound {
+    kind: CompoundKind,
+    items: Vec<Member>,
+    members: Vec<(&'static str, Member)>,
+    entries: Vec<(Member, Member)>,
+    key: Option<Member>,
+}
+
+impl Compound {
+    fn new(kind: CompoundKind) -> Compound {
+        Compound { kind, items: Vec::new(), members: Vec::new(), entries: Vec::new(), key: None }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MemberError> {
+        self.items.push(v.serialize(MemberSerializer)?);
+        Ok(())
+    }
+
+    fn member<T: Serialize + ?Sized>(&mut self, name: &'static str, v: &T) -> Result<(), MemberError> {
+        self.members.push((name, v.serialize(MemberSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Member, MemberError> {
+        Ok(match self.kind {
+            CompoundKind::Seq => Member::Seq(self.items),
+            CompoundKind::TupleStruct(name) => Member::TupleStruct(name, self.items),
+            CompoundKind::Struct(name) => Member::Struct(name, self.members),
+            CompoundKind::Map => Member::Map(self.entries),
+            CompoundKind::TupleVariant(name) => Member::TupleVariant(name, self.items),
+            CompoundKind::StructVariant(name) => Member::StructVariant(name, self.members),
+        })
+    }
+}
+
+impl ser::SerializeSeq for Compound {
+    type Ok = Member;
+    type Error = MemberError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MemberError> { self.element(v) }
+
+    fn end(self) -> Result<Member, MemberError> { Compound::end(self) }
+}
+
+impl ser::SerializeTuple for Compound {
+    type Ok = Member;
+    type Error = MemberError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MemberError> { self.element(v) }
+
+    fn end(self) -> Result<Member, MemberError> { Compound::end(self) }
+}
+
+impl ser::SerializeTupleStruct for Compound {
+    type Ok = Member;
+    type Error = MemberError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MemberError> { self.element(v) }
+
+    fn end(self) -> Result<Member, MemberError> { Compound::end(self) }
+}
+
+impl ser::SerializeTupleVariant for Compound {
+    type Ok = Member;
+    type Error = MemberError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MemberError> { self.element(v) }
+
+    fn end(self) -> Result<Member, MemberError> { Compound::end(self) }
+}
+
+impl ser::SerializeStruct for Compound {
+    type Ok = Member;
+    type Error = MemberError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, name: &'static str, v: &T) -> Result<(), MemberError> {
+        self.member(name, v)
+    }
+
+    fn end(self) -> Result<Member, MemberError> { Compound::end(self) }
+}
+
+impl ser::SerializeStructVariant for Compound {
+    type Ok = Member;
+    type Error = MemberError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, name: &'static str, v: &T) -> Result<(), MemberError> {
+        self.member(name, v)
+    }
+
+    fn end(self) -> Result<Member, MemberError> { Compound::end(self) }
+}
+
+impl ser::SerializeMap for Compound {
+    type Ok = Member;
+    type Error = MemberError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), MemberError> {
+        self.key = Some(key.serialize(MemberSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MemberError> {
+        let key = self.key.take().unwrap();
+        self.entries.push((key, v.serialize(MemberSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Member, MemberError> { Compound::end(self) }
+}
+
+struct MemberSerializer;
+
+impl Serializer for MemberSerializer {
+    type Ok = Member;
+    type Error = MemberError;
+    type SerializeSeq = Compound;
+    type SerializeTuple = Compound;
+    type SerializeTupleStruct = Compound;
+    type SerializeTupleVariant = Compound;
+    type SerializeMap = Compound;
+    type SerializeStruct = Compound;
+    type SerializeStructVariant = Compound;
+
+    fn serialize_bool(self, v: bool) -> Result<Member, MemberError> { Ok(Member::Value(Value::Bool(v))) }
+
+    fn serialize_i8(self, v: i8) -> Result<Member, MemberError> { Ok(Member::Value(Value::Int(v.into()))) }
+
+    fn serialize_i16(self, v: i16) -> Result<Member, MemberError> { Ok(Member::Value(Value::Int(v.into()))) }
+
+    fn serialize_i32(self, v: i32) -> Result<Member, MemberError> { Ok(Member::Value(Value::Int(v.into()))) }
+
+    fn serialize_i64(self, v: i64) -> Result<Member, MemberError> { Ok(Member::Value(Value::Int(v))) }
+
+    fn serialize_u8(self, v: u8) -> Result<Member, MemberError> { Ok(Member::Value(Value::UInt(v.into()))) }
+
+    fn serialize_u16(self, v: u16) -> Result<Member, MemberError> { Ok(Member::Value(Value::UInt(v.into()))) }
+
+    fn serialize_u32(self, v: u32) -> Result<Member, MemberError> { Ok(Member::Value(Value::UInt(v.into()))) }
+
+    fn serialize_u64(self, v: u64) -> Result<Member, MemberError> { Ok(Member::Value(Value::UInt(v))) }
+
+    fn serialize_f32(self, v: f32) -> Result<Member, MemberError> { Ok(Member::Value(Value::Float(v))) }
+
+    fn serialize_f64(self, v: f64) -> Result<Member, MemberError> { Ok(Member::Value(Value::Float(v as f32))) }
+
+    fn serialize_char(self, v: char) -> Result<Member, MemberError> { Ok(Member::Value(Value::Str(v.to_string()))) }
+
+    fn serialize_str(self, v: &str) -> Result<Member, MemberError> { Ok(Member::Value(Value::Str(v.to_string()))) }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Member, MemberError> { Ok(Member::Value(Value::Bytes(v.to_vec()))) }
+
+    fn serialize_none(self) -> Result<Member, MemberError> { Ok(Member::Value(Value::None)) }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, v: &T) -> Result<Member, MemberError> { v.serialize(self) }
+
+    fn serialize_unit(self) -> Result<Member, MemberError> { Ok(Member::Value(Value::None)) }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Member, MemberError> { Ok(Member::Value(Value::None)) }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Member, MemberError> {
+        Ok(Member::Value(Value::Str(variant.to_string())))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, v: &T) -> Result<Member, MemberError> {
+        v.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32, variant: &'static str, v: &T)
+        -> Result<Member, MemberError> {
+
+        Ok(Member::Variant(variant, Box::new(v.serialize(self)?)))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Compound, MemberError> { Ok(Compound::new(CompoundKind::Seq)) }
+
+    fn serialize_tuple(self, _: usize) -> Result<Compound, MemberError> { Ok(Compound::new(CompoundKind::Seq)) }
+
+    fn serialize_tuple_struct(self, name: &'static str, _: usize) -> Result<Compound, MemberError> {
+        Ok(Compound::new(CompoundKind::TupleStruct(name)))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize)
+        -> Result<Compound, MemberError> {
+
+        Ok(Compound::new(CompoundKind::TupleVariant(variant)))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Compound, MemberError> { Ok(Compound::new(CompoundKind::Map)) }
+
+    fn serialize_struct(self, name: &'static str, _: usize) -> Result<Compound, MemberError> {
+        Ok(Compound::new(CompoundKind::Struct(name)))
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, _: usize)
+        -> Result<Compound, MemberError> {
+
+        Ok(Compound::new(CompoundKind::StructVariant(variant)))
+    }
+}
+
+impl<'de> IntoDeserializer<'de, MemberError> for Member {
+    type Deserializer = Member;
+
+    fn into_deserializer(self) -> Member { self }
+}
+
+impl<'de> de::Deserializer<'de> for Member {
+    type Error = MemberError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MemberError> {
+        match self {
+            Member::Value(Value::None) => visitor.visit_unit(),
+            Member::Value(Value::Bool(v)) => visitor.visit_bool(v),
+            Member::Value(Value::Int(v)) => visitor.visit_i64(v),
+            Member::Value(Value::UInt(v)) => visitor.visit_u64(v),
+            Member::Value(Value::Float(v)) => visitor.visit_f32(v),
+            Member::Value(Value::Str(v)) => visitor.visit_string(v),
+            Member::Value(Value::Bytes(v)) => visitor.visit_byte_buf(v),
+            Member::Seq(items) | Member::TupleStruct(_, items) => visitor.visit_seq(SeqDeserializer::new(items.into_iter())),
+            Member::Struct(_, members) => visitor.visit_map(MapDeserializer::new(members.into_iter()
+                .map(|(name, member)| (Member::Value(Value::Str(name.to_string())), member)))),
+            Member::Map(entries) => visitor.visit_map(MapDeserializer::new(entries.into_iter())),
+            member => visitor.visit_enum(member),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MemberError> {
+        match self {
+            Member::Value(Value::None) => visitor.visit_none(),
+            member => visitor.visit_some(member),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, MemberError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V)
+        -> Result<V::Value, MemberError> {
+
+        match self {
+            Member::Value(Value::Str(variant)) => visitor.visit_enum(IntoDeserializer::<MemberError>::into_deserializer(variant)),
+            member => visitor.visit_enum(member),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> EnumAccess<'de> for Member {
+    type Error = MemberError;
+    type Variant = Member;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Member), MemberError> {
+        let (variant, member) = match self {
+            Member::Variant(variant, member) => (variant, *member),
+            Member::TupleVariant(variant, items) => (variant, Member::Seq(items)),
+            Member::StructVariant(variant, members) => (variant, Member::Struct(variant, members)),
+            _ => return Err(de::Error::custom("enum variant expected"))
+        };
+        Ok((seed.deserialize(IntoDeserializer::<MemberError>::into_deserializer(variant))?, member))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Member {
+    type Error = MemberError;
+
+    fn unit_variant(self) -> Result<(), MemberError> { Ok(()) }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, MemberError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, MemberError> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, MemberError> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+}
+
+pub(crate) fn field_members(record_tag: Tag, field_tag: Tag, field: &Field) -> Result<Member, MemberError> {
+    FieldBodySerializer { record_tag, field_tag, field }.serialize(MemberSerializer)
+}
+
+pub(crate) fn members_field(record_tag: Tag, field_tag: Tag, members: Member) -> Option<Field> {
+    let deserializer = FieldBodyDeserializer { record_tag, field_tag };
+    match deserializer.deserialize(members) {
+        Ok(Right((_, field))) => Some(field),
+        _ => None
+    }
+}
+
+fn flatten(record_tag: Tag, field_tag: Tag, field: &Field) -> Vec<(String, Value)> {
+    match field_members(record_tag, field_tag, field) {
+        Ok(members) => members.leaves(),
+        Err(_) => vec![(String::new(), Value::Str(format!("{:?}", field)))]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemberChange {
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldChange {
+    Added { tag: Tag, occurrence: usize, field: Field },
+    Removed { tag: Tag, occurrence: usize, field: Field },
+    Modified { tag: Tag, occurrence: usize, members: Vec<MemberChange> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReferenceChange {
+    Added { ref_num: Option<i32> },
+    Removed { ref_num: Option<i32> },
+    Modified { ref_num: Option<i32>, new_ref_num: Option<i32>, fields: Vec<FieldChange> },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct RecordKey {
+    pub tag: Tag,
+    pub id: String,
+}
+
+impl Display for RecordKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.id.is_empty() { write!(f, "{}", self.tag) } else { write!(f, "{} {}", self.tag, self.id) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordChange {
+    Added { key: RecordKey, new_index: usize },
+    Removed { key: RecordKey, old_index: usize },
+    Modified {
+        key: RecordKey, old_index: usize, new_index: usize,
+        flags: Option<(RecordFlags, RecordFlags)>,
+        fields: Vec<FieldChange>,
+        references: Vec<ReferenceChange>,
+    },
+}
+
+fn member_changes(record_tag: Tag, field_tag: Tag, old: &Field, new: &Field) -> Vec<MemberChange> {
+    let old = flatten(record_tag, field_tag, old);
+    let new = flatten(record_tag, field_tag, new);
+    let mut changes = Vec::new();
+    for (path, old_value) in &old {
+        match new.iter().find(|(p, _)| p == path) {
+            Some((_, new_value)) if new_value == old_value => { },
+            new_value => changes.push(MemberChange {
+                path: path.clone(), old: Some(old_value.clone()), new: new_value.map(|(_, v)| v.clone())
+            }),
+        }
+    }
+    for (path, new_value) in &new {
+        if !old.iter().any(|(p, _)| p == path) {
+            changes.push(MemberChange { path: path.clone(), old: None, new: Some(new_value.clone()) });
+        }
+    }
+    changes
+}
+
+pub fn diff_fields(record_tag: Tag, old: &[(Tag, Field)], new: &[(Tag, Field)]) -> Vec<FieldChange> {
+    let occurrences = |fields: &[(Tag, Field)]| {
+        let mut counts = HashMap::new();
+        fields.iter().map(|(tag, _)| {
+            let count = counts.entry(*tag).or_insert(0);
+            *count += 1;
+            *count - 1
+        }).collect::<Vec<_>>()
+    };
+    let old_occurrences = occurrences(old);
+    let new_occurrences = occurrences(new);
+    let find = |fields: &[(Tag, Field)], occurrences: &[usize], tag: Tag, occurrence: usize| {
+        fields.iter().zip(occurrences).position(|((t, _), &o)| *t == tag && o == occurrence)
+    };
+    let mut changes = Vec::new();
+    for (index, (tag, old_field)) in old.iter().enumerate() {
+        let occurrence = old_occurrences[index];
+        match find(new, &new_occurrences, *tag, occurrence) {
+            None => changes.push(FieldChange::Removed { tag: *tag, occurrence, field: old_field.clone() }),
+            Some(new_index) => {
+                let new_field = &new[new_index].1;
+                if new_field != old_field {
+                    let members = member_changes(record_tag, *tag, old_field, new_field);
+                    changes.push(FieldChange::Modified { tag: *tag, occurrence, members });
+                }
+            }
+        }
+    }
+    for (index, (tag, new_field)) in new.iter().enumerate() {
+        let occurrence = new_occurrences[index];
+        if find(old, &old_occurrences, *tag, occurrence).is_none() {
+            changes.push(FieldChange::Added { tag: *tag, occurrence, field: new_field.clone() });
+        }
+    }
+    changes
+}
+
+type ReferenceKey = (Option<String>, u8, Option<u32>, usize);
+
+fn reference_keys(cell: &CellData, file: FileContext) -> Vec<ReferenceKey> {
+    let mut counts = HashMap::new();
+    cell.references.iter().map(|reference| {
+        let (master, master_index) = reference_master(reference, file);
+        let key = (master, master_index, reference.object_index());
+        let count = counts.entry(key.clone()).or_insert(0);
+        *count += 1;
+        (key.0, key.1, key.2, *count - 1)
+    }).collect()
+}
+
+fn diff_references(old: &CellData, old_file: FileContext, new: &CellData, new_file: FileContext) -> Vec<ReferenceChange> {
+    let old_keys = reference_keys(old, old_file);
+    let new_keys = reference_keys(new, new_file);
+    let mut changes = Vec::new();
+    for (old_reference, old_key) in old.references.iter().zip(&old_keys) {
+        let ref_num = old_reference.ref_num.or(old_reference.moved);
+        match new_keys.iter().position(|x| x == old_key) {
+            None => changes.push(ReferenceChange::Removed { ref_num }),
+            Some(new_index) => {
+                let mut new_reference = new.references[new_index].clone();
+                let new_ref_num = new_reference.ref_num.or(new_reference.moved);
+                if new_reference.ref_num.is_some() && old_reference.ref_num.is_some() {
+                    new_reference.ref_num = old_reference.ref_num;
+                }
+                if new_reference.moved.is_some() && old_reference.moved.is_some() {
+                    new_reference.moved = old_reference.moved;
+                }
+                let fields = diff_fields(CELL, &old_reference.to_fields(), &new_reference.to_fields());
+                if !fields.is_empty() {
+                    changes.push(ReferenceChange::Modified { ref_num, new_ref_num, fields });
+                }
+            }
+        }
+    }
+    for (new_reference, new_key) in new.references.iter().zip(&new_keys) {
+        if !old_keys.contains(new_key) {
+            changes.push(ReferenceChange::Added { ref_num: new_reference.ref_num.or(new_reference.moved) });
+        }
+    }
+    changes
+}
+
+fn string_field(record: &Record, field_tag: Tag) -> Option<&str> {
+    record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (t, Field::StringZ(s)) if t == field_tag => Some(s.string.as_str()),
+        (t, Field::String(s)) if t == field_tag => Some(s.as_str()),
+        _ => None
+    })
+}
+
+pub fn record_id(record: &Record, topic: Option<&str>) -> Option<String> {
+    match record.tag {
+        INFO => Some(format!("{}:{}", topic?, string_field(record, INAM)?)),
+        CELL => {
+            let cell = CellData::from_fields(&record.fields);
+            if let Some(grid) = cell.grid() {
+                Some(format!("{},{}", grid.x, grid.y))
+            } else {
+                cell.header.name.map(|x| x.string)
+            }
+        },
+        LAND => record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+            (INTV, Field::Grid(grid)) => Some(format!("{},{}", grid.x, grid.y)),
+            _ => None
+        }),
+        PGRD => record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+            (DATA, Field::PathGrid(path_grid)) => Some(format!(
+                "{}@{},{}", string_field(record, NAME).unwrap_or(""), path_grid.grid.x, path_grid.grid.y
+            )),
+            _ => None
+        }),
+        SCPT => record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+            (SCHD, Field::ScriptMetadata(metadata)) => Some(metadata.name.clone()),
+            _ => None
+        }),
+        SKIL | MGEF => record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+            (INDX, Field::Skill(x)) => Some(x.to_string()),
+            (INDX, Field::EffectIndex(x)) => Some(x.to_string()),
+            (INDX, Field::I32(x)) => Some(x.to_string()),
+            _ => None
+        }),
+        _ => string_field(record, NAME).map(String::from)
+    }
+}
+
+pub(crate) type RecordLookup = (Tag, String, usize);
+
+pub(crate) fn record_keys(records: &[Record]) -> Vec<(RecordLookup, RecordKey)> {
+    let mut topic = None;
+    let mut counts = HashMap::new();
+    records.iter().map(|record| {
+        if record.tag == DIAL {
+            topic = string_field(record, NAME);
+        } else if record.tag != INFO {
+            topic = None;
+        }
+        let id = record_id(record, topic).unwrap_or_default();
+        let count = counts.entry((record.tag, id.to_lowercase())).or_insert(0);
+        *count += 1;
+        ((record.tag, id.to_lowercase(), *count - 1), RecordKey { tag: record.tag, id })
+    }).collect()
+}
+
+pub(crate) type RecordDifference = (Option<(RecordFlags, RecordFlags)>, Vec<FieldChange>, Vec<ReferenceChange>);
+
+pub(crate) fn diff_record(old: &Record, old_file: FileContext, new: &Record, new_file: FileContext) -> RecordDifference {
+    let flags = if old.flags != new.flags { Some((old.flags, new.flags)) } else { None };
+    let (fields, references) = if new.tag == CELL {
+        let old_cell = CellData::from_fields(&old.fields);
+        let new_cell = CellData::from_fields(&new.fields);
+        (
+            diff_fields(CELL, &old_cell.header.to_fields(), &new_cell.header.to_fields()),
+            diff_references(&old_cell, old_file, &new_cell, new_file)
+        )
+    } else {
+        (diff_fields(new.tag, &old.fields, &new.fields), Vec::new())
+    };
+    (flags, fields, references)
+}
+
+pub fn diff_records(old: &[Record], new: &[Record]) -> Vec<RecordChange> {
+    let old_masters = file_masters(old);
+    let new_masters = file_masters(new);
+    let old_file = FileContext { name: None, masters: &old_masters };
+    let new_file = FileContext { name: None, masters: &new_masters };
+    let old_keys = record_keys(old);
+    let new_keys = record_keys(new);
+    let old_lookup = old_keys.iter().enumerate().map(|(index, (lookup, _))| (lookup, index)).collect::<HashMap<_, _>>();
+    let mut matched = vec![false; old.len()];
+    let mut changes = Vec::new();
+    for (new_index, (lookup, key)) in new_keys.iter().enumerate() {
+        let old_index = if let Some(&old_index) = old_lookup.get(lookup) {
+            old_index
+        } else {
+            changes.push(RecordChange::Added { key: key.clone(), new_index });
+            continue;
+        };
+        matched[old_index] = true;
+        let (old_record, new_record) = (&old[old_index], &new[new_index]);
+        if old_record == new_record { continue; }
+        let (flags, fields, references) = diff_record(old_record, old_file, new_record, new_file);
+        changes.push(RecordChange::Modified { key: key.clone(), old_index, new_index, flags, fields, references });
+    }
+    for (old_index, (_, key)) in old_keys.into_iter().enumerate() {
+        if !matched[old_index] {
+            changes.push(RecordChange::Removed { key, old_index });
+        }
+    }
+    changes
+}
+
+pub(crate) fn write_field_changes(f: &mut fmt::Formatter<'_>, indent: &str, changes: &[FieldChange]) -> fmt::Result {
+    let value = |v: &Option<Value>| v.as_ref().map_or_else(|| "-".to_string(), |v| v.to_string());
+    for change in changes {
+        match change {
+            FieldChange::Added { tag, .. } => writeln!(f, "{}+ {}", indent, tag)?,
+            FieldChange::Removed { tag, .. } => writeln!(f, "{}- {}", indent, tag)?,
+            FieldChange::Modified { tag, members, .. } => for member in members {
+                let separator = if member.path.is_empty() { "" } else { " " };
+                writeln!(f, "{}~ {}{}{}: {} -> {}", indent, tag, separator, member.path, value(&member.old), value(&member.new))?;
+            },
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn write_reference_changes(f: &mut fmt::Formatter<'_>, indent: &str, changes: &[ReferenceChange]) -> fmt::Result {
+    let ref_num = |x: &Option<i32>| x.map_or_else(|| "?".to_string(), |x| x.to_string());
+    for change in changes {
+        match change {
+            ReferenceChange::Added { ref_num: x } => writeln!(f, "{}+ FRMR {}", indent, ref_num(x))?,
+            ReferenceChange::Removed { ref_num: x } => writeln!(f, "{}- FRMR {}", indent, ref_num(x))?,
+            ReferenceChange::Modified { ref_num: x, new_ref_num: y, fields } => {
+                if x == y {
+                    writeln!(f, "{}~ FRMR {}", indent, ref_num(x))?;
+                } else {
+                    writeln!(f, "{}~ FRMR {} -> {}", indent, ref_num(x), ref_num(y))?;
+                }
+                write_field_changes(f, &format!("{}    ", indent), fields)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+impl Display for RecordChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordChange::Added { key, .. } => writeln!(f, "+ {}", key),
+            RecordChange::Removed { key, .. } => writeln!(f, "- {}", key),
+            RecordChange::Modified { key, flags, fields, references, .. } => {
+                writeln!(f, "~ {}", key)?;
+                if let Some((old, new)) = flags {
+                    writeln!(f, "    flags: {} -> {}", old, new)?;
+                }
+                write_field_changes(f, "    ", fields)?;
+                write_reference_changes(f, "    ", references)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weapon(chop_max: u8, speed: f32) -> Field {
+        Field::Weapon(Weapon {
+            weight: 10.0, value: 25, weapon_type: WeaponType::LongBladeOneHand, health: 450,
+            speed, reach: 1.0, enchantment: 0,
+            chop_min: 2, chop_max, slash_min: 1, slash_max: 8, thrust_min: 1, thrust_max: 6,
+            flags: WeaponFlags::empty()
+        })
+    }
+
+    fn record(tag: Tag, fields: Vec<(Tag, Field)>) -> Record {
+        Record { tag, flags: RecordFlags::empty(), fields }
+    }
+
+    #[test]
+    fn diff_plugins() {
+        let nan = f32::from_bits(0x7FC00001);
+        let old = vec![
+            record(WEAP, vec![(NAME, Field::StringZ("Iron_Sword".into())), (WPDT, weapon(10, nan))]),
+            record(MISC, vec![(NAME, Field::StringZ("gold_001".into()))]),
+            record(DIAL, vec![(NAME, Field::StringZ("rumors".into()))]),
+            record(INFO, vec![(INAM, Field::StringZ("1".into())), (NAME, Field::String("Old".into()))]),
+        ];
+        let new = vec![
+            record(WEAP, vec![(NAME, Field::StringZ("iron_sword".into())), (WPDT, weapon(12, nan))]),
+            record(DIAL, vec![(NAME, Field::StringZ("rumors".into()))]),
+            record(INFO, vec![(INAM, Field::StringZ("1".into())), (NAME, Field::String("New".into()))]),
+            record(MISC, vec![(NAME, Field::StringZ("gold_005".into()))]),
+        ];
+        let changes = diff_records(&old, &new);
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[0], RecordChange::Modified {
+            key: RecordKey { tag: WEAP, id: "iron_sword".into() }, old_index: 0, new_index: 0, flags: None,
+            fields: vec![
+                FieldChange::Modified { tag: NAME, occurrence: 0, members: vec![MemberChange {
+                    path: String::new(), old: Some(Value::Str("Iron_Sword".into())), new: Some(Value::Str("iron_sword".into()))
+                }] },
+                FieldChange::Modified { tag: WPDT, occurrence: 0, members: vec![MemberChange {
+                    path: "Weapon.chop_max".into(), old: Some(Value::UInt(10)), new: Some(Value::UInt(12))
+                }] },
+            ],
+            references: Vec::new()
+        });
+        assert_eq!(changes[1].to_string(), "~ INFO rumors:1\n    ~ NAME: \"Old\" -> \"New\"\n");
+        assert_eq!(changes[2].to_string(), "+ MISC gold_005\n");
+        assert_eq!(changes[3].to_string(), "- MISC gold_001\n");
+        let mut nan_changed = new.clone();
+        nan_changed[0].fields[1].1 = weapon(12, f32::from_bits(0x7FC00002));
+        let changes = diff_records(&new, &nan_changed);
+        assert_eq!(changes[0].to_string(), "~ WEAP iron_sword\n    ~ WPDT Weapon.speed: \"nan7FC00001\" -> \"nan7FC00002\"\n");
+    }
+
+    #[test]
+    fn diff_cell_references() {
+        let position = |x| Field::Position(Position { x, y: 0.0, z: 0.0, x_rot: 0.0, y_rot: 0.0, z_rot: 0.0 });
+        let cell = |refs: Vec<(i32, f32)>| {
+            let mut fields = vec![
+                (NAME, Field::StringZ("Balmora, Guild of Mages".into())),
+                (DATA, Field::Cell(Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } })),
+            ];
+            for (ref_num, x) in refs {
+                fields.push((FRMR, Field::I32(ref_num)));
+                fields.push((NAME, Field::StringZ("chest".into())));
+                fields.push((DATA, position(x)));
+            }
+            record(CELL, fields)
+        };
+        let changes = diff_records(&[cell(vec![(1, 0.0), (2, 5.0)])], &[cell(vec![(2, 6.0), (3, 0.0)])]);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].to_string(), concat!(
+            "~ CELL Balmora, Guild of Mages\n",
+            "    - FRMR 1\n",
+            "    ~ FRMR 2\n",
+            "        ~ DATA Position.x: 5 -> 6\n",
+            "    + FRMR 3\n",
+        ));
+        let header = |masters: &[&str]| record(TES3, masters.iter().map(|&x| (MAST, Field::StringZ(x.into()))).collect());
+        let old = vec![header(&["Morrowind.esm", "Tribunal.esm"]), cell(vec![(0x0200_0001, 0.0), (0x0100_0002, 0.0)])];
+        let new = vec![header(&["Tribunal.esm"]), cell(vec![(0x0100_0001, 1.0)])];
+        let changes = diff_records(&old, &new);
+        assert_eq!(changes[1].to_string(), concat!(
+            "~ CELL Balmora, Guild of Mages\n",
+            "    ~ FRMR 33554433 -> 16777217\n",
+            "        ~ DATA Position.x: 0 -> 1\n",
+            "    - FRMR 16777218\n",
+        ));
+    }
+
+    #[test]
+    fn members_round_trip() {
+        let fields = vec![
+            (WEAP, WPDT, weapon(12, 1.5)),
+            (MISC, MCDT, Field::MiscItem(MiscItem { weight: 0.5, value: 10, is_key: true })),
+            (NPC_, NPDT, Field::Npc(Npc {
+                level: 5, disposition: 50, reputation: 0, rank: 2, gold: 100, padding: 0, stats: either::Left(7)
+            })),
+            (NPC_, AIDT, Field::Ai(Ai {
+                hello: 30, fight: 10, flee: 20, alarm: 0, padding_8: 0, padding_16: 0, services: Services::BOOKS
+            })),
+            (NPC_, AI_W, Field::AiWander(AiWander {
+                distance: 512, duration: 5, time_of_day: 0, idle: [60, 20, 10, 0, 0, 0, 0, 0], repeat: true
+            })),
+            (NPC_, NPCO, Field::Item(Item { count: 3, item_id: "gold_001".into() })),
+            (CELL, DATA, Field::Cell(Cell { flags: CellFlags::HAS_WATER, grid: Grid { x: -2, y: 7 } })),
+            (NPC_, FNAM, Field::StringZ("Fargoth".into())),
+            (LEVC, DATA, Field::I32(-100)),
+            (CELL, INTV, Field::F32(-0.5)),
+        ];
+        for (record_tag, field_tag, field) in fields {
+            let members = field_members(record_tag, field_tag, &field).unwrap();
+            assert_eq!(members_field(record_tag, field_tag, members), Some(field));
+        }
+    }
+}
@@ -133,7 +133,8 @@ pub(crate) enum FieldType {
     Creature, Light, MiscItem, Apparatus, Weapon, Armor, BipedObject, BodyPart, Clothing, Enchantment,
     Tool, RepairItem, Position, PositionOrCell, Grid, PathGrid, ScriptVars,
     I16List, I32List, F32List, Weather, Color, SoundChance, Potion, Class, Skill, EffectIndex,
-    Item, Sound, EffectMetadata, Race, SoundGen, Info, Faction, SkillMetadata, Interior
+    Item, Sound, EffectMetadata, Race, SoundGen, Info, Faction, SkillMetadata, Interior,
+    DialogueCondition
 }
 
 impl FieldType {
@@ -299,6 +300,7 @@ impl FieldType {
             (TES3, SCRS) => FieldType::U8ListZip,
             (_, SCTX) => FieldType::Multiline(Newline::Dos),
             (SCPT, SCVR) => FieldType::StringZList,
+            (INFO, SCVR) => FieldType::DialogueCondition,
             (_, SCVR) => FieldType::String(None),
             (SKIL, SKDT) => FieldType::SkillMetadata,
             (_, SLCS) => FieldType::ScriptVars,
@@ -375,7 +377,7 @@ pub struct Ingredient {
     pub effect_4_attribute: Either<Option<i32>, Attribute>,
 }
 
-fn eq_f32(a: &f32, b: &f32) -> bool {
+pub(crate) fn eq_f32(a: &f32, b: &f32) -> bool {
     a.to_bits() == b.to_bits()
 }
 
@@ -1913,7 +1915,7 @@ pub struct Interior {
     pub fog_density: f32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Grid {
     pub x: i32,
     pub y: i32,
@@ -2158,6 +2160,270 @@ pub struct Info {
     pub padding: u8,
 }
 
+macro_attr! {
+    #[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Copy, Clone)]
+    #[derive(Debug, N, EnumDisplay!, EnumFromStr!)]
+    #[repr(u8)]
+    pub enum DialogueConditionKind {
+        Function = 1,
+        Global = 2,
+        Local = 3,
+        Journal = 4,
+        Item = 5,
+        Dead = 6,
+        NotId = 7,
+        NotFaction = 8,
+        NotClass = 9,
+        NotRace = 10,
+        NotCell = 11,
+        NotLocal = 12
+    }
+}
+
+enum_serde!(DialogueConditionKind, "dialogue condition kind", as u8, Unsigned, u64);
+
+impl DialogueConditionKind {
+    fn from_char(c: char) -> Option<DialogueConditionKind> {
+        c.to_digit(16).and_then(|x| DialogueConditionKind::n(x as u8)).filter(|x| x.to_char() == c)
+    }
+
+    fn to_char(self) -> char {
+        std::char::from_digit(self as u32, 16).unwrap().to_ascii_uppercase()
+    }
+
+    fn detail(self) -> Option<&'static str> {
+        match self {
+            DialogueConditionKind::Journal => Some("JX"),
+            DialogueConditionKind::Item => Some("IX"),
+            DialogueConditionKind::Dead => Some("DX"),
+            DialogueConditionKind::NotId => Some("XX"),
+            DialogueConditionKind::NotFaction => Some("FX"),
+            DialogueConditionKind::NotClass => Some("CX"),
+            DialogueConditionKind::NotRace => Some("RX"),
+            DialogueConditionKind::NotCell => Some("LX"),
+            _ => None
+        }
+    }
+
+    pub fn is_variable(self) -> bool {
+        matches!(self, DialogueConditionKind::Global | DialogueConditionKind::Local | DialogueConditionKind::NotLocal)
+    }
+}
+
+macro_attr! {
+    #[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Copy, Clone)]
+    #[derive(Debug, N, EnumDisplay!, EnumFromStr!)]
+    #[repr(u8)]
+    pub enum DialogueFunction {
+        RankLow = 0,
+        RankHigh = 1,
+        RankRequirement = 2,
+        Reputation = 3,
+        HealthPercent = 4,
+        PcReputation = 5,
+        PcLevel = 6,
+        PcHealthPercent = 7,
+        PcMagicka = 8,
+        PcFatigue = 9,
+        PcStrength = 10,
+        PcBlock = 11,
+        PcArmorer = 12,
+        PcMediumArmor = 13,
+        PcHeavyArmor = 14,
+        PcBluntWeapon = 15,
+        PcLongBlade = 16,
+        PcAxe = 17,
+        PcSpear = 18,
+        PcAthletics = 19,
+        PcEnchant = 20,
+        PcDestruction = 21,
+        PcAlteration = 22,
+        PcIllusion = 23,
+        PcConjuration = 24,
+        PcMysticism = 25,
+        PcRestoration = 26,
+        PcAlchemy = 27,
+        PcUnarmored = 28,
+        PcSecurity = 29,
+        PcSneak = 30,
+        PcAcrobatics = 31,
+        PcLightArmor = 32,
+        PcShortBlade = 33,
+        PcMarksman = 34,
+        PcMercantile = 35,
+        PcSpeechcraft = 36,
+        PcHandToHand = 37,
+        PcSex = 38,
+        PcExpelled = 39,
+        PcCommonDisease = 40,
+        PcBlightDisease = 41,
+        PcClothingModifier = 42,
+        PcCrimeLevel = 43,
+        SameSex = 44,
+        SameRace = 45,
+        SameFaction = 46,
+        FactionRankDifference = 47,
+        Detected = 48,
+        Alarmed = 49,
+        Choice = 50,
+        PcIntelligence = 51,
+        PcWillpower = 52,
+        PcAgility = 53,
+        PcSpeed = 54,
+        PcEndurance = 55,
+        PcPersonality = 56,
+        PcLuck = 57,
+        PcCorprus = 58,
+        Weather = 59,
+        PcVampire = 60,
+        Level = 61,
+        Attacked = 62,
+        TalkedToPc = 63,
+        PcHealth = 64,
+        CreatureTarget = 65,
+        FriendHit = 66,
+        Fight = 67,
+        Hello = 68,
+        Alarm = 69,
+        Flee = 70,
+        ShouldAttack = 71,
+        Werewolf = 72,
+        PcWerewolfKills = 73
+    }
+}
+
+enum_serde!(DialogueFunction, "dialogue function", as u8, Unsigned, u64);
+
+macro_attr! {
+    #[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Copy, Clone)]
+    #[derive(Debug, N, EnumDisplay!, EnumFromStr!)]
+    #[repr(u8)]
+    pub enum DialogueVarType {
+        Short = b's',
+        Long = b'l',
+        Float = b'f'
+    }
+}
+
+enum_serde!(DialogueVarType, "dialogue variable type", as u8, Unsigned, u64);
+
+macro_attr! {
+    #[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Copy, Clone)]
+    #[derive(Debug, N, EnumDisplay!, EnumFromStr!)]
+    #[repr(u8)]
+    pub enum DialogueConditionOperator {
+        Equal = 0,
+        NotEqual = 1,
+        Greater = 2,
+        GreaterOrEqual = 3,
+        Less = 4,
+        LessOrEqual = 5
+    }
+}
+
+enum_serde!(DialogueConditionOperator, "dialogue condition operator", as u8, Unsigned, u64);
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct DialogueCondition {
+    pub index: u8,
+    pub kind: DialogueConditionKind,
+    pub function: Option<DialogueFunction>,
+    pub var_type: Option<DialogueVarType>,
+    pub operator: DialogueConditionOperator,
+    pub name: String,
+}
+
+impl DialogueCondition {
+    fn is_valid(&self) -> bool {
+        self.index <= 5 &&
+        self.function.is_some() == (self.kind == DialogueConditionKind::Function) &&
+        self.var_type.is_some() == self.kind.is_variable()
+    }
+}
+
+impl Display for DialogueCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.index, self.kind.to_char())?;
+        if let Some(function) = self.function {
+            write!(f, "{:02}", function as u8)?;
+        } else if let Some(var_type) = self.var_type {
+            write!(f, "{}X", var_type as u8 as char)?;
+        } else {
+            write!(f, "{}", self.kind.detail().unwrap_or("XX"))?;
+        }
+        write!(f, "{}{}", self.operator as u8, self.name)
+    }
+}
+
+impl FromStr for DialogueCondition {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let mut next = || chars.next().ok_or(());
+        let index = next()?.to_digit(10).filter(|&x| x <= 5).ok_or(())? as u8;
+        let kind = DialogueConditionKind::from_char(next()?).ok_or(())?;
+        let detail = [next()?, next()?];
+        let operator = next()?.to_digit(10).and_then(|x| DialogueConditionOperator::n(x as u8)).ok_or(())?;
+        let name = chars.as_str().to_string();
+        let (function, var_type) = if kind == DialogueConditionKind::Function {
+            let function = detail[0].to_digit(10).and_then(|h| detail[1].to_digit(10).map(|l| h * 10 + l))
+                .and_then(|x| DialogueFunction::n(x as u8)).ok_or(())?;
+            (Some(function), None)
+        } else if kind.is_variable() {
+            if detail[1] != 'X' || !detail[0].is_ascii() { return Err(()); }
+            (None, Some(DialogueVarType::n(detail[0] as u8).ok_or(())?))
+        } else {
+            if kind.detail().map(|x| x.chars().ne(detail.iter().copied())).unwrap_or(true) { return Err(()); }
+            (None, None)
+        };
+        Ok(DialogueCondition { index, kind, function, var_type, operator, name })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename="DialogueCondition")]
+struct DialogueConditionHRSurrogate {
+    index: u8,
+    kind: DialogueConditionKind,
+    function: Option<DialogueFunction>,
+    var_type: Option<DialogueVarType>,
+    operator: DialogueConditionOperator,
+    name: String,
+}
+
+impl Serialize for DialogueCondition {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        if serializer.is_human_readable() {
+            DialogueConditionHRSurrogate {
+                index: self.index, kind: self.kind, function: self.function, var_type: self.var_type,
+                operator: self.operator, name: self.name.clone()
+            }.serialize(serializer)
+        } else {
+            serializer.serialize_str(&self.to_string())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DialogueCondition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        if deserializer.is_human_readable() {
+            let s = DialogueConditionHRSurrogate::deserialize(deserializer)?;
+            let c = DialogueCondition {
+                index: s.index, kind: s.kind, function: s.function, var_type: s.var_type,
+                operator: s.operator, name: s.name
+            };
+            if !c.is_valid() {
+                return Err(D::Error::custom("dialogue condition function and variable type do not match its kind"));
+            }
+            Ok(c)
+        } else {
+            let s = String::deserialize(deserializer)?;
+            DialogueCondition::from_str(&s).map_err(|()| D::Error::invalid_value(Unexpected::Str(&s), &"dialogue condition"))
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Rank {
     pub attribute_1: u32,
@@ -2246,6 +2512,7 @@ define_field!(
     Creature(Creature),
     CreatureFlags(FlagsAndBlood<CreatureFlags>),
     DialogType(DialogType),
+    DialogueCondition(DialogueCondition),
     Effect(Effect),
     EffectIndex(EffectIndex),
     EffectMetadata(EffectMetadata),
@@ -0,0 +1,180 @@
+use std::fmt::{self, Display};
+
+use crate::cell::*;
+use crate::conflict::*;
+use crate::diff::*;
+use crate::field::*;
+use crate::merge::*;
+use crate::record::*;
+use crate::strings::*;
+
+const SUMMONED_CREATURES: &[&str] = &[
+    "ancestor_ghost_summon", "atronach_flame_summon", "atronach_frost_summon", "atronach_storm_summon",
+    "bonelord_summon", "bonewalker_greater_summ", "bonewalker_summon", "centurion_sphere_summon",
+    "clannfear_summon", "daedroth_summon", "dremora_summon", "golden saint_summon", "hunger_summon",
+    "scamp_summon", "skeleton_summon", "winged twilight_summon", "fabricant_summon",
+    "bm_bear_black_summon", "bm_wolf_bone_summon", "bm_wolf_grey_summon",
+];
+
+const FOG_DENSITY: f32 = 0.01;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum FixPass {
+    FogBug,
+    CellNames,
+    Summons,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Fix {
+    FogDensity(RecordKey),
+    CellName { key: RecordKey, name: String },
+    CellRegion { key: RecordKey, region: String },
+    PersistentSummon(RecordKey),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedPlugin {
+    pub plugins: Vec<String>,
+    pub records: Vec<Record>,
+    pub fixes: Vec<Fix>,
+}
+
+impl FixedPlugin {
+    pub fn into_plugin(self, master_sizes: &[u64]) -> Result<Vec<Record>, MasterSizesError> {
+        patch_plugin(self.plugins, master_sizes, "Known bug fixes", self.records)
+    }
+}
+
+impl Display for FixedPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for fix in &self.fixes {
+            match fix {
+                Fix::FogDensity(key) => writeln!(f, "{}: fog density 0 -> {}", key, FOG_DENSITY)?,
+                Fix::CellName { key, name } => writeln!(f, "{}: restored name {:?}", key, name)?,
+                Fix::CellRegion { key, region } => writeln!(f, "{}: restored region {:?}", key, region)?,
+                Fix::PersistentSummon(key) => writeln!(f, "{}: made persistent", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn intended(headers: &[CellHeader], get: impl Fn(&CellHeader) -> Option<&StringZ>) -> Option<&StringZ> {
+    let base = get(&headers[0]);
+    headers.iter().rev().map(get).find(|&x| x != base).unwrap_or(base)
+}
+
+fn fix_cell(key: &RecordKey, records: &[&Record], passes: &[FixPass], fixes: &mut Vec<Fix>) -> Option<Record> {
+    let headers = records.iter().map(|x| CellData::from_fields(&x.fields).header).collect::<Vec<_>>();
+    let mut header = headers.last().unwrap().clone();
+    let flags = header.cell.as_ref()?.flags;
+    if flags.contains(CellFlags::INTERIOR) {
+        if passes.contains(&FixPass::FogBug) && !flags.contains(CellFlags::BEHAVE_LIKE_EXTERIOR) {
+            if let Some(interior) = header.interior.as_mut() {
+                if interior.fog_density == 0.0 {
+                    interior.fog_density = FOG_DENSITY;
+                    fixes.push(Fix::FogDensity(key.clone()));
+                }
+            }
+        }
+    } else if passes.contains(&FixPass::CellNames) {
+        if let Some(name) = intended(&headers, |x| x.name.as_ref()).filter(|&x| Some(x) != header.name.as_ref()).cloned() {
+            fixes.push(Fix::CellName { key: key.clone(), name: name.string.clone() });
+            header.name = Some(name);
+        }
+        if let Some(region) = intended(&headers, |x| x.region.as_ref()).filter(|&x| Some(x) != header.region.as_ref()).cloned() {
+            fixes.push(Fix::CellRegion { key: key.clone(), region: region.string.clone() });
+            header.region = Some(region);
+        }
+    }
+    if fixes.is_empty() { return None; }
+    let fields = header.to_fields().into_iter().filter(|(tag, _)| *tag != NAM0).collect();
+    Some(Record { tag: CELL, flags: records.last().unwrap().flags, fields })
+}
+
+fn is_summon(key: &RecordKey) -> bool {
+    SUMMONED_CREATURES.iter().any(|x| x.eq_ignore_ascii_case(&key.id))
+}
+
+pub fn fix_load_order(plugins: &[(&str, &[Record])], passes: &[FixPass]) -> FixedPlugin {
+    let mut records = Vec::new();
+    let mut fixes = Vec::new();
+    for (key, definitions) in record_definitions(plugins) {
+        let definitions = definitions.iter().map(|x| &plugins[x.plugin].1[x.index]).collect::<Vec<_>>();
+        let winner = *definitions.last().unwrap();
+        let mut record_fixes = Vec::new();
+        let record = match key.tag {
+            CELL => fix_cell(&key, &definitions, passes, &mut record_fixes),
+            CREA if passes.contains(&FixPass::Summons) && is_summon(&key) && !winner.flags.contains(RecordFlags::PERSIST) => {
+                record_fixes.push(Fix::PersistentSummon(key));
+                Some(Record { flags: winner.flags | RecordFlags::PERSIST, ..winner.clone() })
+            },
+            _ => None
+        };
+        if let Some(record) = record {
+            records.push(record);
+            fixes.extend(record_fixes);
+        }
+    }
+    FixedPlugin { plugins: plugins.iter().map(|(name, _)| name.to_string()).collect(), records, fixes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tag: Tag, fields: Vec<(Tag, Field)>) -> Record {
+        Record { tag, flags: RecordFlags::empty(), fields }
+    }
+
+    fn exterior(name: &str, region: &str) -> Record {
+        record(CELL, vec![
+            (NAME, Field::StringZ(name.into())),
+            (DATA, Field::Cell(Cell { flags: CellFlags::empty(), grid: Grid { x: -3, y: -9 } })),
+            (RGNN, Field::StringZ(region.into())),
+            (FRMR, Field::I32(1)),
+            (NAME, Field::StringZ("flora_kelp_01".into())),
+        ])
+    }
+
+    fn interior(name: &str, fog_density: f32) -> Record {
+        let color = Color { r: 0, g: 0, b: 0 };
+        record(CELL, vec![
+            (NAME, Field::StringZ(name.into())),
+            (DATA, Field::Cell(Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } })),
+            (AMBI, Field::Interior(Interior { ambient: color, sunlight: color, fog: color, fog_density })),
+        ])
+    }
+
+    #[test]
+    fn fix_known_bugs() {
+        let master = vec![
+            exterior("", "Bitter Coast Region"),
+            interior("Seyda Neen, Census and Excise Office", 0.0),
+            interior("Balmora, Guild of Mages", 0.5),
+            record(CREA, vec![(NAME, Field::StringZ("Scamp_summon".into()))]),
+            record(CREA, vec![(NAME, Field::StringZ("scamp".into()))]),
+        ];
+        let first = vec![exterior("Seyda Neen", "Ascadian Isles Region")];
+        let second = vec![exterior("", "Bitter Coast Region")];
+        let plugins: &[(&str, &[Record])] = &[("Morrowind.esm", &master), ("First.esp", &first), ("Second.esp", &second)];
+        let fixed = fix_load_order(plugins, &[FixPass::FogBug, FixPass::CellNames, FixPass::Summons]);
+        let mut cell = exterior("Seyda Neen", "Ascadian Isles Region");
+        cell.fields.truncate(3);
+        let mut summon = master[3].clone();
+        summon.flags = RecordFlags::PERSIST;
+        assert_eq!(fixed.records, vec![cell, interior("Seyda Neen, Census and Excise Office", FOG_DENSITY), summon]);
+        assert_eq!(fixed.to_string(), "\
+CELL -3,-9: restored name \"Seyda Neen\"
+CELL -3,-9: restored region \"Ascadian Isles Region\"
+CELL Seyda Neen, Census and Excise Office: fog density 0 -> 0.01
+CREA Scamp_summon: made persistent
+");
+        let fixed = fix_load_order(plugins, &[FixPass::Summons]);
+        assert_eq!(fixed.fixes, vec![Fix::PersistentSummon(RecordKey { tag: CREA, id: "Scamp_summon".into() })]);
+        let plugin = fixed.into_plugin(&[1, 2, 3]).unwrap();
+        assert_eq!(plugin.len(), 2);
+        assert_eq!(plugin[0].tag, TES3);
+    }
+}
@@ -0,0 +1,455 @@
+use flate2::write::{ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+
+use crate::field::*;
+use crate::record::*;
+
+pub const LAND_SIZE: usize = 65;
+
+const VERTEX_HEIGHTS_SIZE: usize = 4 + LAND_SIZE * LAND_SIZE + 3;
+
+#[derive(Debug)]
+pub enum LandError {
+    InvalidCompressedData(io::Error),
+    UnexpectedVertexHeightsSize(usize),
+    UnexpectedFieldType(Tag),
+    DeltaOverflow { grid: Grid, x: usize, y: usize },
+}
+
+impl Display for LandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LandError::InvalidCompressedData(e) => write!(f, "invalid compressed data ({})", e),
+            LandError::UnexpectedVertexHeightsSize(size) =>
+                write!(f, "unexpected vertex heights size {}, expected {}", size, VERTEX_HEIGHTS_SIZE),
+            LandError::UnexpectedFieldType(tag) => write!(f, "{} field should have byte list type", tag),
+            LandError::DeltaOverflow { grid, x, y } =>
+                write!(f, "height delta at ({}, {}) in ({}, {}) cell does not fit into byte", x, y, grid.x, grid.y),
+        }
+    }
+}
+
+impl Error for LandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LandError::InvalidCompressedData(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VertexHeights {
+    pub offset: f32,
+    pub heights: Vec<i32>,
+    pub padding: [u8; 3],
+}
+
+impl VertexHeights {
+    pub fn from_bytes(bytes: &[u8]) -> Result<VertexHeights, LandError> {
+        if bytes.len() != VERTEX_HEIGHTS_SIZE {
+            return Err(LandError::UnexpectedVertexHeightsSize(bytes.len()));
+        }
+        let offset = f32::from_le_bytes(bytes[.. 4].try_into().unwrap());
+        let deltas = &bytes[4 .. 4 + LAND_SIZE * LAND_SIZE];
+        let mut heights = Vec::with_capacity(LAND_SIZE * LAND_SIZE);
+        let mut row_start = 0i32;
+        for y in 0 .. LAND_SIZE {
+            row_start += deltas[y * LAND_SIZE] as i8 as i32;
+            let mut height = row_start;
+            heights.push(height);
+            for x in 1 .. LAND_SIZE {
+                height += deltas[y * LAND_SIZE + x] as i8 as i32;
+                heights.push(height);
+            }
+        }
+        let padding = bytes[4 + LAND_SIZE * LAND_SIZE ..].try_into().unwrap();
+        Ok(VertexHeights { offset, heights, padding })
+    }
+
+    pub fn to_bytes(&self, grid: Grid) -> Result<Vec<u8>, LandError> {
+        let mut bytes = Vec::with_capacity(VERTEX_HEIGHTS_SIZE);
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        let delta = |x: usize, y: usize, d: i32| -> Result<u8, LandError> {
+            let d: i8 = d.try_into().map_err(|_| LandError::DeltaOverflow { grid, x, y })?;
+            Ok(d as u8)
+        };
+        for y in 0 .. LAND_SIZE {
+            let row_start = if y == 0 { 0 } else { self.heights[(y - 1) * LAND_SIZE] };
+            bytes.push(delta(0, y, self.heights[y * LAND_SIZE] - row_start)?);
+            for x in 1 .. LAND_SIZE {
+                bytes.push(delta(x, y, self.heights[y * LAND_SIZE + x] - self.heights[y * LAND_SIZE + x - 1])?);
+            }
+        }
+        bytes.extend_from_slice(&self.padding);
+        Ok(bytes)
+    }
+
+    pub fn from_field(field: &Field) -> Result<VertexHeights, LandError> {
+        if let Field::U8List(compressed) = field {
+            let bytes = (|| {
+                let mut decoder = ZlibDecoder::new(Vec::new());
+                decoder.write_all(&compressed[..])?;
+                decoder.finish()
+            })().map_err(LandError::InvalidCompressedData)?;
+            VertexHeights::from_bytes(&bytes)
+        } else {
+            Err(LandError::UnexpectedFieldType(VHGT))
+        }
+    }
+
+    pub fn to_field(&self, grid: Grid) -> Result<Field, LandError> {
+        let bytes = self.to_bytes(grid)?;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(5));
+        encoder.write_all(&bytes).unwrap();
+        Ok(Field::U8List(encoder.finish().unwrap()))
+    }
+
+    pub fn height(&self, x: usize, y: usize) -> f32 {
+        self.offset + self.heights[y * LAND_SIZE + x] as f32
+    }
+
+    pub fn set_height(&mut self, x: usize, y: usize, height: f32) {
+        self.heights[y * LAND_SIZE + x] = (height - self.offset).round() as i32;
+    }
+}
+
+fn land_grid(record: &Record) -> Option<Grid> {
+    record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (INTV, Field::Grid(grid)) => Some(*grid),
+        _ => None
+    })
+}
+
+fn land_vertex_heights(record: &Record) -> Option<&Field> {
+    record.fields.iter().find(|(tag, _)| *tag == VHGT).map(|(_, field)| field)
+}
+
+struct Land {
+    index: usize,
+    grid: Grid,
+    heights: VertexHeights,
+}
+
+fn effective_lands<'a>(records: impl IntoIterator<Item=&'a Record>) -> Result<Vec<Land>, LandError> {
+    let mut lands: Vec<Option<Land>> = Vec::new();
+    let mut by_grid = HashMap::new();
+    for (index, record) in records.into_iter().enumerate() {
+        if record.tag != LAND { continue; }
+        let grid = if let Some(grid) = land_grid(record) { grid } else { continue; };
+        let land = match land_vertex_heights(record) {
+            Some(field) if !record.flags.contains(RecordFlags::DELETED) =>
+                Some(Land { index, grid, heights: VertexHeights::from_field(field)? }),
+            _ => None
+        };
+        match by_grid.entry(grid) {
+            Entry::Occupied(e) => lands[*e.get()] = land,
+            Entry::Vacant(e) => {
+                e.insert(lands.len());
+                lands.push(land);
+            }
+        }
+    }
+    Ok(lands.into_iter().flatten().collect())
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SeamDirection {
+    East,
+    North,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeamVertex {
+    pub position: usize,
+    pub height: f32,
+    pub neighbor_height: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeamMismatch {
+    pub grid: Grid,
+    pub direction: SeamDirection,
+    pub record_index: usize,
+    pub neighbor_record_index: usize,
+    pub vertices: Vec<SeamVertex>,
+}
+
+impl SeamDirection {
+    pub fn neighbor(self, grid: Grid) -> Grid {
+        match self {
+            SeamDirection::East => Grid { x: grid.x + 1, y: grid.y },
+            SeamDirection::North => Grid { x: grid.x, y: grid.y + 1 },
+        }
+    }
+}
+
+impl SeamMismatch {
+    pub fn neighbor_grid(&self) -> Grid { self.direction.neighbor(self.grid) }
+}
+
+fn edge_vertices(direction: SeamDirection, position: usize) -> ((usize, usize), (usize, usize)) {
+    match direction {
+        SeamDirection::East => ((LAND_SIZE - 1, position), (0, position)),
+        SeamDirection::North => ((position, LAND_SIZE - 1), (position, 0)),
+    }
+}
+
+fn find_seam_mismatches(lands: &[Land]) -> Vec<SeamMismatch> {
+    let by_grid: HashMap<Grid, &Land> = lands.iter().map(|land| (land.grid, land)).collect();
+    let mut mismatches = Vec::new();
+    for land in lands {
+        for &direction in &[SeamDirection::East, SeamDirection::North] {
+            let neighbor = if let Some(&neighbor) = by_grid.get(&direction.neighbor(land.grid)) { neighbor } else { continue; };
+            let vertices = (0 .. LAND_SIZE).filter_map(|position| {
+                let ((x, y), (neighbor_x, neighbor_y)) = edge_vertices(direction, position);
+                let height = land.heights.height(x, y);
+                let neighbor_height = neighbor.heights.height(neighbor_x, neighbor_y);
+                if height.to_bits() == neighbor_height.to_bits() {
+                    None
+                } else {
+                    Some(SeamVertex { position, height, neighbor_height })
+                }
+            }).collect::<Vec<_>>();
+            if !vertices.is_empty() {
+                mismatches.push(SeamMismatch {
+                    grid: land.grid, direction,
+                    record_index: land.index, neighbor_record_index: neighbor.index,
+                    vertices
+                });
+            }
+        }
+    }
+    mismatches.sort_by_key(|x| (x.grid, x.direction));
+    mismatches
+}
+
+pub fn check_seams<'a>(records: impl IntoIterator<Item=&'a Record>) -> Result<Vec<SeamMismatch>, LandError> {
+    let lands = effective_lands(records)?;
+    Ok(find_seam_mismatches(&lands))
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum StitchMode {
+    Average,
+    PreferEarlier,
+    PreferLater,
+}
+
+const STITCH_WIDTHS: &[usize] = &[1, 2, 4, 8, 16, 32];
+
+fn spread_border_changes(heights: &VertexHeights, original: &[i32], shared: &[bool], shared_edges: [bool; 4],
+    width: usize) -> VertexHeights {
+
+    let last = LAND_SIZE - 1;
+    let change = |x: usize, y: usize| (heights.heights[y * LAND_SIZE + x] - original[y * LAND_SIZE + x]) as f32;
+    let falloff = |distance: usize| if distance >= width { 0.0 } else { 1.0 - distance as f32 / width as f32 };
+    let mut spread = heights.clone();
+    for y in 0 .. LAND_SIZE {
+        for x in 0 .. LAND_SIZE {
+            if shared[y * LAND_SIZE + x] { continue; }
+            let edges = [
+                (falloff(x), change(0, y)),
+                (falloff(last - x), change(last, y)),
+                (falloff(y), change(x, 0)),
+                (falloff(last - y), change(x, last)),
+            ];
+            let edges = edges.iter().zip(shared_edges.iter()).filter(|(_, &shared)| shared).map(|(&edge, _)| edge);
+            let weight = edges.clone().map(|(w, _)| w).sum::<f32>();
+            let correction = edges.map(|(w, c)| w * c).sum::<f32>() / weight.max(1.0);
+            spread.heights[y * LAND_SIZE + x] = original[y * LAND_SIZE + x] + correction.round() as i32;
+        }
+    }
+    spread
+}
+
+pub fn stitch_seams(records: &mut [Record], mode: StitchMode) -> Result<Vec<usize>, LandError> {
+    let mut lands = effective_lands(records.iter())?;
+    let original = lands.iter().map(|x| x.heights.heights.clone()).collect::<Vec<_>>();
+    let mut shared = HashMap::new();
+    for (land_index, land) in lands.iter().enumerate() {
+        for y in 0 .. LAND_SIZE {
+            for x in 0 .. LAND_SIZE {
+                if x != 0 && x != LAND_SIZE - 1 && y != 0 && y != LAND_SIZE - 1 { continue; }
+                let world = (
+                    land.grid.x * (LAND_SIZE as i32 - 1) + x as i32,
+                    land.grid.y * (LAND_SIZE as i32 - 1) + y as i32
+                );
+                shared.entry(world).or_insert_with(Vec::<(usize, usize, usize)>::new).push((land_index, x, y));
+            }
+        }
+    }
+    let mut modified = vec![false; lands.len()];
+    for vertices in shared.values() {
+        if vertices.len() < 2 { continue; }
+        let heights = vertices.iter().map(|&(i, x, y)| lands[i].heights.height(x, y)).collect::<Vec<_>>();
+        if heights.iter().all(|h| h.to_bits() == heights[0].to_bits()) { continue; }
+        let target = match mode {
+            StitchMode::Average => heights.iter().sum::<f32>() / heights.len() as f32,
+            StitchMode::PreferEarlier => {
+                let (i, _) = vertices.iter().enumerate().min_by_key(|(_, &(i, _, _))| lands[i].index).unwrap();
+                heights[i]
+            },
+            StitchMode::PreferLater => {
+                let (i, _) = vertices.iter().enumerate().max_by_key(|(_, &(i, _, _))| lands[i].index).unwrap();
+                heights[i]
+            },
+        };
+        for &(i, x, y) in vertices {
+            let old = lands[i].heights.heights[y * LAND_SIZE + x];
+            lands[i].heights.set_height(x, y, target);
+            if lands[i].heights.heights[y * LAND_SIZE + x] != old {
+                modified[i] = true;
+            }
+        }
+    }
+    let grids = lands.iter().map(|x| x.grid).collect::<HashSet<_>>();
+    let mut shared_vertices = vec![vec![false; LAND_SIZE * LAND_SIZE]; lands.len()];
+    for vertices in shared.values().filter(|x| x.len() >= 2) {
+        for &(i, x, y) in vertices {
+            shared_vertices[i][y * LAND_SIZE + x] = true;
+        }
+    }
+    let lands = lands.iter().zip(original).zip(shared_vertices).zip(modified).filter(|(_, m)| *m).map(|(x, _)| x);
+    let mut modified_records = Vec::new();
+    for ((land, original), land_shared) in lands {
+        let Grid { x, y } = land.grid;
+        let shared_edges = [
+            grids.contains(&Grid { x: x - 1, y }),
+            grids.contains(&Grid { x: x + 1, y }),
+            grids.contains(&Grid { x, y: y - 1 }),
+            grids.contains(&Grid { x, y: y + 1 }),
+        ];
+        let mut field = None;
+        for &width in STITCH_WIDTHS {
+            let result = spread_border_changes(&land.heights, &original, &land_shared, shared_edges, width).to_field(land.grid);
+            let fits = result.is_ok();
+            field = Some(result);
+            if fits { break; }
+        }
+        let field = field.unwrap()?;
+        let record = &mut records[land.index];
+        record.fields.iter_mut().find(|(tag, _)| *tag == VHGT).unwrap().1 = field;
+        modified_records.push(land.index);
+    }
+    modified_records.sort_unstable();
+    Ok(modified_records)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use crate::land::*;
+
+    fn land(grid: Grid, height: impl Fn(usize, usize) -> i32) -> Record {
+        let mut heights = VertexHeights { offset: 0.0, heights: Vec::new(), padding: [0; 3] };
+        for y in 0 .. LAND_SIZE {
+            for x in 0 .. LAND_SIZE {
+                heights.heights.push(height(x, y));
+            }
+        }
+        Record {
+            tag: LAND,
+            flags: RecordFlags::empty(),
+            fields: vec![
+                (INTV, Field::Grid(grid)),
+                (DATA, Field::I32(9)),
+                (VHGT, heights.to_field(grid).unwrap()),
+            ]
+        }
+    }
+
+    #[test]
+    fn vertex_heights_round_trip() {
+        let mut bytes = vec![0; 4 + LAND_SIZE * LAND_SIZE + 3];
+        bytes[0 .. 4].copy_from_slice(&(-3.0f32).to_le_bytes());
+        bytes[4] = 5;
+        bytes[5] = 0xFF;
+        bytes[4 + LAND_SIZE] = 2;
+        let heights = VertexHeights::from_bytes(&bytes).unwrap();
+        assert_eq!(heights.height(0, 0), 2.0);
+        assert_eq!(heights.height(1, 0), 1.0);
+        assert_eq!(heights.height(0, 1), 4.0);
+        assert_eq!(heights.height(64, 64), 4.0);
+        assert_eq!(heights.to_bytes(Grid { x: 0, y: 0 }).unwrap(), bytes);
+    }
+
+    #[test]
+    fn seams_match() {
+        let records = vec![
+            land(Grid { x: 0, y: 0 }, |x, y| (x + y) as i32),
+            land(Grid { x: 1, y: 0 }, |x, y| (x + y + 64) as i32),
+            land(Grid { x: 0, y: 1 }, |x, y| (x + y + 64) as i32),
+        ];
+        assert!(check_seams(&records).unwrap().is_empty());
+    }
+
+    #[test]
+    fn stitch_mismatched_seam() {
+        let mut records = vec![
+            land(Grid { x: 0, y: 0 }, |_, _| 0),
+            land(Grid { x: 1, y: 0 }, |_, _| 10),
+        ];
+        let mismatches = check_seams(&records).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].direction, SeamDirection::East);
+        assert_eq!(mismatches[0].neighbor_grid(), Grid { x: 1, y: 0 });
+        assert_eq!(mismatches[0].vertices.len(), LAND_SIZE);
+        assert_eq!(mismatches[0].vertices[0].height, 0.0);
+        assert_eq!(mismatches[0].vertices[0].neighbor_height, 10.0);
+        let modified = stitch_seams(&mut records, StitchMode::PreferLater).unwrap();
+        assert_eq!(modified, vec![0]);
+        assert!(check_seams(&records).unwrap().is_empty());
+        let heights = VertexHeights::from_field(&records[0].fields[2].1).unwrap();
+        assert_eq!(heights.height(64, 10), 10.0);
+        assert_eq!(heights.height(63, 10), 0.0);
+    }
+
+    #[test]
+    fn stitch_spreads_steep_seam() {
+        let mut records = vec![
+            land(Grid { x: 0, y: 0 }, |_, _| 0),
+            land(Grid { x: 1, y: 0 }, |_, _| 0),
+        ];
+        let mut raised = VertexHeights::from_field(&records[1].fields[2].1).unwrap();
+        raised.offset = 200.0;
+        records[1].fields[2].1 = raised.to_field(Grid { x: 1, y: 0 }).unwrap();
+        assert_eq!(stitch_seams(&mut records, StitchMode::PreferLater).unwrap(), vec![0]);
+        assert!(check_seams(&records).unwrap().is_empty());
+        let heights = VertexHeights::from_field(&records[0].fields[2].1).unwrap();
+        assert_eq!(heights.height(64, 10), 200.0);
+        assert_eq!(heights.height(63, 10), 100.0);
+        assert_eq!(heights.height(62, 10), 0.0);
+    }
+
+    #[test]
+    fn later_land_overrides_earlier() {
+        let mut records = vec![
+            land(Grid { x: 0, y: 0 }, |_, _| 0),
+            land(Grid { x: 0, y: 1 }, |_, _| 6),
+            land(Grid { x: 0, y: 1 }, |_, _| 0),
+        ];
+        assert!(check_seams(&records).unwrap().is_empty());
+        let mut deleted = land(Grid { x: 0, y: 1 }, |_, _| 0);
+        deleted.flags.insert(RecordFlags::DELETED);
+        let mut without_heights = land(Grid { x: 0, y: 1 }, |_, _| 0);
+        without_heights.fields.pop();
+        for removal in [deleted, without_heights].iter() {
+            let mut overridden = records[.. 2].to_vec();
+            assert_eq!(check_seams(&overridden).unwrap().len(), 1);
+            overridden.push(removal.clone());
+            assert!(check_seams(&overridden).unwrap().is_empty());
+        }
+        records.pop();
+        assert_eq!(stitch_seams(&mut records, StitchMode::Average).unwrap(), vec![0, 1]);
+        let heights = VertexHeights::from_field(&records[1].fields[2].1).unwrap();
+        assert_eq!(heights.height(5, 0), 3.0);
+        assert!(check_seams(&records).unwrap().is_empty());
+    }
+}
@@ -7,6 +7,8 @@
 #![allow(clippy::option_map_unit_fn)]
 #![allow(clippy::match_ref_pats)]
 #![allow(clippy::or_fun_call)]
+#![allow(clippy::manual_is_multiple_of)]
+#![allow(clippy::unnecessary_map_or)]
 #![recursion_limit="512"]
 
 #[macro_use]
@@ -35,6 +37,30 @@ pub mod code;
 
 mod serde_helpers;
 
+mod data_files;
+
+pub mod land;
+pub mod path_grid;
+pub mod cell;
+pub mod placement;
+pub mod connectivity;
+pub mod dialogue;
+pub mod dialogue_graph;
+pub mod voice;
+pub mod script;
+pub mod script_compiler;
+pub mod script_parser;
+pub mod script_lint;
+pub mod book;
+pub mod translation;
+pub mod name_mapping;
+pub mod diff;
+pub mod patch;
+pub mod conflict;
+pub mod merge;
+pub mod clean;
+pub mod fix;
+
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -296,4 +322,44 @@ mod tests {
         let res_yaml = serde_yaml::to_string(&res).unwrap();
         assert_eq!(res_yaml, format!("---\n{}", yaml).trim_matches(' '));
     }
+
+    #[test]
+    fn info_conditions() {
+        let yaml = "\
+- INFO:
+    - SCVR:
+        index: 0
+        kind: Function
+        function: PcLevel
+        var_type: ~
+        operator: GreaterOrEqual
+        name: \"\"
+    - INTV: 10
+    - SCVR:
+        index: 1
+        kind: Local
+        function: ~
+        var_type: Short
+        operator: Equal
+        name: NoLore
+    - INTV: 0
+    - SCVR: 2?X0odd
+    - FLTV: 1.5
+        ";
+        let res: Vec<Record> = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(res[0].fields[0].1, Field::DialogueCondition(DialogueCondition::from_str("01063").unwrap()));
+        assert_eq!(res[0].fields[2].1, Field::DialogueCondition(DialogueCondition::from_str("13sX0NoLore").unwrap()));
+        assert_eq!(res[0].fields[4].1, Field::String("2?X0odd".into()));
+        let res_yaml = serde_yaml::to_string(&res).unwrap();
+        assert_eq!(res_yaml, format!("---\n{}", yaml).trim_matches(' '));
+        let bytes = code::serialize(&res[0], CodePage::English, false).unwrap();
+        let read = {
+            let mut bytes = &bytes[..];
+            let mut records = Records::new(CodePage::English, RecordReadMode::Strict, 0, &mut bytes);
+            records.next().unwrap().unwrap()
+        };
+        assert_eq!(read, res[0]);
+        let deserialized: Record = code::deserialize(&bytes, CodePage::English, false).unwrap();
+        assert_eq!(deserialized, res[0]);
+    }
 }
@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use crate::conflict::*;
+use crate::diff::*;
+use crate::field::*;
+use crate::patch::*;
+use crate::record::*;
+
+const OBJECT_TAGS: &[Tag] = &[
+    ACTI, ALCH, APPA, ARMO, BODY, BOOK, BSGN, CLAS, CLOT, CONT, CREA, DOOR, ENCH, FACT, INGR,
+    LIGH, LOCK, MISC, NPC_, PROB, RACE, REPA, SNDG, SOUN, SPEL, STAT, WEAP,
+];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MergeDecision {
+    LeveledList { key: RecordKey, plugins: Vec<usize>, entries: usize },
+    Combined { key: RecordKey, plugins: Vec<usize> },
+    FieldConflict { key: RecordKey, field: FieldSelector, member: Option<String>, plugins: Vec<usize>, winner: usize },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MasterSizesError {
+    pub masters: usize,
+    pub sizes: usize,
+}
+
+impl Display for MasterSizesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} master sizes given for {} masters", self.sizes, self.masters)
+    }
+}
+
+impl std::error::Error for MasterSizesError { }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedPlugin {
+    pub plugins: Vec<String>,
+    pub records: Vec<Record>,
+    pub decisions: Vec<MergeDecision>,
+}
+
+type FieldKey = (Tag, usize);
+
+fn keyed(fields: &[(Tag, Field)]) -> Vec<(FieldKey, &Field)> {
+    fields.iter().zip(occurrences(fields)).map(|((tag, field), occurrence)| ((*tag, occurrence), field)).collect()
+}
+
+type MemberConflict = (String, Vec<usize>, usize);
+
+fn merge_members(record_tag: Tag, field_tag: Tag, base: &Field, changes: &[(usize, &Field)])
+    -> Option<(Field, Vec<MemberConflict>)> {
+
+    let mut merged = field_members(record_tag, field_tag, base).ok()?;
+    if members_field(record_tag, field_tag, merged.clone()).as_ref() != Some(base) { return None; }
+    let base_leaves = merged.leaves();
+    let mut edits = vec![Vec::new(); base_leaves.len()];
+    for &(plugin, field) in changes {
+        let leaves = field_members(record_tag, field_tag, field).ok()?.leaves();
+        if leaves.len() != base_leaves.len() || leaves.iter().zip(&base_leaves).any(|(x, y)| x.0 != y.0) { return None; }
+        for (index, (_, value)) in leaves.into_iter().enumerate() {
+            if value != base_leaves[index].1 {
+                edits[index].push((plugin, value));
+            }
+        }
+    }
+    let mut conflicts = Vec::new();
+    for (((path, _), value), edits) in base_leaves.into_iter().zip(merged.values_mut()).zip(edits) {
+        let (winner, last) = if let Some(last) = edits.last() { last.clone() } else { continue; };
+        if edits.iter().any(|(_, x)| *x != last) {
+            let mut plugins = Vec::new();
+            for (plugin, _) in &edits {
+                if !plugins.contains(plugin) { plugins.push(*plugin); }
+            }
+            conflicts.push((path, plugins, winner));
+        }
+        *value = last;
+    }
+    Some((members_field(record_tag, field_tag, merged)?, conflicts))
+}
+
+fn merge_fields(key: &RecordKey, definitions: &[(usize, &[(Tag, Field)])], decisions: &mut Vec<MergeDecision>)
+    -> (Vec<(Tag, Field)>, Vec<usize>) {
+
+    let base = keyed(definitions[0].1);
+    let base_lookup = base.iter().cloned().collect::<HashMap<_, _>>();
+    let mut order = base.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+    let mut changes: HashMap<FieldKey, Vec<(usize, Option<&Field>)>> = HashMap::new();
+    let mut contributors = Vec::new();
+    for &(plugin, fields) in &definitions[1 ..] {
+        let fields = keyed(fields);
+        let lookup = fields.iter().cloned().collect::<HashMap<_, _>>();
+        for (index, (field_key, _)) in fields.iter().enumerate() {
+            if order.contains(field_key) { continue; }
+            let position = if index == 0 { 0 } else {
+                order.iter().position(|x| *x == fields[index - 1].0).map_or(order.len(), |x| x + 1)
+            };
+            order.insert(position, *field_key);
+        }
+        for field_key in &order {
+            let value = lookup.get(field_key).copied();
+            if value == base_lookup.get(field_key).copied() { continue; }
+            changes.entry(*field_key).or_default().push((plugin, value));
+            if !contributors.contains(&plugin) { contributors.push(plugin); }
+        }
+    }
+    let mut fields = Vec::new();
+    for field_key in &order {
+        let base_value = base_lookup.get(field_key).copied();
+        let changed = if let Some(changed) = changes.get(field_key) { changed } else {
+            fields.extend(base_value.map(|x| (field_key.0, x.clone())));
+            continue;
+        };
+        let (winner, last) = *changed.last().unwrap();
+        let field = FieldSelector { tag: field_key.0, occurrence: field_key.1 };
+        let value = if changed.iter().all(|(_, x)| *x == last) {
+            last.cloned()
+        } else if let Some((merged, conflicts)) = base_value.zip(changed.iter().map(|(plugin, x)| x.map(|x| (*plugin, x))).collect::<Option<Vec<_>>>())
+            .and_then(|(base, changed)| merge_members(key.tag, field_key.0, base, &changed)) {
+
+            for (member, plugins, winner) in conflicts {
+                decisions.push(MergeDecision::FieldConflict { key: key.clone(), field, member: Some(member), plugins, winner });
+            }
+            Some(merged)
+        } else {
+            let mut plugins = Vec::new();
+            for (plugin, _) in changed {
+                if !plugins.contains(plugin) { plugins.push(*plugin); }
+            }
+            decisions.push(MergeDecision::FieldConflict { key: key.clone(), field, member: None, plugins, winner });
+            last.cloned()
+        };
+        fields.extend(value.map(|x| (field_key.0, x)));
+    }
+    (fields, contributors)
+}
+
+fn merge_flags(definitions: &[(usize, &Record)]) -> RecordFlags {
+    let base = definitions[0].1.flags;
+    definitions.iter().rev().map(|x| x.1.flags).find(|&flags| flags != base).unwrap_or(base)
+}
+
+struct ListField {
+    heads: &'static [Tag],
+    tails: &'static [Tag],
+    ordered: bool,
+    before: Option<Tag>,
+}
+
+const LIST_FIELDS: &[ListField] = &[
+    ListField { heads: &[NPCO], tails: &[], ordered: false, before: Some(AIDT) },
+    ListField { heads: &[NPCS], tails: &[], ordered: false, before: Some(AIDT) },
+    ListField { heads: &[DODT], tails: &[DNAM], ordered: false, before: None },
+    ListField { heads: &[AI_W, AI_T, AI_F, AI_E, AI_A], tails: &[CNDT], ordered: true, before: None },
+];
+
+type ListEntry<'a> = Vec<&'a (Tag, Field)>;
+
+fn split_lists(fields: &[(Tag, Field)]) -> (Vec<(Tag, Field)>, Vec<Vec<ListEntry<'_>>>) {
+    let mut scalars = Vec::new();
+    let mut lists = vec![Vec::new(); LIST_FIELDS.len()];
+    let mut current = None;
+    for field in fields {
+        if let Some(index) = LIST_FIELDS.iter().position(|x| x.heads.contains(&field.0)) {
+            lists[index].push(vec![field]);
+            current = Some(index);
+        } else if let Some(index) = current.filter(|&x| LIST_FIELDS[x].tails.contains(&field.0)) {
+            lists[index].last_mut().unwrap().push(field);
+        } else {
+            scalars.push(field.clone());
+            current = None;
+        }
+    }
+    (scalars, lists)
+}
+
+fn merge_set<T: Clone + PartialEq>(lists: &[(usize, Vec<T>)], plugins: &mut Vec<usize>) -> Vec<T> {
+    let base = &lists[0].1;
+    let mut entries = base.clone();
+    for (plugin, list) in &lists[1 ..] {
+        let mut removed = base.clone();
+        let mut changed = false;
+        for entry in list {
+            if let Some(index) = removed.iter().position(|x| x == entry) {
+                removed.swap_remove(index);
+            } else {
+                entries.push(entry.clone());
+                changed = true;
+            }
+        }
+        for entry in removed {
+            if let Some(index) = entries.iter().position(|x| *x == entry) {
+                entries.remove(index);
+                changed = true;
+            }
+        }
+        if changed && !plugins.contains(plugin) { plugins.push(*plugin); }
+    }
+    entries
+}
+
+fn merge_list<T: Clone + PartialEq>(key: &RecordKey, tag: Tag, lists: &[(usize, Vec<T>)],
+    plugins: &mut Vec<usize>, decisions: &mut Vec<MergeDecision>) -> Vec<T> {
+
+    let base = &lists[0].1;
+    let changed = lists[1 ..].iter().filter(|(_, list)| list != base).collect::<Vec<_>>();
+    for (plugin, _) in &changed {
+        if !plugins.contains(plugin) { plugins.push(*plugin); }
+    }
+    let winner = if let Some(winner) = changed.last() { winner } else { return base.clone(); };
+    if changed.iter().any(|(_, list)| *list != winner.1) {
+        decisions.push(MergeDecision::FieldConflict {
+            key: key.clone(),
+            field: FieldSelector { tag, occurrence: 0 },
+            member: None,
+            plugins: changed.iter().map(|(plugin, _)| *plugin).collect(),
+            winner: winner.0
+        });
+    }
+    winner.1.clone()
+}
+
+fn merge_object(key: &RecordKey, definitions: &[(usize, &Record)]) -> (Record, Vec<MergeDecision>) {
+    let mut decisions = Vec::new();
+    let split = definitions.iter().map(|(plugin, record)| (*plugin, split_lists(&record.fields))).collect::<Vec<_>>();
+    let scalars = split.iter().map(|(plugin, (fields, _))| (*plugin, &fields[..])).collect::<Vec<_>>();
+    let (mut fields, mut plugins) = merge_fields(key, &scalars, &mut decisions);
+    for (index, list) in LIST_FIELDS.iter().enumerate() {
+        let lists = split.iter().map(|(plugin, (_, lists))| (*plugin, lists[index].clone())).collect::<Vec<_>>();
+        let entries = if list.ordered {
+            merge_list(key, list.heads[0], &lists, &mut plugins, &mut decisions)
+        } else {
+            merge_set(&lists, &mut plugins)
+        };
+        let position = list.before.and_then(|tag| fields.iter().position(|(x, _)| *x == tag)).unwrap_or(fields.len());
+        fields.splice(position .. position, entries.into_iter().flatten().cloned());
+    }
+    plugins.sort_unstable();
+    decisions.insert(0, MergeDecision::Combined { key: key.clone(), plugins });
+    (Record { tag: key.tag, flags: merge_flags(definitions), fields }, decisions)
+}
+
+fn is_entry_tag(tag: Tag) -> bool { [CNAM, INAM, INTV, INDX].contains(&tag) }
+
+fn leveled_entries(record: &Record) -> Vec<(&Field, &Field)> {
+    let mut entries = Vec::new();
+    let mut item = None;
+    for (tag, field) in &record.fields {
+        match *tag {
+            CNAM | INAM => item = Some(field),
+            INTV => if let Some(item) = item.take() { entries.push((item, field)); },
+            _ => { },
+        }
+    }
+    entries
+}
+
+fn merge_leveled_list(key: &RecordKey, definitions: &[(usize, &Record)]) -> (Record, Vec<MergeDecision>) {
+    let mut decisions = Vec::new();
+    let headers = definitions.iter().map(|(plugin, record)| {
+        (*plugin, record.fields.iter().filter(|(tag, _)| !is_entry_tag(*tag)).cloned().collect::<Vec<_>>())
+    }).collect::<Vec<_>>();
+    let headers = headers.iter().map(|(plugin, fields)| (*plugin, &fields[..])).collect::<Vec<_>>();
+    let (mut fields, mut plugins) = merge_fields(key, &headers, &mut decisions);
+    let lists = definitions.iter().map(|(plugin, record)| (*plugin, leveled_entries(record))).collect::<Vec<_>>();
+    let mut entries = merge_set(&lists, &mut plugins);
+    entries.sort_by_key(|(_, level)| if let Field::I16(level) = level { *level } else { 0 });
+    let item_tag = if key.tag == LEVC { CNAM } else { INAM };
+    fields.push((INDX, Field::I32(entries.len() as i32)));
+    for (item, level) in &entries {
+        fields.push((item_tag, (*item).clone()));
+        fields.push((INTV, (*level).clone()));
+    }
+    plugins.sort_unstable();
+    decisions.insert(0, MergeDecision::LeveledList { key: key.clone(), plugins, entries: entries.len() });
+    (Record { tag: key.tag, flags: merge_flags(definitions), fields }, decisions)
+}
+
+pub fn merge_load_order(plugins: &[(&str, &[Record])]) -> MergedPlugin {
+    let report = find_conflicts(plugins);
+    let mut records = Vec::new();
+    let mut decisions = Vec::new();
+    for conflict in report.differing() {
+        let definitions = conflict.definitions.iter()
+            .map(|x| (x.plugin, &plugins[x.plugin].1[x.index]))
+            .collect::<Vec<_>>();
+        let (record, record_decisions) = match conflict.key.tag {
+            LEVC | LEVI => merge_leveled_list(&conflict.key, &definitions),
+            tag if OBJECT_TAGS.contains(&tag) => merge_object(&conflict.key, &definitions),
+            _ => continue
+        };
+        if &record == definitions.last().unwrap().1 { continue; }
+        records.push(record);
+        decisions.extend(record_decisions);
+    }
+    MergedPlugin { plugins: report.plugins, records, decisions }
+}
+
+pub(crate) fn patch_plugin(masters: Vec<String>, master_sizes: &[u64], description: &str, patch: Vec<Record>)
+    -> Result<Vec<Record>, MasterSizesError> {
+
+    if masters.len() != master_sizes.len() {
+        return Err(MasterSizesError { masters: masters.len(), sizes: master_sizes.len() });
+    }
+    let mut header = vec![(HEDR, Field::FileMetadata(FileMetadata {
+        version: 0x3FA66666,
+        file_type: FileType::ESP,
+        author: String::new(),
+        description: vec![description.into()],
+        records: patch.len() as u32
+    }))];
+    for (name, size) in masters.into_iter().zip(master_sizes) {
+        header.push((MAST, Field::StringZ(name.into())));
+        header.push((DATA, Field::I64(*size as i64)));
+    }
+    let mut records = vec![Record { tag: TES3, flags: RecordFlags::empty(), fields: header }];
+    records.extend(patch);
+    Ok(records)
+}
+
+impl MergedPlugin {
+    pub fn into_plugin(self, master_sizes: &[u64]) -> Result<Vec<Record>, MasterSizesError> {
+        patch_plugin(self.plugins, master_sizes, "Merged objects", self.records)
+    }
+}
+
+impl Display for MergedPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plugins = |plugins: &[usize]| plugins.iter().map(|&x| self.plugins[x].as_str()).collect::<Vec<_>>().join(", ");
+        for decision in &self.decisions {
+            match decision {
+                MergeDecision::LeveledList { key, plugins: x, entries } =>
+                    writeln!(f, "{}: merged leveled list from {} ({} entries)", key, plugins(x), entries)?,
+                MergeDecision::Combined { key, plugins: x } =>
+                    writeln!(f, "{}: combined changes from {}", key, plugins(x))?,
+                MergeDecision::FieldConflict { key, field, member: None, plugins: x, winner } =>
+                    writeln!(f, "{}: {} conflicts between {}, took {}", key, field, plugins(x), self.plugins[*winner])?,
+                MergeDecision::FieldConflict { key, field, member: Some(member), plugins: x, winner } =>
+                    writeln!(f, "{}: {} {} conflicts between {}, took {}", key, field, member, plugins(x), self.plugins[*winner])?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leveled_items(entries: &[(&str, i16)]) -> Record {
+        let mut fields = vec![
+            (NAME, Field::StringZ("random_ring".into())),
+            (DATA, Field::I32(1)),
+            (NNAM, Field::U8(0)),
+            (INDX, Field::I32(entries.len() as i32)),
+        ];
+        for &(item, level) in entries {
+            fields.push((INAM, Field::StringZ(item.into())));
+            fields.push((INTV, Field::I16(level)));
+        }
+        Record { tag: LEVI, flags: RecordFlags::empty(), fields }
+    }
+
+    fn misc(value: u32, weight: f32, script: Option<&str>) -> Record {
+        let mut fields = vec![
+            (NAME, Field::StringZ("misc_skull".into())),
+            (MCDT, Field::MiscItem(MiscItem { weight, value, is_key: false })),
+        ];
+        if let Some(script) = script {
+            fields.push((SCRI, Field::StringZ(script.into())));
+        }
+        Record { tag: MISC, flags: RecordFlags::empty(), fields }
+    }
+
+    #[test]
+    fn merge_leveled_lists_and_objects() {
+        let master = vec![leveled_items(&[("ring_a", 1), ("ring_b", 5)]), misc(10, 1.0, None)];
+        let mut first = vec![leveled_items(&[("ring_a", 1), ("ring_b", 5), ("ring_c", 10)]), misc(10, 1.0, Some("skull_script"))];
+        first[0].fields[2].1 = Field::U8(25);
+        let second = vec![leveled_items(&[("ring_d", 3), ("ring_b", 5)]), misc(20, 1.0, None)];
+        let merged = merge_load_order(&[("Morrowind.esm", &master), ("First.esp", &first), ("Second.esp", &second)]);
+        let mut expected = leveled_items(&[("ring_d", 3), ("ring_b", 5), ("ring_c", 10)]);
+        expected.fields[2].1 = Field::U8(25);
+        assert_eq!(merged.records, vec![expected, misc(20, 1.0, Some("skull_script"))]);
+        assert_eq!(merged.to_string(), "\
+LEVI random_ring: merged leveled list from First.esp, Second.esp (3 entries)
+MISC misc_skull: combined changes from First.esp, Second.esp
+");
+        assert_eq!(merged.clone().into_plugin(&[100, 200]), Err(MasterSizesError { masters: 3, sizes: 2 }));
+        let plugin = merged.into_plugin(&[100, 200, 300]).unwrap();
+        assert_eq!(plugin.len(), 3);
+        assert_eq!(plugin[0].fields[1], (MAST, Field::StringZ("Morrowind.esm".into())));
+        assert_eq!(plugin[0].fields[6], (DATA, Field::I64(300)));
+    }
+
+    #[test]
+    fn merge_conflicting_fields() {
+        let master = vec![misc(10, 1.0, None)];
+        let first = vec![misc(15, 1.0, None)];
+        let second = vec![misc(20, 2.0, None)];
+        let third = vec![misc(10, 1.0, None)];
+        let merged = merge_load_order(&[("Morrowind.esm", &master), ("First.esp", &first), ("Second.esp", &second), ("Third.esp", &third)]);
+        assert_eq!(merged.records, vec![misc(20, 2.0, None)]);
+        assert_eq!(merged.decisions[1], MergeDecision::FieldConflict {
+            key: RecordKey { tag: MISC, id: "misc_skull".into() },
+            field: FieldSelector { tag: MCDT, occurrence: 0 },
+            member: Some("MiscItem.value".into()),
+            plugins: vec![1, 2],
+            winner: 2
+        });
+        assert_eq!(merged.to_string(), "\
+MISC misc_skull: combined changes from First.esp, Second.esp
+MISC misc_skull: MCDT MiscItem.value conflicts between First.esp, Second.esp, took Second.esp
+");
+        let unchanged = merge_load_order(&[("Morrowind.esm", &master), ("First.esp", &first)]);
+        assert!(unchanged.records.is_empty());
+        let weight = vec![misc(10, 2.0, None)];
+        let merged = merge_load_order(&[("Morrowind.esm", &master), ("First.esp", &first), ("Weight.esp", &weight)]);
+        assert_eq!(merged.records, vec![misc(15, 2.0, None)]);
+        assert_eq!(merged.to_string(), "MISC misc_skull: combined changes from First.esp, Weight.esp\n");
+    }
+
+    fn npc(items: &[(&str, i32)], spells: &[&str], wander: &[u16]) -> Record {
+        let mut fields = vec![
+            (NAME, Field::StringZ("fargoth".into())),
+            (FNAM, Field::String("Fargoth".into())),
+        ];
+        for &(item_id, count) in items {
+            fields.push((NPCO, Field::Item(Item { count, item_id: item_id.into() })));
+        }
+        for &spell in spells {
+            fields.push((NPCS, Field::String(spell.into())));
+        }
+        fields.push((AIDT, Field::Ai(Ai { hello: 30, fight: 30, flee: 30, alarm: 0, padding_8: 0, padding_16: 0, services: Services::empty() })));
+        for &distance in wander {
+            fields.push((AI_W, Field::AiWander(AiWander { distance, duration: 5, time_of_day: 0, idle: [0; 8], repeat: false })));
+        }
+        Record { tag: NPC_, flags: RecordFlags::empty(), fields }
+    }
+
+    #[test]
+    fn merge_list_fields() {
+        let master = vec![npc(&[("gold_001", 5), ("common_shirt_01", 1)], &["hearth heal"], &[128])];
+        let first = vec![npc(&[("gold_001", 5), ("common_shirt_01", 1), ("ring_a", 1)], &["hearth heal", "bound dagger"], &[256])];
+        let second = vec![npc(&[("common_shirt_01", 1), ("gold_001", 10)], &[], &[512])];
+        let third = vec![npc(&[("gold_001", 5), ("common_shirt_01", 1)], &["hearth heal"], &[128])];
+        let merged = merge_load_order(&[("Morrowind.esm", &master), ("First.esp", &first), ("Second.esp", &second), ("Third.esp", &third)]);
+        assert_eq!(merged.records, vec![npc(&[("common_shirt_01", 1), ("ring_a", 1), ("gold_001", 10)], &["bound dagger"], &[512])]);
+        assert_eq!(merged.to_string(), "\
+NPC_ fargoth: combined changes from First.esp, Second.esp
+NPC_ fargoth: AI_W conflicts between First.esp, Second.esp, took Second.esp
+");
+    }
+}
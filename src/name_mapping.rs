@@ -0,0 +1,268 @@
+use encoding::{DecoderTrap, EncoderTrap};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use crate::cell::*;
+use crate::code::CodePage;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NameMappingError {
+    MissingSeparator { line: usize },
+    Undecodable { line: usize },
+    Unencodable { line: usize },
+}
+
+impl Display for NameMappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameMappingError::MissingSeparator { line } => write!(f, "line {} has no tab separator", line),
+            NameMappingError::Undecodable { line } => write!(f, "line {} is not valid in the code page", line),
+            NameMappingError::Unencodable { line } => write!(f, "line {} does not fit the code page", line),
+        }
+    }
+}
+
+impl Error for NameMappingError { }
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct NameMapping {
+    pub entries: Vec<(String, String)>,
+}
+
+impl NameMapping {
+    pub fn read(bytes: &[u8], code_page: CodePage) -> Result<NameMapping, NameMappingError> {
+        let mut entries = Vec::new();
+        for (index, line) in bytes.split(|&b| b == b'\n').enumerate() {
+            let line = code_page.encoding().decode(line, DecoderTrap::Strict)
+                .map_err(|_| NameMappingError::Undecodable { line: index + 1 })?;
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() { continue; }
+            let tab = line.find('\t').ok_or(NameMappingError::MissingSeparator { line: index + 1 })?;
+            entries.push((line[.. tab].to_string(), line[tab + 1 ..].to_string()));
+        }
+        Ok(NameMapping { entries })
+    }
+
+    pub fn write(&self, code_page: CodePage) -> Result<Vec<u8>, NameMappingError> {
+        let mut bytes = Vec::new();
+        for (index, (from, to)) in self.entries.iter().enumerate() {
+            let line = format!("{}\t{}\r\n", from, to);
+            let encoded = code_page.encoding().encode(&line, EncoderTrap::Strict)
+                .map_err(|_| NameMappingError::Unencodable { line: index + 1 })?;
+            bytes.extend_from_slice(&encoded);
+        }
+        Ok(bytes)
+    }
+
+    pub fn reversed(&self) -> NameMapping {
+        NameMapping { entries: self.entries.iter().map(|(from, to)| (to.clone(), from.clone())).collect() }
+    }
+
+    fn lookup(&self) -> HashMap<String, &str> {
+        self.entries.iter().map(|(from, to)| (from.to_lowercase(), to.as_str())).collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LocalizationMappings {
+    pub cells: NameMapping,
+    pub topics: NameMapping,
+    pub markers: NameMapping,
+}
+
+impl LocalizationMappings {
+    pub fn reversed(&self) -> LocalizationMappings {
+        LocalizationMappings {
+            cells: self.cells.reversed(),
+            topics: self.topics.reversed(),
+            markers: self.markers.reversed(),
+        }
+    }
+}
+
+fn map_string(s: &mut String, lookup: &HashMap<String, &str>) -> bool {
+    match lookup.get(&s.to_lowercase()) {
+        Some(&to) if to != s => {
+            *s = to.to_string();
+            true
+        },
+        _ => false
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let mut chars = s.chars();
+    for p in prefix.chars() {
+        if !chars.next()?.to_lowercase().eq(p.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(chars.as_str())
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new()
+    }
+}
+
+fn match_case(matched: &str, to: &str) -> String {
+    let letters = matched.chars().filter(|c| c.is_alphabetic()).collect::<Vec<_>>();
+    if letters.len() > 1 && letters.iter().all(|c| c.is_uppercase()) {
+        return to.to_uppercase();
+    }
+    let capitalized = |word: &str| word.chars().next().map_or(false, |c| c.is_uppercase());
+    let words = matched.split_whitespace().collect::<Vec<_>>();
+    if words.len() > 1 && words.iter().all(|&x| capitalized(x)) {
+        to.split(' ').map(capitalize).collect::<Vec<_>>().join(" ")
+    } else if capitalized(matched) {
+        capitalize(to)
+    } else {
+        to.to_string()
+    }
+}
+
+fn replace_markers(text: &mut String, markers: &[(&str, &str)]) -> bool {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    let mut previous = None;
+    let mut changed = false;
+    'outer: while !rest.is_empty() {
+        for &(from, to) in markers {
+            let tail = if let Some(tail) = strip_prefix_ignore_case(rest, from) { tail } else { continue; };
+            let word_start = from.chars().next().map_or(false, |c| c.is_alphanumeric());
+            let word_end = from.chars().last().map_or(false, |c| c.is_alphanumeric());
+            if word_start && previous.map_or(false, |c: char| c.is_alphanumeric()) { continue; }
+            if word_end && tail.chars().next().map_or(false, |c| c.is_alphanumeric()) { continue; }
+            let matched = &rest[.. rest.len() - tail.len()];
+            result.push_str(&match_case(matched, to));
+            previous = matched.chars().last();
+            rest = tail;
+            changed = true;
+            continue 'outer;
+        }
+        let c = rest.chars().next().unwrap();
+        result.push(c);
+        previous = Some(c);
+        rest = &rest[c.len_utf8() ..];
+    }
+    if changed {
+        *text = result;
+    }
+    changed
+}
+
+pub fn apply_localization(records: &mut [Record], mappings: &LocalizationMappings) -> usize {
+    let cells = mappings.cells.lookup();
+    let topics = mappings.topics.lookup();
+    let mut markers = mappings.markers.entries.iter()
+        .filter(|(from, _)| !from.is_empty())
+        .map(|(from, to)| (from.as_str(), to.as_str()))
+        .collect::<Vec<_>>();
+    markers.sort_by_key(|(from, _)| std::cmp::Reverse(from.chars().count()));
+    let mut changed = 0;
+    for record in records.iter_mut() {
+        if record.tag == CELL {
+            let mut cell = CellData::from_fields(&record.fields);
+            let mut cell_changed = false;
+            let names = cell.header.name.iter_mut()
+                .chain(cell.references.iter_mut().filter_map(|x| x.door_destination_cell.as_mut()));
+            for name in names {
+                cell_changed |= map_string(&mut name.string, &cells);
+            }
+            if cell_changed {
+                record.fields = cell.to_fields();
+                changed += 1;
+            }
+            continue;
+        }
+        let mut record_changed = false;
+        let record_tag = record.tag;
+        for (tag, field) in record.fields.iter_mut() {
+            let field_changed = match (record_tag, *tag, field) {
+                (INFO, ANAM, Field::StringZ(s)) | (NPC_, DNAM, Field::StringZ(s)) | (CREA, DNAM, Field::StringZ(s)) =>
+                    map_string(&mut s.string, &cells),
+                (DIAL, NAME, Field::StringZ(s)) => map_string(&mut s.string, &topics),
+                (INFO, NAME, Field::String(s)) => replace_markers(s, &markers),
+                _ => false
+            };
+            record_changed |= field_changed;
+        }
+        if record_changed {
+            changed += 1;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write_mapping() {
+        let bytes = b"Balmora, Guild of Mages\t\xc1\xe0\xeb\xec\xee\xf0\xe0, \xc3\xe8\xeb\xfc\xe4\xe8\xff \xcc\xe0\xe3\xee\xe2\r\n\r\nCaldera\t\xca\xe0\xeb\xfc\xe4\xe5\xf0\xe0\r\n";
+        let mapping = NameMapping::read(bytes, CodePage::Russian).unwrap();
+        assert_eq!(mapping.entries, vec![
+            ("Balmora, Guild of Mages".to_string(), "Балмора, Гильдия Магов".to_string()),
+            ("Caldera".to_string(), "Кальдера".to_string()),
+        ]);
+        let mut written = bytes.to_vec();
+        written.drain(46 .. 48);
+        assert_eq!(mapping.write(CodePage::Russian).unwrap(), written);
+        assert_eq!(NameMapping::read(b"Caldera", CodePage::Russian), Err(NameMappingError::MissingSeparator { line: 1 }));
+        assert_eq!(mapping.write(CodePage::English), Err(NameMappingError::Unencodable { line: 1 }));
+    }
+
+    #[test]
+    fn apply_and_reverse() {
+        let mappings = LocalizationMappings {
+            cells: NameMapping { entries: vec![("Caldera".into(), "Кальдера".into())] },
+            topics: NameMapping { entries: vec![("latest rumors".into(), "последние слухи".into())] },
+            markers: NameMapping { entries: vec![
+                ("latest rumors".into(), "последних слухах".into()),
+                ("rumors".into(), "слухи".into()),
+            ] },
+        };
+        let record = |tag, fields| Record { tag, flags: RecordFlags::empty(), fields };
+        let original = vec![
+            record(CELL, vec![
+                (NAME, Field::StringZ("caldera".into())),
+                (DATA, Field::Cell(Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } })),
+                (FRMR, Field::I32(1)),
+                (NAME, Field::StringZ("caldera".into())),
+                (DNAM, Field::StringZ("Caldera".into())),
+            ]),
+            record(DIAL, vec![(NAME, Field::StringZ("latest rumors".into()))]),
+            record(INFO, vec![
+                (ANAM, Field::StringZ("Caldera".into())),
+                (NAME, Field::String("Ask about Latest Rumors or rumors, not rumorsmith. RUMORS!".into())),
+            ]),
+            record(MISC, vec![(NAME, Field::StringZ("Caldera".into()))]),
+            record(CREA, vec![
+                (NAME, Field::StringZ("strider".into())),
+                (DODT, Field::Position(Position { x: 0.0, y: 0.0, z: 0.0, x_rot: 0.0, y_rot: 0.0, z_rot: 0.0 })),
+                (DNAM, Field::StringZ("caldera".into())),
+            ]),
+        ];
+        let mut records = original.clone();
+        assert_eq!(apply_localization(&mut records, &mappings), 4);
+        assert_eq!(records[0].fields[0].1, Field::StringZ("Кальдера".into()));
+        assert_eq!(records[0].fields[3].1, Field::StringZ("caldera".into()));
+        assert_eq!(records[0].fields[4].1, Field::StringZ("Кальдера".into()));
+        assert_eq!(records[1].fields[0].1, Field::StringZ("последние слухи".into()));
+        assert_eq!(records[2].fields[1].1, Field::String("Ask about Последних Слухах or слухи, not rumorsmith. СЛУХИ!".into()));
+        assert_eq!(records[3], original[3]);
+        assert_eq!(records[4].fields[2].1, Field::StringZ("Кальдера".into()));
+        assert_eq!(apply_localization(&mut records, &mappings.reversed()), 4);
+        assert_eq!(records[4].fields[2].1, Field::StringZ("Caldera".into()));
+        assert_eq!(records[0].fields[0].1, Field::StringZ("Caldera".into()));
+        assert_eq!(records[0].fields[3..], original[0].fields[3..]);
+        assert_eq!(records[2], original[2]);
+    }
+}
@@ -0,0 +1,849 @@
+use either::{Left, Right};
+use serde::de::{self, DeserializeSeed, Error as de_Error};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::cell::*;
+use crate::diff::*;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct FieldSelector {
+    pub tag: Tag,
+    pub occurrence: usize,
+}
+
+impl Display for FieldSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.occurrence == 0 {
+            write!(f, "{}", self.tag)
+        } else {
+            write!(f, "{}#{}", self.tag, self.occurrence)
+        }
+    }
+}
+
+impl FromStr for FieldSelector {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tag, occurrence) = match s.find('#') {
+            Some(hash) => (&s[.. hash], s[hash + 1 ..].parse().map_err(|_| ())?),
+            None => (s, 0)
+        };
+        Ok(FieldSelector { tag: Tag::from_str(tag)?, occurrence })
+    }
+}
+
+impl Serialize for FieldSelector {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for FieldSelector {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        FieldSelector::from_str(&s).map_err(|()| D::Error::invalid_value(de::Unexpected::Str(&s), &"field selector"))
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FieldOperation {
+    Set { selector: FieldSelector, field: Field },
+    Remove { selector: FieldSelector },
+    Insert { after: Option<FieldSelector>, tag: Tag, field: Field },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ReferenceOperation {
+    Set { ref_num: i32, temporary: bool, fields: Vec<(Tag, Field)> },
+    Remove { ref_num: i32 },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RecordOperation {
+    Add { after: Option<RecordKey>, record: Record },
+    Remove,
+    Modify { flags: Option<RecordFlags>, fields: Vec<FieldOperation>, references: Vec<ReferenceOperation> },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecordPatch {
+    pub selector: RecordKey,
+    pub operation: RecordOperation,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Patch {
+    pub records: Vec<RecordPatch>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PatchError {
+    RecordNotFound(RecordKey),
+    RecordExists(RecordKey),
+    FieldNotFound(RecordKey, FieldSelector),
+    ReferenceNotFound(RecordKey, i32),
+    InvalidReference(RecordKey, i32),
+    NotACell(RecordKey),
+    UnnumberedReference(RecordKey),
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::RecordNotFound(record) => write!(f, "{} not found", record),
+            PatchError::RecordExists(record) => write!(f, "{} already exists", record),
+            PatchError::FieldNotFound(record, field) => write!(f, "{} field not found in {}", field, record),
+            PatchError::ReferenceNotFound(record, ref_num) => write!(f, "reference {} not found in {}", ref_num, record),
+            PatchError::InvalidReference(record, ref_num) => write!(f, "reference {} for {} has no FRMR field", ref_num, record),
+            PatchError::NotACell(record) => write!(f, "{} is not a cell", record),
+            PatchError::UnnumberedReference(record) => write!(f, "reference without FRMR field in {}", record),
+        }
+    }
+}
+
+impl Error for PatchError { }
+
+pub(crate) fn occurrences(fields: &[(Tag, Field)]) -> Vec<usize> {
+    let mut counts = HashMap::new();
+    fields.iter().map(|(tag, _)| {
+        let count = counts.entry(*tag).or_insert(0);
+        *count += 1;
+        *count - 1
+    }).collect()
+}
+
+fn find_field(fields: &[(Tag, Field)], selector: FieldSelector) -> Option<usize> {
+    fields.iter().zip(occurrences(fields)).position(|((tag, _), occurrence)| *tag == selector.tag && occurrence == selector.occurrence)
+}
+
+fn field_selector(fields: &[(Tag, Field)], index: usize) -> FieldSelector {
+    FieldSelector { tag: fields[index].0, occurrence: occurrences(fields)[index] }
+}
+
+fn apply_field_operations(fields: &mut Vec<(Tag, Field)>, operations: &[FieldOperation], record: &RecordKey, errors: &mut Vec<PatchError>) {
+    for operation in operations {
+        let not_found = |selector| PatchError::FieldNotFound(record.clone(), selector);
+        match operation {
+            FieldOperation::Set { selector, field } => match find_field(fields, *selector) {
+                Some(index) => fields[index].1 = field.clone(),
+                None => errors.push(not_found(*selector))
+            },
+            FieldOperation::Remove { selector } => match find_field(fields, *selector) {
+                Some(index) => { fields.remove(index); },
+                None => errors.push(not_found(*selector))
+            },
+            FieldOperation::Insert { after, tag, field } => {
+                let index = match after {
+                    None => Some(0),
+                    Some(after) => find_field(fields, *after).map(|x| x + 1)
+                };
+                match index {
+                    Some(index) => fields.insert(index, (*tag, field.clone())),
+                    None => errors.push(not_found(after.unwrap()))
+                }
+            },
+        }
+    }
+}
+
+fn header_fields(header: &CellHeader) -> Vec<(Tag, Field)> {
+    header.to_fields().into_iter().filter(|(tag, _)| *tag != NAM0).collect()
+}
+
+fn reference_num(reference: &Reference) -> Option<i32> {
+    reference.ref_num.or(reference.moved)
+}
+
+fn apply_reference_operations(cell: &mut CellData, operations: &[ReferenceOperation], record: &RecordKey, errors: &mut Vec<PatchError>) {
+    for operation in operations {
+        match operation {
+            ReferenceOperation::Set { ref_num, temporary, fields } => {
+                let mut reference = match CellData::from_fields(fields).references.pop() {
+                    Some(reference) => reference,
+                    None => {
+                        errors.push(PatchError::InvalidReference(record.clone(), *ref_num));
+                        continue;
+                    }
+                };
+                reference.temporary = *temporary;
+                if let Some(index) = cell.references.iter().position(|x| reference_num(x) == Some(*ref_num)) {
+                    cell.references.remove(index);
+                }
+                let index = if *temporary {
+                    cell.references.len()
+                } else {
+                    cell.references.iter().position(|x| x.temporary).unwrap_or(cell.references.len())
+                };
+                cell.references.insert(index, reference);
+            },
+            ReferenceOperation::Remove { ref_num } => {
+                match cell.references.iter().position(|x| reference_num(x) == Some(*ref_num)) {
+                    Some(index) => { cell.references.remove(index); },
+                    None => errors.push(PatchError::ReferenceNotFound(record.clone(), *ref_num))
+                }
+            },
+        }
+    }
+    if cell.header.temporary_count.is_some() {
+        cell.header.temporary_count = Some(cell.temporary_references().count() as i32);
+    }
+}
+
+fn changes_id(operations: &[FieldOperation]) -> bool {
+    operations.iter().any(|operation| {
+        let tag = match operation {
+            FieldOperation::Set { selector, .. } | FieldOperation::Remove { selector } => selector.tag,
+            FieldOperation::Insert { tag, .. } => *tag,
+        };
+        [NAME, INAM, SCHD, DATA, INTV, INDX].contains(&tag)
+    })
+}
+
+impl Patch {
+    pub fn apply(&self, records: &mut Vec<Record>) -> Vec<PatchError> {
+        let mut errors = Vec::new();
+        let mut lookup: Option<HashMap<RecordLookup, usize>> = None;
+        for patch in &self.records {
+            let index = {
+                let lookup = lookup.get_or_insert_with(|| record_keys(records).into_iter().enumerate()
+                    .map(|(index, (key, _))| (key, index)).collect());
+                let find = |selector: &RecordKey| lookup.get(&(selector.tag, selector.id.to_lowercase(), 0)).copied();
+                match &patch.operation {
+                    RecordOperation::Add { after, record } => {
+                        if find(&patch.selector).is_some() {
+                            errors.push(PatchError::RecordExists(patch.selector.clone()));
+                            continue;
+                        }
+                        let index = match after {
+                            None => 0,
+                            Some(after) => match find(after) {
+                                Some(index) => index + 1,
+                                None => {
+                                    errors.push(PatchError::RecordNotFound(after.clone()));
+                                    continue;
+                                }
+                            }
+                        };
+                        records.insert(index, record.clone());
+                        None
+                    },
+                    _ => match find(&patch.selector) {
+                        Some(index) => Some(index),
+                        None => {
+                            errors.push(PatchError::RecordNotFound(patch.selector.clone()));
+                            continue;
+                        }
+                    }
+                }
+            };
+            let index = if let Some(index) = index { index } else {
+                lookup = None;
+                continue;
+            };
+            match &patch.operation {
+                RecordOperation::Add { .. } => unreachable!(),
+                RecordOperation::Remove => {
+                    records.remove(index);
+                    lookup = None;
+                },
+                RecordOperation::Modify { flags, fields, references } => {
+                    let record = &mut records[index];
+                    if let Some(flags) = flags {
+                        record.flags = *flags;
+                    }
+                    if record.tag == CELL {
+                        let mut cell = CellData::from_fields(&record.fields);
+                        let mut header = header_fields(&cell.header);
+                        apply_field_operations(&mut header, fields, &patch.selector, &mut errors);
+                        let temporary_count = cell.header.temporary_count;
+                        cell.header = CellData::from_fields(&header).header;
+                        cell.header.temporary_count = temporary_count;
+                        apply_reference_operations(&mut cell, references, &patch.selector, &mut errors);
+                        record.fields = cell.to_fields();
+                    } else {
+                        apply_field_operations(&mut record.fields, fields, &patch.selector, &mut errors);
+                        if !references.is_empty() {
+                            errors.push(PatchError::NotACell(patch.selector.clone()));
+                        }
+                    }
+                    if changes_id(fields) {
+                        lookup = None;
+                    }
+                },
+            }
+        }
+        errors
+    }
+
+    pub fn from_diff(old: &[Record], new: &[Record]) -> Result<Patch, PatchError> {
+        let new_keys = record_keys(new);
+        let mut records = Vec::new();
+        for change in diff_records(old, new) {
+            match change {
+                RecordChange::Added { key, new_index } => records.push(RecordPatch {
+                    selector: key,
+                    operation: RecordOperation::Add {
+                        after: if new_index == 0 { None } else { Some(new_keys[new_index - 1].1.clone()) },
+                        record: new[new_index].clone()
+                    }
+                }),
+                RecordChange::Removed { key, .. } => records.push(RecordPatch { selector: key, operation: RecordOperation::Remove }),
+                RecordChange::Modified { key, new_index, flags, fields, references, .. } => {
+                    let new_record = &new[new_index];
+                    let new_cell = if new_record.tag == CELL { Some(CellData::from_fields(&new_record.fields)) } else { None };
+                    let new_fields = new_cell.as_ref().map_or_else(|| new_record.fields.clone(), |x| header_fields(&x.header));
+                    let fields = fields.into_iter().filter(|change| new_cell.is_none() || !matches!(change,
+                        FieldChange::Added { tag: NAM0, .. } | FieldChange::Removed { tag: NAM0, .. } |
+                        FieldChange::Modified { tag: NAM0, .. }
+                    )).collect::<Vec<_>>();
+                    let numbered = |ref_num: Option<i32>| ref_num.ok_or_else(|| PatchError::UnnumberedReference(key.clone()));
+                    let set = |ref_num: i32, new_ref_num: i32| {
+                        let reference = new_cell.as_ref().and_then(|x| x.references.iter().find(|x| reference_num(x) == Some(new_ref_num)))
+                            .ok_or_else(|| PatchError::ReferenceNotFound(key.clone(), new_ref_num))?;
+                        Ok(ReferenceOperation::Set { ref_num, temporary: reference.temporary, fields: reference.to_fields() })
+                    };
+                    let references = references.into_iter().map(|change| match change {
+                        ReferenceChange::Added { ref_num } => {
+                            let ref_num = numbered(ref_num)?;
+                            set(ref_num, ref_num)
+                        },
+                        ReferenceChange::Modified { ref_num, new_ref_num, .. } => set(numbered(ref_num)?, numbered(new_ref_num)?),
+                        ReferenceChange::Removed { ref_num } => Ok(ReferenceOperation::Remove { ref_num: numbered(ref_num)? }),
+                    }).collect::<Result<Vec<_>, _>>()?;
+                    records.push(RecordPatch {
+                        selector: key,
+                        operation: RecordOperation::Modify {
+                            flags: flags.map(|(_, new)| new),
+                            fields: field_operations(&new_fields, &fields),
+                            references
+                        }
+                    });
+                },
+            }
+        }
+        Ok(Patch { records })
+    }
+}
+
+fn field_operations(new_fields: &[(Tag, Field)], changes: &[FieldChange]) -> Vec<FieldOperation> {
+    let mut sets = Vec::new();
+    let mut removes = Vec::new();
+    let mut inserts = Vec::new();
+    for change in changes {
+        match change {
+            FieldChange::Modified { tag, occurrence, .. } => {
+                let selector = FieldSelector { tag: *tag, occurrence: *occurrence };
+                let field = new_fields[find_field(new_fields, selector).unwrap()].1.clone();
+                sets.push(FieldOperation::Set { selector, field });
+            },
+            FieldChange::Removed { tag, occurrence, .. } =>
+                removes.push(FieldSelector { tag: *tag, occurrence: *occurrence }),
+            FieldChange::Added { tag, occurrence, field } => {
+                let index = find_field(new_fields, FieldSelector { tag: *tag, occurrence: *occurrence }).unwrap();
+                let after = if index == 0 { None } else { Some(field_selector(new_fields, index - 1)) };
+                inserts.push(FieldOperation::Insert { after, tag: *tag, field: field.clone() });
+            },
+        }
+    }
+    removes.sort_by_key(|x| std::cmp::Reverse(x.occurrence));
+    sets.extend(removes.into_iter().map(|selector| FieldOperation::Remove { selector }));
+    sets.extend(inserts);
+    sets
+}
+
+struct RecordKeySerializer<'a>(&'a RecordKey);
+
+impl<'a> Serialize for RecordKeySerializer<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut serializer = serializer.serialize_map(Some(1))?;
+        serializer.serialize_entry(&self.0.tag, &self.0.id)?;
+        serializer.end()
+    }
+}
+
+struct RecordKeyDeserializer;
+
+impl<'de> de::Visitor<'de> for RecordKeyDeserializer {
+    type Value = RecordKey;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "record selector") }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: de::MapAccess<'de> {
+        let (tag, id) = map.next_entry()?.ok_or_else(|| A::Error::custom("missed record tag"))?;
+        if map.next_key::<Tag>()?.is_some() {
+            return Err(A::Error::custom("duplicated record tag"));
+        }
+        Ok(RecordKey { tag, id })
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for RecordKeyDeserializer {
+    type Value = RecordKey;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+struct FieldOperationSerializer<'a>(Tag, &'a FieldOperation);
+
+struct SetSerializer<'a>(Tag, FieldSelector, &'a Field);
+
+impl<'a> Serialize for SetSerializer<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut serializer = serializer.serialize_map(Some(1))?;
+        serializer.serialize_entry(&self.1, &FieldBodySerializer { record_tag: self.0, field_tag: self.1.tag, field: self.2 })?;
+        serializer.end()
+    }
+}
+
+impl<'a> Serialize for FieldOperationSerializer<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match self.1 {
+            FieldOperation::Set { selector, field } => {
+                let mut serializer = serializer.serialize_map(Some(1))?;
+                serializer.serialize_entry("set", &SetSerializer(self.0, *selector, field))?;
+                serializer.end()
+            },
+            FieldOperation::Remove { selector } => {
+                let mut serializer = serializer.serialize_map(Some(1))?;
+                serializer.serialize_entry("remove", selector)?;
+                serializer.end()
+            },
+            FieldOperation::Insert { after, tag, field } => {
+                let mut serializer = serializer.serialize_map(Some(if after.is_some() { 2 } else { 1 }))?;
+                serializer.serialize_entry("insert", &FieldSerializer(self.0, Right((*tag, field))))?;
+                if let Some(after) = after {
+                    serializer.serialize_entry("after", after)?;
+                }
+                serializer.end()
+            },
+        }
+    }
+}
+
+struct SetDeserializer(Tag);
+
+impl<'de> de::Visitor<'de> for SetDeserializer {
+    type Value = (FieldSelector, Field);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "field") }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: de::MapAccess<'de> {
+        let selector: FieldSelector = map.next_key()?.ok_or_else(|| A::Error::custom("missed field selector"))?;
+        let body = map.next_value_seed(FieldBodyDeserializer { record_tag: self.0, field_tag: selector.tag })?;
+        if map.next_key::<FieldSelector>()?.is_some() {
+            return Err(A::Error::custom("duplicated field selector"));
+        }
+        match body {
+            Left(_) => Err(A::Error::custom("record flags cannot be set as a field")),
+            Right((_, field)) => Ok((selector, field)),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for SetDeserializer {
+    type Value = (FieldSelector, Field);
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+struct FieldOperationDeserializer(Tag);
+
+impl<'de> de::Visitor<'de> for FieldOperationDeserializer {
+    type Value = FieldOperation;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "field operation") }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: de::MapAccess<'de> {
+        let mut operation = None;
+        let mut after = None;
+        while let Some(key) = map.next_key::<String>()? {
+            let value = match key.as_str() {
+                "set" => {
+                    let (selector, field) = map.next_value_seed(SetDeserializer(self.0))?;
+                    FieldOperation::Set { selector, field }
+                },
+                "remove" => FieldOperation::Remove { selector: map.next_value()? },
+                "insert" => match map.next_value_seed(FieldDeserializer { record_tag: self.0 })? {
+                    Left(_) => return Err(A::Error::custom("record flags cannot be inserted as a field")),
+                    Right((tag, field)) => FieldOperation::Insert { after: None, tag, field },
+                },
+                "after" => {
+                    after = Some(map.next_value()?);
+                    continue;
+                },
+                key => return Err(A::Error::unknown_field(key, &["set", "remove", "insert", "after"])),
+            };
+            if operation.replace(value).is_some() {
+                return Err(A::Error::custom("multiple operations in one entry"));
+            }
+        }
+        match operation {
+            Some(FieldOperation::Insert { tag, field, .. }) => Ok(FieldOperation::Insert { after, tag, field }),
+            Some(_) if after.is_some() => Err(A::Error::custom("'after' is allowed for 'insert' only")),
+            Some(operation) => Ok(operation),
+            None => Err(A::Error::custom("missed field operation")),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for FieldOperationDeserializer {
+    type Value = FieldOperation;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+struct FieldOperationsSerializer<'a>(Tag, &'a [FieldOperation]);
+
+impl<'a> Serialize for FieldOperationsSerializer<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut serializer = serializer.serialize_seq(Some(self.1.len()))?;
+        for operation in self.1 {
+            serializer.serialize_element(&FieldOperationSerializer(self.0, operation))?;
+        }
+        serializer.end()
+    }
+}
+
+struct FieldOperationsDeserializer(Tag);
+
+impl<'de> de::Visitor<'de> for FieldOperationsDeserializer {
+    type Value = Vec<FieldOperation>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "field operation list") }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: de::SeqAccess<'de> {
+        let mut operations = Vec::new();
+        while let Some(operation) = seq.next_element_seed(FieldOperationDeserializer(self.0))? {
+            operations.push(operation);
+        }
+        Ok(operations)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for FieldOperationsDeserializer {
+    type Value = Vec<FieldOperation>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+struct ReferenceFieldsSerializer<'a>(&'a [(Tag, Field)]);
+
+impl<'a> Serialize for ReferenceFieldsSerializer<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut serializer = serializer.serialize_seq(Some(self.0.len()))?;
+        for (tag, field) in self.0 {
+            serializer.serialize_element(&FieldSerializer(CELL, Right((*tag, field))))?;
+        }
+        serializer.end()
+    }
+}
+
+struct ReferenceFieldsDeserializer;
+
+impl<'de> de::Visitor<'de> for ReferenceFieldsDeserializer {
+    type Value = Vec<(Tag, Field)>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "reference field list") }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: de::SeqAccess<'de> {
+        let mut fields = Vec::new();
+        while let Some(field) = seq.next_element_seed(FieldDeserializer { record_tag: CELL })? {
+            match field {
+                Left(_) => return Err(A::Error::custom("record flags are not allowed in a reference")),
+                Right(field) => fields.push(field),
+            }
+        }
+        Ok(fields)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ReferenceFieldsDeserializer {
+    type Value = Vec<(Tag, Field)>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl Serialize for ReferenceOperation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match self {
+            ReferenceOperation::Set { ref_num, temporary, fields } => {
+                let mut serializer = serializer.serialize_map(Some(3))?;
+                serializer.serialize_entry("set", ref_num)?;
+                serializer.serialize_entry("temporary", temporary)?;
+                serializer.serialize_entry("fields", &ReferenceFieldsSerializer(fields))?;
+                serializer.end()
+            },
+            ReferenceOperation::Remove { ref_num } => {
+                let mut serializer = serializer.serialize_map(Some(1))?;
+                serializer.serialize_entry("remove", ref_num)?;
+                serializer.end()
+            },
+        }
+    }
+}
+
+struct ReferenceOperationDeserializer;
+
+impl<'de> de::Visitor<'de> for ReferenceOperationDeserializer {
+    type Value = ReferenceOperation;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "reference operation") }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: de::MapAccess<'de> {
+        let mut set = None;
+        let mut remove = None;
+        let mut temporary = None;
+        let mut fields = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "set" => set = Some(map.next_value()?),
+                "remove" => remove = Some(map.next_value()?),
+                "temporary" => temporary = Some(map.next_value()?),
+                "fields" => fields = Some(map.next_value_seed(ReferenceFieldsDeserializer)?),
+                key => return Err(A::Error::unknown_field(key, &["set", "remove", "temporary", "fields"])),
+            }
+        }
+        match (set, remove, fields) {
+            (Some(ref_num), None, Some(fields)) =>
+                Ok(ReferenceOperation::Set { ref_num, temporary: temporary.unwrap_or(true), fields }),
+            (None, Some(ref_num), None) if temporary.is_none() => Ok(ReferenceOperation::Remove { ref_num }),
+            _ => Err(A::Error::custom("expected 'set' with 'fields' or 'remove'")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ReferenceOperation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_map(ReferenceOperationDeserializer)
+    }
+}
+
+impl Serialize for RecordPatch {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut serializer = serializer.serialize_map(None)?;
+        serializer.serialize_entry(&self.selector.tag, &self.selector.id)?;
+        match &self.operation {
+            RecordOperation::Add { after, record } => {
+                serializer.serialize_entry("add", record)?;
+                if let Some(after) = after {
+                    serializer.serialize_entry("after", &RecordKeySerializer(after))?;
+                }
+            },
+            RecordOperation::Remove => serializer.serialize_entry("remove", &true)?,
+            RecordOperation::Modify { flags, fields, references } => {
+                if let Some(flags) = flags {
+                    serializer.serialize_entry("flags", flags)?;
+                }
+                if !fields.is_empty() {
+                    serializer.serialize_entry("fields", &FieldOperationsSerializer(self.selector.tag, fields))?;
+                }
+                if !references.is_empty() {
+                    serializer.serialize_entry("references", references)?;
+                }
+            },
+        }
+        serializer.end()
+    }
+}
+
+struct RecordPatchDeserializer;
+
+impl<'de> de::Visitor<'de> for RecordPatchDeserializer {
+    type Value = RecordPatch;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "record patch") }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: de::MapAccess<'de> {
+        let (tag, id): (Tag, String) = map.next_entry()?.ok_or_else(|| A::Error::custom("missed record tag"))?;
+        let selector = RecordKey { tag, id };
+        let mut add = None;
+        let mut after = None;
+        let mut remove = false;
+        let mut flags = None;
+        let mut fields = Vec::new();
+        let mut references = Vec::new();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "add" => add = Some(map.next_value::<Record>()?),
+                "after" => after = Some(map.next_value_seed(RecordKeyDeserializer)?),
+                "remove" => remove = map.next_value()?,
+                "flags" => flags = Some(map.next_value()?),
+                "fields" => fields = map.next_value_seed(FieldOperationsDeserializer(tag))?,
+                "references" => references = map.next_value()?,
+                key => return Err(A::Error::unknown_field(key, &["add", "after", "remove", "flags", "fields", "references"])),
+            }
+        }
+        let is_modify = flags.is_some() || !fields.is_empty() || !references.is_empty();
+        if after.is_some() && add.is_none() {
+            return Err(A::Error::custom("'after' is allowed for 'add' only"));
+        }
+        let operation = match (add, remove, is_modify) {
+            (Some(record), false, false) => {
+                if record.tag != tag {
+                    return Err(A::Error::custom("added record tag does not match the selector"));
+                }
+                RecordOperation::Add { after, record }
+            },
+            (None, true, false) => RecordOperation::Remove,
+            (None, false, _) => RecordOperation::Modify { flags, fields, references },
+            _ => return Err(A::Error::custom("'add', 'remove' and modifications are mutually exclusive")),
+        };
+        Ok(RecordPatch { selector, operation })
+    }
+}
+
+impl<'de> Deserialize<'de> for RecordPatch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_map(RecordPatchDeserializer)
+    }
+}
+
+impl Serialize for Patch {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.records.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Patch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        <Vec<RecordPatch>>::deserialize(deserializer).map(|records| Patch { records })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tag: Tag, fields: Vec<(Tag, Field)>) -> Record {
+        Record { tag, flags: RecordFlags::empty(), fields }
+    }
+
+    fn misc(id: &str, value: u32) -> Record {
+        record(MISC, vec![
+            (NAME, Field::StringZ(id.into())),
+            (MCDT, Field::MiscItem(MiscItem { weight: 0.5, value, is_key: false })),
+        ])
+    }
+
+    fn cell(refs: &[(i32, &str)]) -> Record {
+        let mut fields = vec![
+            (NAME, Field::StringZ("Vivec, Arena".into())),
+            (DATA, Field::Cell(Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } })),
+        ];
+        for &(ref_num, object) in refs {
+            fields.push((FRMR, Field::I32(ref_num)));
+            fields.push((NAME, Field::StringZ(object.into())));
+        }
+        record(CELL, fields)
+    }
+
+    fn master() -> Vec<Record> {
+        vec![
+            misc("gold_001", 1),
+            misc("Misc_Quill", 1),
+            cell(&[(1, "chest"), (2, "barrel")]),
+            record(DIAL, vec![(NAME, Field::StringZ("rumors".into()))]),
+            record(INFO, vec![(INAM, Field::StringZ("100".into())), (NAME, Field::String("Hello.".into()))]),
+        ]
+    }
+
+    fn plugin() -> Vec<Record> {
+        let mut records = master();
+        records[1].fields[1].1 = Field::MiscItem(MiscItem { weight: 0.5, value: 5, is_key: false });
+        records[1].fields.push((SCRI, Field::StringZ("quill_script".into())));
+        records[2] = cell(&[(2, "barrel_02"), (3, "crate")]);
+        records[4].fields[1].1 = Field::String("Greetings.".into());
+        records.remove(0);
+        records.insert(1, misc("misc_ink", 2));
+        records
+    }
+
+    #[test]
+    fn patch_from_diff_round_trip() {
+        let patch = Patch::from_diff(&master(), &plugin()).unwrap();
+        let mut records = master();
+        assert_eq!(patch.apply(&mut records), Vec::new());
+        assert_eq!(records, plugin());
+    }
+
+    #[test]
+    fn patch_survives_master_update() {
+        let patch = Patch::from_diff(&master(), &plugin()).unwrap();
+        let mut updated = master();
+        updated.push(misc("misc_new", 7));
+        updated[3].fields.push((SCRI, Field::StringZ("new_script".into())));
+        assert_eq!(patch.apply(&mut updated), Vec::new());
+        let mut expected = plugin();
+        expected.push(misc("misc_new", 7));
+        expected[3].fields.push((SCRI, Field::StringZ("new_script".into())));
+        assert_eq!(updated, expected);
+    }
+
+    #[test]
+    fn patch_keeps_reference_persistence() {
+        let cell = |refs: &[(i32, &str)], temporary_count: i32| {
+            let mut record = cell(refs);
+            let position = record.fields.iter().position(|(_, x)| x == &Field::StringZ("barrel".into())).unwrap() - 1;
+            record.fields.insert(position, (NAM0, Field::I32(temporary_count)));
+            record
+        };
+        let master = vec![cell(&[(1, "chest"), (2, "barrel")], 1)];
+        let plugin = vec![cell(&[(1, "chest"), (4, "lamp"), (2, "barrel"), (3, "crate")], 2)];
+        let patch = Patch::from_diff(&master, &plugin).unwrap();
+        let mut records = master.clone();
+        assert_eq!(patch.apply(&mut records), Vec::new());
+        assert_eq!(records, plugin);
+        let yaml = serde_yaml::to_string(&patch).unwrap();
+        assert!(yaml.contains("    - set: 4\n      temporary: false\n"));
+        assert_eq!(serde_yaml::from_str::<Patch>(&yaml).unwrap(), patch);
+    }
+
+    #[test]
+    fn patch_from_diff_references() {
+        let header = |masters: &[&str]| record(TES3, masters.iter().map(|&x| (MAST, Field::StringZ(x.into()))).collect());
+        let master = vec![header(&["A.esm", "B.esm"]), cell(&[(0x0200_0001, "chest")])];
+        let plugin = vec![header(&["B.esm", "A.esm"]), cell(&[(0x0100_0001, "chest_02")])];
+        let patch = Patch::from_diff(&master, &plugin).unwrap();
+        let mut records = master.clone();
+        assert_eq!(patch.apply(&mut records), Vec::new());
+        assert_eq!(records, plugin);
+    }
+
+    #[test]
+    fn patch_yaml() {
+        let patch = Patch::from_diff(&master(), &plugin()).unwrap();
+        let yaml = serde_yaml::to_string(&patch).unwrap();
+        assert!(yaml.contains("- MISC: Misc_Quill\n  fields:\n    - set:\n        MCDT:\n"));
+        assert!(yaml.contains("    - insert:\n        SCRI: quill_script\n      after: MCDT\n"));
+        assert!(yaml.contains("- MISC: gold_001\n  remove: true\n"));
+        let parsed: Patch = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(parsed, patch);
+        let yaml = "- MISC: misc_quill\n  fields:\n    - remove: SCRI\n- BOOK: bk_missing\n  remove: true\n";
+        let patch: Patch = serde_yaml::from_str(yaml).unwrap();
+        let mut records = master();
+        assert_eq!(patch.apply(&mut records), vec![
+            PatchError::FieldNotFound(RecordKey { tag: MISC, id: "misc_quill".into() }, FieldSelector { tag: SCRI, occurrence: 0 }),
+            PatchError::RecordNotFound(RecordKey { tag: BOOK, id: "bk_missing".into() }),
+        ]);
+        assert_eq!(records, master());
+        assert!(serde_yaml::from_str::<Patch>("- MISC: x\n  remove: true\n  after: {MISC: y}\n").is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use flate2::write::{ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+
+use crate::field::*;
+use crate::record::*;
+
+const POINT_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum PathGridError {
+    InvalidCompressedData(io::Error),
+    UnexpectedFieldType(Tag),
+    MissingPathGrid,
+    UnexpectedPointsSize(usize),
+    UnexpectedConnectionsSize(usize),
+    InvalidAutoGenerated { point: usize, value: u8 },
+    PointCountMismatch { expected: u16, actual: usize },
+    ConnectionCountMismatch { expected: usize, actual: usize },
+    InvalidConnection { point: usize, target: u32 },
+    TooManyPoints(usize),
+    TooManyConnections(usize),
+}
+
+impl Display for PathGridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathGridError::InvalidCompressedData(e) => write!(f, "invalid compressed data ({})", e),
+            PathGridError::UnexpectedFieldType(tag) => write!(f, "{} field should have byte list type", tag),
+            PathGridError::MissingPathGrid => write!(f, "{} field is missing", DATA),
+            PathGridError::UnexpectedPointsSize(size) =>
+                write!(f, "unexpected path grid points size {}, expected multiple of {}", size, POINT_SIZE),
+            PathGridError::UnexpectedConnectionsSize(size) =>
+                write!(f, "unexpected path grid connections size {}, expected multiple of 4", size),
+            PathGridError::InvalidAutoGenerated { point, value } =>
+                write!(f, "invalid auto generated flag {} at point {}", value, point),
+            PathGridError::PointCountMismatch { expected, actual } =>
+                write!(f, "path grid declares {} points, but {} found", expected, actual),
+            PathGridError::ConnectionCountMismatch { expected, actual } =>
+                write!(f, "path grid points declare {} connections, but {} found", expected, actual),
+            PathGridError::InvalidConnection { point, target } =>
+                write!(f, "point {} is connected to non-existent point {}", point, target),
+            PathGridError::TooManyPoints(count) => write!(f, "{} path grid points do not fit into u16", count),
+            PathGridError::TooManyConnections(point) =>
+                write!(f, "point {} has more connections than fit into byte", point),
+        }
+    }
+}
+
+impl Error for PathGridError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PathGridError::InvalidCompressedData(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PathGridPoint {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    pub auto_generated: bool,
+    pub connection_count: u8,
+    pub padding: u16,
+}
+
+fn unzip(field: &Field, tag: Tag) -> Result<Vec<u8>, PathGridError> {
+    if let Field::U8List(compressed) = field {
+        (|| {
+            let mut decoder = ZlibDecoder::new(Vec::new());
+            decoder.write_all(&compressed[..])?;
+            decoder.finish()
+        })().map_err(PathGridError::InvalidCompressedData)
+    } else {
+        Err(PathGridError::UnexpectedFieldType(tag))
+    }
+}
+
+fn zip(bytes: &[u8]) -> Field {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(5));
+    encoder.write_all(bytes).unwrap();
+    Field::U8List(encoder.finish().unwrap())
+}
+
+fn set_field(record: &mut Record, tag: Tag, value: Field, insert: bool) {
+    if let Some((_, field)) = record.fields.iter_mut().find(|(t, _)| *t == tag) {
+        *field = value;
+    } else if insert {
+        record.fields.push((tag, value));
+    }
+}
+
+pub fn decode_points(bytes: &[u8]) -> Result<Vec<PathGridPoint>, PathGridError> {
+    if bytes.len() % POINT_SIZE != 0 {
+        return Err(PathGridError::UnexpectedPointsSize(bytes.len()));
+    }
+    bytes.chunks(POINT_SIZE).enumerate().map(|(point, bytes)| {
+        let auto_generated = match bytes[12] {
+            0 => false,
+            1 => true,
+            value => return Err(PathGridError::InvalidAutoGenerated { point, value })
+        };
+        Ok(PathGridPoint {
+            x: i32::from_le_bytes(bytes[0 .. 4].try_into().unwrap()),
+            y: i32::from_le_bytes(bytes[4 .. 8].try_into().unwrap()),
+            z: i32::from_le_bytes(bytes[8 .. 12].try_into().unwrap()),
+            auto_generated,
+            connection_count: bytes[13],
+            padding: u16::from_le_bytes(bytes[14 .. 16].try_into().unwrap()),
+        })
+    }).collect()
+}
+
+pub fn encode_points(points: &[PathGridPoint]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(points.len() * POINT_SIZE);
+    for point in points {
+        bytes.extend_from_slice(&point.x.to_le_bytes());
+        bytes.extend_from_slice(&point.y.to_le_bytes());
+        bytes.extend_from_slice(&point.z.to_le_bytes());
+        bytes.push(if point.auto_generated { 1 } else { 0 });
+        bytes.push(point.connection_count);
+        bytes.extend_from_slice(&point.padding.to_le_bytes());
+    }
+    bytes
+}
+
+pub fn decode_connections(bytes: &[u8], points: &[PathGridPoint]) -> Result<Vec<(usize, usize)>, PathGridError> {
+    if bytes.len() % 4 != 0 {
+        return Err(PathGridError::UnexpectedConnectionsSize(bytes.len()));
+    }
+    let targets = bytes.chunks(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect::<Vec<_>>();
+    let expected = points.iter().map(|x| x.connection_count as usize).sum();
+    if targets.len() != expected {
+        return Err(PathGridError::ConnectionCountMismatch { expected, actual: targets.len() });
+    }
+    let mut targets = targets.into_iter();
+    let mut edges = Vec::with_capacity(expected);
+    for (point, p) in points.iter().enumerate() {
+        for target in targets.by_ref().take(p.connection_count as usize) {
+            if target as usize >= points.len() {
+                return Err(PathGridError::InvalidConnection { point, target });
+            }
+            edges.push((point, target as usize));
+        }
+    }
+    Ok(edges)
+}
+
+pub fn encode_connections(edges: &[(usize, usize)]) -> Vec<u8> {
+    let mut edges = edges.to_vec();
+    edges.sort_by_key(|&(from, _)| from);
+    edges.into_iter().flat_map(|(_, to)| (to as u32).to_le_bytes()).collect()
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PathGridGraph {
+    pub points: Vec<PathGridPoint>,
+    pub edges: Vec<(usize, usize)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Distance(f64);
+
+impl Eq for Distance { }
+
+impl PartialOrd for Distance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Distance {
+    fn cmp(&self, other: &Self) -> Ordering { self.0.total_cmp(&other.0) }
+}
+
+impl PathGridGraph {
+    pub fn from_record(record: &Record) -> Result<PathGridGraph, PathGridError> {
+        let path_grid = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+            (DATA, Field::PathGrid(path_grid)) => Some(path_grid),
+            _ => None
+        }).ok_or(PathGridError::MissingPathGrid)?;
+        let points = if let Some((_, field)) = record.fields.iter().find(|(tag, _)| *tag == PGRP) {
+            decode_points(&unzip(field, PGRP)?)?
+        } else {
+            Vec::new()
+        };
+        if points.len() != path_grid.points as usize {
+            return Err(PathGridError::PointCountMismatch { expected: path_grid.points, actual: points.len() });
+        }
+        let connections = if let Some((_, field)) = record.fields.iter().find(|(tag, _)| *tag == PGRC) {
+            unzip(field, PGRC)?
+        } else {
+            Vec::new()
+        };
+        let edges = decode_connections(&connections, &points)?;
+        Ok(PathGridGraph { points, edges })
+    }
+
+    pub fn to_record(&self, record: &mut Record) -> Result<(), PathGridError> {
+        let count: u16 = self.points.len().try_into().map_err(|_| PathGridError::TooManyPoints(self.points.len()))?;
+        let mut points = self.points.clone();
+        for point in &mut points {
+            point.connection_count = 0;
+        }
+        for &(from, to) in &self.edges {
+            if from >= points.len() || to >= points.len() {
+                return Err(PathGridError::InvalidConnection { point: from, target: to as u32 });
+            }
+            let point = &mut points[from];
+            point.connection_count = point.connection_count.checked_add(1)
+                .ok_or(PathGridError::TooManyConnections(from))?;
+        }
+        let mut has_path_grid = false;
+        for (tag, field) in &mut record.fields {
+            if let (DATA, Field::PathGrid(path_grid)) = (*tag, field) {
+                path_grid.points = count;
+                has_path_grid = true;
+            }
+        }
+        if !has_path_grid {
+            return Err(PathGridError::MissingPathGrid);
+        }
+        let points = zip(&encode_points(&points));
+        let connections = zip(&encode_connections(&self.edges));
+        set_field(record, PGRP, points, !self.points.is_empty());
+        set_field(record, PGRC, connections, !self.points.is_empty());
+        Ok(())
+    }
+
+    pub fn neighbors(&self, point: usize) -> impl Iterator<Item=usize> + '_ {
+        self.edges.iter().filter(move |&&(from, _)| from == point).map(|&(_, to)| to)
+    }
+
+    fn adjacency(&self) -> Vec<Vec<usize>> {
+        let mut adjacency = vec![Vec::new(); self.points.len()];
+        for &(from, to) in &self.edges {
+            adjacency[from].push(to);
+        }
+        adjacency
+    }
+
+    pub fn distance(&self, from: usize, to: usize) -> f64 {
+        let (a, b) = (&self.points[from], &self.points[to]);
+        let d = |a: i32, b: i32| (a as f64 - b as f64).powi(2);
+        (d(a.x, b.x) + d(a.y, b.y) + d(a.z, b.z)).sqrt()
+    }
+
+    pub fn shortest_path(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        if from >= self.points.len() || to >= self.points.len() { return None; }
+        let adjacency = self.adjacency();
+        let mut distances = vec![f64::INFINITY; self.points.len()];
+        let mut previous = vec![None; self.points.len()];
+        let mut queue = BinaryHeap::new();
+        distances[from] = 0.0;
+        queue.push(Reverse((Distance(0.0), from)));
+        while let Some(Reverse((Distance(distance), point))) = queue.pop() {
+            if point == to { break; }
+            if distance > distances[point] { continue; }
+            for &next in &adjacency[point] {
+                let next_distance = distance + self.distance(point, next);
+                if next_distance < distances[next] {
+                    distances[next] = next_distance;
+                    previous[next] = Some(point);
+                    queue.push(Reverse((Distance(next_distance), next)));
+                }
+            }
+        }
+        if distances[to].is_infinite() { return None; }
+        let mut path = vec![to];
+        while let Some(point) = previous[*path.last().unwrap()] {
+            path.push(point);
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    pub fn connected_components(&self) -> Vec<Vec<usize>> {
+        let mut adjacency = self.adjacency();
+        for &(from, to) in &self.edges {
+            adjacency[to].push(from);
+        }
+        let mut visited = vec![false; self.points.len()];
+        let mut components = Vec::new();
+        for start in 0 .. self.points.len() {
+            if visited[start] { continue; }
+            visited[start] = true;
+            let mut component = Vec::new();
+            let mut stack = vec![start];
+            while let Some(point) = stack.pop() {
+                component.push(point);
+                for &next in &adjacency[point] {
+                    if !visited[next] {
+                        visited[next] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+            component.sort_unstable();
+            components.push(component);
+        }
+        components
+    }
+}
+
+pub fn check_path_grids<'a>(records: impl IntoIterator<Item=&'a Record>) -> Vec<(usize, PathGridError)> {
+    records.into_iter().enumerate()
+        .filter(|(_, record)| record.tag == PGRD && !record.flags.contains(RecordFlags::DELETED))
+        .filter_map(|(index, record)| PathGridGraph::from_record(record).err().map(|e| (index, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: i32, y: i32) -> PathGridPoint {
+        PathGridPoint { x, y, z: 0, auto_generated: false, connection_count: 0, padding: 0 }
+    }
+
+    fn path_grid_record(points: u16) -> Record {
+        Record {
+            tag: PGRD,
+            flags: RecordFlags::empty(),
+            fields: vec![
+                (DATA, Field::PathGrid(PathGrid { grid: Grid { x: 0, y: 0 }, flags: 0, points })),
+                (NAME, Field::StringZ("".into())),
+            ]
+        }
+    }
+
+    fn graph() -> PathGridGraph {
+        PathGridGraph {
+            points: vec![point(0, 0), point(100, 0), point(100, 100), point(0, 100), point(500, 500)],
+            edges: vec![(0, 1), (1, 0), (1, 2), (2, 1), (2, 3), (3, 2), (0, 3), (3, 0)],
+        }
+    }
+
+    #[test]
+    fn path_grid_round_trip() {
+        let graph = graph();
+        let mut record = path_grid_record(0);
+        graph.to_record(&mut record).unwrap();
+        let decoded = PathGridGraph::from_record(&record).unwrap();
+        assert_eq!(decoded.points.iter().map(|x| x.connection_count).collect::<Vec<_>>(), vec![2, 2, 2, 2, 0]);
+        let mut edges = graph.edges.clone();
+        edges.sort_by_key(|&(from, _)| from);
+        assert_eq!(decoded.edges, edges);
+        assert!(check_path_grids(&[record.clone()]).is_empty());
+        let mut invalid = graph.clone();
+        invalid.edges.push((7, 0));
+        assert!(matches!(invalid.to_record(&mut record), Err(PathGridError::InvalidConnection { point: 7, target: 0 })));
+    }
+
+    #[test]
+    fn point_count_mismatch() {
+        let mut record = path_grid_record(0);
+        graph().to_record(&mut record).unwrap();
+        if let Field::PathGrid(path_grid) = &mut record.fields[0].1 {
+            path_grid.points = 4;
+        }
+        let errors = check_path_grids(&[record]);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].1, PathGridError::PointCountMismatch { expected: 4, actual: 5 }));
+    }
+
+    #[test]
+    fn shortest_path_and_components() {
+        let graph = graph();
+        assert_eq!(graph.neighbors(1).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(graph.shortest_path(0, 2).map(|x| x.len()), Some(3));
+        assert_eq!(graph.shortest_path(0, 0), Some(vec![0]));
+        assert_eq!(graph.shortest_path(0, 4), None);
+        assert_eq!(graph.connected_components(), vec![vec![0, 1, 2, 3], vec![4]]);
+    }
+}
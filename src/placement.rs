@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use crate::cell::*;
+use crate::field::*;
+use crate::record::*;
+
+pub const CELL_SIZE: f32 = 8192.0;
+
+pub fn position_grid(position: &Position) -> Grid {
+    Grid {
+        x: (position.x / CELL_SIZE).floor() as i32,
+        y: (position.y / CELL_SIZE).floor() as i32
+    }
+}
+
+fn exterior_cell(record: &Record) -> Option<CellData> {
+    if record.tag != CELL || record.flags.contains(RecordFlags::DELETED) { return None; }
+    let cell = CellData::from_fields(&record.fields);
+    cell.grid()?;
+    Some(cell)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MisplacedReference {
+    pub record_index: usize,
+    pub reference_index: usize,
+    pub grid: Grid,
+    pub actual_grid: Grid,
+}
+
+pub fn find_misplaced_references<'a>(records: impl IntoIterator<Item=&'a Record>) -> Vec<MisplacedReference> {
+    let mut misplaced = Vec::new();
+    for (record_index, record) in records.into_iter().enumerate() {
+        let cell = if let Some(cell) = exterior_cell(record) { cell } else { continue; };
+        let cell_grid = cell.grid().unwrap();
+        for (reference_index, reference) in cell.references.iter().enumerate() {
+            if reference.is_deleted() { continue; }
+            let position = if let Some(position) = &reference.position { position } else { continue; };
+            let grid = reference.moved_to.unwrap_or(cell_grid);
+            let actual_grid = position_grid(position);
+            if actual_grid != grid {
+                misplaced.push(MisplacedReference { record_index, reference_index, grid, actual_grid });
+            }
+        }
+    }
+    misplaced
+}
+
+fn new_exterior_cell(grid: Grid) -> Record {
+    Record {
+        tag: CELL,
+        flags: RecordFlags::empty(),
+        fields: vec![
+            (NAME, Field::StringZ("".into())),
+            (DATA, Field::Cell(Cell { flags: CellFlags::empty(), grid })),
+        ]
+    }
+}
+
+fn move_in_plugin(records: &mut Vec<Record>) -> Vec<MisplacedReference> {
+    let misplaced = find_misplaced_references(records.iter());
+    if misplaced.is_empty() { return misplaced; }
+    let mut cells: HashMap<usize, CellData> = HashMap::new();
+    let mut by_grid = HashMap::new();
+    for (index, record) in records.iter().enumerate() {
+        if let Some(cell) = exterior_cell(record) {
+            by_grid.insert(cell.grid().unwrap(), index);
+        }
+    }
+    let mut moved = Vec::new();
+    for m in misplaced.iter().rev() {
+        let source = cells.entry(m.record_index).or_insert_with(|| CellData::from_fields(&records[m.record_index].fields));
+        let reference = &mut source.references[m.reference_index];
+        if reference.is_moved() || reference.master_index() != Some(0) {
+            reference.moved = reference.moved.or(reference.ref_num);
+            reference.moved_to = Some(m.actual_grid);
+            continue;
+        }
+        let reference = source.references.remove(m.reference_index);
+        if reference.temporary {
+            if let Some(count) = source.header.temporary_count.as_mut() {
+                *count -= 1;
+            }
+        }
+        moved.push((m.actual_grid, reference));
+    }
+    for (grid, reference) in moved.into_iter().rev() {
+        let target_index = *by_grid.entry(grid).or_insert_with(|| {
+            records.push(new_exterior_cell(grid));
+            records.len() - 1
+        });
+        let target = cells.entry(target_index).or_insert_with(|| CellData::from_fields(&records[target_index].fields));
+        if reference.temporary {
+            *target.header.temporary_count.get_or_insert(0) += 1;
+            target.references.push(reference);
+        } else {
+            let index = target.references.iter().position(|x| x.temporary).unwrap_or(target.references.len());
+            target.references.insert(index, reference);
+        }
+    }
+    for (index, cell) in cells {
+        records[index].fields = cell.to_fields();
+    }
+    misplaced
+}
+
+pub fn move_misplaced_references(plugins: &mut [Vec<Record>]) -> Vec<Vec<MisplacedReference>> {
+    plugins.iter_mut().map(move_in_plugin).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct PlacedReference {
+    pub plugin: usize,
+    pub record_index: usize,
+    pub reference_index: usize,
+    pub object: String,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SpatialIndex {
+    references: Vec<PlacedReference>,
+    by_grid: HashMap<Grid, Vec<usize>>,
+}
+
+impl SpatialIndex {
+    pub fn new(plugins: &[(&str, &[Record])]) -> SpatialIndex {
+        let mut definitions: Vec<Option<PlacedReference>> = Vec::new();
+        let mut by_key = HashMap::new();
+        for (plugin, &(name, records)) in plugins.iter().enumerate() {
+            let masters = file_masters(records);
+            let file = FileContext { name: Some(name), masters: &masters };
+            for (record_index, record) in records.iter().enumerate() {
+                let cell = if let Some(cell) = exterior_cell(record) { cell } else { continue; };
+                for (reference_index, reference) in cell.references.iter().enumerate() {
+                    let placed = reference.position.clone().filter(|_| !reference.is_deleted()).map(|position| PlacedReference {
+                        plugin, record_index, reference_index,
+                        object: reference.object.as_ref().map_or_else(String::new, |x| x.string.clone()),
+                        position
+                    });
+                    let object_index = if let Some(object_index) = reference.object_index() { object_index } else {
+                        definitions.push(placed);
+                        continue;
+                    };
+                    let (master, master_index) = reference_master(reference, file);
+                    match by_key.get(&(master.clone(), master_index, object_index)) {
+                        Some(&definition) => definitions[definition] = placed,
+                        None => {
+                            by_key.insert((master, master_index, object_index), definitions.len());
+                            definitions.push(placed);
+                        }
+                    }
+                }
+            }
+        }
+        let mut index = SpatialIndex::default();
+        for placed in definitions.into_iter().flatten() {
+            index.by_grid.entry(position_grid(&placed.position)).or_insert_with(Vec::new).push(index.references.len());
+            index.references.push(placed);
+        }
+        index
+    }
+
+    pub fn references(&self) -> &[PlacedReference] { &self.references }
+
+    fn candidates(&self, min: [f32; 2], max: [f32; 2]) -> impl Iterator<Item=&PlacedReference> {
+        let to_grid = |x: f32| (x / CELL_SIZE).floor() as i32;
+        let (min_x, min_y, max_x, max_y) = (to_grid(min[0]), to_grid(min[1]), to_grid(max[0]), to_grid(max[1]));
+        let mut grids = self.by_grid.keys()
+            .filter(|grid| (min_x ..= max_x).contains(&grid.x) && (min_y ..= max_y).contains(&grid.y))
+            .collect::<Vec<_>>();
+        grids.sort_by_key(|grid| (grid.x, grid.y));
+        grids.into_iter().flat_map(move |grid| &self.by_grid[grid]).map(move |&i| &self.references[i])
+    }
+
+    pub fn within_radius(&self, center: [f32; 3], radius: f32) -> Vec<&PlacedReference> {
+        let min = [center[0] - radius, center[1] - radius];
+        let max = [center[0] + radius, center[1] + radius];
+        self.candidates(min, max).filter(|x| {
+            let (dx, dy, dz) = (x.position.x - center[0], x.position.y - center[1], x.position.z - center[2]);
+            dx * dx + dy * dy + dz * dz <= radius * radius
+        }).collect()
+    }
+
+    pub fn within_box(&self, min: [f32; 3], max: [f32; 3]) -> Vec<&PlacedReference> {
+        self.candidates([min[0], min[1]], [max[0], max[1]]).filter(|x| {
+            (min[0] ..= max[0]).contains(&x.position.x) &&
+            (min[1] ..= max[1]).contains(&x.position.y) &&
+            (min[2] ..= max[2]).contains(&x.position.z)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(x: f32, y: f32) -> Position {
+        Position { x, y, z: 0.0, x_rot: 0.0, y_rot: 0.0, z_rot: 0.0 }
+    }
+
+    fn exterior(grid: Grid, references: &[(i32, &str, f32, f32)]) -> Record {
+        let mut record = new_exterior_cell(grid);
+        record.fields.push((NAM0, Field::I32(references.len() as i32)));
+        for &(ref_num, object, x, y) in references {
+            record.fields.push((FRMR, Field::I32(ref_num)));
+            record.fields.push((NAME, Field::StringZ(object.into())));
+            record.fields.push((DATA, Field::Position(position(x, y))));
+        }
+        record
+    }
+
+    #[test]
+    fn position_to_grid() {
+        assert_eq!(position_grid(&position(0.0, 8191.5)), Grid { x: 0, y: 0 });
+        assert_eq!(position_grid(&position(-0.5, 8192.0)), Grid { x: -1, y: 1 });
+        assert_eq!(position_grid(&position(-8192.0, -8192.5)), Grid { x: -1, y: -2 });
+    }
+
+    #[test]
+    fn move_misplaced() {
+        let records = vec![
+            exterior(Grid { x: 0, y: 0 }, &[(1, "rock", 100.0, 100.0), (2, "tree", 9000.0, 100.0), (3, "flora", -10.0, 10.0)]),
+            exterior(Grid { x: 1, y: 0 }, &[(4, "rock", 8200.0, 100.0)]),
+        ];
+        let misplaced = find_misplaced_references(&records);
+        assert_eq!(misplaced.len(), 2);
+        assert_eq!(misplaced[0].actual_grid, Grid { x: 1, y: 0 });
+        assert_eq!(misplaced[1].actual_grid, Grid { x: -1, y: 0 });
+        let mut plugins = vec![records];
+        assert_eq!(move_misplaced_references(&mut plugins), vec![misplaced]);
+        let records = plugins.pop().unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(find_misplaced_references(&records).is_empty());
+        let cells = records.iter().map(|x| CellData::from_fields(&x.fields)).collect::<Vec<_>>();
+        assert_eq!(cells[0].references.len(), 1);
+        assert_eq!(cells[0].header.temporary_count, Some(1));
+        assert_eq!(cells[1].references.iter().map(|x| x.ref_num.unwrap()).collect::<Vec<_>>(), vec![4, 2]);
+        assert_eq!(cells[1].header.temporary_count, Some(2));
+        assert_eq!(cells[2].grid(), Some(Grid { x: -1, y: 0 }));
+        assert_eq!(cells[2].references[0].ref_num, Some(3));
+    }
+
+    #[test]
+    fn move_misplaced_master_reference() {
+        let mut plugins = vec![vec![exterior(Grid { x: 0, y: 0 }, &[(0x0100_0002, "tree", 9000.0, 100.0)])]];
+        let misplaced = move_misplaced_references(&mut plugins);
+        assert_eq!(misplaced[0].len(), 1);
+        let records = &plugins[0];
+        assert_eq!(records.len(), 1);
+        assert!(find_misplaced_references(records).is_empty());
+        let cell = CellData::from_fields(&records[0].fields);
+        assert_eq!(cell.references.len(), 1);
+        assert_eq!(cell.references[0].moved, Some(0x0100_0002));
+        assert_eq!(cell.references[0].moved_to, Some(Grid { x: 1, y: 0 }));
+        assert_eq!(&records[0].fields[3 .. 5], &[(MVRF, Field::I32(0x0100_0002)), (CNDT, Field::Grid(Grid { x: 1, y: 0 }))]);
+    }
+
+    #[test]
+    fn move_misplaced_within_plugin() {
+        let mut plugins = vec![
+            vec![
+                exterior(Grid { x: 0, y: 0 }, &[(1, "tree", 9000.0, 100.0)]),
+                exterior(Grid { x: 1, y: 0 }, &[(2, "rock", 8200.0, 100.0)]),
+            ],
+            vec![exterior(Grid { x: 1, y: 0 }, &[(1, "flora", 8300.0, 100.0)])],
+        ];
+        let moved = move_misplaced_references(&mut plugins);
+        assert_eq!(moved.iter().map(|x| x.len()).collect::<Vec<_>>(), vec![1, 0]);
+        let objects = |record: &Record| CellData::from_fields(&record.fields).references.iter()
+            .map(|x| x.object.as_ref().unwrap().string.clone()).collect::<Vec<_>>();
+        assert_eq!(objects(&plugins[0][1]), vec!["rock", "tree"]);
+        assert_eq!(objects(&plugins[1][0]), vec!["flora"]);
+    }
+
+    #[test]
+    fn spatial_queries() {
+        let records = [
+            exterior(Grid { x: 0, y: 0 }, &[(1, "a", 100.0, 100.0), (2, "b", 8000.0, 100.0)]),
+            exterior(Grid { x: 1, y: 0 }, &[(3, "c", 8300.0, 100.0)]),
+        ];
+        let index = SpatialIndex::new(&[("Base.esm", &records[..])]);
+        let objects = |x: Vec<&PlacedReference>| x.into_iter().map(|x| x.object.clone()).collect::<Vec<_>>();
+        assert_eq!(objects(index.within_radius([8100.0, 100.0, 0.0], 250.0)), vec!["b", "c"]);
+        assert_eq!(objects(index.within_radius([100.0, 100.0, 0.0], 10.0)), vec!["a"]);
+        assert_eq!(objects(index.within_box([0.0, 0.0, -1.0], [8192.0, 200.0, 1.0])), vec!["a", "b"]);
+        assert_eq!(objects(index.within_radius([0.0, 0.0, 0.0], f32::INFINITY)), vec!["a", "b", "c"]);
+        let mut deleted = exterior(Grid { x: 1, y: 0 }, &[(0x0100_0003, "c", 8300.0, 100.0)]);
+        deleted.fields.push((DELE, Field::I32(0)));
+        let patch = [
+            Record { tag: TES3, flags: RecordFlags::empty(), fields: vec![(MAST, Field::StringZ("Base.esm".into()))] },
+            exterior(Grid { x: 0, y: 0 }, &[(0x0100_0001, "a", 200.0, 100.0), (1, "d", 300.0, 100.0)]),
+            deleted,
+        ];
+        let index = SpatialIndex::new(&[("Base.esm", &records[..]), ("Patch.esp", &patch[..])]);
+        assert_eq!(objects(index.within_radius([0.0, 0.0, 0.0], f32::INFINITY)), vec!["a", "b", "d"]);
+        assert_eq!(index.within_radius([200.0, 100.0, 0.0], 1.0)[0].plugin, 1);
+        assert!(index.within_radius([100.0, 100.0, 0.0], 10.0).is_empty());
+    }
+
+    #[test]
+    fn move_misplaced_moved_reference() {
+        let mut record = exterior(Grid { x: 0, y: 0 }, &[]);
+        record.fields.splice(3 .. 3, [
+            (MVRF, Field::I32(0x0100_0002)), (CNDT, Field::Grid(Grid { x: 1, y: 0 })),
+            (FRMR, Field::I32(0x0100_0002)), (NAME, Field::StringZ("tree".into())),
+            (DATA, Field::Position(position(-100.0, 100.0))),
+        ]);
+        let mut plugins = vec![vec![record]];
+        let misplaced = move_misplaced_references(&mut plugins);
+        assert_eq!(misplaced[0].len(), 1);
+        assert_eq!(misplaced[0][0].grid, Grid { x: 1, y: 0 });
+        assert!(find_misplaced_references(&plugins[0]).is_empty());
+        let cell = CellData::from_fields(&plugins[0][0].fields);
+        assert_eq!(cell.references[0].moved, Some(0x0100_0002));
+        assert_eq!(cell.references[0].moved_to, Some(Grid { x: -1, y: 0 }));
+    }
+}
@@ -14,6 +14,7 @@ use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use either::{Right, Left, Either};
 use std::convert::TryInto;
+use std::str::FromStr;
 use once_cell::sync::{self};
 
 use crate::strings::*;
@@ -164,6 +165,13 @@ fn string_field<E>(code_page: CodePage) -> impl Fn(&[u8]) -> IResult<&[u8], Stri
     }
 }
 
+fn dialogue_condition_field<'a, E: ParseError<&'a [u8]>>(code_page: CodePage) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], Field, E> {
+    map(string_field(code_page), |s| match DialogueCondition::from_str(&s) {
+        Ok(c) => Field::DialogueCondition(c),
+        Err(()) => Field::String(s)
+    })
+}
+
 fn string_z_field<E>(code_page: CodePage) -> impl Fn(&[u8]) -> IResult<&[u8], StringZ, E> {
     move |input| {
         Ok((&input[input.len()..], {
@@ -1429,6 +1437,7 @@ fn field_body<'a>(code_page: CodePage, mode: RecordReadMode, record_tag: Tag, fi
             FieldType::Item => map(item_field(code_page), Field::Item)(input),
             FieldType::String(Some(len)) => map(string_len_field(code_page, mode, len), Field::String)(input),
             FieldType::String(None) => map(string_field(code_page), Field::String)(input),
+            FieldType::DialogueCondition => dialogue_condition_field(code_page)(input),
             FieldType::StringZ => map(string_z_field(code_page), Field::StringZ)(input),
             FieldType::StringZList => map(string_z_list_field(code_page), Field::StringZList)(input),
             FieldType::FileMetadata => map(file_metadata_field(code_page), Field::FileMetadata)(input),
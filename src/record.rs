@@ -8,6 +8,7 @@ use serde::de::Error as de_Error;
 use flate2::write::{ZlibDecoder, ZlibEncoder};
 use flate2::Compression;
 use std::io::Write;
+use std::str::FromStr;
 use nameof::name_of;
 
 use crate::field::*;
@@ -39,10 +40,10 @@ impl Record {
     }
 }
     
-struct FieldBodySerializer<'a> {
-    record_tag: Tag,
-    field_tag: Tag,
-    field: &'a Field
+pub(crate) struct FieldBodySerializer<'a> {
+    pub record_tag: Tag,
+    pub field_tag: Tag,
+    pub field: &'a Field
 }
 
 impl<'a> Serialize for FieldBodySerializer<'a> {
@@ -57,6 +58,11 @@ impl<'a> Serialize for FieldBodySerializer<'a> {
             } else {
                 Err(S::Error::custom(&format!("{} {} field should have string type", self.record_tag, self.field_tag)))
             },
+            FieldType::DialogueCondition => match self.field {
+                Field::DialogueCondition(v) => v.serialize(serializer),
+                Field::String(s) => serializer.serialize_str(s),
+                _ => Err(S::Error::custom(&format!("{} {} field should have dialogue condition type", self.record_tag, self.field_tag)))
+            },
             FieldType::StringZ => if let Field::StringZ(s) = self.field {
                 s.serialize(serializer)
             } else {
@@ -394,7 +400,7 @@ impl<'a> Serialize for FieldBodySerializer<'a> {
     }
 }
 
-struct FieldSerializer<'a>(Tag, Either<RecordFlags, (Tag, &'a Field)>);
+pub(crate) struct FieldSerializer<'a>(pub Tag, pub Either<RecordFlags, (Tag, &'a Field)>);
 
 impl<'a> Serialize for FieldSerializer<'a> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
@@ -469,9 +475,9 @@ impl<'de> de::Visitor<'de> for ZlibEncoderDeserializer {
     }
 }
 
-struct FieldBodyDeserializer {
-    record_tag: Tag,
-    field_tag: Tag
+pub(crate) struct FieldBodyDeserializer {
+    pub record_tag: Tag,
+    pub field_tag: Tag
 }
 
 impl<'de> DeserializeSeed<'de> for FieldBodyDeserializer {
@@ -489,6 +495,14 @@ impl<'de> DeserializeSeed<'de> for FieldBodyDeserializer {
                 } else {
                     String::deserialize(deserializer)
                 }.map(Field::String),
+                FieldType::DialogueCondition => if deserializer.is_human_readable() {
+                    DialogueConditionOrString::deserialize(deserializer).map(|x| x.into())
+                } else {
+                    String::deserialize(deserializer).map(|s| match DialogueCondition::from_str(&s) {
+                        Ok(c) => Field::DialogueCondition(c),
+                        Err(()) => Field::String(s)
+                    })
+                },
                 FieldType::StringZ =>
                     StringZ::deserialize(deserializer).map(Field::StringZ),
                 FieldType::Multiline(newline) =>
@@ -569,8 +583,8 @@ impl<'de> DeserializeSeed<'de> for FieldBodyDeserializer {
     }
 }
 
-struct FieldDeserializer {
-    record_tag: Tag
+pub(crate) struct FieldDeserializer {
+    pub record_tag: Tag
 }
 
 impl<'de> de::Visitor<'de> for FieldDeserializer {
@@ -679,6 +693,22 @@ impl<'de> Deserialize<'de> for Record {
     }
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DialogueConditionOrString {
+    DialogueCondition(DialogueCondition),
+    String(String)
+}
+
+impl From<DialogueConditionOrString> for Field {
+    fn from(v: DialogueConditionOrString) -> Self {
+        match v {
+            DialogueConditionOrString::DialogueCondition(c) => Field::DialogueCondition(c),
+            DialogueConditionOrString::String(s) => Field::String(s),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 enum DialogTypeOption {
     None(i32),
@@ -0,0 +1,1103 @@
+use byteorder::{ByteOrder, LittleEndian};
+use encoding::{DecoderTrap, EncoderTrap};
+use flate2::write::ZlibDecoder;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Display, Write as FmtWrite};
+use std::io::{self, Write};
+
+use crate::code::CodePage;
+use crate::dialogue::record_string;
+use crate::field::*;
+use crate::record::*;
+
+pub const OP_END: u16 = 0x0101;
+pub const OP_SET: u16 = 0x0105;
+pub const OP_IF: u16 = 0x0106;
+pub const OP_ELSE: u16 = 0x0107;
+pub const OP_ELSE_IF: u16 = 0x0108;
+pub const OP_END_IF: u16 = 0x0109;
+pub const OP_REFERENCE: u16 = 0x010C;
+pub const OP_WHILE: u16 = 0x010E;
+pub const OP_END_WHILE: u16 = 0x010F;
+pub const OP_RETURN: u16 = 0x0124;
+pub const OP_MESSAGE_BOX: u16 = 0x1000;
+
+const STATEMENT_OPCODES: &[u16] = &[
+    OP_END, OP_SET, OP_IF, OP_ELSE, OP_ELSE_IF, OP_END_IF, OP_REFERENCE, OP_WHILE, OP_END_WHILE, OP_RETURN, OP_MESSAGE_BOX
+];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ScriptArgKind {
+    String,
+    Short,
+    Long,
+    Float,
+    Axis,
+}
+
+impl ScriptArgKind {
+    fn from_str(s: &str) -> Option<ScriptArgKind> {
+        match s {
+            "string" => Some(ScriptArgKind::String),
+            "short" => Some(ScriptArgKind::Short),
+            "long" => Some(ScriptArgKind::Long),
+            "float" => Some(ScriptArgKind::Float),
+            "axis" => Some(ScriptArgKind::Axis),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ScriptFunction {
+    pub opcode: u16,
+    pub name: String,
+    pub args: Vec<ScriptArgKind>,
+    pub returns_value: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FunctionTableError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for FunctionTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "function table line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for FunctionTableError { }
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ScriptFunctions {
+    functions: Vec<ScriptFunction>,
+}
+
+impl ScriptFunctions {
+    pub fn parse(text: &str) -> Result<ScriptFunctions, FunctionTableError> {
+        let mut functions: Vec<ScriptFunction> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| FunctionTableError { line: index + 1, message };
+            let line = line.split(';').next().unwrap();
+            let mut words = line.split_whitespace();
+            let opcode = if let Some(opcode) = words.next() { opcode } else { continue; };
+            let opcode = u16::from_str_radix(opcode.trim_start_matches("0x"), 16)
+                .map_err(|_| error(format!("invalid opcode '{}'", opcode)))?;
+            let name = words.next().ok_or_else(|| error("function name expected".into()))?;
+            let mut args = Vec::new();
+            let mut returns_value = false;
+            for word in words {
+                if returns_value {
+                    return Err(error(format!("unexpected '{}' after value", word)));
+                }
+                if word == "value" {
+                    returns_value = true;
+                } else {
+                    args.push(ScriptArgKind::from_str(word).ok_or_else(|| error(format!("unknown argument kind '{}'", word)))?);
+                }
+            }
+            if STATEMENT_OPCODES.contains(&opcode) {
+                return Err(error(format!("{} opcode {:04X}h is reserved for statements", name, opcode)));
+            }
+            if functions.iter().any(|x| x.opcode == opcode || x.name.eq_ignore_ascii_case(name)) {
+                return Err(error(format!("duplicate function {} ({:04X}h)", name, opcode)));
+            }
+            functions.push(ScriptFunction { opcode, name: name.to_string(), args, returns_value });
+        }
+        Ok(ScriptFunctions { functions })
+    }
+
+    pub fn is_empty(&self) -> bool { self.functions.is_empty() }
+
+    pub fn iter(&self) -> impl Iterator<Item=&ScriptFunction> { self.functions.iter() }
+
+    pub fn get(&self, opcode: u16) -> Option<&ScriptFunction> {
+        self.functions.iter().find(|x| x.opcode == opcode)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&ScriptFunction> {
+        self.functions.iter().find(|x| x.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+    InvalidCompressedData(io::Error),
+    UnexpectedFieldType(Tag),
+    MissingScriptData,
+    UnexpectedEnd { offset: usize },
+    UnknownVariableType { offset: usize, value: u8 },
+    InvalidAxis { offset: usize, value: u8 },
+    InvalidExpression { offset: usize },
+    NotAFunction { offset: usize, opcode: u16 },
+    UnknownFunction { offset: usize, opcode: u16 },
+    UndecodableString { offset: usize },
+    UnencodableString(String),
+    StringTooLong(String),
+    ExpressionTooLong(usize),
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::InvalidCompressedData(e) => write!(f, "invalid compressed data ({})", e),
+            ScriptError::UnexpectedFieldType(tag) => write!(f, "{} field should have byte list type", tag),
+            ScriptError::MissingScriptData => write!(f, "{} field is missing", SCDT),
+            ScriptError::UnexpectedEnd { offset } => write!(f, "unexpected end of script data at {:04X}h", offset),
+            ScriptError::UnknownVariableType { offset, value } =>
+                write!(f, "unknown variable type {:02X}h at {:04X}h", value, offset),
+            ScriptError::InvalidAxis { offset, value } => write!(f, "invalid axis {:02X}h at {:04X}h", value, offset),
+            ScriptError::InvalidExpression { offset } => write!(f, "invalid expression at {:04X}h", offset),
+            ScriptError::NotAFunction { offset, opcode } =>
+                write!(f, "opcode {:04X}h at {:04X}h is not a function", opcode, offset),
+            ScriptError::UnknownFunction { offset, opcode } =>
+                write!(f, "the arguments of unknown function {:04X}h at {:04X}h cannot be delimited", opcode, offset),
+            ScriptError::UndecodableString { offset } =>
+                write!(f, "the string at {:04X}h does not fit the code page", offset),
+            ScriptError::UnencodableString(s) => write!(f, "the '{}' string does not fit the code page", s),
+            ScriptError::StringTooLong(s) => write!(f, "the '{}' string is longer than 255 bytes", s),
+            ScriptError::ExpressionTooLong(len) => write!(f, "expression size {} exceeds 255 bytes", len),
+        }
+    }
+}
+
+impl Error for ScriptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScriptError::InvalidCompressedData(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ScriptVarType {
+    Short,
+    Long,
+    Float,
+}
+
+impl ScriptVarType {
+    pub fn from_u8(b: u8) -> Option<ScriptVarType> {
+        match b {
+            b's' => Some(ScriptVarType::Short),
+            b'l' => Some(ScriptVarType::Long),
+            b'f' => Some(ScriptVarType::Float),
+            _ => None
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ScriptVarType::Short => b's',
+            ScriptVarType::Long => b'l',
+            ScriptVarType::Float => b'f',
+        }
+    }
+
+    pub fn keyword(self) -> &'static str {
+        match self {
+            ScriptVarType::Short => "short",
+            ScriptVarType::Long => "long",
+            ScriptVarType::Float => "float",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum VarRef {
+    Local { var_type: ScriptVarType, index: u16 },
+    Global(String),
+    Remote { object: String, var_type: ScriptVarType, index: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptArg {
+    String(String),
+    Short(i16),
+    Long(i32),
+    Float(f32),
+    Axis(char),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub reference: Option<String>,
+    pub opcode: u16,
+    pub args: Vec<ScriptArg>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnknownCall {
+    pub reference: Option<String>,
+    pub opcode: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprToken {
+    Number(String),
+    Operator(String),
+    Var(VarRef),
+    Call(FunctionCall),
+    Unknown(UnknownCall),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    End,
+    Return,
+    Set { var: VarRef, expr: Vec<ExprToken> },
+    If { skip: u8, expr: Vec<ExprToken> },
+    ElseIf { skip: u8, expr: Vec<ExprToken> },
+    Else { skip: u8 },
+    EndIf,
+    While { skip: u8, expr: Vec<ExprToken> },
+    EndWhile,
+    MessageBox { reference: Option<String>, text: String, vars: Vec<VarRef>, buttons: Vec<String> },
+    Call(FunctionCall),
+    Unknown(UnknownCall),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptLine {
+    pub offset: usize,
+    pub instruction: Instruction,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    code_page: CodePage,
+    functions: &'a ScriptFunctions,
+    unknown_ends: Option<Vec<Option<(usize, usize)>>>,
+    scanning: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn is_end(&self) -> bool { self.offset >= self.bytes.len() }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ScriptError> {
+        if self.bytes.len() - self.offset < len {
+            return Err(ScriptError::UnexpectedEnd { offset: self.bytes.len() });
+        }
+        let bytes = &self.bytes[self.offset .. self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn peek(&self) -> Option<u8> { self.bytes.get(self.offset).copied() }
+
+    fn u8(&mut self) -> Result<u8, ScriptError> { Ok(self.take(1)?[0]) }
+
+    fn u16(&mut self) -> Result<u16, ScriptError> { Ok(LittleEndian::read_u16(self.take(2)?)) }
+
+    fn string(&mut self) -> Result<String, ScriptError> {
+        let offset = self.offset;
+        let len = self.u8()? as usize;
+        let bytes = self.take(len)?;
+        self.code_page.encoding().decode(bytes, DecoderTrap::Strict)
+            .map_err(|_| ScriptError::UndecodableString { offset })
+    }
+
+    fn var_type(&mut self) -> Result<ScriptVarType, ScriptError> {
+        let offset = self.offset;
+        let value = self.u8()?;
+        ScriptVarType::from_u8(value).ok_or(ScriptError::UnknownVariableType { offset, value })
+    }
+
+    fn var_ref(&mut self) -> Result<VarRef, ScriptError> {
+        match self.peek() {
+            Some(b'G') => {
+                self.offset += 1;
+                Ok(VarRef::Global(self.string()?))
+            },
+            Some(b'r') => {
+                self.offset += 1;
+                let object = self.string()?;
+                let var_type = self.var_type()?;
+                Ok(VarRef::Remote { object, var_type, index: self.u16()? })
+            },
+            _ => {
+                let var_type = self.var_type()?;
+                Ok(VarRef::Local { var_type, index: self.u16()? })
+            }
+        }
+    }
+
+    fn arg(&mut self, kind: ScriptArgKind) -> Result<ScriptArg, ScriptError> {
+        Ok(match kind {
+            ScriptArgKind::String => ScriptArg::String(self.string()?),
+            ScriptArgKind::Short => ScriptArg::Short(LittleEndian::read_i16(self.take(2)?)),
+            ScriptArgKind::Long => ScriptArg::Long(LittleEndian::read_i32(self.take(4)?)),
+            ScriptArgKind::Float => ScriptArg::Float(LittleEndian::read_f32(self.take(4)?)),
+            ScriptArgKind::Axis => {
+                let offset = self.offset;
+                match self.u8()? {
+                    value @ b'X' ..= b'Z' => ScriptArg::Axis(value as char),
+                    value => return Err(ScriptError::InvalidAxis { offset, value })
+                }
+            },
+        })
+    }
+
+    fn scan_unknown_ends(&mut self) -> Vec<Option<(usize, usize)>> {
+        let len = self.bytes.len();
+        let mut unknowns = vec![None; len + 1];
+        let mut ends: Vec<Option<(usize, usize)>> = vec![None; len + 2];
+        self.scanning = true;
+        for offset in (0 ..= len).rev() {
+            self.offset = offset;
+            unknowns[offset] = match self.instruction() {
+                Ok(Instruction::End) => if self.is_end() { Some(0) } else { None },
+                Ok(_) => unknowns[self.offset],
+                Err(ScriptError::UnknownFunction { .. }) => ends[self.offset].map(|(_, count)| count + 1),
+                Err(_) => None,
+            };
+            ends[offset] = match (unknowns[offset], ends[offset + 1]) {
+                (Some(count), Some((_, min))) if count > min => ends[offset + 1],
+                (Some(count), _) => Some((offset, count)),
+                (None, next) => next,
+            };
+        }
+        self.scanning = false;
+        ends
+    }
+
+    fn unknown_end(&mut self, offset: usize, opcode: u16) -> Result<usize, ScriptError> {
+        if self.scanning {
+            return Err(ScriptError::UnknownFunction { offset, opcode });
+        }
+        if self.unknown_ends.is_none() {
+            let saved = self.offset;
+            self.unknown_ends = Some(self.scan_unknown_ends());
+            self.offset = saved;
+        }
+        self.unknown_ends.as_ref().unwrap()[self.offset].map(|(end, _)| end).ok_or(ScriptError::UnknownFunction { offset, opcode })
+    }
+
+    fn call(&mut self, reference: Option<String>, opcode: u16, offset: usize, end: Option<usize>)
+        -> Result<Result<FunctionCall, UnknownCall>, ScriptError> {
+
+        if opcode == OP_MESSAGE_BOX {
+            return Err(ScriptError::NotAFunction { offset, opcode });
+        }
+        let functions = self.functions;
+        let function = match functions.get(opcode) {
+            Some(function) => function,
+            None => {
+                let end = match end {
+                    Some(end) => end,
+                    None => self.unknown_end(offset, opcode)?
+                };
+                let data = self.bytes[self.offset .. end].to_vec();
+                self.offset = end;
+                return Ok(Err(UnknownCall { reference, opcode, data }));
+            }
+        };
+        let args = function.args.iter().map(|&kind| self.arg(kind)).collect::<Result<_, _>>()?;
+        Ok(Ok(FunctionCall { reference, opcode, args }))
+    }
+
+    fn expr(&mut self) -> Result<Vec<ExprToken>, ScriptError> {
+        let len = self.u8()? as usize;
+        let start = self.offset;
+        let end = start + len;
+        if end > self.bytes.len() {
+            return Err(ScriptError::UnexpectedEnd { offset: self.bytes.len() });
+        }
+        let mut tokens = Vec::new();
+        while self.offset < end {
+            let offset = self.offset;
+            let b = self.bytes[offset];
+            let token = match b {
+                b' ' => { self.offset += 1; continue; },
+                b'0' ..= b'9' | b'.' => ExprToken::Number(self.number(end)),
+                b'-' if matches!(self.bytes.get(offset + 1), Some(b'0' ..= b'9') | Some(b'.')) && offset + 1 < end =>
+                    ExprToken::Number(self.number(end)),
+                b'+' | b'-' | b'*' | b'/' | b'(' | b')' => {
+                    self.offset += 1;
+                    ExprToken::Operator((b as char).to_string())
+                },
+                b'=' | b'!' | b'<' | b'>' => {
+                    self.offset += 1;
+                    if self.offset < end && self.bytes[self.offset] == b'=' {
+                        self.offset += 1;
+                        ExprToken::Operator(format!("{}=", b as char))
+                    } else if b == b'<' || b == b'>' {
+                        ExprToken::Operator((b as char).to_string())
+                    } else {
+                        return Err(ScriptError::InvalidExpression { offset });
+                    }
+                },
+                b'X' | b'x' => {
+                    self.offset += 1;
+                    let reference = if b == b'x' { Some(self.string()?) } else { None };
+                    let opcode = self.u16()?;
+                    match self.call(reference, opcode, offset, Some(end))? {
+                        Ok(call) => ExprToken::Call(call),
+                        Err(call) => ExprToken::Unknown(call),
+                    }
+                },
+                _ => ExprToken::Var(self.var_ref()?)
+            };
+            tokens.push(token);
+        }
+        if self.offset != end {
+            return Err(ScriptError::InvalidExpression { offset: start });
+        }
+        Ok(tokens)
+    }
+
+    fn number(&mut self, end: usize) -> String {
+        let start = self.offset;
+        self.offset += 1;
+        while self.offset < end && matches!(self.bytes[self.offset], b'0' ..= b'9' | b'.') {
+            self.offset += 1;
+        }
+        String::from_utf8(self.bytes[start .. self.offset].to_vec()).unwrap()
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, ScriptError> {
+        let offset = self.offset;
+        let opcode = self.u16()?;
+        Ok(match opcode {
+            OP_END => Instruction::End,
+            OP_RETURN => Instruction::Return,
+            OP_SET => {
+                let var = self.var_ref()?;
+                Instruction::Set { var, expr: self.expr()? }
+            },
+            OP_IF => {
+                let skip = self.u8()?;
+                Instruction::If { skip, expr: self.expr()? }
+            },
+            OP_ELSE_IF => {
+                let skip = self.u8()?;
+                Instruction::ElseIf { skip, expr: self.expr()? }
+            },
+            OP_ELSE => Instruction::Else { skip: self.u8()? },
+            OP_END_IF => Instruction::EndIf,
+            OP_WHILE => {
+                let skip = self.u8()?;
+                Instruction::While { skip, expr: self.expr()? }
+            },
+            OP_END_WHILE => Instruction::EndWhile,
+            OP_REFERENCE => {
+                let reference = self.string()?;
+                let offset = self.offset;
+                let opcode = self.u16()?;
+                self.function(Some(reference), opcode, offset)?
+            },
+            opcode => self.function(None, opcode, offset)?
+        })
+    }
+
+    fn function(&mut self, reference: Option<String>, opcode: u16, offset: usize) -> Result<Instruction, ScriptError> {
+        if opcode != OP_MESSAGE_BOX {
+            return Ok(match self.call(reference, opcode, offset, None)? {
+                Ok(call) => Instruction::Call(call),
+                Err(call) => Instruction::Unknown(call),
+            });
+        }
+        let text = self.string()?;
+        let vars = (0 .. self.u8()?).map(|_| self.var_ref()).collect::<Result<_, _>>()?;
+        let buttons = (0 .. self.u8()?).map(|_| self.string()).collect::<Result<_, _>>()?;
+        Ok(Instruction::MessageBox { reference, text, vars, buttons })
+    }
+}
+
+pub fn disassemble(bytes: &[u8], code_page: CodePage, functions: &ScriptFunctions) -> Result<Vec<ScriptLine>, ScriptError> {
+    let mut reader = Reader { bytes, offset: 0, code_page, functions, unknown_ends: None, scanning: false };
+    let mut lines = Vec::new();
+    while !reader.is_end() {
+        let offset = reader.offset;
+        let instruction = reader.instruction()?;
+        let end = instruction == Instruction::End;
+        lines.push(ScriptLine { offset, instruction });
+        if end { break; }
+    }
+    Ok(lines)
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str, code_page: CodePage) -> Result<(), ScriptError> {
+    let bytes = code_page.encoding().encode(s, EncoderTrap::Strict)
+        .map_err(|_| ScriptError::UnencodableString(s.to_string()))?;
+    if bytes.len() > 255 {
+        return Err(ScriptError::StringTooLong(s.to_string()));
+    }
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+fn write_var_ref(out: &mut Vec<u8>, var: &VarRef, code_page: CodePage) -> Result<(), ScriptError> {
+    match var {
+        VarRef::Local { var_type, index } => {
+            out.push(var_type.to_u8());
+            out.extend_from_slice(&index.to_le_bytes());
+        },
+        VarRef::Global(name) => {
+            out.push(b'G');
+            write_string(out, name, code_page)?;
+        },
+        VarRef::Remote { object, var_type, index } => {
+            out.push(b'r');
+            write_string(out, object, code_page)?;
+            out.push(var_type.to_u8());
+            out.extend_from_slice(&index.to_le_bytes());
+        },
+    }
+    Ok(())
+}
+
+fn write_args(out: &mut Vec<u8>, args: &[ScriptArg], code_page: CodePage) -> Result<(), ScriptError> {
+    for arg in args {
+        match arg {
+            ScriptArg::String(s) => write_string(out, s, code_page)?,
+            ScriptArg::Short(v) => out.extend_from_slice(&v.to_le_bytes()),
+            ScriptArg::Long(v) => out.extend_from_slice(&v.to_le_bytes()),
+            ScriptArg::Float(v) => out.extend_from_slice(&v.to_bits().to_le_bytes()),
+            ScriptArg::Axis(v) => out.push(*v as u8),
+        }
+    }
+    Ok(())
+}
+
+fn write_expr(out: &mut Vec<u8>, expr: &[ExprToken], code_page: CodePage) -> Result<(), ScriptError> {
+    let mut bytes = Vec::new();
+    for (i, token) in expr.iter().enumerate() {
+        if i != 0 {
+            bytes.push(b' ');
+        }
+        match token {
+            ExprToken::Number(s) | ExprToken::Operator(s) => bytes.extend_from_slice(s.as_bytes()),
+            ExprToken::Var(var) => write_var_ref(&mut bytes, var, code_page)?,
+            ExprToken::Call(call) => {
+                if let Some(reference) = &call.reference {
+                    bytes.push(b'x');
+                    write_string(&mut bytes, reference, code_page)?;
+                } else {
+                    bytes.push(b'X');
+                }
+                bytes.extend_from_slice(&call.opcode.to_le_bytes());
+                write_args(&mut bytes, &call.args, code_page)?;
+            },
+            ExprToken::Unknown(call) => {
+                if let Some(reference) = &call.reference {
+                    bytes.push(b'x');
+                    write_string(&mut bytes, reference, code_page)?;
+                } else {
+                    bytes.push(b'X');
+                }
+                bytes.extend_from_slice(&call.opcode.to_le_bytes());
+                bytes.extend_from_slice(&call.data);
+            },
+        }
+    }
+    if bytes.len() > 255 {
+        return Err(ScriptError::ExpressionTooLong(bytes.len()));
+    }
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+fn write_reference(out: &mut Vec<u8>, reference: &Option<String>, code_page: CodePage) -> Result<(), ScriptError> {
+    if let Some(reference) = reference {
+        out.extend_from_slice(&OP_REFERENCE.to_le_bytes());
+        write_string(out, reference, code_page)?;
+    }
+    Ok(())
+}
+
+pub fn assemble_instruction(out: &mut Vec<u8>, instruction: &Instruction, code_page: CodePage) -> Result<(), ScriptError> {
+    let opcode = |out: &mut Vec<u8>, opcode: u16| out.extend_from_slice(&opcode.to_le_bytes());
+    match instruction {
+        Instruction::End => opcode(out, OP_END),
+        Instruction::Return => opcode(out, OP_RETURN),
+        Instruction::Set { var, expr } => {
+            opcode(out, OP_SET);
+            write_var_ref(out, var, code_page)?;
+            write_expr(out, expr, code_page)?;
+        },
+        Instruction::If { skip, expr } | Instruction::ElseIf { skip, expr } | Instruction::While { skip, expr } => {
+            opcode(out, match instruction {
+                Instruction::If { .. } => OP_IF,
+                Instruction::ElseIf { .. } => OP_ELSE_IF,
+                _ => OP_WHILE
+            });
+            out.push(*skip);
+            write_expr(out, expr, code_page)?;
+        },
+        Instruction::Else { skip } => {
+            opcode(out, OP_ELSE);
+            out.push(*skip);
+        },
+        Instruction::EndIf => opcode(out, OP_END_IF),
+        Instruction::EndWhile => opcode(out, OP_END_WHILE),
+        Instruction::MessageBox { reference, text, vars, buttons } => {
+            write_reference(out, reference, code_page)?;
+            opcode(out, OP_MESSAGE_BOX);
+            write_string(out, text, code_page)?;
+            out.push(vars.len() as u8);
+            for var in vars {
+                write_var_ref(out, var, code_page)?;
+            }
+            out.push(buttons.len() as u8);
+            for button in buttons {
+                write_string(out, button, code_page)?;
+            }
+        },
+        Instruction::Call(call) => {
+            write_reference(out, &call.reference, code_page)?;
+            opcode(out, call.opcode);
+            write_args(out, &call.args, code_page)?;
+        },
+        Instruction::Unknown(call) => {
+            write_reference(out, &call.reference, code_page)?;
+            opcode(out, call.opcode);
+            out.extend_from_slice(&call.data);
+        },
+    }
+    Ok(())
+}
+
+pub fn assemble<'a>(instructions: impl IntoIterator<Item=&'a Instruction>, code_page: CodePage) -> Result<Vec<u8>, ScriptError> {
+    let mut bytes = Vec::new();
+    for instruction in instructions {
+        assemble_instruction(&mut bytes, instruction, code_page)?;
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ScriptVarNames {
+    pub shorts: Vec<String>,
+    pub longs: Vec<String>,
+    pub floats: Vec<String>,
+}
+
+impl ScriptVarNames {
+    pub fn new(vars: &ScriptVars, names: &[String]) -> ScriptVarNames {
+        let mut names = names.iter().cloned();
+        let mut take = |var_type: ScriptVarType, count: u32| (1 ..= count).map(|index|
+            names.next().unwrap_or_else(|| format!("{}{}", var_type.keyword(), index))
+        ).collect();
+        let shorts = take(ScriptVarType::Short, vars.shorts);
+        let longs = take(ScriptVarType::Long, vars.longs);
+        let floats = take(ScriptVarType::Float, vars.floats);
+        ScriptVarNames { shorts, longs, floats }
+    }
+
+    pub fn names(&self, var_type: ScriptVarType) -> &[String] {
+        match var_type {
+            ScriptVarType::Short => &self.shorts,
+            ScriptVarType::Long => &self.longs,
+            ScriptVarType::Float => &self.floats,
+        }
+    }
+
+    pub fn name(&self, var_type: ScriptVarType, index: u16) -> Option<&str> {
+        let index = (index as usize).checked_sub(1)?;
+        self.names(var_type).get(index).map(|x| x.as_str())
+    }
+
+    pub fn find(&self, name: &str) -> Option<(ScriptVarType, u16)> {
+        [ScriptVarType::Short, ScriptVarType::Long, ScriptVarType::Float].iter().find_map(|&var_type|
+            self.names(var_type).iter().position(|x| x.eq_ignore_ascii_case(name)).map(|index| (var_type, index as u16 + 1))
+        )
+    }
+}
+
+fn script_var_names(record: &Record) -> Option<(String, ScriptVarNames)> {
+    let mut metadata = None;
+    let mut names: &[String] = &[];
+    for (tag, field) in &record.fields {
+        match (*tag, field) {
+            (SCHD, Field::ScriptMetadata(m)) => metadata = Some(m),
+            (SCVR, Field::StringZList(list)) => names = &list.vec,
+            _ => { }
+        }
+    }
+    metadata.map(|m| (m.name.to_lowercase(), ScriptVarNames::new(&m.vars, names)))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScriptContext {
+    pub functions: ScriptFunctions,
+    pub globals: HashSet<String>,
+    pub object_vars: HashMap<String, ScriptVarNames>,
+}
+
+impl ScriptContext {
+    pub fn new<'a>(functions: ScriptFunctions, records: impl IntoIterator<Item=&'a Record>) -> ScriptContext {
+        let mut globals = HashSet::new();
+        let mut scripts = HashMap::new();
+        let mut objects = Vec::new();
+        for record in records {
+            if record.flags.contains(RecordFlags::DELETED) { continue; }
+            if record.tag == SCPT {
+                scripts.extend(script_var_names(record));
+            } else if let Some(id) = record_string(record, NAME) {
+                if record.tag == GLOB {
+                    globals.insert(id.to_lowercase());
+                }
+                if let Some(script) = record_string(record, SCRI).filter(|x| !x.is_empty()) {
+                    objects.push((id.to_lowercase(), script.to_lowercase()));
+                }
+            }
+        }
+        let mut object_vars = scripts.clone();
+        object_vars.extend(objects.into_iter().filter_map(|(id, script)| scripts.get(&script).map(|x| (id, x.clone()))));
+        ScriptContext { functions, globals, object_vars }
+    }
+
+    fn remote_var(&self, object: &str, var_type: ScriptVarType, index: u16) -> Option<&str> {
+        self.object_vars.get(&object.to_lowercase())?.name(var_type, index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    pub name: String,
+    pub vars: ScriptVars,
+    pub var_names: ScriptVarNames,
+    pub code: Vec<ScriptLine>,
+}
+
+fn unzip(field: &Field) -> Result<Vec<u8>, ScriptError> {
+    if let Field::U8List(compressed) = field {
+        (|| {
+            let mut decoder = ZlibDecoder::new(Vec::new());
+            decoder.write_all(&compressed[..])?;
+            decoder.finish()
+        })().map_err(ScriptError::InvalidCompressedData)
+    } else {
+        Err(ScriptError::UnexpectedFieldType(SCDT))
+    }
+}
+
+pub(crate) fn quote_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\\\""))
+}
+
+fn quote(s: &str) -> String {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == ',' || c == '-' || c == '"') {
+        quote_string(s)
+    } else {
+        s.to_string()
+    }
+}
+
+impl Script {
+    pub fn from_record(record: &Record, code_page: CodePage, functions: &ScriptFunctions) -> Result<Script, ScriptError> {
+        let mut metadata = None;
+        let mut names: &[String] = &[];
+        let mut data = None;
+        for (tag, field) in &record.fields {
+            match (*tag, field) {
+                (SCHD, Field::ScriptMetadata(m)) => metadata = Some(m),
+                (SCVR, Field::StringZList(list)) => names = &list.vec,
+                (SCDT, field) => data = Some(unzip(field)?),
+                _ => { }
+            }
+        }
+        let data = data.ok_or(ScriptError::MissingScriptData)?;
+        let (name, vars) = metadata.map_or_else(
+            || (String::new(), ScriptVars { shorts: 0, longs: 0, floats: 0 }),
+            |m| (m.name.clone(), m.vars.clone())
+        );
+        Ok(Script {
+            var_names: ScriptVarNames::new(&vars, names),
+            name,
+            vars,
+            code: disassemble(&data, code_page, functions)?
+        })
+    }
+
+    fn var(&self, var: &VarRef, context: &ScriptContext) -> String {
+        let local = |var_type, index| self.var_names.name(var_type, index).map_or_else(
+            || format!("{}{}", var_type.keyword(), index),
+            |x| x.to_string()
+        );
+        match var {
+            VarRef::Local { var_type, index } => local(*var_type, *index),
+            VarRef::Global(name) => name.clone(),
+            VarRef::Remote { object, var_type, index } => context.remote_var(object, *var_type, *index).map_or_else(
+                || format!("{}.{}{}", quote(object), var_type.keyword(), index),
+                |name| format!("{}.{}", quote(object), name)
+            ),
+        }
+    }
+
+    fn call(call: &FunctionCall, context: &ScriptContext) -> String {
+        let mut text = String::new();
+        if let Some(reference) = &call.reference {
+            write!(text, "{}->", quote(reference)).unwrap();
+        }
+        text.push_str(context.functions.get(call.opcode).map_or("?", |x| &x.name));
+        for arg in &call.args {
+            match arg {
+                ScriptArg::String(s) => write!(text, " {}", quote(s)).unwrap(),
+                ScriptArg::Short(v) => write!(text, " {}", v).unwrap(),
+                ScriptArg::Long(v) => write!(text, " {}", v).unwrap(),
+                ScriptArg::Float(v) => write!(text, " {}", v).unwrap(),
+                ScriptArg::Axis(v) => write!(text, " {}", v).unwrap(),
+            }
+        }
+        text
+    }
+
+    fn unknown(call: &UnknownCall) -> String {
+        let reference = call.reference.as_ref().map_or_else(String::new, |x| format!("{}->", quote(x)));
+        format!("{}unknown opcode {:04X}h, {} bytes", reference, call.opcode, call.data.len())
+    }
+
+    fn expr(&self, expr: &[ExprToken], context: &ScriptContext) -> String {
+        expr.iter().map(|token| match token {
+            ExprToken::Number(s) | ExprToken::Operator(s) => s.clone(),
+            ExprToken::Var(var) => self.var(var, context),
+            ExprToken::Call(call) => Self::call(call, context),
+            ExprToken::Unknown(call) => format!("<{}>", Self::unknown(call)),
+        }).collect::<Vec<_>>().join(" ")
+    }
+
+    fn statement(&self, instruction: &Instruction, context: &ScriptContext) -> String {
+        match instruction {
+            Instruction::End => "End".into(),
+            Instruction::Return => "Return".into(),
+            Instruction::Set { var, expr } => format!("set {} to {}", self.var(var, context), self.expr(expr, context)),
+            Instruction::If { expr, .. } => format!("if ( {} )", self.expr(expr, context)),
+            Instruction::ElseIf { expr, .. } => format!("elseif ( {} )", self.expr(expr, context)),
+            Instruction::Else { .. } => "else".into(),
+            Instruction::EndIf => "endif".into(),
+            Instruction::While { expr, .. } => format!("while ( {} )", self.expr(expr, context)),
+            Instruction::EndWhile => "endwhile".into(),
+            Instruction::MessageBox { reference, text, vars, buttons } => {
+                let mut s = String::new();
+                if let Some(reference) = reference {
+                    write!(s, "{}->", quote(reference)).unwrap();
+                }
+                write!(s, "MessageBox {}", quote_string(text)).unwrap();
+                for var in vars {
+                    write!(s, " {}", self.var(var, context)).unwrap();
+                }
+                for button in buttons {
+                    write!(s, " {}", quote_string(button)).unwrap();
+                }
+                s
+            },
+            Instruction::Call(call) => Self::call(call, context),
+            Instruction::Unknown(call) => format!("; {}", Self::unknown(call)),
+        }
+    }
+
+    pub fn listing(&self, context: &ScriptContext) -> String {
+        let mut listing = String::new();
+        for line in &self.code {
+            let skip = match &line.instruction {
+                Instruction::If { skip, .. } | Instruction::ElseIf { skip, .. } |
+                Instruction::Else { skip } | Instruction::While { skip, .. } => format!(" [skip {}]", skip),
+                _ => String::new()
+            };
+            writeln!(listing, "{:04X}  {}{}", line.offset, self.statement(&line.instruction, context), skip).unwrap();
+        }
+        listing
+    }
+
+    pub fn decompile(&self, context: &ScriptContext) -> String {
+        let mut source = String::new();
+        writeln!(source, "Begin {}", self.name).unwrap();
+        let mut declared = false;
+        for var_type in [ScriptVarType::Short, ScriptVarType::Long, ScriptVarType::Float].iter() {
+            for name in self.var_names.names(*var_type) {
+                if !declared {
+                    writeln!(source).unwrap();
+                    declared = true;
+                }
+                writeln!(source, "{} {}", var_type.keyword(), name).unwrap();
+            }
+        }
+        if !self.code.is_empty() {
+            writeln!(source).unwrap();
+        }
+        let mut indent = 0usize;
+        for line in &self.code {
+            let statement = self.statement(&line.instruction, context);
+            match &line.instruction {
+                Instruction::End => {
+                    writeln!(source).unwrap();
+                    writeln!(source, "{}", statement).unwrap();
+                    continue;
+                },
+                Instruction::ElseIf { .. } | Instruction::Else { .. } =>
+                    indent = indent.saturating_sub(1),
+                Instruction::EndIf | Instruction::EndWhile =>
+                    indent = indent.saturating_sub(1),
+                _ => { }
+            }
+            writeln!(source, "{}{}", "\t".repeat(indent), statement).unwrap();
+            if matches!(line.instruction, Instruction::If { .. } | Instruction::ElseIf { .. } |
+                Instruction::Else { .. } | Instruction::While { .. }) {
+                indent += 1;
+            }
+        }
+        source
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::strings::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    pub(crate) fn functions() -> ScriptFunctions {
+        ScriptFunctions::parse("\
+            1009 GetJournalIndex string value
+            100B AddItem string long
+            1023 GetSecondsPassed value ; frame time
+            1024 Random short value
+            1031 Lock short
+        ").unwrap()
+    }
+
+    #[test]
+    fn parse_function_table() {
+        let functions = functions();
+        assert_eq!(functions.by_name("additem"), Some(&ScriptFunction {
+            opcode: 0x100B, name: "AddItem".into(), args: vec![ScriptArgKind::String, ScriptArgKind::Long], returns_value: false
+        }));
+        assert_eq!(functions.get(0x1023).map(|x| x.returns_value), Some(true));
+        let error = ScriptFunctions::parse("1009 GetJournalIndex string\n1001 Say text").unwrap_err();
+        assert_eq!(error.to_string(), "function table line 2: unknown argument kind 'text'");
+        let error = ScriptFunctions::parse("0106 Test").unwrap_err();
+        assert_eq!(error.to_string(), "function table line 1: Test opcode 0106h is reserved for statements");
+    }
+
+    fn bytecode() -> Vec<u8> {
+        let mut code = Vec::new();
+        code.extend_from_slice(&[0x06, 0x01, 3, 17]);
+        code.extend_from_slice(b"X\x09\x10\x07A1_Ring >= 10");
+        code.extend_from_slice(&[0x05, 0x01, b's', 1, 0, 7]);
+        code.extend_from_slice(b"s\x01\x00 + 1");
+        code.extend_from_slice(&[0x0C, 0x01, 7]);
+        code.extend_from_slice(b"fargoth");
+        code.extend_from_slice(&[0x0B, 0x10, 8]);
+        code.extend_from_slice(b"gold_001");
+        code.extend_from_slice(&25i32.to_le_bytes());
+        code.extend_from_slice(&[0x00, 0x10, 5]);
+        code.extend_from_slice(b"%.2f!");
+        code.extend_from_slice(&[1, b'f', 1, 0, 1, 2]);
+        code.extend_from_slice(b"Ok");
+        code.extend_from_slice(&[0x09, 0x01, 0x01, 0x01]);
+        code
+    }
+
+    #[test]
+    fn disassemble_bytecode() {
+        let lines = disassemble(&bytecode(), CodePage::English, &functions()).unwrap();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0].instruction, Instruction::If {
+            skip: 3,
+            expr: vec![
+                ExprToken::Call(FunctionCall {
+                    reference: None,
+                    opcode: 0x1009,
+                    args: vec![ScriptArg::String("A1_Ring".into())]
+                }),
+                ExprToken::Operator(">=".into()),
+                ExprToken::Number("10".into()),
+            ]
+        });
+        assert_eq!(lines[2].offset, 34);
+        assert_eq!(lines[5].instruction, Instruction::End);
+        let unknown = [
+            0x06, 0x01, 0, 6, b'X', 0xFF, 0x7F, 1, 2, 3,
+            0x0C, 0x01, 1, b'a', 0xFE, 0x7F, 4, 0x01,
+            0x31, 0x10, 5, 0,
+            0x01, 0x01
+        ];
+        let unknown_lines = disassemble(&unknown, CodePage::English, &functions()).unwrap();
+        assert_eq!(unknown_lines.iter().map(|x| &x.instruction).collect::<Vec<_>>(), vec![
+            &Instruction::If {
+                skip: 0,
+                expr: vec![ExprToken::Unknown(UnknownCall { reference: None, opcode: 0x7FFF, data: vec![1, 2, 3] })]
+            },
+            &Instruction::Unknown(UnknownCall { reference: Some("a".into()), opcode: 0x7FFE, data: vec![4, 0x01] }),
+            &Instruction::Call(FunctionCall { reference: None, opcode: 0x1031, args: vec![ScriptArg::Short(5)] }),
+            &Instruction::End,
+        ]);
+        assert_eq!(assemble(unknown_lines.iter().map(|x| &x.instruction), CodePage::English).unwrap(), unknown);
+        let error = disassemble(&[0x05, 0x01, b's', 1], CodePage::English, &functions()).unwrap_err();
+        assert!(matches!(error, ScriptError::UnexpectedEnd { offset: 4 }));
+        let error = disassemble(&[0xFE, 0x7F, 1, 2], CodePage::English, &functions()).unwrap_err();
+        assert!(matches!(error, ScriptError::UnknownFunction { offset: 0, opcode: 0x7FFE }));
+        let mut many = [0x31, 0x10, 5, 0, 0xFE, 0x7F, 1].repeat(3000);
+        many.extend_from_slice(&[0x01, 0x01]);
+        let many_lines = disassemble(&many, CodePage::English, &ScriptFunctions::default()).unwrap();
+        assert_eq!(many_lines.last().map(|x| &x.instruction), Some(&Instruction::End));
+        assert_eq!(assemble(many_lines.iter().map(|x| &x.instruction), CodePage::English).unwrap(), many);
+        let instructions = lines.iter().map(|x| &x.instruction);
+        assert_eq!(assemble(instructions, CodePage::English).unwrap(), bytecode());
+    }
+
+    #[test]
+    fn decompile_script() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(5));
+        encoder.write_all(&bytecode()).unwrap();
+        let record = Record {
+            tag: SCPT,
+            flags: RecordFlags::empty(),
+            fields: vec![
+                (SCHD, Field::ScriptMetadata(ScriptMetadata {
+                    name: "ring_script".into(),
+                    vars: ScriptVars { shorts: 1, longs: 0, floats: 1 },
+                    data_size: 0,
+                    var_table_size: 0
+                })),
+                (SCVR, Field::StringZList(StringZList::from(vec!["count".to_string(), "timer".to_string()]))),
+                (SCDT, Field::U8List(encoder.finish().unwrap())),
+            ]
+        };
+        let script = Script::from_record(&record, CodePage::English, &functions()).unwrap();
+        let context = ScriptContext::new(functions(), &[
+            Record { tag: NPC_, flags: RecordFlags::empty(), fields: vec![
+                (NAME, Field::StringZ("fargoth".into())),
+                (SCRI, Field::StringZ("ring_script".into())),
+            ] },
+            record.clone(),
+        ]);
+        assert_eq!(script.decompile(&context), "\
+Begin ring_script
+
+short count
+float timer
+
+if ( GetJournalIndex A1_Ring >= 10 )
+\tset count to count + 1
+\tfargoth->AddItem gold_001 25
+\tMessageBox \"%.2f!\" timer \"Ok\"
+endif
+
+End
+");
+        assert_eq!(script.listing(&context).lines().next(), Some("0000  if ( GetJournalIndex A1_Ring >= 10 ) [skip 3]"));
+        let remote = Script {
+            code: vec![
+                ScriptLine { offset: 0, instruction: Instruction::Set {
+                    var: VarRef::Remote { object: "fargoth".into(), var_type: ScriptVarType::Float, index: 1 },
+                    expr: vec![ExprToken::Var(VarRef::Remote { object: "vivec".into(), var_type: ScriptVarType::Long, index: 2 })]
+                } },
+                ScriptLine { offset: 0, instruction: Instruction::End },
+            ],
+            ..script
+        };
+        assert_eq!(remote.decompile(&context).lines().nth(5), Some("set fargoth.timer to vivec.long2"));
+    }
+}
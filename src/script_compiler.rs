@@ -0,0 +1,654 @@
+use encoding::EncoderTrap;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io::Write;
+
+use crate::code::CodePage;
+use crate::field::*;
+use crate::record::*;
+use crate::script::*;
+use crate::strings::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for CompileError { }
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    Word(String),
+    String(String),
+    Operator(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(s) => write!(f, "{}", s),
+            Token::String(s) => write!(f, "{}", quote_string(s)),
+            Token::Operator(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+const OPERATORS: &[&str] = &["->", "==", "!=", "<=", ">=", "<", ">", "(", ")", "+", "-", "*", "/"];
+
+pub(crate) fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = line;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() || rest.starts_with(';') { break; }
+        if let Some(string) = rest.strip_prefix('"') {
+            let end = string.match_indices('"').map(|(i, _)| i).find(|&i| !string[.. i].ends_with('\\'))
+                .ok_or_else(|| "unterminated string".to_string())?;
+            tokens.push(Token::String(string[.. end].replace("\\\"", "\"")));
+            rest = &string[end + 1 ..];
+        } else if let Some(&operator) = OPERATORS.iter().find(|&&x| rest.starts_with(x)) {
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len() ..];
+        } else if rest.starts_with(&['=', '!'][..]) {
+            return Err(format!("unexpected '{}'", &rest[.. 1]));
+        } else {
+            let end = rest.find(|c: char|
+                c.is_whitespace() || c == ',' || c == ';' || c == '"' || c == '=' || c == '!' ||
+                OPERATORS.iter().any(|x| x.starts_with(c))
+            ).unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[.. end].to_string()));
+            rest = &rest[end ..];
+        }
+    }
+    Ok(tokens)
+}
+
+pub(crate) fn is_keyword(word: &str, keyword: &str) -> bool {
+    word.eq_ignore_ascii_case(keyword)
+}
+
+pub(crate) fn is_number(word: &str) -> bool {
+    word.chars().all(|c| c.is_ascii_digit() || c == '.') && word.parse::<f64>().is_ok()
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum BlockKind {
+    If,
+    While,
+}
+
+struct Block {
+    kind: BlockKind,
+    branch: usize,
+    line: usize,
+    in_else: bool,
+}
+
+struct Compiler<'a> {
+    context: &'a ScriptContext,
+    name: Option<String>,
+    ended: bool,
+    var_names: ScriptVarNames,
+    locals: HashMap<String, (ScriptVarType, u16)>,
+    instructions: Vec<(usize, Instruction)>,
+    blocks: Vec<Block>,
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Compiler<'a> {
+    fn var(&self, word: &str) -> Result<VarRef, String> {
+        if let Some(&(var_type, index)) = self.locals.get(&word.to_lowercase()) {
+            return Ok(VarRef::Local { var_type, index });
+        }
+        if let Some(dot) = word.find('.') {
+            return Self::remote_var(self.context, &word[.. dot], &word[dot + 1 ..]);
+        }
+        if self.context.globals.contains(&word.to_lowercase()) {
+            return Ok(VarRef::Global(word.to_string()));
+        }
+        Err(format!("undeclared variable '{}'", word))
+    }
+
+    fn remote_var(context: &ScriptContext, object: &str, name: &str) -> Result<VarRef, String> {
+        let (var_type, index) = if let Some(vars) = context.object_vars.get(&object.to_lowercase()) {
+            vars.find(name).ok_or_else(|| format!("'{}' has no variable '{}'", object, name))?
+        } else {
+            [ScriptVarType::Short, ScriptVarType::Long, ScriptVarType::Float].iter().find_map(|&var_type|
+                name.get(.. var_type.keyword().len()).filter(|x| x.eq_ignore_ascii_case(var_type.keyword()))
+                    .and_then(|_| name[var_type.keyword().len() ..].parse::<u16>().ok())
+                    .filter(|&index| index != 0)
+                    .map(|index| (var_type, index))
+            ).ok_or_else(|| format!("remote variable '{}.{}' cannot be resolved", object, name))?
+        };
+        Ok(VarRef::Remote { object: object.to_string(), var_type, index })
+    }
+
+    fn arg(kind: ScriptArgKind, token: Option<&Token>, function: &str) -> Result<ScriptArg, String> {
+        let token = token.ok_or_else(|| format!("missing argument for {}", function))?;
+        let text = match token {
+            Token::Word(s) | Token::String(s) => s.as_str(),
+            Token::Operator(_) => return Err(format!("unexpected '{}' in {} arguments", token, function)),
+        };
+        let invalid = || format!("invalid {} argument '{}'", function, text);
+        Ok(match kind {
+            ScriptArgKind::String => ScriptArg::String(text.to_string()),
+            ScriptArgKind::Short => ScriptArg::Short(text.parse().map_err(|_| invalid())?),
+            ScriptArgKind::Long => ScriptArg::Long(text.parse().map_err(|_| invalid())?),
+            ScriptArgKind::Float => ScriptArg::Float(text.parse().map_err(|_| invalid())?),
+            ScriptArgKind::Axis => match text {
+                "x" | "X" => ScriptArg::Axis('X'),
+                "y" | "Y" => ScriptArg::Axis('Y'),
+                "z" | "Z" => ScriptArg::Axis('Z'),
+                _ => return Err(invalid())
+            },
+        })
+    }
+
+    fn call<'t>(&self, reference: Option<String>, function: &ScriptFunction, args: &mut impl Iterator<Item=&'t Token>)
+        -> Result<FunctionCall, String> {
+
+        let args = function.args.iter().map(|&kind| Self::arg(kind, args.next(), &function.name)).collect::<Result<_, _>>()?;
+        Ok(FunctionCall { reference, opcode: function.opcode, args })
+    }
+
+    fn expr(&self, mut tokens: &[Token]) -> Result<Vec<ExprToken>, String> {
+        if tokens.first() == Some(&Token::Operator("(")) && tokens.last() == Some(&Token::Operator(")")) {
+            let mut depth = 0;
+            let wraps = tokens.iter().enumerate().all(|(i, token)| {
+                match token {
+                    Token::Operator("(") => depth += 1,
+                    Token::Operator(")") => depth -= 1,
+                    _ => { }
+                }
+                depth > 0 || i == tokens.len() - 1
+            });
+            if wraps {
+                tokens = &tokens[1 .. tokens.len() - 1];
+            }
+        }
+        let mut expr = Vec::new();
+        let mut expect_operand = true;
+        let mut depth = 0usize;
+        let mut tokens = tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            match token {
+                Token::Operator("(") if expect_operand => {
+                    depth += 1;
+                    expr.push(ExprToken::Operator("(".into()));
+                },
+                Token::Operator(")") if !expect_operand && depth > 0 => {
+                    depth -= 1;
+                    expr.push(ExprToken::Operator(")".into()));
+                },
+                Token::Operator("-") if expect_operand => match tokens.next() {
+                    Some(Token::Word(number)) if is_number(number) => {
+                        expr.push(ExprToken::Number(format!("-{}", number)));
+                        expect_operand = false;
+                    },
+                    _ => return Err("unary minus is supported for numbers only".into())
+                },
+                Token::Operator(operator) if !expect_operand && !["->", "(", ")"].contains(operator) => {
+                    expr.push(ExprToken::Operator(operator.to_string()));
+                    expect_operand = true;
+                },
+                Token::Word(word) | Token::String(word) if expect_operand => {
+                    if tokens.peek() == Some(&&Token::Operator("->")) {
+                        tokens.next();
+                        let function = match tokens.next() {
+                            Some(Token::Word(name)) => name,
+                            _ => return Err(format!("function name expected after '{}->'", word))
+                        };
+                        let function = self.value_function(function)?;
+                        expr.push(ExprToken::Call(self.call(Some(word.clone()), function, &mut tokens)?));
+                    } else if let Token::String(_) = token {
+                        return Err(format!("unexpected {} in expression", token));
+                    } else if is_number(word) {
+                        expr.push(ExprToken::Number(word.clone()));
+                    } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+                        return Err(format!("invalid number '{}'", word));
+                    } else if let Some(function) = self.context.functions.by_name(word).filter(|_| !self.locals.contains_key(&word.to_lowercase())) {
+                        if !function.returns_value {
+                            return Err(format!("{} does not return a value", function.name));
+                        }
+                        expr.push(ExprToken::Call(self.call(None, function, &mut tokens)?));
+                    } else {
+                        expr.push(ExprToken::Var(self.var(word)?));
+                    }
+                    expect_operand = false;
+                },
+                token => return Err(format!("unexpected '{}' in expression", token))
+            }
+        }
+        if expect_operand {
+            return Err("incomplete expression".into());
+        }
+        if depth != 0 {
+            return Err("unbalanced parentheses".into());
+        }
+        Ok(expr)
+    }
+
+    fn value_function(&self, name: &str) -> Result<&'a ScriptFunction, String> {
+        let function = self.context.functions.by_name(name).ok_or_else(|| format!("unknown function '{}'", name))?;
+        if !function.returns_value {
+            return Err(format!("{} does not return a value", function.name));
+        }
+        Ok(function)
+    }
+
+    fn declare(&mut self, var_type: ScriptVarType, tokens: &[Token]) -> Result<(), String> {
+        let name = match tokens {
+            [Token::Word(name)] if !is_number(name) => name,
+            _ => return Err(format!("{} declaration expects a single variable name", var_type.keyword()))
+        };
+        if self.locals.contains_key(&name.to_lowercase()) {
+            return Err(format!("variable '{}' is already declared", name));
+        }
+        let names = match var_type {
+            ScriptVarType::Short => &mut self.var_names.shorts,
+            ScriptVarType::Long => &mut self.var_names.longs,
+            ScriptVarType::Float => &mut self.var_names.floats,
+        };
+        names.push(name.clone());
+        self.locals.insert(name.to_lowercase(), (var_type, names.len() as u16));
+        Ok(())
+    }
+
+    fn statement(&mut self, line: usize, tokens: &[Token]) -> Result<(), String> {
+        let keyword = match &tokens[0] {
+            Token::Word(word) => word.to_lowercase(),
+            _ => String::new()
+        };
+        if self.ended {
+            return Err("statement after End".into());
+        }
+        if self.name.is_none() {
+            return match (keyword.as_str(), tokens) {
+                ("begin", [_, Token::Word(name)]) | ("begin", [_, Token::String(name)]) => {
+                    self.name = Some(name.clone());
+                    Ok(())
+                },
+                _ => Err("script should start with Begin <name>".into())
+            };
+        }
+        let instruction = match keyword.as_str() {
+            "begin" => return Err("unexpected Begin".into()),
+            "short" => return self.declare(ScriptVarType::Short, &tokens[1 ..]),
+            "long" => return self.declare(ScriptVarType::Long, &tokens[1 ..]),
+            "float" => return self.declare(ScriptVarType::Float, &tokens[1 ..]),
+            "end" => {
+                self.ended = true;
+                if let Some(block) = self.blocks.pop() {
+                    self.blocks.clear();
+                    let end = if block.kind == BlockKind::If { "endif" } else { "endwhile" };
+                    return Err(format!("missing {} for block started at line {}", end, block.line));
+                }
+                Instruction::End
+            },
+            "return" => Instruction::Return,
+            "set" => {
+                let to = tokens.iter().position(|x| matches!(x, Token::Word(w) if is_keyword(w, "to")))
+                    .ok_or_else(|| "set statement expects 'to'".to_string())?;
+                let var = match &tokens[1 .. to] {
+                    [Token::Word(name)] => self.var(name)?,
+                    _ => return Err("set statement expects a variable name".into())
+                };
+                Instruction::Set { var, expr: self.expr(&tokens[to + 1 ..])? }
+            },
+            "if" | "while" => {
+                let kind = if keyword == "if" { BlockKind::If } else { BlockKind::While };
+                self.blocks.push(Block { kind, branch: self.instructions.len(), line, in_else: false });
+                return self.branch(line, &tokens[1 ..], |expr| match kind {
+                    BlockKind::If => Instruction::If { skip: 0, expr },
+                    BlockKind::While => Instruction::While { skip: 0, expr },
+                });
+            },
+            "elseif" | "else" | "endif" | "endwhile" => {
+                let kind = if keyword == "endwhile" { BlockKind::While } else { BlockKind::If };
+                let block = match self.blocks.pop() {
+                    Some(block) if block.kind == kind => block,
+                    _ => return Err(format!("unexpected {}", keyword))
+                };
+                if block.in_else && keyword != "endif" {
+                    self.blocks.push(block);
+                    return Err(format!("unexpected {} after else", keyword));
+                }
+                self.set_skip(&block)?;
+                let branch = self.instructions.len();
+                match keyword.as_str() {
+                    "elseif" => {
+                        self.blocks.push(Block { kind, branch, line, in_else: false });
+                        return self.branch(line, &tokens[1 ..], |expr| Instruction::ElseIf { skip: 0, expr });
+                    },
+                    "else" => {
+                        self.blocks.push(Block { kind, branch, line, in_else: true });
+                        Instruction::Else { skip: 0 }
+                    },
+                    "endif" => Instruction::EndIf,
+                    _ => Instruction::EndWhile
+                }
+            },
+            _ => self.function_statement(tokens)?
+        };
+        if tokens.len() > 1 && matches!(instruction, Instruction::End | Instruction::Return |
+            Instruction::Else { .. } | Instruction::EndIf | Instruction::EndWhile) && keyword != "end" {
+            return Err(format!("unexpected '{}' after {}", tokens[1], keyword));
+        }
+        self.instructions.push((line, instruction));
+        Ok(())
+    }
+
+    fn branch(&mut self, line: usize, tokens: &[Token], instruction: impl FnOnce(Vec<ExprToken>) -> Instruction)
+        -> Result<(), String> {
+
+        let expr = self.expr(tokens);
+        let instruction = instruction(expr.as_ref().map_or_else(|_| Vec::new(), |x| x.clone()));
+        self.instructions.push((line, instruction));
+        expr.map(|_| ())
+    }
+
+    fn set_skip(&mut self, block: &Block) -> Result<(), String> {
+        let count = self.instructions.len() - block.branch - 1;
+        let count = if count > u8::MAX as usize {
+            return Err(format!("block started at line {} contains more than 255 statements", block.line));
+        } else {
+            count as u8
+        };
+        match &mut self.instructions[block.branch].1 {
+            Instruction::If { skip, .. } | Instruction::ElseIf { skip, .. } |
+            Instruction::Else { skip } | Instruction::While { skip, .. } => *skip = count,
+            _ => unreachable!()
+        }
+        Ok(())
+    }
+
+    fn function_statement(&self, tokens: &[Token]) -> Result<Instruction, String> {
+        let (reference, tokens) = match tokens {
+            [Token::Word(reference), Token::Operator("->"), rest @ ..] |
+            [Token::String(reference), Token::Operator("->"), rest @ ..] => (Some(reference.clone()), rest),
+            _ => (None, tokens)
+        };
+        let name = match tokens.first() {
+            Some(Token::Word(name)) => name,
+            Some(token) => return Err(format!("unexpected '{}'", token)),
+            None => return Err("function name expected".into())
+        };
+        let mut args = tokens[1 ..].iter();
+        let instruction = if is_keyword(name, "MessageBox") {
+            let text = match args.next() {
+                Some(Token::String(text)) | Some(Token::Word(text)) => text.clone(),
+                _ => return Err("MessageBox expects a text".into())
+            };
+            let mut vars = Vec::new();
+            let mut buttons = Vec::new();
+            for arg in args {
+                match arg {
+                    Token::Word(var) if buttons.is_empty() => vars.push(self.var(var)?),
+                    Token::String(button) => buttons.push(button.clone()),
+                    token => return Err(format!("unexpected '{}' in MessageBox arguments", token))
+                }
+            }
+            if vars.len() > u8::MAX as usize || buttons.len() > u8::MAX as usize {
+                return Err("too many MessageBox arguments".into());
+            }
+            Instruction::MessageBox { reference, text, vars, buttons }
+        } else {
+            let function = self.context.functions.by_name(name).ok_or_else(|| format!("unknown function '{}'", name))?;
+            let call = self.call(reference, function, &mut args)?;
+            if let Some(token) = args.next() {
+                return Err(format!("unexpected '{}' after {} arguments", token, function.name));
+            }
+            Instruction::Call(call)
+        };
+        Ok(instruction)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompiledScript {
+    pub metadata: ScriptMetadata,
+    pub var_names: StringZList,
+    pub data: Vec<u8>,
+}
+
+impl CompiledScript {
+    pub fn apply(&self, record: &mut Record) {
+        let position = record.fields.iter().position(|(tag, _)| [SCHD, SCVR, SCDT, SCTX].contains(tag))
+            .unwrap_or(record.fields.len());
+        record.fields.retain(|(tag, _)| ![SCHD, SCVR, SCDT].contains(tag));
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(5));
+        encoder.write_all(&self.data).unwrap();
+        let mut fields = vec![(SCHD, Field::ScriptMetadata(self.metadata.clone()))];
+        if !self.var_names.vec.is_empty() {
+            fields.push((SCVR, Field::StringZList(self.var_names.clone())));
+        }
+        fields.push((SCDT, Field::U8List(encoder.finish().unwrap())));
+        let position = position.min(record.fields.len());
+        record.fields.splice(position .. position, fields);
+    }
+}
+
+pub fn compile<S: AsRef<str>>(source: &[S], code_page: CodePage, context: &ScriptContext)
+    -> Result<CompiledScript, Vec<CompileError>> {
+
+    let mut compiler = Compiler {
+        context,
+        name: None,
+        ended: false,
+        var_names: ScriptVarNames::default(),
+        locals: HashMap::new(),
+        instructions: Vec::new(),
+        blocks: Vec::new(),
+        errors: Vec::new(),
+    };
+    for (index, text) in source.iter().enumerate() {
+        let line = index + 1;
+        let result = tokenize(text.as_ref()).and_then(|tokens|
+            if tokens.is_empty() { Ok(()) } else { compiler.statement(line, &tokens) }
+        );
+        if let Err(message) = result {
+            compiler.errors.push(CompileError { line, message });
+        }
+    }
+    let last_line = source.len();
+    if compiler.name.is_none() {
+        compiler.errors.push(CompileError { line: last_line, message: "script should start with Begin <name>".into() });
+    } else if !compiler.ended {
+        compiler.errors.push(CompileError { line: last_line, message: "missing End".into() });
+    }
+    let mut data = Vec::new();
+    for (line, instruction) in &compiler.instructions {
+        if let Err(e) = assemble_instruction(&mut data, instruction, code_page) {
+            compiler.errors.push(CompileError { line: *line, message: e.to_string() });
+        }
+    }
+    let name = compiler.name.unwrap_or_default();
+    if code_page.encoding().encode(&name, EncoderTrap::Strict).map_or(true, |x| x.len() > 32) {
+        compiler.errors.push(CompileError { line: 1, message: format!("invalid script name '{}'", name) });
+    }
+    if !compiler.errors.is_empty() {
+        compiler.errors.sort_by_key(|x| x.line);
+        return Err(compiler.errors);
+    }
+    let names = &compiler.var_names;
+    let var_names = names.shorts.iter().chain(names.longs.iter()).chain(names.floats.iter()).cloned().collect::<Vec<_>>();
+    let var_table_size = var_names.iter()
+        .map(|x| code_page.encoding().encode(x, EncoderTrap::Strict).map_or(x.len(), |x| x.len()) + 1)
+        .sum::<usize>();
+    Ok(CompiledScript {
+        metadata: ScriptMetadata {
+            name,
+            vars: ScriptVars {
+                shorts: names.shorts.len() as u32,
+                longs: names.longs.len() as u32,
+                floats: names.floats.len() as u32
+            },
+            data_size: data.len() as u32,
+            var_table_size: var_table_size as u32
+        },
+        var_names: var_names.into(),
+        data
+    })
+}
+
+pub fn compile_record(record: &mut Record, code_page: CodePage, context: &ScriptContext) -> Result<(), Vec<CompileError>> {
+    let source = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (SCTX, Field::StringList(lines)) => Some(lines.clone()),
+        _ => None
+    }).ok_or_else(|| vec![CompileError { line: 0, message: format!("{} field is missing", SCTX) }])?;
+    compile(&source, code_page, context)?.apply(record);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::tests::functions;
+
+    const SOURCE: &[&str] = &[
+        "Begin ring_script",
+        "",
+        "short count",
+        "float timer ; seconds",
+        "",
+        "if ( GetJournalIndex A1_Ring >= 10 )",
+        "\tset count to count + 1",
+        "\tfargoth->AddItem gold_001, 25",
+        "\tMessageBox \"%.2f!\" timer \"Ok\"",
+        "elseif ( count == -1 )",
+        "\tset timer to ( timer + GetSecondsPassed ) * 2",
+        "else",
+        "\tset GlobalCounter to 0",
+        "\tset fargoth.state to vivec.long2",
+        "endif",
+        "",
+        "End ring_script",
+    ];
+
+    fn context() -> ScriptContext {
+        ScriptContext::new(functions(), &[
+            Record { tag: GLOB, flags: RecordFlags::empty(), fields: vec![(NAME, Field::StringZ("GlobalCounter".into()))] },
+            Record { tag: NPC_, flags: RecordFlags::empty(), fields: vec![
+                (NAME, Field::StringZ("fargoth".into())),
+                (SCRI, Field::StringZ("fargoth_script".into())),
+            ] },
+            Record { tag: SCPT, flags: RecordFlags::empty(), fields: vec![
+                (SCHD, Field::ScriptMetadata(ScriptMetadata {
+                    name: "fargoth_script".into(),
+                    vars: ScriptVars { shorts: 0, longs: 1, floats: 1 },
+                    data_size: 0,
+                    var_table_size: 0
+                })),
+                (SCVR, Field::StringZList(vec!["state".to_string(), "speed".to_string()].into())),
+            ] },
+        ])
+    }
+
+    #[test]
+    fn compile_script() {
+        let mut record = Record {
+            tag: SCPT,
+            flags: RecordFlags::empty(),
+            fields: vec![(SCTX, Field::StringList(SOURCE.iter().map(|x| x.to_string()).collect()))]
+        };
+        let context = context();
+        compile_record(&mut record, CodePage::English, &context).unwrap();
+        assert_eq!(record.fields.iter().map(|x| x.0).collect::<Vec<_>>(), vec![SCHD, SCVR, SCDT, SCTX]);
+        let script = Script::from_record(&record, CodePage::English, &functions()).unwrap();
+        assert_eq!(script.name, "ring_script");
+        assert_eq!(script.vars, ScriptVars { shorts: 1, longs: 0, floats: 1 });
+        let skips = script.code.iter().filter_map(|x| match x.instruction {
+            Instruction::If { skip, .. } | Instruction::ElseIf { skip, .. } | Instruction::Else { skip } => Some(skip),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(skips, vec![3, 1, 2]);
+        assert_eq!(script.code[script.code.len() - 3].instruction, Instruction::Set {
+            var: VarRef::Remote { object: "fargoth".into(), var_type: ScriptVarType::Long, index: 1 },
+            expr: vec![ExprToken::Var(VarRef::Remote { object: "vivec".into(), var_type: ScriptVarType::Long, index: 2 })]
+        });
+        assert_eq!(script.decompile(&context), "\
+Begin ring_script
+
+short count
+float timer
+
+if ( GetJournalIndex A1_Ring >= 10 )
+\tset count to count + 1
+\tfargoth->AddItem gold_001 25
+\tMessageBox \"%.2f!\" timer \"Ok\"
+elseif ( count == -1 )
+\tset timer to ( timer + GetSecondsPassed ) * 2
+else
+\tset GlobalCounter to 0
+\tset fargoth.state to vivec.long2
+endif
+
+End
+");
+        let compiled = compile(SOURCE, CodePage::English, &context).unwrap();
+        assert_eq!(compiled.metadata.data_size as usize, compiled.data.len());
+        assert_eq!(compiled.metadata.var_table_size, 12);
+        let source = ["Begin quotes", "MessageBox \"Say \\\"hi\\\"\" \"\\\"Ok\\\"\"", "End"];
+        let compiled = compile(&source, CodePage::English, &context).unwrap();
+        let code = disassemble(&compiled.data, CodePage::English, &functions()).unwrap();
+        assert_eq!(code[0].instruction, Instruction::MessageBox {
+            reference: None, text: "Say \"hi\"".into(), vars: Vec::new(), buttons: vec!["\"Ok\"".into()]
+        });
+        let script = Script { name: "quotes".into(), vars: compiled.metadata.vars, var_names: Default::default(), code };
+        assert_eq!(script.decompile(&context), format!("Begin quotes\n\n{}\n\nEnd\n", source[1]));
+    }
+
+    #[test]
+    fn compile_errors() {
+        let errors = compile(&[
+            "Begin broken",
+            "short x",
+            "short x",
+            "if ( x == )",
+            "AddItem gold_001",
+            "FlyAway",
+            "set x to \"text",
+            "set x to 1e5",
+            "set y to GlobalCounter",
+            "set fargoth.mood to 1",
+            "set vivec.mood to 1",
+            "End",
+        ], CodePage::English, &context()).unwrap_err();
+        assert_eq!(errors.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec![
+            "line 3: variable 'x' is already declared",
+            "line 4: incomplete expression",
+            "line 5: missing argument for AddItem",
+            "line 6: unknown function 'FlyAway'",
+            "line 7: unterminated string",
+            "line 8: invalid number '1e5'",
+            "line 9: undeclared variable 'y'",
+            "line 10: 'fargoth' has no variable 'mood'",
+            "line 11: remote variable 'vivec.mood' cannot be resolved",
+            "line 12: missing endif for block started at line 4",
+        ]);
+        let errors = compile(&[
+            "Begin branches",
+            "short x",
+            "if ( x == 1 )",
+            "else",
+            "elseif ( x == 2 )",
+            "else",
+            "endif",
+            "End",
+        ], CodePage::English, &context()).unwrap_err();
+        assert_eq!(errors.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec![
+            "line 5: unexpected elseif after else",
+            "line 6: unexpected else after else",
+        ]);
+    }
+}
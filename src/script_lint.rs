@@ -0,0 +1,366 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+
+use crate::dialogue::record_string;
+use crate::field::*;
+use crate::record::*;
+use crate::script::*;
+use crate::script_compiler::CompileError;
+use crate::script_parser::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LintKind {
+    Syntax(String),
+    MissingEnd,
+    NameMismatch { begin: String, header: String },
+    VarCountMismatch { declared: ScriptVars, header: ScriptVars },
+    DuplicateVariable(String),
+    UndeclaredVariable(String),
+    UnusedVariable(String),
+    UnknownId(String),
+    UnknownFunction(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Lint {
+    pub line: usize,
+    pub kind: LintKind,
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line != 0 {
+            write!(f, "line {}: ", self.line)?;
+        }
+        match &self.kind {
+            LintKind::Syntax(message) => write!(f, "{}", message),
+            LintKind::MissingEnd => write!(f, "missing End"),
+            LintKind::NameMismatch { begin, header } =>
+                write!(f, "Begin {} does not match {} name '{}'", begin, SCHD, header),
+            LintKind::VarCountMismatch { declared, header } => write!(f,
+                "{} declares {}/{}/{} short/long/float variables, but the source declares {}/{}/{}",
+                SCHD, header.shorts, header.longs, header.floats, declared.shorts, declared.longs, declared.floats
+            ),
+            LintKind::DuplicateVariable(name) => write!(f, "variable '{}' is declared more than once", name),
+            LintKind::UndeclaredVariable(name) => write!(f, "undeclared variable '{}'", name),
+            LintKind::UnusedVariable(name) => write!(f, "variable '{}' is never used", name),
+            LintKind::UnknownId(id) => write!(f, "unknown ID '{}'", id),
+            LintKind::UnknownFunction(name) => write!(f, "unknown function '{}'", name),
+        }
+    }
+}
+
+const NON_ID_ARGS: &[&str] = &["Say", "StreamMusic", "PlayGroup", "ShowMap", "MessageBox"];
+
+#[derive(Debug, Clone, Default)]
+pub struct LintContext {
+    pub functions: ScriptFunctions,
+    pub ids: HashSet<String>,
+    pub globals: HashSet<String>,
+    pub script_locals: HashMap<String, HashSet<String>>,
+    pub actor_scripts: HashMap<String, String>,
+}
+
+fn script_source(record: &Record) -> Option<&[String]> {
+    record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (SCTX, Field::StringList(lines)) => Some(&lines[..]),
+        _ => None
+    })
+}
+
+fn script_header(record: &Record) -> Option<&ScriptMetadata> {
+    record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (SCHD, Field::ScriptMetadata(metadata)) => Some(metadata),
+        _ => None
+    })
+}
+
+fn declarations(nodes: &[Node]) -> Vec<(usize, ScriptVarType, &str)> {
+    nodes.iter().filter_map(|node| match &node.statement {
+        Statement::Declare { var_type, name } => Some((node.line, *var_type, name.as_str())),
+        _ => None
+    }).collect()
+}
+
+impl LintContext {
+    pub fn new<'a>(functions: ScriptFunctions, records: impl IntoIterator<Item=&'a Record>) -> LintContext {
+        let mut context = LintContext { functions, ..LintContext::default() };
+        context.ids.insert("player".into());
+        for record in records {
+            if record.flags.contains(RecordFlags::DELETED) { continue; }
+            match record.tag {
+                INFO => { },
+                SCPT => if let Some(header) = script_header(record) {
+                    let id = header.name.to_lowercase();
+                    context.ids.insert(id.clone());
+                    if let Some(source) = script_source(record) {
+                        let (ast, _) = parse_script(source, &context.functions);
+                        let locals = declarations(&ast.body).into_iter().map(|x| x.2.to_lowercase()).collect();
+                        context.script_locals.insert(id, locals);
+                    }
+                },
+                tag => if let Some(id) = record_string(record, NAME) {
+                    let id = id.to_lowercase();
+                    if tag == GLOB {
+                        context.globals.insert(id.clone());
+                    }
+                    if let Some(script) = record_string(record, SCRI).filter(|x| !x.is_empty()) {
+                        context.actor_scripts.insert(id.clone(), script.to_lowercase());
+                    }
+                    context.ids.insert(id);
+                }
+            }
+        }
+        context
+    }
+}
+
+struct Linter<'a> {
+    context: &'a LintContext,
+    locals: Option<HashSet<String>>,
+    used: HashSet<String>,
+    lints: Vec<Lint>,
+}
+
+impl<'a> Linter<'a> {
+    fn id(&mut self, line: usize, id: &str) {
+        if !self.context.ids.contains(&id.to_lowercase()) {
+            self.lints.push(Lint { line, kind: LintKind::UnknownId(id.to_string()) });
+        }
+    }
+
+    fn var(&mut self, line: usize, name: &str) {
+        if let Some(dot) = name.find('.') {
+            let object = name[.. dot].to_lowercase();
+            self.id(line, &name[.. dot]);
+            if !self.context.ids.contains(&object) { return; }
+            let declared = match self.context.actor_scripts.get(&object) {
+                Some(script) => self.context.script_locals.get(script).map_or(true, |x| x.contains(&name[dot + 1 ..].to_lowercase())),
+                None => false
+            };
+            if !declared {
+                self.lints.push(Lint { line, kind: LintKind::UndeclaredVariable(name.to_string()) });
+            }
+            return;
+        }
+        let name_lower = name.to_lowercase();
+        self.used.insert(name_lower.clone());
+        if let Some(locals) = &self.locals {
+            if !locals.contains(&name_lower) && !self.context.globals.contains(&name_lower) {
+                self.lints.push(Lint { line, kind: LintKind::UndeclaredVariable(name.to_string()) });
+            }
+        }
+    }
+
+    fn call(&mut self, line: usize, call: &Call) {
+        if let Some(reference) = &call.reference {
+            self.id(line, reference);
+        }
+        let function = self.context.functions.by_name(&call.function);
+        if function.is_none() && !self.context.functions.is_empty() {
+            self.lints.push(Lint { line, kind: LintKind::UnknownFunction(call.function.clone()) });
+        }
+        let checks_ids = !NON_ID_ARGS.iter().any(|x| x.eq_ignore_ascii_case(&call.function));
+        for (index, arg) in call.args.iter().enumerate() {
+            let kind = function.and_then(|x| x.args.get(index));
+            match arg {
+                Arg::Word(word) | Arg::String(word) if checks_ids && kind == Some(&ScriptArgKind::String) =>
+                    self.id(line, word),
+                Arg::Word(word) => { self.used.insert(word.to_lowercase()); },
+                _ => { }
+            }
+        }
+    }
+
+    fn expr(&mut self, line: usize, expr: &Expr) {
+        match expr {
+            Expr::Number(_) => { },
+            Expr::Var(name) => self.var(line, name),
+            Expr::Call(call) => self.call(line, call),
+            Expr::Binary { left, right, .. } => {
+                self.expr(line, left);
+                self.expr(line, right);
+            },
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match &node.statement {
+                Statement::Declare { .. } | Statement::Return => { },
+                Statement::Set { target, value } => {
+                    self.var(node.line, target);
+                    self.expr(node.line, value);
+                },
+                Statement::If { branches, otherwise } => {
+                    for branch in branches {
+                        self.expr(branch.line, &branch.condition);
+                        self.nodes(&branch.body);
+                    }
+                    if let Some(otherwise) = otherwise {
+                        self.nodes(otherwise);
+                    }
+                },
+                Statement::While { condition, body } => {
+                    self.expr(node.line, condition);
+                    self.nodes(body);
+                },
+                Statement::Call(call) => self.call(node.line, call),
+            }
+        }
+    }
+}
+
+fn syntax_lints(errors: Vec<CompileError>) -> Vec<Lint> {
+    errors.into_iter().map(|x| Lint { line: x.line, kind: LintKind::Syntax(x.message) }).collect()
+}
+
+pub fn lint_script(record: &Record, context: &LintContext) -> Vec<Lint> {
+    let source = if let Some(source) = script_source(record) { source } else { return Vec::new(); };
+    let (ast, errors) = parse_script(source, &context.functions);
+    let mut lints = syntax_lints(errors);
+    if ast.end.is_none() {
+        lints.push(Lint { line: source.len(), kind: LintKind::MissingEnd });
+    }
+    let declarations = declarations(&ast.body);
+    let mut locals = HashSet::new();
+    let mut declared = ScriptVars { shorts: 0, longs: 0, floats: 0 };
+    for &(line, var_type, name) in &declarations {
+        if !locals.insert(name.to_lowercase()) {
+            lints.push(Lint { line, kind: LintKind::DuplicateVariable(name.to_string()) });
+            continue;
+        }
+        match var_type {
+            ScriptVarType::Short => declared.shorts += 1,
+            ScriptVarType::Long => declared.longs += 1,
+            ScriptVarType::Float => declared.floats += 1,
+        }
+    }
+    if let Some(header) = script_header(record) {
+        if let Some(begin) = &ast.name {
+            if !begin.eq_ignore_ascii_case(&header.name) {
+                lints.push(Lint { line: 1, kind: LintKind::NameMismatch { begin: begin.clone(), header: header.name.clone() } });
+            }
+        }
+        if header.vars != declared {
+            lints.push(Lint { line: 0, kind: LintKind::VarCountMismatch { declared, header: header.vars.clone() } });
+        }
+    }
+    let mut linter = Linter { context, locals: Some(locals), used: HashSet::new(), lints: Vec::new() };
+    linter.nodes(&ast.body);
+    let mut reported = HashSet::new();
+    for &(line, _, name) in &declarations {
+        if !linter.used.contains(&name.to_lowercase()) && reported.insert(name.to_lowercase()) {
+            linter.lints.push(Lint { line, kind: LintKind::UnusedVariable(name.to_string()) });
+        }
+    }
+    lints.extend(linter.lints);
+    lints.sort_by_key(|x| x.line);
+    lints
+}
+
+pub fn lint_result_script(record: &Record, context: &LintContext) -> Vec<Lint> {
+    let source = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+        (BNAM, Field::StringList(lines)) => Some(&lines[..]),
+        _ => None
+    });
+    let source = if let Some(source) = source { source } else { return Vec::new(); };
+    let (nodes, errors) = parse_result_script(source, &context.functions);
+    let mut lints = syntax_lints(errors);
+    let locals = record_string(record, ONAM)
+        .and_then(|speaker| context.actor_scripts.get(&speaker.to_lowercase()))
+        .and_then(|script| context.script_locals.get(script))
+        .cloned();
+    let mut linter = Linter { context, locals, used: HashSet::new(), lints: Vec::new() };
+    linter.nodes(&nodes);
+    lints.extend(linter.lints);
+    lints.sort_by_key(|x| x.line);
+    lints
+}
+
+pub fn lint_records(records: &[Record], functions: ScriptFunctions) -> Vec<(usize, Lint)> {
+    let context = LintContext::new(functions, records);
+    let mut lints = Vec::new();
+    for (index, record) in records.iter().enumerate() {
+        if record.flags.contains(RecordFlags::DELETED) { continue; }
+        let record_lints = match record.tag {
+            SCPT => lint_script(record, &context),
+            INFO => lint_result_script(record, &context),
+            _ => continue
+        };
+        lints.extend(record_lints.into_iter().map(|x| (index, x)));
+    }
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::tests::functions;
+
+    fn record(tag: Tag, fields: Vec<(Tag, Field)>) -> Record {
+        Record { tag, flags: RecordFlags::empty(), fields }
+    }
+
+    fn lines(source: &[&str]) -> Field {
+        Field::StringList(source.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn lint_scripts() {
+        let records = vec![
+            record(GLOB, vec![(NAME, Field::StringZ("DayCount".into()))]),
+            record(MISC, vec![(NAME, Field::StringZ("gold_001".into()))]),
+            record(NPC_, vec![(NAME, Field::StringZ("fargoth".into())), (SCRI, Field::StringZ("fargoth_script".into()))]),
+            record(SCPT, vec![
+                (SCHD, Field::ScriptMetadata(ScriptMetadata {
+                    name: "fargoth_script".into(),
+                    vars: ScriptVars { shorts: 2, longs: 0, floats: 0 },
+                    data_size: 0,
+                    var_table_size: 0
+                })),
+                (SCTX, lines(&[
+                    "Begin Fargoth_Script",
+                    "short state",
+                    "short unused",
+                    "float timer",
+                    "if ( state == 1 )",
+                    "  set DayCount to DayCount + 1",
+                    "  set tiemr to 0",
+                    "  player->AddItem gold_002 1",
+                    "  FlyAway",
+                    "  set state to GetFlightSpeed player + GetSecondsPassed",
+                    "endif",
+                ])),
+            ]),
+            record(INFO, vec![
+                (ONAM, Field::StringZ("fargoth".into())),
+                (BNAM, lines(&[
+                    "set state to 1",
+                    "set other to 2",
+                    "AddItem gold_001 1",
+                    "set fargoth.State to 2",
+                    "set fargoth.mood to 2",
+                    "set gold_001.count to 1",
+                ])),
+            ]),
+        ];
+        let lints = lint_records(&records, functions());
+        assert_eq!(lints.iter().map(|(i, x)| format!("{} {}", i, x)).collect::<Vec<_>>(), vec![
+            "3 SCHD declares 2/0/0 short/long/float variables, but the source declares 2/0/1",
+            "3 line 3: variable 'unused' is never used",
+            "3 line 4: variable 'timer' is never used",
+            "3 line 7: undeclared variable 'tiemr'",
+            "3 line 8: unknown ID 'gold_002'",
+            "3 line 9: unknown function 'FlyAway'",
+            "3 line 10: unknown function 'GetFlightSpeed'",
+            "3 line 11: missing End",
+            "4 line 2: undeclared variable 'other'",
+            "4 line 5: undeclared variable 'fargoth.mood'",
+            "4 line 6: undeclared variable 'gold_001.count'",
+        ]);
+        let lints = lint_records(&records, ScriptFunctions::default());
+        assert!(lints.iter().all(|(_, x)| !matches!(x.kind, LintKind::UnknownFunction(_))));
+        assert!(lints.iter().any(|(_, x)| x.kind == LintKind::UndeclaredVariable("tiemr".into())));
+    }
+}
@@ -0,0 +1,459 @@
+use crate::script::*;
+use crate::script_compiler::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Arg {
+    Word(String),
+    String(String),
+    Number(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Call {
+    pub reference: Option<String>,
+    pub function: String,
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Expr {
+    Number(String),
+    Var(String),
+    Call(Call),
+    Binary { operator: String, left: Box<Expr>, right: Box<Expr> },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Branch {
+    pub line: usize,
+    pub condition: Expr,
+    pub body: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Statement {
+    Declare { var_type: ScriptVarType, name: String },
+    Set { target: String, value: Expr },
+    If { branches: Vec<Branch>, otherwise: Option<Vec<Node>> },
+    While { condition: Expr, body: Vec<Node> },
+    Return,
+    Call(Call),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Node {
+    pub line: usize,
+    pub statement: Statement,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct ScriptAst {
+    pub name: Option<String>,
+    pub body: Vec<Node>,
+    pub end: Option<usize>,
+}
+
+fn keyword(tokens: &[Token]) -> String {
+    match tokens.first() {
+        Some(Token::Word(word)) => word.to_lowercase(),
+        _ => String::new()
+    }
+}
+
+fn binary_operator(token: Option<&Token>, operators: &[&str]) -> Option<&'static str> {
+    match token {
+        Some(Token::Operator(operator)) if operators.contains(operator) => Some(operator),
+        _ => None
+    }
+}
+
+struct ExprParser<'a> {
+    functions: &'a ScriptFunctions,
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&'a Token> { self.tokens.get(self.position) }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.additive()?;
+        if let Some(operator) = binary_operator(self.peek(), &["==", "!=", "<", "<=", ">", ">="]) {
+            self.position += 1;
+            let right = self.additive()?;
+            return Ok(Expr::Binary { operator: operator.into(), left: Box::new(left), right: Box::new(right) });
+        }
+        Ok(left)
+    }
+
+    fn additive(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        while let Some(operator) = binary_operator(self.peek(), &["+", "-"]) {
+            self.position += 1;
+            let right = self.term()?;
+            left = Expr::Binary { operator: operator.into(), left: Box::new(left), right: Box::new(right) };
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.primary()?;
+        while let Some(operator) = binary_operator(self.peek(), &["*", "/"]) {
+            self.position += 1;
+            let right = self.primary()?;
+            left = Expr::Binary { operator: operator.into(), left: Box::new(left), right: Box::new(right) };
+        }
+        Ok(left)
+    }
+
+    fn args(&mut self, function: &str) -> Vec<Arg> {
+        let count = self.functions.by_name(function).map(|x| x.args.len());
+        let mut args = Vec::new();
+        while count != Some(args.len()) {
+            match self.peek() {
+                Some(Token::Word(word)) if is_number(word) => args.push(Arg::Number(word.clone())),
+                Some(Token::Word(word)) => args.push(Arg::Word(word.clone())),
+                Some(Token::String(string)) => args.push(Arg::String(string.clone())),
+                _ => break
+            }
+            self.position += 1;
+        }
+        args
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Operator("(")) => {
+                let expr = self.comparison()?;
+                match self.next() {
+                    Some(Token::Operator(")")) => Ok(expr),
+                    _ => Err("missing ')'".into())
+                }
+            },
+            Some(Token::Operator("-")) => match self.next() {
+                Some(Token::Word(number)) if is_number(number) => Ok(Expr::Number(format!("-{}", number))),
+                _ => Err("unary minus is supported for numbers only".into())
+            },
+            Some(Token::Word(word)) | Some(Token::String(word)) if self.peek() == Some(&Token::Operator("->")) => {
+                self.position += 1;
+                match self.next() {
+                    Some(Token::Word(function)) => Ok(Expr::Call(Call {
+                        reference: Some(word.clone()),
+                        function: function.clone(),
+                        args: self.args(function)
+                    })),
+                    _ => Err(format!("function name expected after '{}->'", word))
+                }
+            },
+            Some(Token::Word(word)) if is_number(word) => Ok(Expr::Number(word.clone())),
+            Some(Token::Word(word)) if self.functions.by_name(word).is_some() ||
+                matches!(self.peek(), Some(Token::Word(_)) | Some(Token::String(_))) =>
+                Ok(Expr::Call(Call { reference: None, function: word.clone(), args: self.args(word) })),
+            Some(Token::Word(word)) => Ok(Expr::Var(word.clone())),
+            Some(token) => Err(format!("unexpected '{}' in expression", token)),
+            None => Err("incomplete expression".into())
+        }
+    }
+}
+
+fn parse_expr(tokens: &[Token], functions: &ScriptFunctions) -> Result<Expr, String> {
+    let mut parser = ExprParser { functions, tokens, position: 0 };
+    let expr = parser.comparison()?;
+    if let Some(token) = parser.peek() {
+        return Err(format!("unexpected '{}' in expression", token));
+    }
+    Ok(expr)
+}
+
+fn parse_call(tokens: &[Token]) -> Result<Call, String> {
+    let (reference, tokens) = match tokens {
+        [Token::Word(reference), Token::Operator("->"), rest @ ..] |
+        [Token::String(reference), Token::Operator("->"), rest @ ..] => (Some(reference.clone()), rest),
+        _ => (None, tokens)
+    };
+    let function = match tokens.first() {
+        Some(Token::Word(function)) => function.clone(),
+        Some(token) => return Err(format!("unexpected '{}'", token)),
+        None => return Err("function name expected".into())
+    };
+    let mut args = Vec::new();
+    let mut tokens = tokens[1 ..].iter();
+    while let Some(token) = tokens.next() {
+        args.push(match token {
+            Token::Word(word) if is_number(word) => Arg::Number(word.clone()),
+            Token::Word(word) => Arg::Word(word.clone()),
+            Token::String(string) => Arg::String(string.clone()),
+            Token::Operator("-") => match tokens.next() {
+                Some(Token::Word(number)) if is_number(number) => Arg::Number(format!("-{}", number)),
+                _ => return Err(format!("unexpected '-' in {} arguments", function))
+            },
+            token => return Err(format!("unexpected '{}' in {} arguments", token, function))
+        });
+    }
+    Ok(Call { reference, function, args })
+}
+
+struct Parser<'a> {
+    functions: &'a ScriptFunctions,
+    lines: Vec<(usize, Vec<Token>)>,
+    position: usize,
+    errors: Vec<CompileError>,
+}
+
+type Terminator = (usize, String, Vec<Token>);
+
+const BLOCK_KEYWORDS: &[&str] = &["elseif", "else", "endif", "endwhile"];
+
+impl<'a> Parser<'a> {
+    fn error(&mut self, line: usize, message: impl Into<String>) {
+        self.errors.push(CompileError { line, message: message.into() });
+    }
+
+    fn block(&mut self, terminators: &[&str]) -> (Vec<Node>, Option<Terminator>) {
+        let mut nodes = Vec::new();
+        while let Some((line, tokens)) = self.lines.get(self.position).cloned() {
+            self.position += 1;
+            let keyword = keyword(&tokens);
+            if terminators.contains(&keyword.as_str()) {
+                return (nodes, Some((line, keyword, tokens)));
+            }
+            if keyword == "end" {
+                self.position -= 1;
+                return (nodes, None);
+            }
+            if BLOCK_KEYWORDS.contains(&keyword.as_str()) {
+                self.error(line, format!("unexpected {}", keyword));
+                continue;
+            }
+            match self.statement(line, &keyword, &tokens) {
+                Ok(Some(statement)) => nodes.push(Node { line, statement }),
+                Ok(None) => { },
+                Err(message) => self.error(line, message)
+            }
+        }
+        (nodes, None)
+    }
+
+    fn condition(&mut self, line: usize, tokens: &[Token]) -> Expr {
+        parse_expr(&tokens[1 ..], self.functions).unwrap_or_else(|message| {
+            self.error(line, message);
+            Expr::Number("0".into())
+        })
+    }
+
+    fn statement(&mut self, line: usize, keyword: &str, tokens: &[Token]) -> Result<Option<Statement>, String> {
+        Ok(Some(match keyword {
+            "begin" => return Err("unexpected Begin".into()),
+            "short" | "long" | "float" => {
+                let var_type = match keyword {
+                    "short" => ScriptVarType::Short,
+                    "long" => ScriptVarType::Long,
+                    _ => ScriptVarType::Float,
+                };
+                match &tokens[1 ..] {
+                    [Token::Word(name)] => Statement::Declare { var_type, name: name.clone() },
+                    _ => return Err(format!("{} declaration expects a single variable name", keyword))
+                }
+            },
+            "set" => {
+                let to = tokens.iter().position(|x| matches!(x, Token::Word(w) if is_keyword(w, "to")))
+                    .ok_or_else(|| "set statement expects 'to'".to_string())?;
+                let target = match &tokens[1 .. to] {
+                    [Token::Word(name)] => name.clone(),
+                    _ => return Err("set statement expects a variable name".into())
+                };
+                Statement::Set { target, value: parse_expr(&tokens[to + 1 ..], self.functions)? }
+            },
+            "return" => Statement::Return,
+            "if" => {
+                let mut branches = Vec::new();
+                let mut otherwise = None;
+                let mut branch = (line, self.condition(line, tokens));
+                loop {
+                    let (body, terminator) = self.block(&["elseif", "else", "endif"]);
+                    if otherwise.is_some() {
+                        otherwise = Some(body);
+                    } else {
+                        branches.push(Branch { line: branch.0, condition: branch.1.clone(), body });
+                    }
+                    match terminator {
+                        Some((next, keyword, tokens)) if keyword == "elseif" && otherwise.is_none() =>
+                            branch = (next, self.condition(next, &tokens)),
+                        Some((_, keyword, _)) if keyword == "else" && otherwise.is_none() =>
+                            otherwise = Some(Vec::new()),
+                        Some((_, keyword, _)) if keyword == "endif" => break,
+                        Some((next, keyword, _)) => self.error(next, format!("unexpected {}", keyword)),
+                        None => {
+                            self.error(line, format!("missing endif for block started at line {}", line));
+                            break;
+                        }
+                    }
+                }
+                Statement::If { branches, otherwise }
+            },
+            "while" => {
+                let condition = self.condition(line, tokens);
+                let (body, terminator) = self.block(&["endwhile"]);
+                if terminator.is_none() {
+                    self.error(line, format!("missing endwhile for block started at line {}", line));
+                }
+                Statement::While { condition, body }
+            },
+            _ => Statement::Call(parse_call(tokens)?)
+        }))
+    }
+}
+
+fn tokenize_lines<S: AsRef<str>>(source: &[S], errors: &mut Vec<CompileError>) -> Vec<(usize, Vec<Token>)> {
+    source.iter().enumerate().filter_map(|(index, text)| match tokenize(text.as_ref()) {
+        Ok(tokens) if tokens.is_empty() => None,
+        Ok(tokens) => Some((index + 1, tokens)),
+        Err(message) => {
+            errors.push(CompileError { line: index + 1, message });
+            None
+        }
+    }).collect()
+}
+
+pub fn parse_script<S: AsRef<str>>(source: &[S], functions: &ScriptFunctions) -> (ScriptAst, Vec<CompileError>) {
+    let mut errors = Vec::new();
+    let lines = tokenize_lines(source, &mut errors);
+    let mut parser = Parser { functions, lines, position: 0, errors };
+    let mut ast = ScriptAst::default();
+    match parser.lines.first().cloned() {
+        Some((line, tokens)) if keyword(&tokens) == "begin" => {
+            parser.position = 1;
+            match &tokens[1 ..] {
+                [Token::Word(name)] | [Token::String(name)] => ast.name = Some(name.clone()),
+                _ => parser.error(line, "Begin expects a script name")
+            }
+        },
+        first => parser.error(first.map_or(1, |x| x.0), "script should start with Begin <name>"),
+    }
+    loop {
+        let (body, _) = parser.block(&[]);
+        ast.body.extend(body);
+        match parser.lines.get(parser.position) {
+            Some((line, _)) if ast.end.is_none() => ast.end = Some(*line),
+            Some((line, _)) => {
+                let line = *line;
+                parser.error(line, "unexpected End");
+            },
+            None => break
+        }
+        parser.position += 1;
+    }
+    if let Some(end) = ast.end {
+        if let Some((line, _)) = parser.lines.iter().find(|(line, _)| *line > end) {
+            let line = *line;
+            parser.error(line, "statement after End");
+        }
+    }
+    parser.errors.sort_by_key(|x| x.line);
+    (ast, parser.errors)
+}
+
+pub fn parse_result_script<S: AsRef<str>>(source: &[S], functions: &ScriptFunctions) -> (Vec<Node>, Vec<CompileError>) {
+    let mut errors = Vec::new();
+    let lines = tokenize_lines(source, &mut errors);
+    let mut parser = Parser { functions, lines, position: 0, errors };
+    let mut nodes = Vec::new();
+    loop {
+        let (body, _) = parser.block(&[]);
+        nodes.extend(body);
+        match parser.lines.get(parser.position) {
+            Some((line, _)) => {
+                let line = *line;
+                parser.error(line, "unexpected End");
+            },
+            None => break
+        }
+        parser.position += 1;
+    }
+    parser.errors.sort_by_key(|x| x.line);
+    (nodes, parser.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::tests::functions;
+
+    fn var(name: &str) -> Box<Expr> { Box::new(Expr::Var(name.into())) }
+
+    fn number(n: &str) -> Box<Expr> { Box::new(Expr::Number(n.into())) }
+
+    #[test]
+    fn parse_script_ast() {
+        let (ast, errors) = parse_script(&[
+            "Begin test",
+            "short a",
+            "if ( a + 1 * b == -2 )",
+            "  player->AddItem \"gold_001\" 10",
+            "elseif ( GetJournalIndex A1_Ring > 5 )",
+            "  set a to ( a + 1 ) / 2",
+            "else",
+            "  while ( a < 3 )",
+            "    set a to a + 1",
+            "  endwhile",
+            "endif",
+            "End",
+        ], &functions());
+        assert!(errors.is_empty());
+        assert_eq!(ast.name.as_deref(), Some("test"));
+        assert_eq!(ast.end, Some(12));
+        assert_eq!(ast.body[0], Node { line: 2, statement: Statement::Declare { var_type: ScriptVarType::Short, name: "a".into() } });
+        let (branches, otherwise) = match &ast.body[1].statement {
+            Statement::If { branches, otherwise } => (branches, otherwise.as_ref().unwrap()),
+            _ => panic!()
+        };
+        assert_eq!(branches[0].condition, Expr::Binary {
+            operator: "==".into(),
+            left: Box::new(Expr::Binary {
+                operator: "+".into(),
+                left: var("a"),
+                right: Box::new(Expr::Binary { operator: "*".into(), left: number("1"), right: var("b") })
+            }),
+            right: number("-2")
+        });
+        assert_eq!(branches[0].body[0].statement, Statement::Call(Call {
+            reference: Some("player".into()),
+            function: "AddItem".into(),
+            args: vec![Arg::String("gold_001".into()), Arg::Number("10".into())]
+        }));
+        assert_eq!(branches[1].line, 5);
+        assert_eq!(branches[1].condition, Expr::Binary {
+            operator: ">".into(),
+            left: Box::new(Expr::Call(Call {
+                reference: None,
+                function: "GetJournalIndex".into(),
+                args: vec![Arg::Word("A1_Ring".into())]
+            })),
+            right: number("5")
+        });
+        assert!(matches!(&otherwise[0].statement, Statement::While { body, .. } if body.len() == 1));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let (ast, errors) = parse_script(&["Begin test", "if ( x == 1", "endwhile", "set x 1"], &functions());
+        assert_eq!(ast.end, None);
+        assert_eq!(errors.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec![
+            "line 2: missing ')'",
+            "line 2: missing endif for block started at line 2",
+            "line 3: unexpected endwhile",
+            "line 4: set statement expects 'to'",
+        ]);
+        let (nodes, errors) = parse_result_script(&["Journal A1_Ring 10", "Goodbye", "End"], &functions());
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(errors, vec![CompileError { line: 3, message: "unexpected End".into() }]);
+        let (_, errors) = parse_script(&["; header", "", "Begin", "End"], &functions());
+        assert_eq!(errors, vec![CompileError { line: 3, message: "Begin expects a script name".into() }]);
+    }
+}
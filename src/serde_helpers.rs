@@ -3,10 +3,12 @@ use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use serde::de::{self, Unexpected, SeqAccess};
 use serde::de::Error as de_Error;
 use serde::ser::Error as ser_Error;
-use serde::ser::{SerializeTuple, SerializeSeq};
+use serde::ser::{SerializeSeq, SerializeTupleStruct};
 use either::{Either, Left,  Right};
 use std::str::FromStr;
 
+pub(crate) const FIXED_STRING: &str = "$esl::FixedString";
+
 pub fn serialize_none_u8<S>(none: u8, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
     if serializer.is_human_readable() {
         serializer.serialize_unit()
@@ -234,18 +236,11 @@ pub fn serialize_string_tuple<S>(s: &str, len: usize, serializer: S) -> Result<S
         if s.as_bytes().last().map_or(false, |&x| x == 0) {
             return Err(S::Error::custom("string tuple value has tail zero"));
         }
-        let mut serializer = serializer.serialize_tuple(len)?;
-        let mut s_len = 0;
-        for c in s.chars() {
-            serializer.serialize_element(&c)?;
-            s_len += 1;
-        }
-        if s_len > len {
+        if s.chars().count() > len {
             return Err(S::Error::custom(&format!("string length is above {} chars", len)));
         }
-        for _ in s_len .. len {
-            serializer.serialize_element(&'\0')?;
-        }
+        let mut serializer = serializer.serialize_tuple_struct(FIXED_STRING, len)?;
+        serializer.serialize_field(s)?;
         serializer.end()
     }
 }
@@ -310,26 +305,14 @@ pub fn serialize_string_list<S>(lines: &[String], separator: &str, len: Option<u
             if text.as_bytes().last().map_or(false, |&x| x == 0) {
                 return Err(S::Error::custom("string list has tail zero"));
             }
-            let mut serializer = serializer.serialize_tuple(len)?;
-            let mut text_len = 0;
-            for c in text.chars() {
-                serializer.serialize_element(&c)?;
-                text_len += 1;
-            }
-            if text_len > len {
+            if text.chars().count() > len {
                 return Err(S::Error::custom(&format!("string list total length is above {} chars", len)));
             }
-            for _ in text_len .. len {
-                serializer.serialize_element(&'\0')?;
-            }
+            let mut serializer = serializer.serialize_tuple_struct(FIXED_STRING, len)?;
+            serializer.serialize_field(&text)?;
             serializer.end()
         } else {
-            let text_len = text.chars().count();
-            let mut serializer = serializer.serialize_seq(Some(text_len))?;
-            for c in text.chars() {
-                serializer.serialize_element(&c)?;
-            }
-            serializer.end()
+            serializer.serialize_str(&text)
         }
     }
 }
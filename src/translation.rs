@@ -0,0 +1,474 @@
+use encoding::EncoderTrap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Write};
+
+use crate::cell::CellData;
+use crate::code::CodePage;
+use crate::dialogue::record_string;
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TranslationUnit {
+    pub key: String,
+    pub record_index: usize,
+    pub field_index: usize,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TranslationError {
+    Po { line: usize, message: String },
+    Xliff { offset: usize, message: String },
+}
+
+impl Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranslationError::Po { line, message } => write!(f, "PO line {}: {}", line, message),
+            TranslationError::Xliff { offset, message } => write!(f, "XLIFF offset {}: {}", offset, message),
+        }
+    }
+}
+
+impl Error for TranslationError { }
+
+fn field_text(field: &Field) -> Option<String> {
+    match field {
+        Field::StringZ(s) => Some(s.string.clone()),
+        Field::String(s) => Some(s.clone()),
+        Field::StringList(lines) => Some(lines.join("\n")),
+        _ => None
+    }
+}
+
+fn set_field_text(field: &mut Field, text: &str) {
+    match field {
+        Field::StringZ(s) => s.string = text.to_string(),
+        Field::String(s) => *s = text.to_string(),
+        Field::StringList(lines) => *lines = text.split('\n').map(String::from).collect(),
+        _ => { }
+    }
+}
+
+fn is_translatable(record_tag: Tag, field_tag: Tag) -> bool {
+    match (record_tag, field_tag) {
+        (INFO, FNAM) | (GLOB, FNAM) | (PCDT, FNAM) => false,
+        (_, FNAM) => true,
+        (INFO, NAME) | (JOUR, NAME) => true,
+        (BOOK, TEXT) => true,
+        (GMST, STRV) => true,
+        _ => false
+    }
+}
+
+fn record_id(record: &Record, topic: &Option<String>) -> Option<String> {
+    let string = |field_tag| record_string(record, field_tag).map(String::from);
+    match record.tag {
+        INFO => Some(format!("{}:{}", topic.as_ref()?, string(INAM)?)),
+        JOUR => Some(String::new()),
+        CELL => CellData::from_fields(&record.fields).grid().map(|grid| format!("{},{}", grid.x, grid.y)),
+        _ => string(NAME)
+    }
+}
+
+fn translatable_fields(record: &Record) -> Vec<(usize, &(Tag, Field))> {
+    if record.tag == CELL {
+        return record.fields.iter().enumerate().find(|(_, (tag, _))| *tag == NAME).into_iter().collect();
+    }
+    record.fields.iter().enumerate().filter(|(_, (tag, _))| is_translatable(record.tag, *tag)).collect()
+}
+
+pub fn extract_translation_units<'a>(records: impl IntoIterator<Item=&'a Record>) -> Vec<TranslationUnit> {
+    let mut units = Vec::new();
+    let mut topic = None;
+    for (record_index, record) in records.into_iter().enumerate() {
+        if record.tag == DIAL {
+            topic = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+                (NAME, Field::StringZ(s)) => Some(s.string.clone()),
+                _ => None
+            });
+        } else if record.tag != INFO {
+            topic = None;
+        }
+        if record.flags.contains(RecordFlags::DELETED) { continue; }
+        let id = if let Some(id) = record_id(record, &topic) { id } else { continue; };
+        let mut occurrences = HashMap::new();
+        for (field_index, (tag, field)) in translatable_fields(record) {
+            let occurrence = occurrences.entry(*tag).or_insert(0);
+            *occurrence += 1;
+            let source = if let Some(source) = field_text(field) { source } else { continue; };
+            if source.is_empty() { continue; }
+            let key = if *occurrence == 1 {
+                format!("{}:{}:{}", record.tag, id, tag)
+            } else {
+                format!("{}:{}:{}:{}", record.tag, id, tag, occurrence)
+            };
+            units.push(TranslationUnit { key, record_index, field_index, source });
+        }
+    }
+    units
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ApplyReport {
+    pub applied: usize,
+    pub unknown_keys: Vec<String>,
+    pub unencodable: Vec<String>,
+}
+
+pub fn apply_translations(records: &mut [Record], translations: &HashMap<String, String>, check_code_page: CodePage)
+    -> ApplyReport {
+
+    let units = extract_translation_units(records.iter());
+    let mut report = ApplyReport::default();
+    let mut known = HashMap::new();
+    for unit in &units {
+        known.entry(unit.key.as_str()).or_insert_with(Vec::new).push(unit);
+    }
+    let mut keys = translations.keys().collect::<Vec<_>>();
+    keys.sort();
+    for key in keys {
+        let key_units = if let Some(key_units) = known.get(key.as_str()) { key_units } else {
+            report.unknown_keys.push(key.clone());
+            continue;
+        };
+        let translation = &translations[key];
+        if check_code_page.encoding().encode(translation, EncoderTrap::Strict).is_err() {
+            report.unencodable.push(key.clone());
+            continue;
+        }
+        for unit in key_units {
+            set_field_text(&mut records[unit.record_index].fields[unit.field_index].1, translation);
+        }
+        report.applied += 1;
+    }
+    report
+}
+
+fn po_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn po_string(text: &mut String, keyword: &str, s: &str) {
+    if !s.contains('\n') || (s.ends_with('\n') && s.matches('\n').count() == 1) {
+        writeln!(text, "{} \"{}\"", keyword, po_escape(s)).unwrap();
+        return;
+    }
+    writeln!(text, "{} \"\"", keyword).unwrap();
+    for line in s.split_inclusive('\n') {
+        writeln!(text, "\"{}\"", po_escape(line)).unwrap();
+    }
+}
+
+pub fn to_po(units: &[TranslationUnit], translations: &HashMap<String, String>) -> String {
+    let mut text = String::new();
+    writeln!(text, "msgid \"\"").unwrap();
+    writeln!(text, "msgstr \"\"").unwrap();
+    writeln!(text, "\"Content-Type: text/plain; charset=UTF-8\\n\"").unwrap();
+    for unit in units {
+        writeln!(text).unwrap();
+        po_string(&mut text, "msgctxt", &unit.key);
+        po_string(&mut text, "msgid", &unit.source);
+        po_string(&mut text, "msgstr", translations.get(&unit.key).map_or("", |x| x.as_str()));
+    }
+    text
+}
+
+fn po_unescape(s: &str, line: usize) -> Result<String, TranslationError> {
+    let error = |message: &str| TranslationError::Po { line, message: message.into() };
+    let s = s.strip_prefix('"').and_then(|x| x.strip_suffix('"')).ok_or_else(|| error("quoted string expected"))?;
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next() {
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            _ => return Err(error("invalid escape sequence"))
+        });
+    }
+    Ok(unescaped)
+}
+
+#[derive(Default)]
+struct PoEntry {
+    context: Option<String>,
+    id: Option<String>,
+    translation: Option<String>,
+    fuzzy: bool,
+}
+
+pub fn from_po(text: &str) -> Result<HashMap<String, String>, TranslationError> {
+    let mut translations = HashMap::new();
+    let mut entry = PoEntry::default();
+    let mut current: Option<&str> = None;
+    let mut finish = |entry: &mut PoEntry| {
+        let entry = std::mem::take(entry);
+        if let (Some(context), Some(translation)) = (entry.context, entry.translation) {
+            if !translation.is_empty() && !entry.fuzzy {
+                translations.insert(context, translation);
+            }
+        }
+    };
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            if entry.id.is_some() || entry.translation.is_some() {
+                finish(&mut entry);
+                current = None;
+            }
+            if comment.starts_with(',') && comment.contains("fuzzy") {
+                entry.fuzzy = true;
+            }
+            continue;
+        }
+        if line.starts_with('"') {
+            let value = po_unescape(line, line_number)?;
+            let target = match current {
+                Some("msgctxt") => &mut entry.context,
+                Some("msgid") => &mut entry.id,
+                Some("msgstr") => &mut entry.translation,
+                _ => return Err(TranslationError::Po { line: line_number, message: "unexpected string".into() })
+            };
+            target.get_or_insert_with(String::new).push_str(&value);
+            continue;
+        }
+        let (keyword, value) = match line.find(char::is_whitespace) {
+            Some(space) => (&line[.. space], line[space ..].trim_start()),
+            None => return Err(TranslationError::Po { line: line_number, message: "keyword and string expected".into() })
+        };
+        let value = po_unescape(value, line_number)?;
+        let starts_entry = keyword == "msgctxt" || (keyword == "msgid" && entry.context.is_none());
+        if starts_entry && (entry.id.is_some() || entry.translation.is_some()) {
+            finish(&mut entry);
+        }
+        let (keyword, target) = match keyword {
+            "msgctxt" => ("msgctxt", &mut entry.context),
+            "msgid" => ("msgid", &mut entry.id),
+            "msgstr" => ("msgstr", &mut entry.translation),
+            keyword => return Err(TranslationError::Po { line: line_number, message: format!("unknown keyword '{}'", keyword) })
+        };
+        *target = Some(value);
+        current = Some(keyword);
+    }
+    finish(&mut entry);
+    Ok(translations)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn xml_unescape(s: &str, offset: usize) -> Result<String, TranslationError> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[.. amp]);
+        rest = &rest[amp ..];
+        let end = rest.find(';').ok_or_else(|| TranslationError::Xliff { offset, message: "unterminated entity".into() })?;
+        let entity = &rest[1 .. end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => if let Some(hex) = entity.strip_prefix("#x") {
+                u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32)
+            } else if let Some(decimal) = entity.strip_prefix('#') {
+                decimal.parse().ok().and_then(std::char::from_u32)
+            } else {
+                None
+            }
+        };
+        let c = c.ok_or_else(|| TranslationError::Xliff { offset, message: format!("unknown entity '&{};'", entity) })?;
+        unescaped.push(c);
+        rest = &rest[end + 1 ..];
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+pub fn to_xliff(units: &[TranslationUnit], translations: &HashMap<String, String>, source_language: &str,
+    target_language: &str) -> String {
+
+    let mut text = String::new();
+    writeln!(text, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(text, "<xliff version=\"1.2\" xmlns=\"urn:oasis:names:tc:xliff:document:1.2\">").unwrap();
+    writeln!(text, "  <file original=\"plugin\" datatype=\"plaintext\" source-language=\"{}\" target-language=\"{}\">",
+        xml_escape(source_language), xml_escape(target_language)).unwrap();
+    writeln!(text, "    <body>").unwrap();
+    for unit in units {
+        writeln!(text, "      <trans-unit id=\"{}\">", xml_escape(&unit.key)).unwrap();
+        writeln!(text, "        <source>{}</source>", xml_escape(&unit.source)).unwrap();
+        if let Some(translation) = translations.get(&unit.key) {
+            writeln!(text, "        <target>{}</target>", xml_escape(translation)).unwrap();
+        }
+        writeln!(text, "      </trans-unit>").unwrap();
+    }
+    writeln!(text, "    </body>").unwrap();
+    writeln!(text, "  </file>").unwrap();
+    writeln!(text, "</xliff>").unwrap();
+    text
+}
+
+fn xml_element<'a>(text: &'a str, name: &str) -> Option<(usize, &'a str, &'a str)> {
+    let open = format!("<{}", name);
+    let mut search = 0;
+    loop {
+        let start = search + text[search ..].find(&open)?;
+        let after = &text[start + open.len() ..];
+        if !after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()) {
+            search = start + open.len();
+            continue;
+        }
+        let tag_end = after.find('>')?;
+        let attributes = &after[.. tag_end];
+        if attributes.ends_with('/') {
+            return Some((start, attributes.trim_end_matches('/'), ""));
+        }
+        let content = &after[tag_end + 1 ..];
+        let close = content.find(&format!("</{}>", name))?;
+        return Some((start, attributes, &content[.. close]));
+    }
+}
+
+fn xml_attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!("{}=\"", name);
+    let mut search = 0;
+    loop {
+        let start = search + attributes[search ..].find(&pattern)?;
+        if start == 0 || attributes[.. start].ends_with(char::is_whitespace) {
+            let value = &attributes[start + pattern.len() ..];
+            return Some(&value[.. value.find('"')?]);
+        }
+        search = start + pattern.len();
+    }
+}
+
+pub fn from_xliff(text: &str) -> Result<HashMap<String, String>, TranslationError> {
+    let mut translations = HashMap::new();
+    let mut offset = 0;
+    while let Some((start, attributes, content)) = xml_element(&text[offset ..], "trans-unit") {
+        let unit_offset = offset + start;
+        let id = xml_attribute(attributes, "id")
+            .ok_or_else(|| TranslationError::Xliff { offset: unit_offset, message: "trans-unit without id".into() })?;
+        let id = xml_unescape(id, unit_offset)?;
+        if let Some((_, _, target)) = xml_element(content, "target") {
+            let target = xml_unescape(target, unit_offset)?;
+            if !target.is_empty() {
+                translations.insert(id, target);
+            }
+        }
+        let consumed = (content.as_ptr() as usize - text[offset ..].as_ptr() as usize) + content.len();
+        offset += consumed;
+    }
+    if translations.is_empty() && !text.contains("<xliff") {
+        return Err(TranslationError::Xliff { offset: 0, message: "xliff element expected".into() });
+    }
+    Ok(translations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        let record = |tag, fields| Record { tag, flags: RecordFlags::empty(), fields };
+        vec![
+            record(WEAP, vec![(NAME, Field::StringZ("iron dagger".into())), (FNAM, Field::StringZ("Iron Dagger".into()))]),
+            record(GMST, vec![(NAME, Field::String("sYes".into())), (STRV, Field::String("Yes".into()))]),
+            record(DIAL, vec![(NAME, Field::StringZ("Background".into())), (DATA, Field::DialogType(DialogType::Topic))]),
+            record(INFO, vec![
+                (INAM, Field::StringZ("123".into())),
+                (FNAM, Field::StringZ("Hlaalu".into())),
+                (NAME, Field::String("I was \"born\" here.".into())),
+            ]),
+            record(BOOK, vec![(NAME, Field::StringZ("bk_a".into())), (TEXT, Field::StringList(vec!["Line 1<BR>".into(), "Line 2".into()]))]),
+            record(CELL, vec![
+                (NAME, Field::StringZ("Tradehouse".into())),
+                (DATA, Field::Cell(Cell { flags: CellFlags::INTERIOR, grid: Grid { x: 0, y: 0 } })),
+                (FRMR, Field::I32(1)),
+                (NAME, Field::StringZ("chest".into())),
+                (FRMR, Field::I32(2)),
+                (NAME, Field::StringZ("barrel".into())),
+            ]),
+            record(CELL, vec![
+                (NAME, Field::StringZ("Seyda Neen".into())),
+                (DATA, Field::Cell(Cell { flags: CellFlags::empty(), grid: Grid { x: -2, y: -9 } })),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn po_round_trip() {
+        let mut records = records();
+        let units = extract_translation_units(&records);
+        assert_eq!(units.iter().map(|x| x.key.as_str()).collect::<Vec<_>>(), vec![
+            "WEAP:iron dagger:FNAM", "GMST:sYes:STRV", "INFO:Background:123:NAME", "BOOK:bk_a:TEXT", "CELL:-2,-9:NAME"
+        ]);
+        let po = to_po(&units, &HashMap::new());
+        assert!(po.contains("msgctxt \"INFO:Background:123:NAME\"\nmsgid \"I was \\\"born\\\" here.\"\nmsgstr \"\"\n"));
+        assert!(po.contains("msgid \"\"\n\"Line 1<BR>\\n\"\n\"Line 2\"\n"));
+        let translated = po
+            .replace("msgid \"Yes\"\nmsgstr \"\"", "msgid \"Yes\"\nmsgstr \"Да\"")
+            .replace("msgid \"Iron Dagger\"\nmsgstr \"\"", "msgid \"Iron Dagger\"\nmsgstr \"\"\n\"Железный \"\n\"кинжал\"")
+            .replace("\"Line 2\"\nmsgstr \"\"", "\"Line 2\"\nmsgstr \"\"\n\"Строка 1<BR>\\n\"\n\"Строка 2\"")
+            .replace("msgid \"Seyda Neen\"\nmsgstr \"\"", "msgid \"Seyda Neen\"\nmsgstr \"Сейда Нин\"");
+        let translations = from_po(&translated).unwrap();
+        assert_eq!(translations.len(), 4);
+        assert_eq!(translations["WEAP:iron dagger:FNAM"], "Железный кинжал");
+        let report = apply_translations(&mut records, &translations, CodePage::English);
+        assert_eq!(report.applied, 0);
+        assert_eq!(report.unencodable.len(), 4);
+        let report = apply_translations(&mut records, &translations, CodePage::Russian);
+        assert_eq!(report, ApplyReport { applied: 4, unknown_keys: Vec::new(), unencodable: Vec::new() });
+        assert_eq!(records[2].fields[0].1, Field::StringZ("Background".into()));
+        assert_eq!(records[5].fields[0].1, Field::StringZ("Tradehouse".into()));
+        assert_eq!(records[6].fields[0].1, Field::StringZ("Сейда Нин".into()));
+        assert_eq!(records[1].fields[1].1, Field::String("Да".into()));
+        assert_eq!(records[4].fields[1].1, Field::StringList(vec!["Строка 1<BR>".into(), "Строка 2".into()]));
+        let mut unknown = HashMap::new();
+        unknown.insert("MISC:nothing:FNAM".to_string(), "x".to_string());
+        unknown.insert("CELL:Tradehouse:NAME".to_string(), "x".to_string());
+        assert_eq!(apply_translations(&mut records, &unknown, CodePage::Russian).unknown_keys,
+            vec!["CELL:Tradehouse:NAME", "MISC:nothing:FNAM"]);
+        assert!(matches!(from_po("msgid \"a\nmsgstr \"\""), Err(TranslationError::Po { line: 1, .. })));
+    }
+
+    #[test]
+    fn xliff_round_trip() {
+        let records = records();
+        let units = extract_translation_units(&records);
+        let mut translations = HashMap::new();
+        translations.insert("INFO:Background:123:NAME".to_string(), "Я \"родился\" <здесь> & там.".to_string());
+        let xliff = to_xliff(&units, &translations, "en", "ru");
+        assert!(xliff.contains("<trans-unit id=\"INFO:Background:123:NAME\">\n        <source>I was &quot;born&quot; here.</source>\n"));
+        assert_eq!(from_xliff(&xliff).unwrap(), translations);
+        let xliff = xliff.replace("<source>Yes</source>", "<source>Yes</source><target state=\"translated\">&#1044;&#x430;</target>");
+        assert_eq!(from_xliff(&xliff).unwrap()["GMST:sYes:STRV"], "Да");
+    }
+}
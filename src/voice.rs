@@ -0,0 +1,187 @@
+use either::Right;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::data_files::*;
+use crate::dialogue::{dialog_type, record_string};
+use crate::field::*;
+use crate::record::*;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SpeakerFilters {
+    pub id: Option<String>,
+    pub race: Option<String>,
+    pub class: Option<String>,
+    pub faction: Option<String>,
+    pub cell: Option<String>,
+    pub sex: Option<Sex>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VoicedLine {
+    pub record_index: usize,
+    pub topic: String,
+    pub info_id: String,
+    pub speaker: SpeakerFilters,
+    pub text: String,
+    pub sound: String,
+}
+
+pub fn voiced_lines<'a>(records: impl IntoIterator<Item=&'a Record>) -> Vec<VoicedLine> {
+    let mut lines = Vec::new();
+    let mut topic = None;
+    for (record_index, record) in records.into_iter().enumerate() {
+        match record.tag {
+            DIAL => topic = if dialog_type(record) == Some(DialogType::Voice) {
+                record_string(record, NAME).map(String::from)
+            } else {
+                None
+            },
+            INFO => {
+                if record.flags.contains(RecordFlags::DELETED) { continue; }
+                let topic = if let Some(topic) = &topic { topic } else { continue; };
+                let sound = if let Some(sound) = record_string(record, SNAM) { sound } else { continue; };
+                let filter = |tag| record_string(record, tag).filter(|x| !x.is_empty()).map(String::from);
+                let sex = record.fields.iter().find_map(|(tag, field)| match (*tag, field) {
+                    (DATA, Field::Info(Info { sex: Right(sex), .. })) => Some(*sex),
+                    _ => None
+                });
+                lines.push(VoicedLine {
+                    record_index,
+                    topic: topic.clone(),
+                    info_id: record_string(record, INAM).unwrap_or("").to_string(),
+                    speaker: SpeakerFilters {
+                        id: filter(ONAM),
+                        race: filter(RNAM),
+                        class: filter(CNAM),
+                        faction: filter(FNAM),
+                        cell: filter(ANAM),
+                        sex
+                    },
+                    text: record_string(record, NAME).unwrap_or("").to_string(),
+                    sound: sound.to_string(),
+                });
+            },
+            _ => topic = None
+        }
+    }
+    lines
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VoiceManifest {
+    pub lines: Vec<VoicedLine>,
+    pub missing: Vec<usize>,
+    pub orphaned: Vec<PathBuf>,
+}
+
+impl VoiceManifest {
+    pub fn new<'a>(records: impl IntoIterator<Item=&'a Record>, data_files: &Path) -> io::Result<VoiceManifest> {
+        let lines = voiced_lines(records);
+        let mut files = HashMap::new();
+        let sound = find_dir(data_files, "Sound");
+        if let Some(sound) = &sound {
+            list_files(sound, sound, &mut files)?;
+        }
+        let used = lines.iter().map(|x| normalize_path(&x.sound)).collect::<HashSet<_>>();
+        let missing = lines.iter().enumerate()
+            .filter(|(_, x)| !files.contains_key(&normalize_path(&x.sound)))
+            .map(|(i, _)| i)
+            .collect();
+        let mut orphaned = files.into_iter()
+            .filter(|(path, _)| path.starts_with("vo/") && !used.contains(path))
+            .map(|(_, path)| path)
+            .collect::<Vec<_>>();
+        orphaned.sort();
+        Ok(VoiceManifest { lines, missing, orphaned })
+    }
+
+    pub fn to_csv(&self) -> String {
+        let quote = |s: &str| if s.contains(&[',', '"', '\n', '\r'][..]) {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        } else {
+            s.to_string()
+        };
+        let optional = |s: &Option<String>| quote(s.as_deref().unwrap_or(""));
+        let missing = self.missing.iter().copied().collect::<HashSet<_>>();
+        let mut csv = String::new();
+        writeln!(csv, "topic,info,speaker,race,class,faction,cell,sex,text,sound,status").unwrap();
+        for (index, line) in self.lines.iter().enumerate() {
+            writeln!(csv, "{},{},{},{},{},{},{},{},{},{},{}",
+                quote(&line.topic), quote(&line.info_id),
+                optional(&line.speaker.id), optional(&line.speaker.race), optional(&line.speaker.class),
+                optional(&line.speaker.faction), optional(&line.speaker.cell),
+                line.speaker.sex.map_or(String::new(), |x| x.to_string()),
+                quote(&line.text), quote(&line.sound),
+                if missing.contains(&index) { "missing" } else { "ok" }
+            ).unwrap();
+        }
+        for path in &self.orphaned {
+            writeln!(csv, ",,,,,,,,,{},orphaned", quote(&path.to_string_lossy())).unwrap();
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use either::Left;
+    use std::fs;
+
+    fn voice_info(id: &str, speaker: &str, sound: &str) -> Record {
+        Record {
+            tag: INFO,
+            flags: RecordFlags::empty(),
+            fields: vec![
+                (INAM, Field::StringZ(id.into())),
+                (DATA, Field::Info(Info {
+                    dialog_type: DialogType::Voice, disp_index: 0, rank: None,
+                    sex: Left(None), pc_rank: None, padding: 0
+                })),
+                (ONAM, Field::StringZ(speaker.into())),
+                (NAME, Field::String("Hello, \"outlander\".".into())),
+                (SNAM, Field::StringZ(sound.into())),
+            ]
+        }
+    }
+
+    #[test]
+    fn voice_manifest() {
+        let data_files = std::env::temp_dir().join(format!("esl-voice-manifest-{}", std::process::id()));
+        let voice_dir = data_files.join("Sound").join("Vo").join("d").join("m");
+        fs::create_dir_all(&voice_dir).unwrap();
+        fs::write(voice_dir.join("Hlo_DM001.mp3"), b"").unwrap();
+        fs::write(voice_dir.join("Hlo_DM099.mp3"), b"").unwrap();
+        let records = vec![
+            Record {
+                tag: DIAL,
+                flags: RecordFlags::empty(),
+                fields: vec![(NAME, Field::StringZ("Hello".into())), (DATA, Field::DialogType(DialogType::Voice))]
+            },
+            voice_info("1", "fargoth", "vo\\d\\m\\hlo_dm001.mp3"),
+            voice_info("2", "", "Vo\\d\\m\\Hlo_DM002.mp3"),
+            Record {
+                tag: DIAL,
+                flags: RecordFlags::empty(),
+                fields: vec![(NAME, Field::StringZ("Background".into())), (DATA, Field::DialogType(DialogType::Topic))]
+            },
+            voice_info("3", "fargoth", "vo\\d\\m\\hlo_dm003.mp3"),
+        ];
+        let manifest = VoiceManifest::new(&records, &data_files).unwrap();
+        fs::remove_dir_all(&data_files).unwrap();
+        assert_eq!(manifest.lines.len(), 2);
+        assert_eq!(manifest.lines[0].speaker.id.as_deref(), Some("fargoth"));
+        assert_eq!(manifest.lines[1].speaker.id, None);
+        assert_eq!(manifest.missing, vec![1]);
+        assert_eq!(manifest.orphaned, vec![voice_dir.join("Hlo_DM099.mp3")]);
+        let csv = manifest.to_csv();
+        let rows = csv.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[1], "Hello,1,fargoth,,,,,,\"Hello, \"\"outlander\"\".\",vo\\d\\m\\hlo_dm001.mp3,ok");
+        assert!(rows[2].ends_with(",missing"));
+        assert!(rows[3].ends_with("Hlo_DM099.mp3,orphaned"));
+    }
+}